@@ -0,0 +1,147 @@
+//! Bot liquidating unhealthy lending obligations and margin positions
+
+mod margin;
+mod obligation;
+
+use clap::{crate_description, crate_name, crate_version, value_t, App, Arg};
+use solana_client::rpc_client::RpcClient;
+use solana_program::pubkey::Pubkey;
+use solana_sdk::signature::{read_keypair_file, Keypair};
+use std::{str::FromStr, thread, time::Duration};
+
+/// Liquidator settings shared by every scan
+pub struct Config {
+    /// Signs liquidations and owns the repay and collateral token accounts
+    pub payer: Keypair,
+    /// Lending program to scan obligations of
+    pub lending_program_id: Pubkey,
+    /// Margin program to scan positions of, if any
+    pub margin_program_id: Option<Pubkey>,
+    /// Token swap program margin positions are sold through
+    pub swap_program_id: Pubkey,
+    /// Only report liquidations instead of submitting them
+    pub dry_run: bool,
+    /// Minimum gain of a liquidation, in basis points of the repaid value
+    pub min_profit_bps: i64,
+    /// Largest shortfall of a margin position's sale from its simulation, in basis points
+    pub max_slippage_bps: u64,
+}
+
+fn is_pubkey(value: String) -> Result<(), String> {
+    Pubkey::from_str(&value)
+        .map(|_| ())
+        .map_err(|err| format!("{}", err))
+}
+
+pub fn main() {
+    let matches = App::new(crate_name!())
+        .about(crate_description!())
+        .version(crate_version!())
+        .arg(
+            Arg::with_name("url")
+                .long("url")
+                .value_name("URL")
+                .takes_value(true)
+                .default_value("http://localhost:8899")
+                .help("JSON RPC URL of the cluster"),
+        )
+        .arg(
+            Arg::with_name("keypair")
+                .long("keypair")
+                .value_name("PATH")
+                .takes_value(true)
+                .required(true)
+                .help("Keypair paying for and signing liquidations"),
+        )
+        .arg(
+            Arg::with_name("lending_program_id")
+                .long("lending-program-id")
+                .value_name("PUBKEY")
+                .takes_value(true)
+                .validator(is_pubkey)
+                .help("Lending program id [default: spl_token_lending::id()]"),
+        )
+        .arg(
+            Arg::with_name("margin_program_id")
+                .long("margin-program-id")
+                .value_name("PUBKEY")
+                .takes_value(true)
+                .validator(is_pubkey)
+                .help("Margin program id, also scans open margin positions when set"),
+        )
+        .arg(
+            Arg::with_name("swap_program_id")
+                .long("swap-program-id")
+                .value_name("PUBKEY")
+                .takes_value(true)
+                .validator(is_pubkey)
+                .help("Token swap program id [default: spl_token_swap::id()]"),
+        )
+        .arg(
+            Arg::with_name("min_profit_bps")
+                .long("min-profit-bps")
+                .value_name("BPS")
+                .takes_value(true)
+                .default_value("0")
+                .help("Skip liquidations gaining less than this, in basis points"),
+        )
+        .arg(
+            Arg::with_name("max_slippage_bps")
+                .long("max-slippage-bps")
+                .value_name("BPS")
+                .takes_value(true)
+                .default_value("50")
+                .help("Fail margin liquidations whose sale falls this far under the simulation"),
+        )
+        .arg(
+            Arg::with_name("interval")
+                .long("interval")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .default_value("10")
+                .help("Seconds between scans"),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry-run")
+                .help("Report unhealthy obligations without liquidating them"),
+        )
+        .arg(
+            Arg::with_name("once")
+                .long("once")
+                .help("Scan once and exit"),
+        )
+        .get_matches();
+
+    let client = RpcClient::new(matches.value_of("url").unwrap().to_owned());
+    let payer = read_keypair_file(matches.value_of("keypair").unwrap())
+        .unwrap_or_else(|err| panic!("Failed to read keypair: {}", err));
+    let pubkey_of = |name| {
+        matches
+            .value_of(name)
+            .map(|value| Pubkey::from_str(value).unwrap())
+    };
+    let config = Config {
+        payer,
+        lending_program_id: pubkey_of("lending_program_id").unwrap_or_else(spl_token_lending::id),
+        margin_program_id: pubkey_of("margin_program_id"),
+        swap_program_id: pubkey_of("swap_program_id").unwrap_or_else(spl_token_swap::id),
+        dry_run: matches.is_present("dry_run"),
+        min_profit_bps: value_t!(matches, "min_profit_bps", i64).unwrap_or_else(|e| e.exit()),
+        max_slippage_bps: value_t!(matches, "max_slippage_bps", u64).unwrap_or_else(|e| e.exit()),
+    };
+    let interval = value_t!(matches, "interval", u64).unwrap_or_else(|e| e.exit());
+
+    loop {
+        if let Err(err) = obligation::run(&client, &config) {
+            println!("Failed to scan obligations: {}", err);
+        }
+        if let Err(err) = margin::run(&client, &config) {
+            println!("Failed to scan margin positions: {}", err);
+        }
+        if matches.is_present("once") {
+            break;
+        }
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
@@ -0,0 +1,779 @@
+//! Pricing and liquidating open margin positions
+
+use anchor_lang::AccountDeserialize;
+use entropy_sdk::{margin as margin_instruction, pda};
+use margin_account::{
+    accounts, margin_account::State, FeeConfig, MarginAccount, Position, Status, TokenPair,
+};
+use solana_client::rpc_client::RpcClient;
+use solana_program::{
+    instruction::Instruction, program_error::ProgramError, program_pack::Pack, pubkey::Pubkey,
+    sysvar,
+};
+use solana_sdk::{signature::Signature, signature::Signer, transaction::Transaction};
+use spl_token::state::Account as Token;
+use spl_token_lending::{
+    instruction::accrue_reserve_interest,
+    math::{Decimal, TryAdd, TryDiv, TrySub},
+    state::{LendingMarket, Obligation, Reserve, TokenConverter},
+    swap_pool::{SwapPool, SwapSimulator},
+};
+use treasury::{Treasury, TreasuryVault};
+
+use crate::{
+    obligation::{profit_bps, token_account, LendingAccounts, BPS},
+    Config,
+};
+
+/// Margin accounts with a locked position
+pub fn open_positions(
+    client: &RpcClient,
+    program_id: &Pubkey,
+) -> Result<Vec<(Pubkey, MarginAccount)>, Box<dyn std::error::Error>> {
+    let mut positions = Vec::new();
+    for (pubkey, account) in client.get_program_accounts(program_id)? {
+        // Accounts of other types fail the discriminator check
+        let margin_account = match MarginAccount::try_deserialize(&mut account.data.as_slice()) {
+            Ok(margin_account) => margin_account,
+            Err(_) => continue,
+        };
+        let locked = matches!(
+            margin_account.position,
+            Some(ref position) if position.status == Status::Locked
+        );
+        if locked {
+            positions.push((pubkey, margin_account));
+        }
+    }
+    Ok(positions)
+}
+
+/// Constant product token swap pool a position is sold into, with the accounts its swap takes
+pub struct PoolAccounts {
+    /// Pool state account
+    pub pubkey: Pubkey,
+    pub pool: SwapPool,
+    /// Authority of the pool's token accounts
+    pub authority: Pubkey,
+    /// Balance of the pool's token A account
+    pub token_a_amount: u64,
+    /// Balance of the pool's token B account
+    pub token_b_amount: u64,
+}
+
+impl PoolAccounts {
+    /// Load the pool at `pubkey`, the pool of a position's token pair
+    pub fn load(
+        client: &RpcClient,
+        swap_program_id: &Pubkey,
+        pubkey: &Pubkey,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let account = client.get_account(pubkey)?;
+        if &account.owner != swap_program_id {
+            return Err(format!("swap pool {} isn't owned by the swap program", pubkey).into());
+        }
+        let pool = SwapPool::unpack(&account.data)?;
+        Ok(Self {
+            pubkey: *pubkey,
+            authority: Pubkey::create_program_address(
+                &[pubkey.as_ref(), &[pool.nonce]],
+                swap_program_id,
+            )?,
+            token_a_amount: Token::unpack(&client.get_account(&pool.token_a)?.data)?.amount,
+            token_b_amount: Token::unpack(&client.get_account(&pool.token_b)?.data)?.amount,
+            pool,
+        })
+    }
+
+    /// Pool token account holding `mint`
+    pub fn token_account(&self, mint: &Pubkey) -> Pubkey {
+        if mint == &self.pool.token_a_mint {
+            self.pool.token_a
+        } else {
+            self.pool.token_b
+        }
+    }
+
+    /// Simulator of trades buying `buy_token_mint` from the pool
+    pub fn simulator<'a>(&self, buy_token_mint: &'a Pubkey) -> SwapSimulator<'a> {
+        SwapSimulator::from_pool(
+            self.pool.clone(),
+            self.token_a_amount,
+            self.token_b_amount,
+            buy_token_mint,
+        )
+    }
+}
+
+/// Open position and the lending accounts it is valued with
+pub struct PositionAccounts<'a> {
+    pub position: &'a Position,
+    pub token_pair: &'a TokenPair,
+    /// Balance of the position's collateral vault
+    pub held_amount: u64,
+    /// Obligation the position borrowed through, with interest accrued
+    pub obligation: &'a Obligation,
+    /// Reserve of the obligation's collateral, holding the position's token
+    pub deposit_reserve: &'a Reserve,
+}
+
+/// Liquidation of a margin position as the program would compute it
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarginLiquidation {
+    /// Collateral vault balance sold into the pool
+    pub amount_in: u64,
+    /// Borrowed tokens the sale returns
+    pub proceeds: u64,
+    /// Health factor the program judges the position at, below one
+    pub health_factor: Decimal,
+    /// Share of the proceeds paid to the liquidator
+    pub liquidation_fee: u64,
+    /// Loan repaid from the rest of the proceeds
+    pub repay_amount: u64,
+    /// Loan left for `cover_bad_debt` to repay from the insurance fund
+    pub bad_debt: u64,
+}
+
+/// Simulate liquidating a position by selling its collateral vault through `simulator`, the
+/// same way the margin program's `liquidate` does. Returns `None` if the position is healthy,
+/// has nothing to sell or the sale would fall short of the program's minimum. Health is judged
+/// at `price`, the average price of the pair in quote per base.
+pub fn simulate_liquidation(
+    accounts: &PositionAccounts,
+    fees: &FeeConfig,
+    simulator: SwapSimulator,
+    price: Decimal,
+) -> Result<Option<MarginLiquidation>, ProgramError> {
+    let position = accounts.position;
+    let amount_in = accounts.held_amount;
+    if amount_in == 0 {
+        return Ok(None);
+    }
+    let collateral = accounts
+        .deposit_reserve
+        .collateral_exchange_rate()?
+        .decimal_collateral_to_liquidity(accounts.obligation.deposited_collateral_tokens.into())?;
+    let held = collateral.try_add(amount_in.into())?;
+    let debt = accounts.obligation.borrowed_liquidity_wads;
+    let risk_params = &accounts.token_pair.risk_params;
+    let health_factor = match position.health_factor(held, debt, price, risk_params)? {
+        Some(health_factor) if health_factor < Decimal::one() => health_factor,
+        _ => return Ok(None),
+    };
+
+    let held_mint = &accounts.deposit_reserve.liquidity.mint_pubkey;
+    let proceeds = simulator
+        .convert(Decimal::from(amount_in), held_mint)?
+        .try_floor_u64()?;
+    if proceeds == 0 || proceeds < position.minimum_sale_proceeds(amount_in, price, risk_params)? {
+        return Ok(None);
+    }
+
+    let liquidation_fee = ((proceeds as u128) * (risk_params.liquidation_fee as u128) / 100) as u64;
+    let repay_amount = fees
+        .max_repay_amount(proceeds - liquidation_fee)
+        .min(position.loan_amount);
+    let repaid = Decimal::from(repay_amount);
+    let remaining_debt = if debt > repaid {
+        debt.try_sub(repaid)?
+    } else {
+        Decimal::zero()
+    };
+    let bad_debt = position
+        .bad_debt(collateral, remaining_debt, price)?
+        .min(position.loan_amount - repay_amount);
+
+    Ok(Some(MarginLiquidation {
+        amount_in,
+        proceeds,
+        health_factor,
+        liquidation_fee,
+        repay_amount,
+        bad_debt,
+    }))
+}
+
+/// Scan open margin positions, liquidate the unhealthy ones that clear the profit threshold and
+/// have the insurance fund cover the bad debt liquidations leave
+pub fn run(client: &RpcClient, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let program_id = match config.margin_program_id {
+        Some(program_id) => program_id,
+        None => return Ok(()),
+    };
+
+    let slot = client.get_slot()?;
+    let lending = LendingAccounts::load(client, &config.lending_program_id, slot)?;
+    let state = State::try_deserialize(
+        &mut client
+            .get_account(&pda::margin_state(&program_id))?
+            .data
+            .as_slice(),
+    )?;
+    let positions = open_positions(client, &program_id)?;
+    println!("{} open margin positions", positions.len());
+
+    let scan = Scan {
+        client,
+        config,
+        program_id: &program_id,
+        lending: &lending,
+        state: &state,
+    };
+    for (pubkey, margin_account) in &positions {
+        if let Err(err) = scan.process_position(pubkey, margin_account) {
+            println!("margin account {}: {}", pubkey, err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Accounts loaded once per scan of the margin program
+struct Scan<'a> {
+    client: &'a RpcClient,
+    config: &'a Config,
+    program_id: &'a Pubkey,
+    lending: &'a LendingAccounts,
+    state: &'a State,
+}
+
+impl<'a> Scan<'a> {
+    fn process_position(
+        &self,
+        pubkey: &Pubkey,
+        margin_account: &MarginAccount,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let position = margin_account.position.as_ref().ok_or("no open position")?;
+        let loan = Loan::load(self.client, self.lending, &position.obligation)?;
+        let vault_signer = pda::margin_vault_signer(pubkey, margin_account.nonce, self.program_id)?;
+
+        // Bad debt left by an earlier liquidation is covered before anything else
+        if position.bad_debt > 0 {
+            println!(
+                "margin account {}: bad debt {} to cover",
+                pubkey, position.bad_debt
+            );
+            if self.config.dry_run {
+                return Ok(());
+            }
+            return self.cover_bad_debt(pubkey, position, &loan, &vault_signer);
+        }
+
+        let collateral_vault = position
+            .collateral_vault
+            .ok_or("position has no collateral vault")?;
+        let held_amount = Token::unpack(&self.client.get_account(&collateral_vault)?.data)?.amount;
+        let token_pair = TokenPair::try_deserialize(
+            &mut self
+                .client
+                .get_account(&position.token_pair)?
+                .data
+                .as_slice(),
+        )?;
+        let held_mint = &loan.deposit_reserve.liquidity.mint_pubkey;
+        let loaned_mint = &loan.borrow_reserve.liquidity.mint_pubkey;
+        let pool = PoolAccounts::load(
+            self.client,
+            &self.config.swap_program_id,
+            &token_pair.swap_pool,
+        )?;
+        let (price_accumulator, twap) = self.pair_twap(&token_pair, &loan)?;
+
+        let accounts = PositionAccounts {
+            position,
+            token_pair: &token_pair,
+            held_amount,
+            obligation: &loan.obligation,
+            deposit_reserve: loan.deposit_reserve,
+        };
+        let liquidation = match simulate_liquidation(
+            &accounts,
+            &self.state.fees,
+            pool.simulator(loaned_mint),
+            twap,
+        )? {
+            Some(liquidation) => liquidation,
+            None => return Ok(()),
+        };
+
+        // The liquidator pays nothing for the fee, so it gains the whole fee on the repaid loan
+        let profit_bps = profit_bps(
+            Decimal::from(liquidation.repay_amount + liquidation.liquidation_fee),
+            Decimal::from(liquidation.repay_amount),
+        )?;
+        println!(
+            "margin account {}: health factor {}, sell {} for {}, repay {} with fee {} ({} bps), \
+             bad debt {}",
+            pubkey,
+            liquidation.health_factor,
+            liquidation.amount_in,
+            liquidation.proceeds,
+            liquidation.repay_amount,
+            liquidation.liquidation_fee,
+            profit_bps,
+            liquidation.bad_debt
+        );
+        if profit_bps < self.config.min_profit_bps {
+            println!(
+                "margin account {}: below profit threshold, skipping",
+                pubkey
+            );
+            return Ok(());
+        }
+        if self.config.dry_run {
+            return Ok(());
+        }
+
+        let liquidator_account =
+            token_account(self.client, &self.config.payer.pubkey(), loaned_mint)?
+                .map(|(pubkey, _)| pubkey)
+                .ok_or("no token account to receive the liquidation fee")?;
+        let repay =
+            loan.repay_accounts(self.client, &self.config.lending_program_id, &vault_signer)?;
+        let fees = &self.state.fees;
+        let accounts = accounts::Liquidate {
+            liquidator: self.config.payer.pubkey(),
+            liquidator_account,
+            swap_program: self.config.swap_program_id,
+            swap_info: pool.pubkey,
+            swap_authority: pool.authority,
+            swap_source: pool.token_account(held_mint),
+            swap_dest: pool.token_account(loaned_mint),
+            pool_mint: pool.pool.pool_mint,
+            pool_fee: pool.pool.pool_fee_account,
+            // Reusing the pool's fee account as the host fee account keeps the transaction
+            // within the packet size
+            host_fee: pool.pool.pool_fee_account,
+            lending_program: repay.lending_program,
+            destination_coll_account: repay.destination_coll_account,
+            repay_reserve_account: repay.repay_reserve_account,
+            repay_reserve_spl_acccount: repay.repay_reserve_spl_acccount,
+            withdraw_reserve: repay.withdraw_reserve,
+            withdraw_reserve_collateral: repay.withdraw_reserve_collateral,
+            obligation: repay.obligation,
+            obligation_mint: repay.obligation_mint,
+            obligation_input: repay.obligation_input,
+            lending_market: repay.lending_market,
+            derived_lending_authority: repay.derived_lending_authority,
+            margin_account: *pubkey,
+            token_pair: position.token_pair,
+            collateral_vault,
+            loaned_vault: position.loaned_vault,
+            vault_signer,
+            state: pda::margin_state(self.program_id),
+            fee_vaults: accounts::FeeVaults {
+                stakers: self.fee_vault(&fees.stakers_fee_owner, loaned_mint)?,
+                insurance: self.fee_vault(&fees.insurance_fee_owner, loaned_mint)?,
+                community: self.fee_vault(&fees.community_fee_owner, loaned_mint)?,
+            },
+            token_program: spl_token::id(),
+            clock: sysvar::clock::id(),
+        };
+        // The sale may come in under the simulation if the pool moves before it lands
+        let minimum_amount_out = ((liquidation.proceeds as u128)
+            * (BPS.saturating_sub(self.config.max_slippage_bps) as u128)
+            / BPS as u128) as u64;
+        let signature = self.send(&[
+            loan.accrue_interest(self.config.lending_program_id),
+            margin_instruction::liquidate(
+                *self.program_id,
+                accounts,
+                price_accumulator,
+                minimum_amount_out,
+            ),
+        ])?;
+        println!("margin account {}: liquidated in {}", pubkey, signature);
+
+        if liquidation.bad_debt > 0 {
+            self.cover_bad_debt(pubkey, position, &loan, &vault_signer)?;
+        }
+        Ok(())
+    }
+
+    /// Have the insurance fund of the borrowed token repay a position's bad debt
+    fn cover_bad_debt(
+        &self,
+        pubkey: &Pubkey,
+        position: &Position,
+        loan: &Loan,
+        vault_signer: &Pubkey,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let repay =
+            loan.repay_accounts(self.client, &self.config.lending_program_id, vault_signer)?;
+        let accounts = accounts::CoverBadDebt {
+            margin_account: *pubkey,
+            token_pair: position.token_pair,
+            loaned_vault: position.loaned_vault,
+            vault_signer: *vault_signer,
+            lending_program: repay.lending_program,
+            destination_coll_account: repay.destination_coll_account,
+            repay_reserve_account: repay.repay_reserve_account,
+            repay_reserve_spl_acccount: repay.repay_reserve_spl_acccount,
+            withdraw_reserve: repay.withdraw_reserve,
+            withdraw_reserve_collateral: repay.withdraw_reserve_collateral,
+            obligation: repay.obligation,
+            obligation_mint: repay.obligation_mint,
+            obligation_input: repay.obligation_input,
+            lending_market: repay.lending_market,
+            derived_lending_authority: repay.derived_lending_authority,
+            insurance_coverage: self
+                .insurance_coverage(&loan.borrow_reserve.liquidity.mint_pubkey)?,
+            token_program: spl_token::id(),
+            clock: sysvar::clock::id(),
+        };
+        let signature = self.send(&[
+            loan.accrue_interest(self.config.lending_program_id),
+            margin_instruction::cover_bad_debt(*self.program_id, accounts),
+        ])?;
+        println!(
+            "margin account {}: covered bad debt in {}",
+            pubkey, signature
+        );
+        Ok(())
+    }
+
+    /// Price accumulator and average price of the pair, in quote per base, that the program
+    /// judges the position's health at
+    fn pair_twap(
+        &self,
+        token_pair: &TokenPair,
+        loan: &Loan,
+    ) -> Result<(Pubkey, Decimal), Box<dyn std::error::Error>> {
+        let (pubkey, twap) = self
+            .lending
+            .liquidation_twap(loan.borrow_reserve, loan.deposit_reserve)?
+            .ok_or("the reserve priced off the market doesn't set liquidation_twap_slots")?;
+        let (_, price_accumulator) = self
+            .lending
+            .price_accumulators
+            .iter()
+            .find(|(accumulator_pubkey, _)| accumulator_pubkey == &pubkey)
+            .ok_or("price accumulator not found")?;
+
+        // Accumulators price the reserve's token in the lending market's quote token
+        let (base, quote) = (&price_accumulator.base_mint, &price_accumulator.quote_mint);
+        if &token_pair.first_token == base && &token_pair.second_token == quote {
+            Ok((pubkey, twap))
+        } else if &token_pair.first_token == quote && &token_pair.second_token == base {
+            Ok((pubkey, Decimal::one().try_div(twap)?))
+        } else {
+            Err("price accumulator doesn't price the position's pair".into())
+        }
+    }
+
+    /// Token account of `mint` receiving a share of the margin program's fees
+    fn fee_vault(
+        &self,
+        owner: &Pubkey,
+        mint: &Pubkey,
+    ) -> Result<Pubkey, Box<dyn std::error::Error>> {
+        token_account(self.client, owner, mint)?
+            .map(|(pubkey, _)| pubkey)
+            .ok_or_else(|| format!("no fee vault of {} owned by {}", mint, owner).into())
+    }
+
+    /// Treasury accounts of the insurance fund covering bad debt in `mint` for this margin program
+    fn insurance_coverage(
+        &self,
+        mint: &Pubkey,
+    ) -> Result<accounts::InsuranceCoverage, Box<dyn std::error::Error>> {
+        for (pubkey, account) in self.client.get_program_accounts(&treasury::ID)? {
+            // Accounts of other types fail the discriminator check
+            let vault = match TreasuryVault::try_deserialize(&mut account.data.as_slice()) {
+                Ok(vault) => vault,
+                Err(_) => continue,
+            };
+            if &vault.mint != mint || &vault.insurance_config.margin_program != self.program_id {
+                continue;
+            }
+            let treasury = Treasury::try_deserialize(
+                &mut self.client.get_account(&vault.treasury)?.data.as_slice(),
+            )?;
+            return Ok(accounts::InsuranceCoverage {
+                treasury_program: treasury::ID,
+                treasury: vault.treasury,
+                treasury_vault: pubkey,
+                insurance: vault.insurance,
+                treasury_signer: pda::treasury_signer(
+                    &vault.treasury,
+                    treasury.nonce,
+                    &treasury::ID,
+                )?,
+                coverage_authority: pda::margin_coverage_authority(self.program_id),
+            });
+        }
+        Err(format!("no insurance fund covers bad debt in {}", mint).into())
+    }
+
+    fn send(&self, instructions: &[Instruction]) -> Result<Signature, Box<dyn std::error::Error>> {
+        let payer = &self.config.payer;
+        let mut transaction = Transaction::new_with_payer(instructions, Some(&payer.pubkey()));
+        let recent_blockhash = self.client.get_recent_blockhash()?.0;
+        transaction.sign(&[payer], recent_blockhash);
+        Ok(self.client.send_and_confirm_transaction(&transaction)?)
+    }
+}
+
+/// Lending accounts a position borrowed through
+struct Loan<'a> {
+    obligation_pubkey: Pubkey,
+    obligation: Obligation,
+    deposit_reserve: &'a Reserve,
+    borrow_reserve: &'a Reserve,
+    market: &'a LendingMarket,
+}
+
+/// Lending accounts the margin program repays a position's loan with, named as in its
+/// instructions
+struct RepayAccounts {
+    lending_program: Pubkey,
+    destination_coll_account: Pubkey,
+    repay_reserve_account: Pubkey,
+    repay_reserve_spl_acccount: Pubkey,
+    withdraw_reserve: Pubkey,
+    withdraw_reserve_collateral: Pubkey,
+    obligation: Pubkey,
+    obligation_mint: Pubkey,
+    obligation_input: Pubkey,
+    lending_market: Pubkey,
+    derived_lending_authority: Pubkey,
+}
+
+impl<'a> Loan<'a> {
+    /// Fetch the obligation itself, since scans skip obligations whose collateral was all
+    /// released, and accrue it with the scanned borrow reserve
+    fn load(
+        client: &RpcClient,
+        lending: &'a LendingAccounts,
+        obligation_pubkey: &Pubkey,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut obligation = Obligation::unpack(&client.get_account(obligation_pubkey)?.data)?;
+        let deposit_reserve = lending
+            .reserves
+            .get(&obligation.collateral_reserve)
+            .ok_or("collateral reserve not found")?;
+        let borrow_reserve = lending
+            .reserves
+            .get(&obligation.borrow_reserve)
+            .ok_or("borrow reserve not found")?;
+        let market = lending
+            .markets
+            .get(&borrow_reserve.lending_market)
+            .ok_or("lending market not found")?;
+        obligation.accrue_interest(borrow_reserve.cumulative_borrow_rate_wads)?;
+        Ok(Self {
+            obligation_pubkey: *obligation_pubkey,
+            obligation,
+            deposit_reserve,
+            borrow_reserve,
+            market,
+        })
+    }
+
+    /// Repayments need the reserves accrued in the same slot
+    fn accrue_interest(&self, lending_program_id: Pubkey) -> Instruction {
+        accrue_reserve_interest(
+            lending_program_id,
+            vec![
+                self.obligation.borrow_reserve,
+                self.obligation.collateral_reserve,
+            ],
+        )
+    }
+
+    /// Repay accounts of the loan. Released collateral and the burnt obligation tokens are held
+    /// by the margin account's `vault_signer`.
+    fn repay_accounts(
+        &self,
+        client: &RpcClient,
+        lending_program_id: &Pubkey,
+        vault_signer: &Pubkey,
+    ) -> Result<RepayAccounts, Box<dyn std::error::Error>> {
+        let destination_coll_account = token_account(
+            client,
+            vault_signer,
+            &self.deposit_reserve.collateral.mint_pubkey,
+        )?
+        .map(|(pubkey, _)| pubkey)
+        .ok_or("margin account has no token account for released collateral")?;
+        let obligation_input = token_account(client, vault_signer, &self.obligation.token_mint)?
+            .map(|(pubkey, _)| pubkey)
+            .ok_or("margin account has no obligation token account")?;
+        let lending_market = self.borrow_reserve.lending_market;
+        Ok(RepayAccounts {
+            lending_program: *lending_program_id,
+            destination_coll_account,
+            repay_reserve_account: self.obligation.borrow_reserve,
+            repay_reserve_spl_acccount: self.borrow_reserve.liquidity.supply_pubkey,
+            withdraw_reserve: self.obligation.collateral_reserve,
+            withdraw_reserve_collateral: self.deposit_reserve.collateral.supply_pubkey,
+            obligation: self.obligation_pubkey,
+            obligation_mint: self.obligation.token_mint,
+            obligation_input,
+            lending_market,
+            derived_lending_authority: pda::lending_market_authority(
+                &lending_market,
+                self.market.bump_seed,
+                lending_program_id,
+            )?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use margin_account::{Direction, RiskParams};
+    use spl_token_lending::state::{ReserveCollateral, ReserveLiquidity};
+
+    fn deposit_reserve(mint: Pubkey) -> Reserve {
+        Reserve {
+            liquidity: ReserveLiquidity {
+                mint_pubkey: mint,
+                available_amount: 1_000,
+                ..ReserveLiquidity::default()
+            },
+            collateral: ReserveCollateral {
+                mint_total_supply: 1_000,
+                ..ReserveCollateral::default()
+            },
+            ..Reserve::default()
+        }
+    }
+
+    fn long_position(loan_amount: u64) -> Position {
+        Position {
+            loan_amount,
+            direction: Direction::Long,
+            token_pair: Pubkey::default(),
+            obligation: Pubkey::default(),
+            loaned_vault: Pubkey::default(),
+            collateral_vault: Some(Pubkey::default()),
+            status: Status::Locked,
+            max_held_amount: u64::MAX,
+            withdrawable_amount: 0,
+            bad_debt: 0,
+        }
+    }
+
+    fn token_pair(base: Pubkey, quote: Pubkey) -> TokenPair {
+        TokenPair {
+            first_token: base,
+            second_token: quote,
+            swap_pool: Pubkey::new_unique(),
+            risk_params: RiskParams {
+                max_leverage: 3,
+                maintenance_margin: 20,
+                liquidation_fee: 5,
+                max_slippage: 10,
+                max_open_interest: u64::MAX,
+            },
+            enabled: true,
+            long_open_interest: 0,
+            short_open_interest: 0,
+            nonce: 0,
+        }
+    }
+
+    fn pool(base: Pubkey, quote: Pubkey) -> SwapPool {
+        SwapPool {
+            nonce: 0,
+            token_a: Pubkey::new_unique(),
+            token_b: Pubkey::new_unique(),
+            pool_mint: Pubkey::new_unique(),
+            token_a_mint: base,
+            token_b_mint: quote,
+            pool_fee_account: Pubkey::new_unique(),
+            fee: Decimal::zero(),
+        }
+    }
+
+    fn obligation(collateral: u64, debt: u64) -> Obligation {
+        Obligation {
+            deposited_collateral_tokens: collateral,
+            cumulative_borrow_rate_wads: Decimal::one(),
+            borrowed_liquidity_wads: Decimal::from(debt),
+            margin_borrowed_amount: debt,
+            ..Obligation::default()
+        }
+    }
+
+    #[test]
+    fn liquidation_matches_program() {
+        let (base, quote) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let deposit_reserve = deposit_reserve(base);
+        let token_pair = token_pair(base, quote);
+        let position = long_position(300);
+        let obligation = obligation(100, 300);
+        let accounts = PositionAccounts {
+            position: &position,
+            token_pair: &token_pair,
+            held_amount: 250,
+            obligation: &obligation,
+            deposit_reserve: &deposit_reserve,
+        };
+
+        // 250 base sell for 249 quote in an even pool, 350 base held worth 350 against 300
+        let simulator = SwapSimulator::from_pool(pool(base, quote), 1_000_000, 1_000_000, &quote);
+        let liquidation =
+            simulate_liquidation(&accounts, &FeeConfig::default(), simulator, Decimal::one())
+                .unwrap()
+                .unwrap();
+        assert_eq!(liquidation.proceeds, 249);
+        assert!(liquidation.health_factor < Decimal::one());
+        assert_eq!(liquidation.liquidation_fee, 12);
+        assert_eq!(liquidation.repay_amount, 237);
+        assert_eq!(liquidation.bad_debt, 0);
+
+        // At twice the average price the position is healthy
+        let simulator = SwapSimulator::from_pool(pool(base, quote), 1_000_000, 2_000_000, &quote);
+        assert_eq!(
+            simulate_liquidation(
+                &accounts,
+                &FeeConfig::default(),
+                simulator,
+                Decimal::from(2u64)
+            )
+            .unwrap(),
+            None
+        );
+
+        // A pool trading 20% under the average price fails the program's 10% slippage limit
+        let simulator = SwapSimulator::from_pool(pool(base, quote), 1_000_000, 800_000, &quote);
+        assert_eq!(
+            simulate_liquidation(&accounts, &FeeConfig::default(), simulator, Decimal::one())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn liquidation_leaves_bad_debt() {
+        let (base, quote) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let deposit_reserve = deposit_reserve(base);
+        let token_pair = token_pair(base, quote);
+        let position = long_position(300);
+        let obligation = obligation(100, 300);
+        let accounts = PositionAccounts {
+            position: &position,
+            token_pair: &token_pair,
+            held_amount: 250,
+            obligation: &obligation,
+            deposit_reserve: &deposit_reserve,
+        };
+
+        // Judged at an average price of 0.5, the 100 collateral left is worth 50 of the 63 still
+        // owed after the sale repays 237
+        let simulator = SwapSimulator::from_pool(pool(base, quote), 1_000_000, 1_000_000, &quote);
+        let liquidation = simulate_liquidation(
+            &accounts,
+            &FeeConfig::default(),
+            simulator,
+            Decimal::from(1u64).try_div(2u64).unwrap(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(liquidation.repay_amount, 237);
+        assert_eq!(liquidation.bad_debt, 13);
+    }
+}
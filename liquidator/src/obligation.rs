@@ -0,0 +1,456 @@
+//! Scanning and liquidating lending obligations
+
+use solana_client::{rpc_client::RpcClient, rpc_request::TokenAccountsFilter};
+use solana_program::{
+    account_info::IntoAccountInfo, program_error::ProgramError, program_option::COption,
+    program_pack::Pack, pubkey::Pubkey,
+};
+use solana_sdk::{account::Account, signature::Signer, transaction::Transaction};
+use spl_token::state::Account as Token;
+use spl_token_lending::{
+    dex_market::{DexMarket, TradeSimulator, ASKS_OFFSET, BIDS_OFFSET},
+    error::LendingError,
+    instruction::{accrue_reserve_interest, liquidate_obligation},
+    math::{Decimal, TryMul, TrySub},
+    state::{
+        LendingMarket, LiquidateResult, Obligation, PriceAccumulator, PriceSource, Reserve,
+        TokenConverter,
+    },
+    twap::TwapConverter,
+};
+use std::collections::HashMap;
+
+use crate::Config;
+
+/// Number of basis points in one
+pub const BPS: u64 = 10_000;
+
+/// Lending program accounts, decoded and accrued to the current slot
+pub struct LendingAccounts {
+    /// Lending markets by address
+    pub markets: HashMap<Pubkey, LendingMarket>,
+    /// Reserves by address
+    pub reserves: HashMap<Pubkey, Reserve>,
+    /// Obligations with an outstanding loan and collateral to seize
+    pub obligations: Vec<(Pubkey, Obligation)>,
+    /// Price accumulators by address
+    pub price_accumulators: Vec<(Pubkey, PriceAccumulator)>,
+    /// Slot the accounts were loaded at
+    pub slot: u64,
+}
+
+impl LendingAccounts {
+    /// Fetch every account owned by the lending program and accrue interest up to `slot`, the
+    /// same way `AccrueReserveInterest` would
+    pub fn load(
+        client: &RpcClient,
+        program_id: &Pubkey,
+        slot: u64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut markets = HashMap::new();
+        let mut reserves = HashMap::new();
+        let mut obligations = Vec::new();
+        let mut price_accumulators = Vec::new();
+        for (pubkey, account) in client.get_program_accounts(program_id)? {
+            match account.data.len() {
+                LendingMarket::LEN => {
+                    if let Ok(market) = LendingMarket::unpack(&account.data) {
+                        markets.insert(pubkey, market);
+                    }
+                }
+                Reserve::LEN => {
+                    if let Ok(mut reserve) = Reserve::unpack(&account.data) {
+                        reserve.accrue_interest(slot)?;
+                        reserves.insert(pubkey, reserve);
+                    }
+                }
+                Obligation::LEN => {
+                    if let Ok(obligation) = Obligation::unpack(&account.data) {
+                        if obligation.borrowed_liquidity_wads > Decimal::zero()
+                            && obligation.deposited_collateral_tokens > 0
+                        {
+                            obligations.push((pubkey, obligation));
+                        }
+                    }
+                }
+                PriceAccumulator::LEN => {
+                    if let Ok(price_accumulator) = PriceAccumulator::unpack(&account.data) {
+                        price_accumulators.push((pubkey, price_accumulator));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (_, obligation) in obligations.iter_mut() {
+            if let Some(reserve) = reserves.get(&obligation.borrow_reserve) {
+                obligation.accrue_interest(reserve.cumulative_borrow_rate_wads)?;
+            }
+        }
+
+        Ok(Self {
+            markets,
+            reserves,
+            obligations,
+            price_accumulators,
+            slot,
+        })
+    }
+
+    /// Price accumulator and average price the program judges the health of a loan between two
+    /// reserves at, or `None` if the reserve priced off the market checks health at spot
+    pub fn liquidation_twap(
+        &self,
+        repay_reserve: &Reserve,
+        withdraw_reserve: &Reserve,
+    ) -> Result<Option<(Pubkey, Decimal)>, Box<dyn std::error::Error>> {
+        let market_reserve = match (repay_reserve.dex_market, withdraw_reserve.dex_market) {
+            (COption::Some(_), _) => repay_reserve,
+            (_, COption::Some(_)) => withdraw_reserve,
+            _ => return Err("One reserve must have a dex market".into()),
+        };
+        let twap_slots = market_reserve.config.liquidation_twap_slots;
+        if twap_slots == 0 {
+            return Ok(None);
+        }
+
+        let (pubkey, price_accumulator) = self
+            .price_accumulators
+            .iter()
+            .find(|(_, price_accumulator)| {
+                price_accumulator.lending_market == market_reserve.lending_market
+                    && COption::Some(price_accumulator.market) == market_reserve.dex_market
+                    && price_accumulator.base_mint == market_reserve.liquidity.mint_pubkey
+            })
+            .ok_or("no price accumulator for the reserve market")?;
+        let twap = price_accumulator.twap(self.slot, twap_slots)?;
+        Ok(Some((*pubkey, twap)))
+    }
+}
+
+/// Liquidation of an unhealthy obligation as the program would compute it
+pub struct Liquidation {
+    /// Amounts the program would repay, settle and withdraw
+    pub result: LiquidateResult,
+    /// Value of the withdrawn collateral over the repaid loan, in basis points above par.
+    /// Includes the liquidation bonus; negative when the collateral is worth less than the repay.
+    pub profit_bps: i64,
+}
+
+/// Dex market accounts used to price a liquidation
+pub struct DexAccounts {
+    /// Serum market
+    pub market: (Pubkey, Account),
+    /// Bids of the market
+    pub bids: (Pubkey, Account),
+    /// Asks of the market
+    pub asks: (Pubkey, Account),
+}
+
+impl DexAccounts {
+    /// Fetch the dex market of an obligation's reserves and its order book
+    pub fn load(
+        client: &RpcClient,
+        repay_reserve: &Reserve,
+        withdraw_reserve: &Reserve,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (dex_market_pubkey, price_source) =
+            match (repay_reserve.dex_market, withdraw_reserve.dex_market) {
+                (COption::Some(pubkey), _) => (pubkey, repay_reserve.price_source),
+                (_, COption::Some(pubkey)) => (pubkey, withdraw_reserve.price_source),
+                _ => return Err("One reserve must have a dex market".into()),
+            };
+        if price_source != PriceSource::DexMarket {
+            return Err("Reserves priced off token swap pools are not supported".into());
+        }
+        let dex_market = client.get_account(&dex_market_pubkey)?;
+
+        let bids_pubkey = DexMarket::pubkey_at_offset(&dex_market.data, BIDS_OFFSET);
+        let asks_pubkey = DexMarket::pubkey_at_offset(&dex_market.data, ASKS_OFFSET);
+        let bids = client.get_account(&bids_pubkey)?;
+        let asks = client.get_account(&asks_pubkey)?;
+
+        Ok(Self {
+            market: (dex_market_pubkey, dex_market),
+            bids: (bids_pubkey, bids),
+            asks: (asks_pubkey, asks),
+        })
+    }
+
+    /// Run `f` with a trade simulator over local copies of the dex accounts
+    fn simulate<T>(
+        &self,
+        quote_token_mint: &Pubkey,
+        buy_token_mint: &Pubkey,
+        sell_token_mint: &Pubkey,
+        f: impl FnOnce(TradeSimulator) -> Result<T, ProgramError>,
+    ) -> Result<T, ProgramError> {
+        let (market_pubkey, mut market_account) = (self.market.0, self.market.1.clone());
+        let (bids_pubkey, mut bids_account) = (self.bids.0, self.bids.1.clone());
+        let (asks_pubkey, mut asks_account) = (self.asks.0, self.asks.1.clone());
+
+        let market_info = (&market_pubkey, &mut market_account).into_account_info();
+        let bids_info = (&bids_pubkey, &mut bids_account).into_account_info();
+        let asks_info = (&asks_pubkey, &mut asks_account).into_account_info();
+        let simulator = TradeSimulator::new(
+            &market_info,
+            &bids_info,
+            &asks_info,
+            quote_token_mint,
+            buy_token_mint,
+            sell_token_mint,
+        )?;
+        f(simulator)
+    }
+}
+
+/// Simulate liquidating up to `liquidity_amount` of an obligation. Returns `None` if the
+/// obligation is healthy. Health is judged at `twap`, the average price of the reserve priced
+/// off the market, when its reserve config asks for one.
+pub fn simulate_liquidation(
+    obligation: &Obligation,
+    repay_reserve: &Reserve,
+    withdraw_reserve: &Reserve,
+    market: &LendingMarket,
+    dex: &DexAccounts,
+    twap: Option<Decimal>,
+    liquidity_amount: u64,
+) -> Result<Option<Liquidation>, ProgramError> {
+    let repay_mint = &repay_reserve.liquidity.mint_pubkey;
+    let withdraw_mint = &withdraw_reserve.liquidity.mint_pubkey;
+    let quote_mint = &market.quote_token_mint;
+    let base_mint = if repay_reserve.dex_market.is_some() {
+        repay_mint
+    } else {
+        withdraw_mint
+    };
+
+    let result = dex.simulate(
+        quote_mint,
+        withdraw_mint,
+        repay_mint,
+        |simulator| match twap {
+            Some(twap) => withdraw_reserve.liquidate_obligation(
+                obligation,
+                liquidity_amount,
+                repay_mint,
+                TwapConverter::new(simulator, twap, base_mint),
+            ),
+            None => withdraw_reserve.liquidate_obligation(
+                obligation,
+                liquidity_amount,
+                repay_mint,
+                simulator,
+            ),
+        },
+    );
+    let result = match result {
+        Ok(result) => result,
+        Err(err) if err == LendingError::HealthyObligation.into() => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    // Market price of the repaid token in withdrawn liquidity
+    let price = dex.simulate(quote_mint, withdraw_mint, repay_mint, |mut simulator| {
+        simulator.best_price(repay_mint)
+    })?;
+    let withdraw_liquidity = withdraw_reserve
+        .collateral_exchange_rate()?
+        .collateral_to_liquidity(result.withdraw_amount)?;
+    let repay_value = price.try_mul(result.repay_amount)?;
+    let profit_bps = profit_bps(Decimal::from(withdraw_liquidity), repay_value)?;
+
+    Ok(Some(Liquidation { result, profit_bps }))
+}
+
+/// Gain of receiving `received` for `paid`, in basis points of `paid`
+pub fn profit_bps(received: Decimal, paid: Decimal) -> Result<i64, ProgramError> {
+    if paid == Decimal::zero() {
+        return Ok(0);
+    }
+    let bps = if received >= paid {
+        received
+            .try_sub(paid)?
+            .try_mul(BPS)?
+            .try_div(paid)?
+            .try_floor_u64()? as i64
+    } else {
+        -(paid
+            .try_sub(received)?
+            .try_mul(BPS)?
+            .try_div(paid)?
+            .try_ceil_u64()? as i64)
+    };
+    Ok(bps)
+}
+
+/// Scan all obligations and liquidate the unhealthy ones that clear the profit threshold
+pub fn run(client: &RpcClient, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let slot = client.get_slot()?;
+    let accounts = LendingAccounts::load(client, &config.lending_program_id, slot)?;
+    println!(
+        "slot {}: {} obligations with outstanding loans",
+        slot,
+        accounts.obligations.len()
+    );
+
+    for (obligation_pubkey, obligation) in &accounts.obligations {
+        if let Err(err) =
+            process_obligation(client, config, &accounts, obligation_pubkey, obligation)
+        {
+            println!("obligation {}: {}", obligation_pubkey, err);
+        }
+    }
+
+    Ok(())
+}
+
+fn process_obligation(
+    client: &RpcClient,
+    config: &Config,
+    accounts: &LendingAccounts,
+    obligation_pubkey: &Pubkey,
+    obligation: &Obligation,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let repay_reserve = accounts
+        .reserves
+        .get(&obligation.borrow_reserve)
+        .ok_or("borrow reserve not found")?;
+    let withdraw_reserve = accounts
+        .reserves
+        .get(&obligation.collateral_reserve)
+        .ok_or("collateral reserve not found")?;
+    let market = accounts
+        .markets
+        .get(&repay_reserve.lending_market)
+        .ok_or("lending market not found")?;
+
+    let source_liquidity = token_account(
+        client,
+        &config.payer.pubkey(),
+        &repay_reserve.liquidity.mint_pubkey,
+    )?;
+    let destination_collateral = token_account(
+        client,
+        &config.payer.pubkey(),
+        &withdraw_reserve.collateral.mint_pubkey,
+    )?;
+    let available = match source_liquidity {
+        Some((_, ref token)) => token.amount,
+        // Dry runs price the full liquidation even without funds
+        None if config.dry_run => u64::MAX,
+        None => return Err("no token account to repay from".into()),
+    };
+
+    let dex = DexAccounts::load(client, repay_reserve, withdraw_reserve)?;
+    let twap = accounts.liquidation_twap(repay_reserve, withdraw_reserve)?;
+    let liquidation = match simulate_liquidation(
+        obligation,
+        repay_reserve,
+        withdraw_reserve,
+        market,
+        &dex,
+        twap.map(|(_, twap)| twap),
+        available,
+    )? {
+        Some(liquidation) => liquidation,
+        None => return Ok(()),
+    };
+
+    println!(
+        "obligation {}: unhealthy, repay {} for {} collateral ({} bps)",
+        obligation_pubkey,
+        liquidation.result.repay_amount,
+        liquidation.result.withdraw_amount,
+        liquidation.profit_bps
+    );
+    if liquidation.profit_bps < config.min_profit_bps {
+        println!(
+            "obligation {}: below profit threshold, skipping",
+            obligation_pubkey
+        );
+        return Ok(());
+    }
+    if config.dry_run {
+        return Ok(());
+    }
+
+    let source_liquidity_pubkey = source_liquidity.map(|(pubkey, _)| pubkey).unwrap();
+    let destination_collateral_pubkey = destination_collateral
+        .map(|(pubkey, _)| pubkey)
+        .ok_or("no token account to receive collateral")?;
+    let lending_market_authority = Pubkey::create_program_address(
+        &[repay_reserve.lending_market.as_ref(), &[market.bump_seed]],
+        &config.lending_program_id,
+    )?;
+
+    let instructions = [
+        accrue_reserve_interest(
+            config.lending_program_id,
+            vec![obligation.borrow_reserve, obligation.collateral_reserve],
+        ),
+        liquidate_obligation(
+            config.lending_program_id,
+            liquidation.result.repay_amount,
+            source_liquidity_pubkey,
+            destination_collateral_pubkey,
+            obligation.borrow_reserve,
+            repay_reserve.liquidity.supply_pubkey,
+            obligation.collateral_reserve,
+            withdraw_reserve.collateral.supply_pubkey,
+            *obligation_pubkey,
+            repay_reserve.lending_market,
+            lending_market_authority,
+            config.payer.pubkey(),
+            dex.market.0,
+            dex.bids.0,
+            dex.asks.0,
+            twap.map(|(price_accumulator_pubkey, _)| price_accumulator_pubkey),
+        ),
+    ];
+    let mut transaction = Transaction::new_with_payer(&instructions, Some(&config.payer.pubkey()));
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(&[&config.payer], recent_blockhash);
+    let signature = client.send_and_confirm_transaction(&transaction)?;
+    println!(
+        "obligation {}: liquidated in {}",
+        obligation_pubkey, signature
+    );
+
+    Ok(())
+}
+
+/// First token account of `mint` owned by `owner`
+pub fn token_account(
+    client: &RpcClient,
+    owner: &Pubkey,
+    mint: &Pubkey,
+) -> Result<Option<(Pubkey, Token)>, Box<dyn std::error::Error>> {
+    let accounts = client.get_token_accounts_by_owner(owner, TokenAccountsFilter::Mint(*mint))?;
+    match accounts.first() {
+        Some(keyed_account) => {
+            let pubkey: Pubkey = keyed_account.pubkey.parse()?;
+            let account = client.get_account(&pubkey)?;
+            Ok(Some((pubkey, Token::unpack(&account.data)?)))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profit_bps_sign() {
+        assert_eq!(
+            profit_bps(Decimal::from(105u64), Decimal::from(100u64)),
+            Ok(500)
+        );
+        assert_eq!(
+            profit_bps(Decimal::from(95u64), Decimal::from(100u64)),
+            Ok(-500)
+        );
+        assert_eq!(profit_bps(Decimal::from(1u64), Decimal::zero()), Ok(0));
+    }
+}
@@ -0,0 +1,460 @@
+#![cfg(feature = "test-bpf")]
+
+use anchor_lang::{AccountDeserialize, AccountSerialize};
+use entropy_sdk::pda;
+use margin_account::{
+    margin_account::State, Direction, FeeConfig, MarginAccount, Position, RiskParams, Status,
+    TokenPair,
+};
+use solana_client::rpc_client::RpcClient;
+use solana_core::test_validator::TestValidatorGenesis;
+use solana_program::{program_option::COption, program_pack::Pack, pubkey::Pubkey};
+use solana_sdk::{
+    account::Account,
+    signature::{write_keypair_file, Keypair, Signer},
+    transaction::Transaction,
+};
+use spl_token::state::{Account as Token, AccountState, Mint};
+use spl_token_lending::{
+    instruction::update_price_accumulator,
+    math::Decimal,
+    state::{
+        BorrowRateCurve, LendingMarket, NewReserveParams, Obligation, PriceAccumulator,
+        PriceObservation, PriceSource, Reserve, ReserveCollateral, ReserveConfig, ReserveLiquidity,
+        PROGRAM_VERSION,
+    },
+    swap_pool::SWAP_POOL_LEN,
+};
+use std::{process::Command, thread::sleep, time::Duration};
+
+const POOL_AMOUNT: u64 = 1_000_000;
+const LOAN_AMOUNT: u64 = 300;
+const COLLATERAL_AMOUNT: u64 = 100;
+const HELD_AMOUNT: u64 = 250;
+/// Slots the reserve priced off the swap pool averages liquidation prices over
+const TWAP_SLOTS: u64 = 30;
+
+fn packable_account<T: Pack>(value: T, owner: &Pubkey) -> Account {
+    let mut account = Account::new(u32::MAX as u64, T::get_packed_len(), owner);
+    T::pack(value, &mut account.data).unwrap();
+    account
+}
+
+fn anchor_account<T: AccountSerialize>(value: &T, owner: &Pubkey) -> Account {
+    let mut data = Vec::new();
+    value.try_serialize(&mut data).unwrap();
+    let mut account = Account::new(u32::MAX as u64, 0, owner);
+    account.data = data;
+    account
+}
+
+fn mint(authority: Pubkey, supply: u64) -> Account {
+    packable_account(
+        Mint {
+            is_initialized: true,
+            mint_authority: COption::Some(authority),
+            supply,
+            ..Mint::default()
+        },
+        &spl_token::id(),
+    )
+}
+
+fn token(mint: Pubkey, owner: Pubkey, amount: u64) -> Account {
+    packable_account(
+        Token {
+            mint,
+            owner,
+            amount,
+            state: AccountState::Initialized,
+            ..Token::default()
+        },
+        &spl_token::id(),
+    )
+}
+
+/// Constant product token swap pool without fees, in the layout of the swap program's `SwapV1`
+fn swap_pool(
+    nonce: u8,
+    token_a: Pubkey,
+    token_b: Pubkey,
+    pool_mint: Pubkey,
+    token_a_mint: Pubkey,
+    token_b_mint: Pubkey,
+    pool_fee_account: Pubkey,
+) -> Account {
+    let mut data = vec![0; SWAP_POOL_LEN];
+    data[0] = 1;
+    data[1] = 1;
+    data[2] = nonce;
+    let pubkeys = [
+        spl_token::id(),
+        token_a,
+        token_b,
+        pool_mint,
+        token_a_mint,
+        token_b_mint,
+        pool_fee_account,
+    ];
+    for (i, pubkey) in pubkeys.iter().enumerate() {
+        data[3 + 32 * i..35 + 32 * i].copy_from_slice(pubkey.as_ref());
+    }
+    let mut account = Account::new(u32::MAX as u64, 0, &spl_token_swap::id());
+    account.data = data;
+    account
+}
+
+struct TestReserve {
+    pubkey: Pubkey,
+    collateral_mint: Pubkey,
+}
+
+/// Reserve priced off `swap_pool` if given, averaging liquidation prices over `TWAP_SLOTS`
+#[allow(clippy::too_many_arguments)]
+fn add_reserve(
+    genesis: &mut TestValidatorGenesis,
+    lending_market: Pubkey,
+    lending_market_authority: Pubkey,
+    liquidity_mint: Pubkey,
+    swap_pool: Option<Pubkey>,
+    liquidity_amount: u64,
+    borrowed_amount: u64,
+    collateral_amount: u64,
+) -> TestReserve {
+    let reserve = TestReserve {
+        pubkey: Pubkey::new_unique(),
+        collateral_mint: Pubkey::new_unique(),
+    };
+    let liquidity_supply = Pubkey::new_unique();
+    let collateral_supply = Pubkey::new_unique();
+    let mut state = Reserve::new(NewReserveParams {
+        current_slot: 0,
+        lending_market,
+        collateral: ReserveCollateral::new(
+            reserve.collateral_mint,
+            collateral_supply,
+            Pubkey::new_unique(),
+        ),
+        liquidity: ReserveLiquidity::new(liquidity_mint, 0, liquidity_supply),
+        dex_market: swap_pool.map_or(COption::None, COption::Some),
+        price_source: PriceSource::TokenSwap,
+        // Without interest the amounts the liquidator repays are exact
+        config: ReserveConfig {
+            optimal_utilization_rate: 80,
+            borrow_rate_curve: BorrowRateCurve::from_single_kink(80, 0, 0, 0),
+            liquidation_twap_slots: swap_pool.map_or(0, |_| TWAP_SLOTS),
+            ..ReserveConfig::default()
+        },
+    });
+    state.liquidity.available_amount = liquidity_amount;
+    state.liquidity.borrowed_amount_wads = Decimal::from(borrowed_amount);
+    state.liquidity.margin_borrowed_amount = borrowed_amount;
+    state.collateral.mint_total_supply = liquidity_amount + borrowed_amount;
+
+    genesis.add_account(
+        reserve.pubkey,
+        packable_account(state, &spl_token_lending::id()),
+    );
+    genesis.add_account(
+        liquidity_supply,
+        token(liquidity_mint, lending_market_authority, liquidity_amount),
+    );
+    genesis.add_account(
+        reserve.collateral_mint,
+        mint(lending_market_authority, liquidity_amount + borrowed_amount),
+    );
+    genesis.add_account(
+        collateral_supply,
+        token(
+            reserve.collateral_mint,
+            lending_market_authority,
+            collateral_amount,
+        ),
+    );
+    reserve
+}
+
+/// A long position worth less than the maintenance margin allows at the average price is
+/// liquidated by the bot through the pair's pool, which keeps the liquidation fee
+#[test]
+fn liquidates_unhealthy_long() {
+    let payer = Keypair::new();
+    let margin_program_id = Pubkey::new_unique();
+    let mut genesis = TestValidatorGenesis::default();
+    genesis
+        .add_program("spl_token_lending", spl_token_lending::id())
+        .add_program("spl_token_swap", spl_token_swap::id())
+        .add_program("margin_account", margin_program_id);
+
+    let base_mint = Pubkey::new_unique();
+    let quote_mint = Pubkey::new_unique();
+    genesis.add_account(base_mint, mint(Pubkey::new_unique(), 2 * POOL_AMOUNT));
+    genesis.add_account(quote_mint, mint(Pubkey::new_unique(), 2 * POOL_AMOUNT));
+    let swap = Pubkey::new_unique();
+
+    // Lending market with the base deposit reserve the position's collateral sits in and the
+    // quote reserve it borrowed from
+    let lending_market = Pubkey::new_unique();
+    let (lending_market_authority, bump_seed) =
+        pda::find_lending_market_authority(&lending_market, &spl_token_lending::id());
+    genesis.add_account(
+        lending_market,
+        packable_account(
+            LendingMarket {
+                version: PROGRAM_VERSION,
+                bump_seed,
+                owner: Pubkey::new_unique(),
+                quote_token_mint: quote_mint,
+                token_program_id: spl_token::id(),
+            },
+            &spl_token_lending::id(),
+        ),
+    );
+    let deposit_reserve = add_reserve(
+        &mut genesis,
+        lending_market,
+        lending_market_authority,
+        base_mint,
+        Some(swap),
+        1_000,
+        0,
+        COLLATERAL_AMOUNT,
+    );
+    let borrow_reserve = add_reserve(
+        &mut genesis,
+        lending_market,
+        lending_market_authority,
+        quote_mint,
+        None,
+        10_000,
+        LOAN_AMOUNT,
+        0,
+    );
+
+    // Accumulator pricing the base at 1 since slot 0, the price of the even pool
+    let price_accumulator = Pubkey::new_unique();
+    genesis.add_account(
+        price_accumulator,
+        packable_account(
+            PriceAccumulator {
+                version: PROGRAM_VERSION,
+                lending_market,
+                market: swap,
+                price_source: PriceSource::TokenSwap,
+                base_mint,
+                quote_mint,
+                observation_interval: 1,
+                last_update_slot: 0,
+                last_price: Decimal::one(),
+                cumulative_price_wads: 0,
+                observations: vec![PriceObservation {
+                    slot: 0,
+                    cumulative_price_wads: 0,
+                }],
+            },
+            &spl_token_lending::id(),
+        ),
+    );
+
+    // Margin account holding the obligation tokens and the released collateral
+    let margin_account = Pubkey::new_unique();
+    let (vault_signer, nonce) = pda::find_margin_vault_signer(&margin_account, &margin_program_id);
+    let obligation = Pubkey::new_unique();
+    let obligation_mint = Pubkey::new_unique();
+    genesis.add_account(
+        obligation_mint,
+        mint(lending_market_authority, COLLATERAL_AMOUNT),
+    );
+    genesis.add_account(
+        Pubkey::new_unique(),
+        token(obligation_mint, vault_signer, COLLATERAL_AMOUNT),
+    );
+    genesis.add_account(
+        Pubkey::new_unique(),
+        token(deposit_reserve.collateral_mint, vault_signer, 0),
+    );
+    genesis.add_account(
+        obligation,
+        packable_account(
+            Obligation {
+                version: PROGRAM_VERSION,
+                deposited_collateral_tokens: COLLATERAL_AMOUNT,
+                collateral_reserve: deposit_reserve.pubkey,
+                cumulative_borrow_rate_wads: Decimal::one(),
+                borrowed_liquidity_wads: Decimal::from(LOAN_AMOUNT),
+                borrow_reserve: borrow_reserve.pubkey,
+                token_mint: obligation_mint,
+                margin_borrowed_amount: LOAN_AMOUNT,
+            },
+            &spl_token_lending::id(),
+        ),
+    );
+
+    // Margin program state, fee vaults and the whitelisted pair
+    let fee_owner = Pubkey::new_unique();
+    genesis.add_account(Pubkey::new_unique(), token(quote_mint, fee_owner, 0));
+    genesis.add_account(
+        pda::margin_state(&margin_program_id),
+        anchor_account(
+            &State {
+                authority: Pubkey::new_unique(),
+                fees: FeeConfig {
+                    open_fee_bps: 0,
+                    close_fee_bps: 0,
+                    stakers_share: 50,
+                    insurance_share: 25,
+                    community_share: 25,
+                    stakers_fee_owner: fee_owner,
+                    insurance_fee_owner: fee_owner,
+                    community_fee_owner: fee_owner,
+                },
+            },
+            &margin_program_id,
+        ),
+    );
+    let (token_pair, token_pair_nonce) =
+        pda::find_token_pair(&base_mint, &quote_mint, &margin_program_id);
+    genesis.add_account(
+        token_pair,
+        anchor_account(
+            &TokenPair {
+                first_token: base_mint,
+                second_token: quote_mint,
+                swap_pool: swap,
+                risk_params: RiskParams {
+                    max_leverage: 3,
+                    maintenance_margin: 20,
+                    liquidation_fee: 5,
+                    max_slippage: 10,
+                    max_open_interest: u64::MAX,
+                },
+                enabled: true,
+                long_open_interest: LOAN_AMOUNT,
+                short_open_interest: 0,
+                nonce: token_pair_nonce,
+            },
+            &margin_program_id,
+        ),
+    );
+
+    // 350 base held, worth 280 quote net of the 20% maintenance margin, against 300 borrowed
+    let loaned_vault = Pubkey::new_unique();
+    let collateral_vault = Pubkey::new_unique();
+    genesis.add_account(loaned_vault, token(quote_mint, vault_signer, 0));
+    genesis.add_account(
+        collateral_vault,
+        token(base_mint, vault_signer, HELD_AMOUNT),
+    );
+    genesis.add_account(
+        margin_account,
+        anchor_account(
+            &MarginAccount {
+                trader: Pubkey::new_unique(),
+                position: Some(Position {
+                    loan_amount: LOAN_AMOUNT,
+                    direction: Direction::Long,
+                    token_pair,
+                    obligation,
+                    loaned_vault,
+                    collateral_vault: Some(collateral_vault),
+                    status: Status::Locked,
+                    max_held_amount: u64::MAX,
+                    withdrawable_amount: 0,
+                    bad_debt: 0,
+                }),
+                nonce,
+            },
+            &margin_program_id,
+        ),
+    );
+
+    // Even pool the position is sold into
+    let (swap_authority, swap_nonce) =
+        Pubkey::find_program_address(&[swap.as_ref()], &spl_token_swap::id());
+    let (token_a, token_b, pool_mint, pool_fee_account) = (
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+        Pubkey::new_unique(),
+    );
+    genesis.add_account(token_a, token(base_mint, swap_authority, POOL_AMOUNT));
+    genesis.add_account(token_b, token(quote_mint, swap_authority, POOL_AMOUNT));
+    genesis.add_account(pool_mint, mint(swap_authority, 0));
+    genesis.add_account(pool_fee_account, token(pool_mint, fee_owner, 0));
+    genesis.add_account(
+        swap,
+        swap_pool(
+            swap_nonce,
+            token_a,
+            token_b,
+            pool_mint,
+            base_mint,
+            quote_mint,
+            pool_fee_account,
+        ),
+    );
+
+    // Liquidator's account for the fee
+    let liquidator_account = Pubkey::new_unique();
+    genesis.add_account(liquidator_account, token(quote_mint, payer.pubkey(), 0));
+
+    let test_validator = genesis.start_with_mint_address(payer.pubkey()).unwrap();
+    let client = RpcClient::new(test_validator.rpc_url());
+
+    // Once the accumulator's slot 0 observation is old enough to average from, sample the pool
+    // so the average is fresh when the bot runs
+    while client.get_slot().unwrap() < TWAP_SLOTS {
+        sleep(Duration::from_millis(400));
+    }
+    let mut transaction = Transaction::new_with_payer(
+        &[update_price_accumulator(
+            spl_token_lending::id(),
+            price_accumulator,
+            swap,
+            token_a,
+            token_b,
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], client.get_recent_blockhash().unwrap().0);
+    client.send_and_confirm_transaction(&transaction).unwrap();
+
+    let keypair_path = std::env::temp_dir().join(format!("liquidator-{}.json", payer.pubkey()));
+    write_keypair_file(&payer, &keypair_path).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_entropy-liquidator"))
+        .args(&["--url", &test_validator.rpc_url()])
+        .args(&["--keypair", keypair_path.to_str().unwrap()])
+        .args(&["--lending-program-id", &spl_token_lending::id().to_string()])
+        .args(&["--margin-program-id", &margin_program_id.to_string()])
+        .args(&["--swap-program-id", &spl_token_swap::id().to_string()])
+        .arg("--once")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // 250 base sold for 249 quote: 12 to the liquidator and 237 to repay the loan
+    let token_amount = |pubkey| {
+        Token::unpack(&client.get_account(&pubkey).unwrap().data)
+            .unwrap()
+            .amount
+    };
+    assert_eq!(token_amount(liquidator_account), 12);
+    assert_eq!(token_amount(collateral_vault), 0);
+    assert_eq!(token_amount(loaned_vault), 0);
+
+    let margin_account = MarginAccount::try_deserialize(
+        &mut client.get_account(&margin_account).unwrap().data.as_slice(),
+    )
+    .unwrap();
+    let position = margin_account.position.unwrap();
+    assert_eq!(position.loan_amount, LOAN_AMOUNT - 237);
+    assert_eq!(position.bad_debt, 0);
+    assert!(position.status == Status::Locked);
+
+    let obligation = Obligation::unpack(&client.get_account(&obligation).unwrap().data).unwrap();
+    assert_eq!(
+        obligation.borrowed_liquidity_wads,
+        Decimal::from(LOAN_AMOUNT - 237)
+    );
+}
@@ -0,0 +1,58 @@
+//! Helpers shared by the on-chain programs
+
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    program::{invoke, invoke_signed},
+    pubkey::Pubkey,
+    system_instruction,
+    sysvar::rent::Rent,
+};
+
+/// Creates `account` at the program derived address of `signer_seeds`, with `space` bytes owned
+/// by `owner` and rent paid by `payer`. Anyone can send lamports to the address beforehand, which
+/// makes `create_account` fail, so a funded address is topped up to the rent-exempt minimum and
+/// allocated and assigned instead. Callers check the address is not in use already.
+pub fn create_pda_account<'info>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    rent: &Rent,
+    space: usize,
+    owner: &Pubkey,
+    signer_seeds: &[&[u8]],
+) -> ProgramResult {
+    let system_accounts = [payer.clone(), account.clone(), system_program.clone()];
+    let lamports = rent.minimum_balance(space);
+    if account.lamports() == 0 {
+        return invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                account.key,
+                lamports,
+                space as u64,
+                owner,
+            ),
+            &system_accounts,
+            &[signer_seeds],
+        );
+    }
+
+    let missing_rent = lamports.saturating_sub(account.lamports());
+    if missing_rent > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, missing_rent),
+            &system_accounts,
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(account.key, space as u64),
+        &system_accounts,
+        &[signer_seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(account.key, owner),
+        &system_accounts,
+        &[signer_seeds],
+    )
+}
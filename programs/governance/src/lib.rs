@@ -0,0 +1,582 @@
+#![feature(proc_macro_hygiene)]
+
+use anchor_lang::prelude::*;
+use entropy_program_utils::create_pda_account;
+use solana_program::instruction::{AccountMeta, Instruction};
+use solana_program::program::invoke_signed;
+use staking::{StakePool, Staker};
+
+/// Seed prefix of vote record addresses, see `VoteRecord::signer_seeds`.
+pub const VOTE_RECORD_SEED: &[u8] = b"vote-record";
+
+/// Governance owns the protocol through a program derived signer. The margin `State::authority`
+/// and the lending market `owner` are set to this signer, so changing either can only happen by
+/// executing a proposal that passed a stake-weighted vote.
+#[program]
+pub mod governance {
+    use super::*;
+
+    /// Initialize a governance instance voted on by the stakers of `stake_pool`, whose authority
+    /// must be the governance signer.
+    pub fn create_governance(
+        ctx: Context<CreateGovernance>,
+        config: GovernanceConfig,
+        nonce: u8,
+    ) -> ProgramResult {
+        config.validate(&ctx.accounts.stake_pool)?;
+        let governance_key = ctx.accounts.governance.to_account_info().key;
+        let signer =
+            Pubkey::create_program_address(&[governance_key.as_ref(), &[nonce]], ctx.program_id)
+                .map_err(|_| ErrorCode::InvalidProgramAddress)?;
+        if ctx.accounts.stake_pool.authority != signer {
+            return Err(ErrorCode::InvalidStakePool.into());
+        }
+        let stake_pool = ctx.accounts.stake_pool.to_account_info();
+
+        let governance = &mut ctx.accounts.governance;
+        governance.staking_program = *stake_pool.owner;
+        governance.stake_pool = *stake_pool.key;
+        governance.config = config;
+        governance.proposal_count = 0;
+        governance.nonce = nonce;
+
+        Ok(())
+    }
+
+    /// Create a proposal to execute an instruction as the governance signer. Voting opens
+    /// immediately and lasts for the configured voting period.
+    pub fn create_proposal(
+        ctx: Context<CreateProposal>,
+        kind: ProposalKind,
+        program_id: Pubkey,
+        accounts: Vec<ProposalAccount>,
+        data: Vec<u8>,
+    ) -> ProgramResult {
+        let governance = &mut ctx.accounts.governance;
+        let voting_ends_at = ctx
+            .accounts
+            .clock
+            .unix_timestamp
+            .checked_add(governance.config.voting_period)
+            .ok_or(ErrorCode::MathOverflow)?;
+        governance.proposal_count += 1;
+
+        let proposal = &mut ctx.accounts.proposal;
+        proposal.governance = *governance.to_account_info().key;
+        proposal.proposer = *ctx.accounts.proposer.key;
+        proposal.id = governance.proposal_count;
+        proposal.kind = kind;
+        proposal.program_id = program_id;
+        proposal.accounts = accounts;
+        proposal.data = data;
+        proposal.voting_ends_at = voting_ends_at;
+        proposal.yes_votes = 0;
+        proposal.no_votes = 0;
+        proposal.executed = false;
+
+        Ok(())
+    }
+
+    /// Vote on an open proposal with the voter's stake. Each voter has a single vote record per
+    /// proposal, created by the vote at the address derived from the proposal and the voter.
+    pub fn vote(ctx: Context<Vote>, approve: bool, nonce: u8) -> ProgramResult {
+        let now = ctx.accounts.clock.unix_timestamp;
+        let proposal = &ctx.accounts.proposal;
+        if now >= proposal.voting_ends_at {
+            return Err(ErrorCode::VotingClosed.into());
+        }
+        let governance = &ctx.accounts.governance;
+        let staker_info = ctx.accounts.staker.to_account_info();
+        let stake_pool_info = ctx.accounts.stake_pool.to_account_info();
+        if staker_info.owner != &governance.staking_program
+            || stake_pool_info.owner != &governance.staking_program
+            || ctx.accounts.staker.pool != governance.stake_pool
+        {
+            return Err(ErrorCode::InvalidStaker.into());
+        }
+        let weight = vote_weight(
+            &ctx.accounts.staker,
+            ctx.accounts.voter.key,
+            &ctx.accounts.stake_pool,
+            now,
+            proposal.voting_ends_at,
+        )?;
+
+        let proposal_key = *proposal.to_account_info().key;
+        let vote_record_info = &ctx.accounts.vote_record;
+        let nonce_seed = [nonce];
+        let seeds = VoteRecord::signer_seeds(&proposal_key, ctx.accounts.voter.key, &nonce_seed);
+        let record_address = Pubkey::create_program_address(&seeds, ctx.program_id)
+            .map_err(|_| ErrorCode::InvalidProgramAddress)?;
+        if vote_record_info.key != &record_address {
+            return Err(ErrorCode::InvalidProgramAddress.into());
+        }
+        // Anyone can send lamports to the address, so only an account owned by this program or
+        // holding data is an existing vote.
+        if vote_record_info.owner == ctx.program_id || !vote_record_info.data_is_empty() {
+            return Err(ErrorCode::AlreadyVoted.into());
+        }
+
+        let vote_record = VoteRecord {
+            proposal: proposal_key,
+            voter: *ctx.accounts.voter.key,
+            approve,
+            weight,
+            nonce,
+        };
+        let mut data = Vec::new();
+        vote_record.try_serialize(&mut data)?;
+
+        create_pda_account(
+            &ctx.accounts.voter,
+            vote_record_info,
+            &ctx.accounts.system_program,
+            &ctx.accounts.rent,
+            data.len(),
+            ctx.program_id,
+            &seeds,
+        )?;
+        vote_record_info
+            .try_borrow_mut_data()?
+            .copy_from_slice(&data);
+
+        ctx.accounts.proposal.cast(approve, weight)
+    }
+
+    /// Execute a proposal that passed once its timelock has elapsed. The proposal's instruction
+    /// is invoked with the governance signer, taking its accounts from the remaining accounts.
+    pub fn execute_proposal(ctx: Context<ExecuteProposal>) -> ProgramResult {
+        let proposal = &ctx.accounts.proposal;
+        let config = &ctx.accounts.governance.config;
+        if proposal.executed {
+            return Err(ErrorCode::AlreadyExecuted.into());
+        }
+        let executable_at = proposal
+            .voting_ends_at
+            .checked_add(config.timelock)
+            .ok_or(ErrorCode::MathOverflow)?;
+        if ctx.accounts.clock.unix_timestamp < executable_at {
+            return Err(ErrorCode::TimelockNotExpired.into());
+        }
+        if !proposal.passed(config) {
+            return Err(ErrorCode::ProposalNotPassed.into());
+        }
+
+        let governance_signer = ctx.accounts.governance_signer.key;
+        let mut ix: Instruction = (&**proposal).into();
+        ix.accounts.iter_mut().for_each(|account| {
+            if &account.pubkey == governance_signer {
+                account.is_signer = true;
+            }
+        });
+        let seeds = &[
+            ctx.accounts.governance.to_account_info().key.as_ref(),
+            &[ctx.accounts.governance.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        invoke_signed(&ix, ctx.remaining_accounts, signer)?;
+
+        ctx.accounts.proposal.executed = true;
+
+        Ok(())
+    }
+
+    /// Update the voting configuration. Only callable by the governance signer, i.e. through an
+    /// executed proposal.
+    pub fn set_config(ctx: Context<SetConfig>, config: GovernanceConfig) -> ProgramResult {
+        config.validate(&ctx.accounts.stake_pool)?;
+        ctx.accounts.governance.config = config;
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CreateGovernance<'info> {
+    #[account(init)]
+    governance: ProgramAccount<'info, Governance>,
+    /// Stake pool whose stakers vote, owned by the staking program.
+    stake_pool: CpiAccount<'info, StakePool>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct CreateProposal<'info> {
+    #[account(mut)]
+    governance: ProgramAccount<'info, Governance>,
+    #[account(init)]
+    proposal: ProgramAccount<'info, Proposal>,
+    #[account(signer)]
+    proposer: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct Vote<'info> {
+    governance: ProgramAccount<'info, Governance>,
+    #[account(mut, has_one = governance)]
+    proposal: ProgramAccount<'info, Proposal>,
+    /// Vote record created by the vote, at the address of `VoteRecord::signer_seeds`.
+    #[account(mut)]
+    vote_record: AccountInfo<'info>,
+    /// Voter, also pays for the vote record account.
+    #[account(signer, mut)]
+    voter: AccountInfo<'info>,
+    /// Stake of the voter the vote is weighted by.
+    staker: CpiAccount<'info, Staker>,
+    #[account("stake_pool.to_account_info().key == &governance.stake_pool")]
+    stake_pool: CpiAccount<'info, StakePool>,
+    clock: Sysvar<'info, Clock>,
+    rent: Sysvar<'info, Rent>,
+    #[account("system_program.key == &solana_program::system_program::ID")]
+    system_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteProposal<'info> {
+    governance: ProgramAccount<'info, Governance>,
+    #[account(mut, has_one = governance)]
+    proposal: ProgramAccount<'info, Proposal>,
+    #[account(seeds = [governance.to_account_info().key.as_ref(), &[governance.nonce]])]
+    governance_signer: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct SetConfig<'info> {
+    #[account(mut)]
+    governance: ProgramAccount<'info, Governance>,
+    #[account(signer, seeds = [governance.to_account_info().key.as_ref(), &[governance.nonce]])]
+    governance_signer: AccountInfo<'info>,
+    #[account("stake_pool.to_account_info().key == &governance.stake_pool")]
+    stake_pool: CpiAccount<'info, StakePool>,
+}
+
+/// Governance instance of the protocol.
+#[account]
+pub struct Governance {
+    /// Program owning the stake pool and stakers.
+    pub staking_program: Pubkey,
+    /// Stake pool whose stakes are the voting weight.
+    pub stake_pool: Pubkey,
+    /// Voting rules applied to proposals.
+    pub config: GovernanceConfig,
+    /// Number of proposals created, used as the id of the next proposal.
+    pub proposal_count: u64,
+    /// nonce for program derived address
+    pub nonce: u8,
+}
+
+/// Voting rules of a governance instance.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct GovernanceConfig {
+    /// Minimum number of votes, in staked token units, that must be cast for a proposal to pass.
+    pub quorum_votes: u64,
+    /// Percentage of cast votes that must approve a proposal for it to pass.
+    pub approval_threshold: u8,
+    /// Seconds a proposal is open for voting.
+    pub voting_period: i64,
+    /// Seconds after voting ends before a passed proposal can be executed.
+    pub timelock: i64,
+}
+
+impl GovernanceConfig {
+    /// Checks the rules, and that unstakes from `stake_pool` stay in cooldown for as long as a
+    /// proposal is open so every vote can be counted.
+    fn validate(&self, stake_pool: &StakePool) -> Result<()> {
+        if self.quorum_votes == 0
+            || self.approval_threshold == 0
+            || self.approval_threshold > 100
+            || self.voting_period <= 0
+            || self.timelock < 0
+        {
+            return Err(ErrorCode::InvalidConfig.into());
+        }
+        if self.voting_period > stake_pool.config.cooldown {
+            return Err(ErrorCode::CooldownTooShort.into());
+        }
+        Ok(())
+    }
+}
+
+/// Proposal to execute a single instruction signed by governance.
+#[account]
+pub struct Proposal {
+    /// Governance instance the proposal belongs to.
+    pub governance: Pubkey,
+    /// Key that created the proposal.
+    pub proposer: Pubkey,
+    /// Sequential id of the proposal within its governance instance.
+    pub id: u64,
+    /// What the proposal does, for display purposes.
+    pub kind: ProposalKind,
+    /// Program the instruction is executed on.
+    pub program_id: Pubkey,
+    /// Accounts of the instruction.
+    pub accounts: Vec<ProposalAccount>,
+    /// Instruction data.
+    pub data: Vec<u8>,
+    /// Unix timestamp voting closes at.
+    pub voting_ends_at: i64,
+    /// Votes approving the proposal.
+    pub yes_votes: u64,
+    /// Votes rejecting the proposal.
+    pub no_votes: u64,
+    /// Set once the instruction has been executed.
+    pub executed: bool,
+}
+
+impl Proposal {
+    /// Adds `weight` votes for or against the proposal.
+    fn cast(&mut self, approve: bool, weight: u64) -> Result<()> {
+        let votes = match approve {
+            true => &mut self.yes_votes,
+            false => &mut self.no_votes,
+        };
+        *votes = votes.checked_add(weight).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Whether the cast votes reach the quorum and approval threshold.
+    pub fn passed(&self, config: &GovernanceConfig) -> bool {
+        let yes_votes = self.yes_votes as u128;
+        let total_votes = yes_votes + self.no_votes as u128;
+        total_votes >= config.quorum_votes as u128
+            && yes_votes * 100 >= total_votes * config.approval_threshold as u128
+    }
+}
+
+impl From<&Proposal> for Instruction {
+    fn from(proposal: &Proposal) -> Instruction {
+        Instruction {
+            program_id: proposal.program_id,
+            accounts: proposal.accounts.iter().map(AccountMeta::from).collect(),
+            data: proposal.data.clone(),
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum ProposalKind {
+    /// Changes protocol parameters, e.g. `add_token_pair` or a reserve config update.
+    ParamChange,
+    /// Spends funds held by the community fund.
+    Spend,
+}
+
+/// Account of a proposal instruction.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct ProposalAccount {
+    pub pubkey: Pubkey,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+impl From<&ProposalAccount> for AccountMeta {
+    fn from(account: &ProposalAccount) -> AccountMeta {
+        match account.is_writable {
+            false => AccountMeta::new_readonly(account.pubkey, account.is_signer),
+            true => AccountMeta::new(account.pubkey, account.is_signer),
+        }
+    }
+}
+
+/// Vote of a voter on a proposal.
+#[account]
+pub struct VoteRecord {
+    pub proposal: Pubkey,
+    pub voter: Pubkey,
+    pub approve: bool,
+    /// Stake the vote was weighted by.
+    pub weight: u64,
+    /// nonce for program derived address
+    pub nonce: u8,
+}
+
+impl VoteRecord {
+    /// Seeds of the vote record address of `voter` on `proposal`.
+    pub fn signer_seeds<'a>(
+        proposal: &'a Pubkey,
+        voter: &'a Pubkey,
+        nonce: &'a [u8],
+    ) -> [&'a [u8]; 4] {
+        [VOTE_RECORD_SEED, proposal.as_ref(), voter.as_ref(), nonce]
+    }
+}
+
+/// Voting weight of `voter`'s stake. The pool's unstake cooldown must last until voting ends, so
+/// the stake can't be unstaked and staked again to vote twice on the proposal.
+fn vote_weight(
+    staker: &Staker,
+    voter: &Pubkey,
+    stake_pool: &StakePool,
+    now: i64,
+    voting_ends_at: i64,
+) -> Result<u64> {
+    if &staker.owner != voter {
+        return Err(ErrorCode::InvalidStaker.into());
+    }
+    let cooldown_ends_at = now
+        .checked_add(stake_pool.config.cooldown)
+        .ok_or(ErrorCode::MathOverflow)?;
+    if cooldown_ends_at < voting_ends_at {
+        return Err(ErrorCode::CooldownTooShort.into());
+    }
+    match staker.stake_weight() {
+        0 => Err(ErrorCode::NoVotingWeight.into()),
+        weight => Ok(weight),
+    }
+}
+
+#[error]
+pub enum ErrorCode {
+    #[msg("Invalid program address. Did you provide the correct nonce?")]
+    InvalidProgramAddress,
+    #[msg("Stake pool authority must be the governance signer.")]
+    InvalidStakePool,
+    #[msg("Invalid governance config.")]
+    InvalidConfig,
+    #[msg("Voting on the proposal has ended.")]
+    VotingClosed,
+    #[msg("Proposal has already been executed.")]
+    AlreadyExecuted,
+    #[msg("Proposal timelock has not expired.")]
+    TimelockNotExpired,
+    #[msg("Proposal did not reach quorum and approval threshold.")]
+    ProposalNotPassed,
+    #[msg("Math operation overflow.")]
+    MathOverflow,
+    #[msg("Staker must be the voter's stake in the governance stake pool.")]
+    InvalidStaker,
+    #[msg("Voter has no stake.")]
+    NoVotingWeight,
+    #[msg("Stake pool cooldown ends before voting does.")]
+    CooldownTooShort,
+    #[msg("Voter has already voted on the proposal.")]
+    AlreadyVoted,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use staking::PoolConfig;
+
+    fn stake_pool(cooldown: i64) -> StakePool {
+        StakePool {
+            authority: Pubkey::new_unique(),
+            native_mint: Pubkey::new_unique(),
+            receipt_mint: Pubkey::new_unique(),
+            stake_vault: Pubkey::new_unique(),
+            reward_vault: Pubkey::new_unique(),
+            config: PoolConfig {
+                inflation_rate_bps: 0,
+                cooldown,
+            },
+            total_staked: 0,
+            reward_per_share: 0,
+            last_update_ts: 0,
+            nonce: 0,
+        }
+    }
+
+    fn staker(owner: Pubkey, staked: u64) -> Staker {
+        Staker {
+            pool: Pubkey::new_unique(),
+            owner,
+            staked,
+            reward_debt: 0,
+            pending_rewards: 0,
+            pending_unstakes: vec![],
+        }
+    }
+
+    fn proposal() -> Proposal {
+        Proposal {
+            governance: Pubkey::new_unique(),
+            proposer: Pubkey::new_unique(),
+            id: 1,
+            kind: ProposalKind::ParamChange,
+            program_id: Pubkey::new_unique(),
+            accounts: vec![],
+            data: vec![],
+            voting_ends_at: 100,
+            yes_votes: 0,
+            no_votes: 0,
+            executed: false,
+        }
+    }
+
+    #[test]
+    fn weighs_votes_by_the_voters_stake() {
+        let voter = Pubkey::new_unique();
+        let pool = stake_pool(100);
+
+        assert_eq!(
+            vote_weight(&staker(voter, 150), &voter, &pool, 0, 100).unwrap(),
+            150
+        );
+        let other = Pubkey::new_unique();
+        assert!(vote_weight(&staker(other, 150), &voter, &pool, 0, 100).is_err());
+        assert!(vote_weight(&staker(voter, 0), &voter, &pool, 0, 100).is_err());
+    }
+
+    #[test]
+    fn requires_cooldown_until_voting_ends() {
+        let voter = Pubkey::new_unique();
+        let staker = staker(voter, 150);
+
+        assert!(vote_weight(&staker, &voter, &stake_pool(99), 0, 100).is_err());
+        assert!(vote_weight(&staker, &voter, &stake_pool(100), 0, 100).is_ok());
+        assert!(vote_weight(&staker, &voter, &stake_pool(50), 50, 100).is_ok());
+    }
+
+    #[test]
+    fn voting_period_must_fit_in_the_cooldown() {
+        let config = GovernanceConfig {
+            quorum_votes: 100,
+            approval_threshold: 60,
+            voting_period: 10,
+            timelock: 0,
+        };
+
+        assert!(config.validate(&stake_pool(10)).is_ok());
+        assert!(config.validate(&stake_pool(9)).is_err());
+    }
+
+    #[test]
+    fn one_vote_record_per_voter_and_proposal() {
+        let program_id = Pubkey::new_unique();
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let voter = Pubkey::new_unique();
+        let address = |proposal: &Pubkey, voter: &Pubkey| {
+            let seeds = VoteRecord::signer_seeds(proposal, voter, &[]);
+            Pubkey::find_program_address(&seeds[..3], &program_id).0
+        };
+
+        assert_eq!(address(&first, &voter), address(&first, &voter));
+        assert_ne!(address(&first, &voter), address(&second, &voter));
+        assert_ne!(
+            address(&first, &voter),
+            address(&first, &Pubkey::new_unique())
+        );
+    }
+
+    #[test]
+    fn passes_with_quorum_and_approval() {
+        let config = GovernanceConfig {
+            quorum_votes: 100,
+            approval_threshold: 60,
+            voting_period: 10,
+            timelock: 0,
+        };
+        let mut proposal = proposal();
+        proposal.cast(true, 50).unwrap();
+        assert!(!proposal.passed(&config));
+        proposal.cast(false, 50).unwrap();
+        assert!(!proposal.passed(&config));
+        proposal.cast(true, 50).unwrap();
+        assert!(proposal.passed(&config));
+        assert!(proposal.cast(true, u64::MAX).is_err());
+    }
+}
@@ -1,8 +1,16 @@
 #![feature(proc_macro_hygiene)]
 
 use anchor_lang::prelude::*;
+use anchor_lang::{InstructionData, ToAccountMetas};
 use anchor_spl::token::{self, TokenAccount, Transfer};
+use entropy_program_utils::create_pda_account;
+use solana_program::instruction::Instruction;
 use solana_program::program::{invoke, invoke_signed};
+use solana_program::program_option::COption;
+use solana_program::program_pack::Pack;
+use spl_token_lending::math::{Decimal, Rate, TryAdd, TryDiv, TryMul, TrySub};
+use spl_token_lending::state::{Obligation, PriceAccumulator, Reserve};
+use spl_token_lending::swap_pool::SwapPool;
 
 #[program]
 pub mod margin_account {
@@ -12,66 +20,135 @@ pub mod margin_account {
     pub struct State {
         /// The key with the ability to change the token pairs whitelist.
         pub authority: Pubkey,
-        /// List of token pairs allowed for margin trading. This allows the user to whitelist
-        /// which tokens can be traded against.
-        pub token_pairs: Vec<TokenPair>,
+        /// Fees charged on positions and their split between the protocol funds.
+        pub fees: FeeConfig,
     }
 
     impl State {
-        pub const MAX_TOKEN_PAIRS: usize = 10;
-
         pub fn new(ctx: Context<Auth>) -> Result<Self> {
-            // init with default to set account size
-            let token_pairs = vec![Default::default(); Self::MAX_TOKEN_PAIRS];
             Ok(State {
                 authority: *ctx.accounts.authority.key,
-                token_pairs,
+                fees: FeeConfig::default(),
             })
         }
 
-        /// Should only be used as a temp function, state restricts from initializing with custom
-        /// size, so need to clear after initializing with default.
-        #[access_control(whitelist_auth(self, &ctx))]
-        pub fn clear_pairs(&mut self, ctx: Context<Auth>) -> Result<()> {
-            self.token_pairs.clear();
-            Ok(())
-        }
-
-        /// Adds token pair to whitelist.
-        #[access_control(whitelist_auth(self, &ctx))]
+        /// Adds token pair to whitelist. Pairs are enabled for trading once added.
+        /// Each pair lives in its own account at the program address derived from its two
+        /// mints (see `TokenPair::signer_seeds`), so the whitelist has no size limit. Positions
+        /// on the pair can only trade through the given token swap pool.
+        #[access_control(whitelist_auth(self, &ctx.accounts.authority))]
         pub fn add_token_pair(
             &mut self,
-            ctx: Context<Auth>,
+            ctx: Context<AddTokenPair>,
             first_token: Pubkey,
             second_token: Pubkey,
+            risk_params: RiskParams,
+            nonce: u8,
         ) -> Result<()> {
+            risk_params.validate()?;
+            if first_token == second_token {
+                return Err(ErrorCode::InvalidTokenPair.into());
+            }
+            let token_pair_info = &ctx.accounts.token_pair;
+            let nonce_seed = [nonce];
+            let seeds = TokenPair::signer_seeds(&first_token, &second_token, &nonce_seed);
+            let pair_address = Pubkey::create_program_address(&seeds, ctx.program_id)
+                .map_err(|_| ErrorCode::InvalidProgramAddress)?;
+            if token_pair_info.key != &pair_address {
+                return Err(ErrorCode::InvalidProgramAddress.into());
+            }
+            // Anyone can send lamports to the address, so only an account owned by this program
+            // or holding data counts as an existing pair.
+            if token_pair_info.owner == ctx.program_id || !token_pair_info.data_is_empty() {
+                return Err(ErrorCode::TokenPairAlreadyExists.into());
+            }
+            let swap_pool_info = &ctx.accounts.swap_pool;
+            if swap_pool_info.owner != &spl_token_swap::id() {
+                return Err(ErrorCode::InvalidSwapPool.into());
+            }
+            let swap_pool = SwapPool::unpack(&swap_pool_info.try_borrow_data()?)?;
+            if !swap_pool.trades(&first_token, &second_token) {
+                return Err(ErrorCode::InvalidSwapPool.into());
+            }
+
             let entry = TokenPair {
                 first_token,
                 second_token,
+                swap_pool: *swap_pool_info.key,
+                risk_params,
+                enabled: true,
+                long_open_interest: 0,
+                short_open_interest: 0,
+                nonce,
             };
-            if self.token_pairs.len() == Self::MAX_TOKEN_PAIRS {
-                return Err(ErrorCode::MaxTokenPairs.into());
-            }
-            if index_of_token_pair(&self.token_pairs, &entry).is_some() {
-                return Err(ErrorCode::TokenPairAlreadyExists.into());
-            }
-            self.token_pairs.push(entry);
+            let mut data = Vec::new();
+            entry.try_serialize(&mut data)?;
+
+            create_pda_account(
+                &ctx.accounts.authority,
+                token_pair_info,
+                &ctx.accounts.system_program,
+                &ctx.accounts.rent,
+                data.len(),
+                ctx.program_id,
+                &seeds,
+            )?;
+            token_pair_info
+                .try_borrow_mut_data()?
+                .copy_from_slice(&data);
             Ok(())
         }
 
-        /// Removes token pair from whitelist.
-        #[access_control(whitelist_auth(self, &ctx))]
-        pub fn remove_token_pair(&mut self, ctx: Context<Auth>, entry: TokenPair) -> Result<()> {
-            if let Some(idx) = index_of_token_pair(&self.token_pairs, &entry) {
-                self.token_pairs.remove(idx);
-                Ok(())
-            } else {
-                Err(ErrorCode::InvalidTokenPair.into())
+        /// Removes token pair from whitelist, returning the account's rent to the authority.
+        /// Pairs with open positions can only be disabled, as closing them updates the pair.
+        #[access_control(whitelist_auth(self, &ctx.accounts.authority))]
+        pub fn remove_token_pair(&mut self, ctx: Context<UpdateTokenPair>) -> Result<()> {
+            let token_pair = &ctx.accounts.token_pair;
+            if token_pair.long_open_interest > 0 || token_pair.short_open_interest > 0 {
+                return Err(ErrorCode::TokenPairInUse.into());
             }
+            let token_pair_info = ctx.accounts.token_pair.to_account_info();
+            let authority_info = &ctx.accounts.authority;
+            **authority_info.lamports.borrow_mut() += token_pair_info.lamports();
+            **token_pair_info.lamports.borrow_mut() = 0;
+            ctx.accounts.token_pair.enabled = false;
+            Ok(())
+        }
+
+        /// Updates the risk parameters of a whitelisted token pair.
+        #[access_control(whitelist_auth(self, &ctx.accounts.authority))]
+        pub fn set_risk_params(
+            &mut self,
+            ctx: Context<UpdateTokenPair>,
+            risk_params: RiskParams,
+        ) -> Result<()> {
+            risk_params.validate()?;
+            ctx.accounts.token_pair.risk_params = risk_params;
+            Ok(())
+        }
+
+        /// Enables or disables opening new positions on a whitelisted token pair. Existing
+        /// positions can still be closed while a pair is disabled.
+        #[access_control(whitelist_auth(self, &ctx.accounts.authority))]
+        pub fn set_pair_enabled(
+            &mut self,
+            ctx: Context<UpdateTokenPair>,
+            enabled: bool,
+        ) -> Result<()> {
+            ctx.accounts.token_pair.enabled = enabled;
+            Ok(())
+        }
+
+        /// Updates the position fees and how they are split between the protocol funds.
+        #[access_control(whitelist_auth(self, &ctx.accounts.authority))]
+        pub fn set_fees(&mut self, ctx: Context<Auth>, fees: FeeConfig) -> Result<()> {
+            fees.validate()?;
+            self.fees = fees;
+            Ok(())
         }
 
         /// Update authority allowed to modify token pair whitelist.
-        #[access_control(whitelist_auth(self, &ctx))]
+        #[access_control(whitelist_auth(self, &ctx.accounts.authority))]
         pub fn set_authority(&mut self, ctx: Context<Auth>, new_authority: Pubkey) -> Result<()> {
             self.authority = new_authority;
             Ok(())
@@ -110,12 +187,24 @@ pub mod margin_account {
         Ok(())
     }
 
-    /// Trade on an amm with the loaned tokens.
+    /// Trade on an amm with the loaned tokens, through the pool of the position's token pair.
     pub fn trade_amm(
         ctx: Context<TradeAmm>,
         amount_in: u64,
         minimum_amount_out: u64,
     ) -> ProgramResult {
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .as_ref()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        if position.token_pair != *ctx.accounts.token_pair.to_account_info().key {
+            return Err(ErrorCode::InvalidTokenPair.into());
+        }
+        if ctx.accounts.swap_info.key != &ctx.accounts.token_pair.swap_pool {
+            return Err(ErrorCode::InvalidSwapPool.into());
+        }
         let accounts = ctx.accounts.to_account_infos();
 
         // create the desired swap amount and minimum amout of slippage the user is willing to sustain
@@ -148,6 +237,7 @@ pub mod margin_account {
 
         invoke_signed(instruction, &accounts[1..], signer)?;
 
+        let destination_vault = ctx.accounts.destination_vault.to_account_info();
         // Mark account as having an open trade
         let margin_account = &mut ctx.accounts.margin_account;
         let position = margin_account
@@ -155,17 +245,40 @@ pub mod margin_account {
             .as_mut()
             .ok_or(ErrorCode::InvalidProgramAddress)?;
         if position.collateral_vault.is_none() {
-            position.collateral_vault = Some(*ctx.accounts.destination_vault.to_account_info().key);
+            position.collateral_vault = Some(*destination_vault.key);
+        }
+
+        // Opening trades can't hold more than the pair's leverage allows
+        if position.collateral_vault == Some(*destination_vault.key)
+            && vault_amount(&destination_vault)? > position.max_held_amount
+        {
+            return Err(ErrorCode::LeverageExceeded.into());
         }
 
         Ok(())
     }
 
     /// repay repays the outstanding loan. If the user is not able to return what they took out it is taken from the collateral
+    /// The loan vault also pays the close fee on the repaid amount.
     pub fn repay(ctx: Context<Repay>, amount: u64) -> ProgramResult {
         if amount == 0 {
             return Err(ErrorCode::InvalidAmount.into());
         };
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .as_ref()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        if position.token_pair != *ctx.accounts.token_pair.to_account_info().key {
+            return Err(ErrorCode::InvalidTokenPair.into());
+        }
+        if position.obligation != *ctx.accounts.obligation.key {
+            return Err(ErrorCode::InvalidObligation.into());
+        }
+        if amount > position.loan_amount {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
 
         let instruction = &spl_token_lending::instruction::repay_reserve_liquidity(
             *ctx.accounts.lending_program.key,
@@ -190,6 +303,16 @@ pub mod margin_account {
         ];
         let signer = &[&seeds[..]];
 
+        charge_fee(
+            &ctx.accounts.state.fees,
+            ctx.accounts.state.fees.close_fee_bps,
+            amount,
+            &ctx.accounts.loan_vault,
+            &ctx.accounts.fee_vaults,
+            &ctx.accounts.vault_signer,
+            &ctx.accounts.token_program,
+            signer,
+        )?;
         invoke_signed(instruction, &ctx.accounts.to_account_infos(), signer)?;
 
         // Mark account as having an open trade
@@ -198,6 +321,9 @@ pub mod margin_account {
             .position
             .as_mut()
             .ok_or(ErrorCode::InvalidProgramAddress)?;
+        ctx.accounts
+            .token_pair
+            .release_open_interest(position.direction, amount);
         position.loan_amount -= amount;
         if position.loan_amount == 0 {
             position.status = Status::Available;
@@ -206,14 +332,487 @@ pub mod margin_account {
         Ok(())
     }
 
-    pub fn borrow(ctx: Context<Borrow>, loan_amount: u64, collateral_amount: u64) -> ProgramResult {
-        let token_pair = TokenPair {
-            first_token: *ctx.accounts.obligation_token_mint.key,
-            second_token: ctx.accounts.deposit_reserve_collateral_supply.mint,
+    /// Partially closes an open position. Sells `amount_in` of the collateral vault into the loaned
+    /// vault and repays the same share of the outstanding loan. Swap proceeds above the repaid
+    /// share become withdrawable while the rest of the position stays open, as far as the rest
+    /// stays above the pair's maintenance margin; the remainder stays in the loaned vault.
+    ///
+    /// The rest of the position is valued like in `liquidate`, at the lending program's average
+    /// price from the price accumulator passed as the only remaining account, so the reserve
+    /// priced off the market must set `liquidation_twap_slots`. The sale only goes through the
+    /// pair's pool.
+    pub fn reduce_position(
+        ctx: Context<ReducePosition>,
+        amount_in: u64,
+        minimum_amount_out: u64,
+    ) -> ProgramResult {
+        if amount_in == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .as_ref()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        if position.status != Status::Locked {
+            return Err(ErrorCode::NoOpenPosition.into());
+        }
+        let collateral_vault_key = *ctx.accounts.collateral_vault.to_account_info().key;
+        let loaned_vault_key = *ctx.accounts.loaned_vault.to_account_info().key;
+        if position.collateral_vault != Some(collateral_vault_key)
+            || position.loaned_vault != loaned_vault_key
+        {
+            return Err(ErrorCode::InvalidPositionVault.into());
+        }
+        if position.token_pair != *ctx.accounts.token_pair.to_account_info().key {
+            return Err(ErrorCode::InvalidTokenPair.into());
+        }
+        if ctx.accounts.swap_info.key != &ctx.accounts.token_pair.swap_pool {
+            return Err(ErrorCode::InvalidSwapPool.into());
+        }
+
+        let collateral_balance = ctx.accounts.collateral_vault.amount;
+        if amount_in > collateral_balance {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        let repay_amount = if amount_in == collateral_balance {
+            position.loan_amount
+        } else {
+            repay_share(position.loan_amount, amount_in, collateral_balance)?
+        };
+        if repay_amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+
+        let accounts = ctx.accounts.to_account_infos();
+        let seeds = &[
+            ctx.accounts.margin_account.to_account_info().key.as_ref(),
+            &[ctx.accounts.margin_account.nonce],
+        ];
+        let signer = &[&seeds[..]];
+
+        // Sell the collateral share back into the loan denomination
+        let balance_before = ctx.accounts.loaned_vault.amount;
+        let swap = &spl_token_swap::instruction::swap(
+            ctx.accounts.swap_program.key,
+            ctx.accounts.token_program.key,
+            ctx.accounts.swap_info.key,
+            ctx.accounts.swap_authority.key,
+            ctx.accounts.vault_signer.key,
+            &collateral_vault_key,
+            ctx.accounts.swap_source.key,
+            ctx.accounts.swap_dest.key,
+            &loaned_vault_key,
+            ctx.accounts.pool_mint.key,
+            ctx.accounts.pool_fee.key,
+            Some(ctx.accounts.host_fee.key),
+            spl_token_swap::instruction::Swap {
+                amount_in,
+                minimum_amount_out,
+            },
+        )?;
+        invoke_signed(swap, &accounts, signer)?;
+
+        let proceeds = vault_amount(&ctx.accounts.loaned_vault.to_account_info())?
+            .checked_sub(balance_before)
+            .ok_or(ErrorCode::InsufficientProceeds)?;
+        let fee = charge_fee(
+            &ctx.accounts.state.fees,
+            ctx.accounts.state.fees.close_fee_bps,
+            repay_amount,
+            &ctx.accounts.loaned_vault,
+            &ctx.accounts.fee_vaults,
+            &ctx.accounts.vault_signer,
+            &ctx.accounts.token_program,
+            signer,
+        )?;
+        let net_proceeds = proceeds
+            .checked_sub(fee)
+            .ok_or(ErrorCode::InsufficientProceeds)?;
+        if net_proceeds < repay_amount {
+            return Err(ErrorCode::InsufficientProceeds.into());
+        }
+
+        // Repay the matching share of the loan from the proceeds
+        let repay = &spl_token_lending::instruction::repay_reserve_liquidity(
+            *ctx.accounts.lending_program.key,
+            repay_amount,
+            loaned_vault_key,
+            *ctx.accounts.destination_coll_account.key,
+            *ctx.accounts.repay_reserve_account.key,
+            *ctx.accounts.repay_reserve_spl_acccount.key,
+            *ctx.accounts.withdraw_reserve.key,
+            *ctx.accounts.withdraw_reserve_collateral.key,
+            *ctx.accounts.obligation.key,
+            *ctx.accounts.obligation_mint.key,
+            *ctx.accounts.obligation_input.key,
+            *ctx.accounts.lending_market.key,
+            *ctx.accounts.derived_lending_authority.key,
+            *ctx.accounts.vault_signer.key,
+        );
+        invoke_signed(repay, &accounts, signer)?;
+
+        // Value what is left of the position after the repayment
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .clone()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        let loan = PositionLoan::load(
+            &position,
+            &ctx.accounts.lending_program,
+            &ctx.accounts.obligation,
+            &ctx.accounts.withdraw_reserve,
+            &ctx.accounts.repay_reserve_account,
+        )?;
+        let price = loan.twap(
+            &ctx.accounts.token_pair,
+            &ctx.accounts.lending_program,
+            ctx.remaining_accounts.get(0),
+            ctx.accounts.clock.slot,
+        )?;
+        // The swap may have taken more or less than `amount_in` out of the collateral vault
+        let held = loan
+            .collateral()?
+            .try_add(vault_amount(&ctx.accounts.collateral_vault.to_account_info())?.into())?;
+        let risk_params = ctx.accounts.token_pair.risk_params;
+
+        ctx.accounts
+            .token_pair
+            .release_open_interest(position.direction, repay_amount);
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .as_mut()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        position.reduce(
+            repay_amount,
+            net_proceeds - repay_amount,
+            held,
+            loan.debt(),
+            price,
+            &risk_params,
+        )?;
+
+        Ok(())
+    }
+
+    /// Closes a position whose health factor is below one, i.e. under the maintenance margin of
+    /// its token pair. Anyone can liquidate: the whole collateral vault is sold into the loaned
+    /// vault, the pair's `liquidation_fee` percent of the proceeds goes to the liquidator and the
+    /// rest repays the loan and the close fee on the repaid amount. Whatever is left stays in the
+    /// loaned vault for the trader. Debt that neither the proceeds nor the obligation's collateral
+    /// cover is recorded as the position's bad debt, for `cover_bad_debt` to repay from the
+    /// insurance fund.
+    ///
+    /// Health is judged at the average price of the lending program's price accumulator, passed
+    /// as the only remaining account, so the reserve priced off the market must set
+    /// `liquidation_twap_slots`. The sale must return at least that price less the pair's
+    /// `max_slippage`, or `minimum_amount_out` if higher, and only goes through the pair's pool.
+    pub fn liquidate(ctx: Context<Liquidate>, minimum_amount_out: u64) -> ProgramResult {
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .clone()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        if position.status != Status::Locked {
+            return Err(ErrorCode::NoOpenPosition.into());
+        }
+        let collateral_vault_key = *ctx.accounts.collateral_vault.to_account_info().key;
+        let loaned_vault_key = *ctx.accounts.loaned_vault.to_account_info().key;
+        if position.collateral_vault != Some(collateral_vault_key)
+            || position.loaned_vault != loaned_vault_key
+        {
+            return Err(ErrorCode::InvalidPositionVault.into());
+        }
+        if position.token_pair != *ctx.accounts.token_pair.to_account_info().key {
+            return Err(ErrorCode::InvalidTokenPair.into());
+        }
+        if ctx.accounts.swap_info.key != &ctx.accounts.token_pair.swap_pool {
+            return Err(ErrorCode::InvalidSwapPool.into());
+        }
+        // Collateral released by the repayment must stay with the margin account
+        if &ctx.accounts.destination_coll_account.owner != ctx.accounts.vault_signer.key {
+            return Err(ErrorCode::InvalidVaultOwner.into());
+        }
+
+        let loan = PositionLoan::load(
+            &position,
+            &ctx.accounts.lending_program,
+            &ctx.accounts.obligation,
+            &ctx.accounts.withdraw_reserve,
+            &ctx.accounts.repay_reserve_account,
+        )?;
+        let price = loan.twap(
+            &ctx.accounts.token_pair,
+            &ctx.accounts.lending_program,
+            ctx.remaining_accounts.get(0),
+            ctx.accounts.clock.slot,
+        )?;
+        let amount_in = ctx.accounts.collateral_vault.amount;
+        if amount_in == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        let collateral = loan.collateral()?;
+        let held = collateral.try_add(amount_in.into())?;
+        let risk_params = ctx.accounts.token_pair.risk_params;
+        match position.health_factor(held, loan.debt(), price, &risk_params)? {
+            Some(health_factor) if health_factor < Decimal::one() => {}
+            _ => return Err(ErrorCode::PositionHealthy.into()),
+        }
+        let minimum_amount_out = position
+            .minimum_sale_proceeds(amount_in, price, &risk_params)?
+            .max(minimum_amount_out);
+
+        let accounts = ctx.accounts.to_account_infos();
+        let seeds = &[
+            ctx.accounts.margin_account.to_account_info().key.as_ref(),
+            &[ctx.accounts.margin_account.nonce],
+        ];
+        let signer = &[&seeds[..]];
+
+        // Sell everything the position holds back into the loan denomination
+        let balance_before = ctx.accounts.loaned_vault.amount;
+        let swap = &spl_token_swap::instruction::swap(
+            ctx.accounts.swap_program.key,
+            ctx.accounts.token_program.key,
+            ctx.accounts.swap_info.key,
+            ctx.accounts.swap_authority.key,
+            ctx.accounts.vault_signer.key,
+            &collateral_vault_key,
+            ctx.accounts.swap_source.key,
+            ctx.accounts.swap_dest.key,
+            &loaned_vault_key,
+            ctx.accounts.pool_mint.key,
+            ctx.accounts.pool_fee.key,
+            Some(ctx.accounts.host_fee.key),
+            spl_token_swap::instruction::Swap {
+                amount_in,
+                minimum_amount_out,
+            },
+        )?;
+        invoke_signed(swap, &accounts, signer)?;
+
+        let proceeds = vault_amount(&ctx.accounts.loaned_vault.to_account_info())?
+            .checked_sub(balance_before)
+            .ok_or(ErrorCode::InsufficientProceeds)?;
+
+        let liquidation_fee =
+            ((proceeds as u128) * (risk_params.liquidation_fee as u128) / 100) as u64;
+        if liquidation_fee > 0 {
+            let cpi_accounts = Transfer {
+                from: ctx.accounts.loaned_vault.to_account_info(),
+                to: ctx.accounts.liquidator_account.to_account_info(),
+                authority: ctx.accounts.vault_signer.clone(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(
+                ctx.accounts.token_program.clone(),
+                cpi_accounts,
+                signer,
+            );
+            token::transfer(cpi_ctx, liquidation_fee)?;
+        }
+
+        // Repay as much of the loan as the rest of the proceeds cover, close fee included
+        let fees = &ctx.accounts.state.fees;
+        let covered_amount = fees
+            .max_repay_amount(proceeds - liquidation_fee)
+            .min(position.loan_amount);
+        charge_fee(
+            fees,
+            fees.close_fee_bps,
+            covered_amount,
+            &ctx.accounts.loaned_vault,
+            &ctx.accounts.fee_vaults,
+            &ctx.accounts.vault_signer,
+            &ctx.accounts.token_program,
+            signer,
+        )?;
+
+        // Debt the obligation's collateral can't cover either is left to the insurance fund
+        let debt = loan.debt();
+        let covered = Decimal::from(covered_amount);
+        let remaining_debt = if debt > covered {
+            debt.try_sub(covered)?
+        } else {
+            Decimal::zero()
         };
-        if index_of_token_pair(&ctx.accounts.state.token_pairs, &token_pair).is_none() {
+        let bad_debt = position
+            .bad_debt(collateral, remaining_debt, price)?
+            .min(position.loan_amount - covered_amount);
+        if covered_amount > 0 {
+            let repay = &spl_token_lending::instruction::repay_reserve_liquidity(
+                *ctx.accounts.lending_program.key,
+                covered_amount,
+                loaned_vault_key,
+                *ctx.accounts.destination_coll_account.to_account_info().key,
+                *ctx.accounts.repay_reserve_account.key,
+                *ctx.accounts.repay_reserve_spl_acccount.key,
+                *ctx.accounts.withdraw_reserve.key,
+                *ctx.accounts.withdraw_reserve_collateral.key,
+                *ctx.accounts.obligation.key,
+                *ctx.accounts.obligation_mint.key,
+                *ctx.accounts.obligation_input.key,
+                *ctx.accounts.lending_market.key,
+                *ctx.accounts.derived_lending_authority.key,
+                *ctx.accounts.vault_signer.key,
+            );
+            invoke_signed(repay, &accounts, signer)?;
+        }
+
+        ctx.accounts
+            .token_pair
+            .release_open_interest(position.direction, covered_amount);
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .as_mut()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        position.loan_amount -= covered_amount;
+        position.bad_debt = bad_debt;
+        if position.loan_amount == 0 {
+            position.status = Status::Available;
+        }
+
+        Ok(())
+    }
+
+    /// Repays the bad debt a liquidation left on a position with tokens of the insurance fund
+    /// holding the borrowed token, paid into the loaned vault by the treasury at the request of
+    /// this program's coverage authority, and repaid straight into the liquidity supply of the
+    /// reserve the position borrowed from. Anyone can cover a position's bad debt.
+    pub fn cover_bad_debt(ctx: Context<CoverBadDebt>) -> ProgramResult {
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .clone()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        if position.status != Status::Locked {
+            return Err(ErrorCode::NoOpenPosition.into());
+        }
+        let loaned_vault_key = *ctx.accounts.loaned_vault.to_account_info().key;
+        if position.loaned_vault != loaned_vault_key {
+            return Err(ErrorCode::InvalidPositionVault.into());
+        }
+        if position.token_pair != *ctx.accounts.token_pair.to_account_info().key {
+            return Err(ErrorCode::InvalidTokenPair.into());
+        }
+        // The coverage may only leave the loaned vault into the liquidity supply of the reserve
+        // the position borrowed from
+        let loan = PositionLoan::load(
+            &position,
+            &ctx.accounts.lending_program,
+            &ctx.accounts.obligation,
+            &ctx.accounts.withdraw_reserve,
+            &ctx.accounts.repay_reserve_account,
+        )?;
+        if ctx.accounts.repay_reserve_spl_acccount.key
+            != &loan.borrow_reserve.liquidity.supply_pubkey
+        {
+            return Err(ErrorCode::InvalidReserve.into());
+        }
+        // Collateral released by the repayment must stay with the margin account
+        if &ctx.accounts.destination_coll_account.owner != ctx.accounts.vault_signer.key {
+            return Err(ErrorCode::InvalidVaultOwner.into());
+        }
+        // The trader may have repaid part of the loan since the liquidation
+        let amount = position.bad_debt.min(position.loan_amount);
+        if amount == 0 {
+            return Err(ErrorCode::NoBadDebt.into());
+        }
+
+        pay_from_insurance(
+            &ctx.accounts.insurance_coverage,
+            &ctx.accounts.loaned_vault,
+            &ctx.accounts.token_program,
+            &ctx.accounts.clock,
+            ctx.program_id,
+            amount,
+        )?;
+
+        let seeds = &[
+            ctx.accounts.margin_account.to_account_info().key.as_ref(),
+            &[ctx.accounts.margin_account.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let repay = &spl_token_lending::instruction::repay_reserve_liquidity(
+            *ctx.accounts.lending_program.key,
+            amount,
+            loaned_vault_key,
+            *ctx.accounts.destination_coll_account.to_account_info().key,
+            *ctx.accounts.repay_reserve_account.key,
+            *ctx.accounts.repay_reserve_spl_acccount.key,
+            *ctx.accounts.withdraw_reserve.key,
+            *ctx.accounts.withdraw_reserve_collateral.key,
+            *ctx.accounts.obligation.key,
+            *ctx.accounts.obligation_mint.key,
+            *ctx.accounts.obligation_input.key,
+            *ctx.accounts.lending_market.key,
+            *ctx.accounts.derived_lending_authority.key,
+            *ctx.accounts.vault_signer.key,
+        );
+        invoke_signed(repay, &ctx.accounts.to_account_infos(), signer)?;
+
+        ctx.accounts
+            .token_pair
+            .release_open_interest(position.direction, amount);
+        let position = ctx
+            .accounts
+            .margin_account
+            .position
+            .as_mut()
+            .ok_or(ErrorCode::NoOpenPosition)?;
+        position.loan_amount -= amount;
+        position.bad_debt = 0;
+        if position.loan_amount == 0 {
+            position.status = Status::Available;
+        }
+
+        Ok(())
+    }
+
+    /// Borrow liquidity against the deposited collateral to open a position. Longs borrow the
+    /// quote (`second_token`) of a whitelisted pair and shorts borrow the base (`first_token`);
+    /// in both cases the loan is later swapped into the deposit reserve's token.
+    pub fn borrow(
+        ctx: Context<Borrow>,
+        loan_amount: u64,
+        collateral_amount: u64,
+        direction: Direction,
+    ) -> ProgramResult {
+        if ctx.accounts.deposit_reserve.owner != ctx.accounts.lending_program.key {
+            return Err(ErrorCode::InvalidReserve.into());
+        }
+        let deposit_reserve = Reserve::unpack(&ctx.accounts.deposit_reserve.try_borrow_data()?)?;
+        let loaned_token = ctx.accounts.loaned_vault.mint;
+        let whitelisted = &ctx.accounts.token_pair;
+        if !whitelisted.matches(&deposit_reserve.liquidity.mint_pubkey, &loaned_token) {
             return Err(ErrorCode::InvalidTokenPair.into());
         }
+        if whitelisted.borrowed_token(direction) != loaned_token {
+            return Err(ErrorCode::InvalidDirection.into());
+        }
+        if !whitelisted.enabled {
+            return Err(ErrorCode::TokenPairDisabled.into());
+        }
+        let open_interest = whitelisted
+            .open_interest(direction)
+            .checked_add(loan_amount)
+            .ok_or(ErrorCode::OpenInterestExceeded)?;
+        if open_interest > whitelisted.risk_params.max_open_interest {
+            return Err(ErrorCode::OpenInterestExceeded.into());
+        }
+        let max_held_amount = max_held_amount(
+            &deposit_reserve,
+            collateral_amount,
+            &whitelisted.risk_params,
+        )?;
         if loan_amount == 0 || collateral_amount == 0 {
             return Err(ErrorCode::InvalidAmount.into());
         };
@@ -245,8 +844,8 @@ pub mod margin_account {
             *ctx.accounts.obligation_token_mint.key,
             *ctx.accounts.obligation_token_output.key,
             *ctx.accounts.dex_market.key,
-            *ctx.accounts.dex_market_order_book_side.key,
-            *ctx.accounts.memory.key,
+            *ctx.accounts.dex_market_bids.key,
+            *ctx.accounts.dex_market_asks.key,
             None,
         );
 
@@ -258,13 +857,34 @@ pub mod margin_account {
 
         invoke_signed(instruction, &accounts[1..], signer)?;
 
+        // The open fee is taken from the borrowed liquidity
+        charge_fee(
+            &ctx.accounts.state.fees,
+            ctx.accounts.state.fees.open_fee_bps,
+            loan_amount,
+            &ctx.accounts.loaned_vault,
+            &ctx.accounts.fee_vaults,
+            &ctx.accounts.vault_signer,
+            &ctx.accounts.token_program,
+            signer,
+        )?;
+
+        let token_pair = &mut ctx.accounts.token_pair;
+        *token_pair.open_interest_mut(direction) = open_interest;
+
         // update margin account with loan_vault and total
         let margin = &mut ctx.accounts.margin_account;
         margin.position = Some(Position {
             loan_amount,
+            direction,
+            token_pair: *token_pair.to_account_info().key,
+            obligation: *ctx.accounts.obligation.key,
             status: Status::Locked,
             loaned_vault: *ctx.accounts.loaned_vault.to_account_info().key,
             collateral_vault: None,
+            max_held_amount,
+            withdrawable_amount: 0,
+            bad_debt: 0,
         });
 
         Ok(())
@@ -274,18 +894,17 @@ pub mod margin_account {
         if amount == 0 {
             return Err(ErrorCode::InvalidAmount.into());
         };
+        let vault_key = *ctx.accounts.vault.to_account_info().key;
         let margin_account = &mut ctx.accounts.margin_account;
         let position = margin_account
             .position
-            .as_ref()
+            .as_mut()
             .ok_or(ErrorCode::WithdrawDisabled)?;
-        if position.status == Status::Locked {
-            return Err(ErrorCode::WithdrawDisabled.into());
-        }
+        position.withdraw(&vault_key, amount)?;
 
         // Transfer funds from collateral vault, if any to the user
         let seeds = &[
-            margin_account.to_account_info().key.as_ref(),
+            ctx.accounts.margin_account.to_account_info().key.as_ref(),
             &[ctx.accounts.margin_account.nonce],
         ];
         let signer = &[&seeds[..]];
@@ -361,6 +980,7 @@ pub struct TradeAmm<'info> {
     #[account(signer)]
     trader: AccountInfo<'info>,
     /// accounts needed to call
+    #[account("swap_program.key == &spl_token_swap::id()")]
     swap_program: AccountInfo<'info>,
     swap_info: AccountInfo<'info>,
     swap_authority: AccountInfo<'info>,
@@ -378,6 +998,8 @@ pub struct TradeAmm<'info> {
     /// accounts needed to access funds from token vault
     #[account(mut, has_one = trader)]
     margin_account: ProgramAccount<'info, MarginAccount>,
+    /// Token pair the position was opened on.
+    token_pair: ProgramAccount<'info, TokenPair>,
     #[account(mut)]
     source_vault: CpiAccount<'info, TokenAccount>,
     #[account(mut)]
@@ -421,36 +1043,226 @@ pub struct Repay<'info> {
 
     #[account(mut)]
     margin_account: ProgramAccount<'info, MarginAccount>,
+    /// Token pair the position was opened on.
+    #[account(mut)]
+    token_pair: ProgramAccount<'info, TokenPair>,
     #[account(seeds = [margin_account.to_account_info().key.as_ref(), &[margin_account.nonce]])]
     vault_signer: AccountInfo<'info>,
+    /// accounts needed to charge the close fee
+    state: ProgramState<'info, State>,
+    fee_vaults: FeeVaults<'info>,
     #[account("token_program.key == &token::ID")]
     token_program: AccountInfo<'info>,
     clock: Sysvar<'info, Clock>,
 }
 
+/// Sells part of the collateral vault and repays the same share of the loan.
 #[derive(Accounts)]
-pub struct Borrow<'info> {
-    state: ProgramState<'info, State>,
+pub struct ReducePosition<'info> {
+    #[account(signer)]
+    trader: AccountInfo<'info>,
+    /// accounts needed to call the swap, through the token pair's pool
+    #[account("swap_program.key == &spl_token_swap::id()")]
+    swap_program: AccountInfo<'info>,
+    swap_info: AccountInfo<'info>,
+    swap_authority: AccountInfo<'info>,
+    #[account(mut)]
+    swap_source: AccountInfo<'info>,
+    #[account(mut)]
+    swap_dest: AccountInfo<'info>,
+    #[account(mut)]
+    pool_mint: AccountInfo<'info>,
+    #[account(mut)]
+    pool_fee: AccountInfo<'info>,
+    host_fee: AccountInfo<'info>,
+    /// accounts needed to repay the loan
     lending_program: AccountInfo<'info>,
     #[account(mut)]
-    source_collateral: AccountInfo<'info>,
-    deposit_reserve: AccountInfo<'info>,
+    destination_coll_account: AccountInfo<'info>,
     #[account(mut)]
-    deposit_reserve_collateral_supply: CpiAccount<'info, TokenAccount>,
+    repay_reserve_account: AccountInfo<'info>,
     #[account(mut)]
-    deposit_reserve_collateral_fees_receiver: AccountInfo<'info>,
+    repay_reserve_spl_acccount: AccountInfo<'info>,
+    withdraw_reserve: AccountInfo<'info>,
     #[account(mut)]
-    borrow_reserve: AccountInfo<'info>,
+    withdraw_reserve_collateral: AccountInfo<'info>,
     #[account(mut)]
-    borrow_reserve_liquidity_supply: AccountInfo<'info>,
-    lending_market: AccountInfo<'info>,
-    lending_market_authority: AccountInfo<'info>,
     obligation: AccountInfo<'info>,
-    obligation_token_mint: AccountInfo<'info>,
-    obligation_token_output: AccountInfo<'info>,
-    memory: AccountInfo<'info>,
-    dex_market: AccountInfo<'info>,
-    dex_market_order_book_side: AccountInfo<'info>,
+    #[account(mut)]
+    obligation_mint: AccountInfo<'info>,
+    #[account(mut)]
+    obligation_input: AccountInfo<'info>,
+    lending_market: AccountInfo<'info>,
+    derived_lending_authority: AccountInfo<'info>,
+    /// accounts needed to access funds from token vaults
+    #[account(mut, has_one = trader)]
+    margin_account: ProgramAccount<'info, MarginAccount>,
+    /// Token pair the position was opened on.
+    #[account(mut)]
+    token_pair: ProgramAccount<'info, TokenPair>,
+    #[account(mut)]
+    collateral_vault: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    loaned_vault: CpiAccount<'info, TokenAccount>,
+    #[account(seeds = [margin_account.to_account_info().key.as_ref(), &[margin_account.nonce]])]
+    vault_signer: AccountInfo<'info>,
+    /// accounts needed to charge the close fee
+    state: ProgramState<'info, State>,
+    fee_vaults: FeeVaults<'info>,
+
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+/// Sells a position under the maintenance margin and repays its loan.
+#[derive(Accounts)]
+pub struct Liquidate<'info> {
+    #[account(signer)]
+    liquidator: AccountInfo<'info>,
+    /// Receives the liquidation fee, in the borrowed token.
+    #[account(mut)]
+    liquidator_account: CpiAccount<'info, TokenAccount>,
+    /// accounts needed to call the swap, through the token pair's pool
+    #[account("swap_program.key == &spl_token_swap::id()")]
+    swap_program: AccountInfo<'info>,
+    swap_info: AccountInfo<'info>,
+    swap_authority: AccountInfo<'info>,
+    #[account(mut)]
+    swap_source: AccountInfo<'info>,
+    #[account(mut)]
+    swap_dest: AccountInfo<'info>,
+    #[account(mut)]
+    pool_mint: AccountInfo<'info>,
+    #[account(mut)]
+    pool_fee: AccountInfo<'info>,
+    host_fee: AccountInfo<'info>,
+    /// accounts needed to repay the loan
+    lending_program: AccountInfo<'info>,
+    /// Receives the collateral released by the repayment, owned by the vault signer.
+    #[account(mut)]
+    destination_coll_account: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    repay_reserve_account: AccountInfo<'info>,
+    #[account(mut)]
+    repay_reserve_spl_acccount: AccountInfo<'info>,
+    withdraw_reserve: AccountInfo<'info>,
+    #[account(mut)]
+    withdraw_reserve_collateral: AccountInfo<'info>,
+    #[account(mut)]
+    obligation: AccountInfo<'info>,
+    #[account(mut)]
+    obligation_mint: AccountInfo<'info>,
+    #[account(mut)]
+    obligation_input: AccountInfo<'info>,
+    lending_market: AccountInfo<'info>,
+    derived_lending_authority: AccountInfo<'info>,
+    /// accounts needed to access funds from token vaults
+    #[account(mut)]
+    margin_account: ProgramAccount<'info, MarginAccount>,
+    /// Token pair the position was opened on.
+    #[account(mut)]
+    token_pair: ProgramAccount<'info, TokenPair>,
+    #[account(mut)]
+    collateral_vault: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    loaned_vault: CpiAccount<'info, TokenAccount>,
+    #[account(seeds = [margin_account.to_account_info().key.as_ref(), &[margin_account.nonce]])]
+    vault_signer: AccountInfo<'info>,
+    /// accounts needed to charge the close fee
+    state: ProgramState<'info, State>,
+    fee_vaults: FeeVaults<'info>,
+
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+/// Repays a position's bad debt from the insurance fund.
+#[derive(Accounts)]
+pub struct CoverBadDebt<'info> {
+    #[account(mut)]
+    margin_account: ProgramAccount<'info, MarginAccount>,
+    /// Token pair the position was opened on.
+    #[account(mut)]
+    token_pair: ProgramAccount<'info, TokenPair>,
+    #[account(mut)]
+    loaned_vault: CpiAccount<'info, TokenAccount>,
+    #[account(seeds = [margin_account.to_account_info().key.as_ref(), &[margin_account.nonce]])]
+    vault_signer: AccountInfo<'info>,
+    /// accounts needed to repay the loan
+    lending_program: AccountInfo<'info>,
+    /// Receives the collateral released by the repayment, owned by the vault signer.
+    #[account(mut)]
+    destination_coll_account: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    repay_reserve_account: AccountInfo<'info>,
+    #[account(mut)]
+    repay_reserve_spl_acccount: AccountInfo<'info>,
+    withdraw_reserve: AccountInfo<'info>,
+    #[account(mut)]
+    withdraw_reserve_collateral: AccountInfo<'info>,
+    #[account(mut)]
+    obligation: AccountInfo<'info>,
+    #[account(mut)]
+    obligation_mint: AccountInfo<'info>,
+    #[account(mut)]
+    obligation_input: AccountInfo<'info>,
+    lending_market: AccountInfo<'info>,
+    derived_lending_authority: AccountInfo<'info>,
+    insurance_coverage: InsuranceCoverage<'info>,
+
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+/// Treasury accounts of the insurance fund holding the borrowed token, which covers bad debt
+/// left by liquidations.
+#[derive(Accounts)]
+pub struct InsuranceCoverage<'info> {
+    #[account("treasury_program.key == &treasury::ID")]
+    treasury_program: AccountInfo<'info>,
+    treasury: AccountInfo<'info>,
+    #[account(mut)]
+    treasury_vault: AccountInfo<'info>,
+    #[account(mut)]
+    insurance: AccountInfo<'info>,
+    treasury_signer: AccountInfo<'info>,
+    /// Signer of this program derived from `treasury::COVERAGE_AUTHORITY_SEED`.
+    coverage_authority: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Borrow<'info> {
+    /// Whitelisted pair of the deposit reserve and borrowed tokens.
+    #[account(mut, seeds = [
+        TOKEN_PAIR_SEED,
+        token_pair.sorted_mints().0.as_ref(),
+        token_pair.sorted_mints().1.as_ref(),
+        &[token_pair.nonce],
+    ])]
+    token_pair: ProgramAccount<'info, TokenPair>,
+    lending_program: AccountInfo<'info>,
+    #[account(mut)]
+    source_collateral: AccountInfo<'info>,
+    deposit_reserve: AccountInfo<'info>,
+    #[account(mut)]
+    deposit_reserve_collateral_supply: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    deposit_reserve_collateral_fees_receiver: AccountInfo<'info>,
+    #[account(mut)]
+    borrow_reserve: AccountInfo<'info>,
+    #[account(mut)]
+    borrow_reserve_liquidity_supply: AccountInfo<'info>,
+    lending_market: AccountInfo<'info>,
+    lending_market_authority: AccountInfo<'info>,
+    obligation: AccountInfo<'info>,
+    obligation_token_mint: AccountInfo<'info>,
+    obligation_token_output: AccountInfo<'info>,
+    dex_market: AccountInfo<'info>,
+    dex_market_bids: AccountInfo<'info>,
+    dex_market_asks: AccountInfo<'info>,
 
     /// User transfer authority
     #[account(seeds = [margin_account.to_account_info().key.as_ref(), &[margin_account.nonce]])]
@@ -459,6 +1271,23 @@ pub struct Borrow<'info> {
     loaned_vault: CpiAccount<'info, TokenAccount>,
     #[account(mut)]
     margin_account: ProgramAccount<'info, MarginAccount>,
+    /// accounts needed to charge the open fee
+    state: ProgramState<'info, State>,
+    fee_vaults: FeeVaults<'info>,
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+}
+
+/// Token accounts receiving the protocol funds' shares of position fees. Each must be owned by
+/// the fund's owner in `FeeConfig` and hold the mint the fee is charged in.
+#[derive(Accounts)]
+pub struct FeeVaults<'info> {
+    #[account(mut)]
+    stakers: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    insurance: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    community: CpiAccount<'info, TokenAccount>,
 }
 
 /// Margin account state which keeps track of positions opened for a given trader.
@@ -477,6 +1306,12 @@ pub struct MarginAccount {
 pub struct Position {
     /// Tracks the size of the loan to know if the amount being paid back is the total amount in order to unlock the account
     pub loan_amount: u64,
+    /// Whether the position is long or short the base token of the traded pair.
+    pub direction: Direction,
+    /// Whitelisted pair the position was opened on, whose open interest it counts towards.
+    pub token_pair: Pubkey,
+    /// Lending obligation the loan was taken out through.
+    pub obligation: Pubkey,
     /// This account holds tokens from the loan before they are used in the trade and conversely to hold
     /// tokens after closing the position and before repaying the loan.
     pub loaned_vault: Pubkey,
@@ -486,6 +1321,202 @@ pub struct Position {
     /// When a position is open, status is locked meaning funds can't be withdrawn. Once a position is closed out,
     /// status is updated to available indicating that the trader can now withdraw the tokens.
     pub status: Status,
+    /// Most tokens the collateral vault may hold after opening trades, derived from the deposited
+    /// collateral and the pair's `max_leverage` when the loan is taken out.
+    pub max_held_amount: u64,
+    /// Amount of the loaned vault released by `reduce_position` that can be withdrawn while the
+    /// remainder of the position stays locked.
+    pub withdrawable_amount: u64,
+    /// Part of the loan a liquidation couldn't cover, left for `cover_bad_debt` to repay from the
+    /// insurance fund.
+    pub bad_debt: u64,
+}
+
+impl Position {
+    /// Value in quote tokens of `amount` borrowed tokens. `price` is quote per base, in token
+    /// base units.
+    pub fn debt_value(&self, amount: Decimal, price: Decimal) -> Result<Decimal> {
+        match self.direction {
+            Direction::Long => Ok(amount),
+            Direction::Short => price.try_mul(amount),
+        }
+    }
+
+    /// Value in quote tokens of `amount` held tokens. A position holds the deposit reserve's
+    /// token, both in its collateral vault and as the obligation's collateral.
+    pub fn holdings_value(&self, amount: Decimal, price: Decimal) -> Result<Decimal> {
+        match self.direction {
+            Direction::Long => price.try_mul(amount),
+            Direction::Short => Ok(amount),
+        }
+    }
+
+    /// Borrowed tokens of `debt` that `held` tokens don't cover at `price`, the position's bad
+    /// debt.
+    pub fn bad_debt(&self, held: Decimal, debt: Decimal, price: Decimal) -> Result<u64> {
+        let debt_value = self.debt_value(debt, price)?;
+        let holdings_value = self.holdings_value(held, price)?;
+        if holdings_value >= debt_value {
+            return Ok(0);
+        }
+        let shortfall = debt_value.try_sub(holdings_value)?;
+        let bad_debt = match self.direction {
+            Direction::Long => shortfall,
+            Direction::Short => shortfall.try_div(price)?,
+        };
+        bad_debt.try_floor_u64()
+    }
+
+    /// Price, quote per base, at which `held_amount` held tokens were sold for `loaned_amount`
+    /// borrowed tokens.
+    pub fn trade_price(&self, held_amount: u64, loaned_amount: u64) -> Result<Decimal> {
+        match self.direction {
+            Direction::Long => Decimal::from(loaned_amount).try_div(held_amount),
+            Direction::Short => Decimal::from(held_amount).try_div(loaned_amount),
+        }
+    }
+
+    /// Fewest borrowed tokens a sale of `held_amount` held tokens may return, `price` less the
+    /// pair's `max_slippage`.
+    pub fn minimum_sale_proceeds(
+        &self,
+        held_amount: u64,
+        price: Decimal,
+        risk_params: &RiskParams,
+    ) -> Result<u64> {
+        let value = match self.direction {
+            Direction::Long => Decimal::from(held_amount).try_mul(price)?,
+            Direction::Short => Decimal::from(held_amount).try_div(price)?,
+        };
+        value
+            .try_mul(Rate::from_percent(100 - risk_params.max_slippage))?
+            .try_floor_u64()
+    }
+
+    /// Most of the `excess` borrowed tokens released by a reduction that can be paid out while
+    /// the rest of the position, `held` tokens against `debt`, keeps a health factor of at least
+    /// one. The excess counts towards the position until it is paid out.
+    pub fn withdrawable_excess(
+        &self,
+        excess: u64,
+        held: Decimal,
+        debt: Decimal,
+        price: Decimal,
+        risk_params: &RiskParams,
+    ) -> Result<u64> {
+        if debt == Decimal::zero() {
+            return Ok(excess);
+        }
+        // Value the position must keep for its health factor to stay at one
+        let required = self
+            .debt_value(debt, price)?
+            .try_div(risk_params.maintenance_ratio())?;
+        let value = self
+            .holdings_value(held, price)?
+            .try_add(self.debt_value(excess.into(), price)?)?;
+        if value <= required {
+            return Ok(0);
+        }
+        let surplus = value
+            .try_sub(required)?
+            .try_div(self.debt_value(Decimal::one(), price)?)?
+            .try_floor_u64()?;
+        Ok(surplus.min(excess))
+    }
+
+    /// Records a reduction that repaid `repay_amount` of the loan and left `excess` borrowed
+    /// tokens over, of which what `withdrawable_excess` allows becomes withdrawable.
+    pub fn reduce(
+        &mut self,
+        repay_amount: u64,
+        excess: u64,
+        held: Decimal,
+        debt: Decimal,
+        price: Decimal,
+        risk_params: &RiskParams,
+    ) -> Result<()> {
+        self.loan_amount = self
+            .loan_amount
+            .checked_sub(repay_amount)
+            .ok_or(ErrorCode::InvalidAmount)?;
+        let released = self.withdrawable_excess(excess, held, debt, price, risk_params)?;
+        self.withdrawable_amount = self
+            .withdrawable_amount
+            .checked_add(released)
+            .ok_or(ErrorCode::InvalidAmount)?;
+        if self.loan_amount == 0 {
+            self.status = Status::Available;
+        }
+        Ok(())
+    }
+
+    /// Takes a withdrawal of `amount` from `vault` out of the position. Only what reductions
+    /// made withdrawable can leave the loaned vault of an open position.
+    pub fn withdraw(&mut self, vault: &Pubkey, amount: u64) -> Result<()> {
+        if self.status == Status::Locked {
+            if &self.loaned_vault != vault || amount > self.withdrawable_amount {
+                return Err(ErrorCode::WithdrawDisabled.into());
+            }
+            self.withdrawable_amount -= amount;
+        }
+        Ok(())
+    }
+
+    /// Unrealized profit or loss of the traded leg in quote tokens: what the `held_amount`
+    /// tokens bought with the loan are worth minus the `debt` owed. Longs gain as the price
+    /// rises, shorts as it falls.
+    pub fn unrealized_pnl(&self, held_amount: u64, debt: Decimal, price: Decimal) -> Result<i128> {
+        let holdings = self
+            .holdings_value(held_amount.into(), price)?
+            .try_round_u64()?;
+        let debt = self.debt_value(debt, price)?.try_round_u64()?;
+        Ok(holdings as i128 - debt as i128)
+    }
+
+    /// Value of the `held` tokens, both bought with the loan and deposited as collateral, net of
+    /// the pair's maintenance margin, over the value of the `debt`. Below one the position's
+    /// equity is under the maintenance margin. `None` without debt.
+    pub fn health_factor(
+        &self,
+        held: Decimal,
+        debt: Decimal,
+        price: Decimal,
+        risk_params: &RiskParams,
+    ) -> Result<Option<Decimal>> {
+        if debt == Decimal::zero() {
+            return Ok(None);
+        }
+        let backing = self
+            .holdings_value(held, price)?
+            .try_mul(risk_params.maintenance_ratio())?;
+        Ok(Some(backing.try_div(self.debt_value(debt, price)?)?))
+    }
+
+    /// Price at which `health_factor` reaches one. `None` without debt, or for a long without
+    /// held tokens, which is under the maintenance margin at any price.
+    pub fn liquidation_price(
+        &self,
+        held: Decimal,
+        debt: Decimal,
+        risk_params: &RiskParams,
+    ) -> Result<Option<Decimal>> {
+        if debt == Decimal::zero() {
+            return Ok(None);
+        }
+        let backing = held.try_mul(risk_params.maintenance_ratio())?;
+        match self.direction {
+            // Base held against a quote debt loses value as the price falls
+            Direction::Long => {
+                if backing == Decimal::zero() {
+                    Ok(None)
+                } else {
+                    Ok(Some(debt.try_div(backing)?))
+                }
+            }
+            // A base debt outgrows the quote held as the price rises
+            Direction::Short => Ok(Some(backing.try_div(debt)?)),
+        }
+    }
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq)]
@@ -494,13 +1525,189 @@ pub enum Status {
     Available,
 }
 
-/// Pair of token key mints to trade.
-/// This is only a struct for now for anchor js interfacing, only allows named structs.
-// * This should probably be serialized as an array
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
+/// Side of a position relative to the base (`first_token`) of its token pair.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Borrow the quote token and buy the base token.
+    Long,
+    /// Borrow the base token and sell it for the quote token.
+    Short,
+}
+
+/// Whitelisted pair of token key mints to trade. `first_token` is the base and `second_token`
+/// the quote. Stored at the program address derived from both mints.
+#[account]
 pub struct TokenPair {
-    first_token: Pubkey,
-    second_token: Pubkey,
+    pub first_token: Pubkey,
+    pub second_token: Pubkey,
+    /// Token swap pool positions on the pair trade and are sold through.
+    pub swap_pool: Pubkey,
+    /// Limits applied to positions opened on this pair.
+    pub risk_params: RiskParams,
+    /// New positions can only be opened while the pair is enabled.
+    pub enabled: bool,
+    /// Outstanding loans of long positions, in units of the quote token.
+    pub long_open_interest: u64,
+    /// Outstanding loans of short positions, in units of the base token.
+    pub short_open_interest: u64,
+    /// nonce for program derived address
+    pub nonce: u8,
+}
+
+/// Prefix of the token pair program address seeds, so they can't collide with the program's
+/// other addresses.
+pub const TOKEN_PAIR_SEED: &[u8] = b"token-pair";
+
+impl TokenPair {
+    /// Seeds of the program address a pair is stored at. Mints are sorted so a pair has a single
+    /// address no matter which token is the base.
+    pub fn signer_seeds<'a>(
+        first_token: &'a Pubkey,
+        second_token: &'a Pubkey,
+        nonce: &'a [u8],
+    ) -> [&'a [u8]; 4] {
+        if first_token < second_token {
+            [
+                TOKEN_PAIR_SEED,
+                first_token.as_ref(),
+                second_token.as_ref(),
+                nonce,
+            ]
+        } else {
+            [
+                TOKEN_PAIR_SEED,
+                second_token.as_ref(),
+                first_token.as_ref(),
+                nonce,
+            ]
+        }
+    }
+
+    /// Both mints of the pair, in the order used by `signer_seeds`.
+    pub fn sorted_mints(&self) -> (&Pubkey, &Pubkey) {
+        if self.first_token < self.second_token {
+            (&self.first_token, &self.second_token)
+        } else {
+            (&self.second_token, &self.first_token)
+        }
+    }
+
+    /// Whether the pair is made up of the two mints, in either order.
+    fn matches(&self, a: &Pubkey, b: &Pubkey) -> bool {
+        (&self.first_token == a && &self.second_token == b)
+            || (&self.first_token == b && &self.second_token == a)
+    }
+
+    /// Token that is borrowed to open a position in the given direction.
+    fn borrowed_token(&self, direction: Direction) -> Pubkey {
+        match direction {
+            Direction::Long => self.second_token,
+            Direction::Short => self.first_token,
+        }
+    }
+
+    /// Aggregate outstanding loans of positions in the given direction.
+    fn open_interest(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::Long => self.long_open_interest,
+            Direction::Short => self.short_open_interest,
+        }
+    }
+
+    fn open_interest_mut(&mut self, direction: Direction) -> &mut u64 {
+        match direction {
+            Direction::Long => &mut self.long_open_interest,
+            Direction::Short => &mut self.short_open_interest,
+        }
+    }
+
+    /// Removes repaid loan principal from the pair's open interest.
+    fn release_open_interest(&mut self, direction: Direction, amount: u64) {
+        let open_interest = self.open_interest_mut(direction);
+        *open_interest = open_interest.saturating_sub(amount);
+    }
+}
+
+/// Governance controlled limits for positions on a token pair.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RiskParams {
+    /// Maximum position size as a multiple of the deposited collateral, e.g. 3 for 3x.
+    pub max_leverage: u8,
+    /// Percentage of the position value that must remain as equity to avoid liquidation.
+    pub maintenance_margin: u8,
+    /// Percentage of the liquidated amount paid to the liquidator.
+    pub liquidation_fee: u8,
+    /// Percentage below the pair's average price that liquidations may sell at.
+    pub max_slippage: u8,
+    /// Cap on the aggregate outstanding loans of each side of the pair, in units of the token
+    /// borrowed by that side.
+    pub max_open_interest: u64,
+}
+
+impl RiskParams {
+    /// Share of a position's value that may back its debt, the rest being the maintenance
+    /// margin.
+    pub fn maintenance_ratio(&self) -> Rate {
+        Rate::from_percent(100u8.saturating_sub(self.maintenance_margin))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.max_leverage < 2
+            || self.maintenance_margin == 0
+            || self.maintenance_margin >= 100
+            || self.liquidation_fee > self.maintenance_margin
+            || self.max_slippage >= 100
+            || self.max_open_interest == 0
+        {
+            return Err(ErrorCode::InvalidRiskParams.into());
+        }
+        Ok(())
+    }
+}
+
+/// Fees charged on margin positions and their split between the protocol funds. Lenders are
+/// paid separately through the reserve's borrow rate.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeConfig {
+    /// Fee on the borrowed amount when a position is opened, in basis points.
+    pub open_fee_bps: u16,
+    /// Fee on loan repayments, charged on top of the repaid amount, in basis points.
+    pub close_fee_bps: u16,
+    /// Percentage of each fee sent to stakers.
+    pub stakers_share: u8,
+    /// Percentage of each fee sent to the insurance fund.
+    pub insurance_share: u8,
+    /// Percentage of each fee sent to the community fund.
+    pub community_share: u8,
+    /// Owner of the token accounts receiving the stakers' share.
+    pub stakers_fee_owner: Pubkey,
+    /// Owner of the token accounts receiving the insurance fund's share.
+    pub insurance_fee_owner: Pubkey,
+    /// Owner of the token accounts receiving the community fund's share.
+    pub community_fee_owner: Pubkey,
+}
+
+impl FeeConfig {
+    /// Highest open or close fee, 10%.
+    pub const MAX_FEE_BPS: u16 = 1_000;
+
+    /// Largest loan repayment whose close fee, charged on top of it, still fits in `available`.
+    /// Liquidations repay this much of the loan out of the sale proceeds.
+    pub fn max_repay_amount(&self, available: u64) -> u64 {
+        ((available as u128) * 10_000 / (10_000 + self.close_fee_bps as u128)) as u64
+    }
+
+    fn validate(&self) -> Result<()> {
+        let total_share =
+            self.stakers_share as u16 + self.insurance_share as u16 + self.community_share as u16;
+        if self.open_fee_bps > Self::MAX_FEE_BPS
+            || self.close_fee_bps > Self::MAX_FEE_BPS
+            || total_share != 100
+        {
+            return Err(ErrorCode::InvalidFeeConfig.into());
+        }
+        Ok(())
+    }
 }
 
 #[derive(Accounts)]
@@ -509,25 +1716,276 @@ pub struct Auth<'info> {
     authority: AccountInfo<'info>,
 }
 
-fn whitelist_auth(state: &State, ctx: &Context<Auth>) -> Result<()> {
-    if &state.authority != ctx.accounts.authority.key {
+/// Creates the account of a new whitelisted token pair.
+#[derive(Accounts)]
+pub struct AddTokenPair<'info> {
+    /// State authority, also pays for the token pair account.
+    #[account(signer, mut)]
+    authority: AccountInfo<'info>,
+    #[account(mut)]
+    token_pair: AccountInfo<'info>,
+    /// Token swap pool trading the pair's two mints.
+    swap_pool: AccountInfo<'info>,
+    rent: Sysvar<'info, Rent>,
+    #[account("system_program.key == &solana_program::system_program::ID")]
+    system_program: AccountInfo<'info>,
+}
+
+/// Modifies an existing whitelisted token pair.
+#[derive(Accounts)]
+pub struct UpdateTokenPair<'info> {
+    #[account(signer, mut)]
+    authority: AccountInfo<'info>,
+    #[account(mut)]
+    token_pair: ProgramAccount<'info, TokenPair>,
+}
+
+fn whitelist_auth(state: &State, authority: &AccountInfo) -> Result<()> {
+    if &state.authority != authority.key {
         return Err(ErrorCode::StateUnauthorized.into());
     }
     Ok(())
 }
 
-/// Validates that token pair exists in whitelist.
-fn index_of_token_pair(pairs: &[TokenPair], check: &TokenPair) -> Option<usize> {
-    let TokenPair {
-        first_token: t1,
-        second_token: t2,
-    } = check;
-    pairs.iter().position(
-        |TokenPair {
-             first_token: c1,
-             second_token: c2,
-         }| { (t1 == c1 && t2 == c2) || (t1 == c2 && t2 == c1) },
-    )
+/// Share of the loan matching `amount_in` out of `collateral_balance`, rounded up so a reduction
+/// never leaves the remaining position less collateralized than before.
+fn repay_share(loan_amount: u64, amount_in: u64, collateral_balance: u64) -> Result<u64> {
+    let numerator = (loan_amount as u128) * (amount_in as u128) + (collateral_balance as u128) - 1;
+    let share = numerator / (collateral_balance as u128);
+    if share > loan_amount as u128 {
+        return Err(ErrorCode::InvalidAmount.into());
+    }
+    Ok(share as u64)
+}
+
+/// Most tokens a position may hold after opening: the deposited collateral, net of the origination
+/// fee and converted to liquidity, times the leverage beyond the collateral itself.
+fn max_held_amount(
+    deposit_reserve: &Reserve,
+    collateral_amount: u64,
+    risk_params: &RiskParams,
+) -> Result<u64> {
+    let (origination_fee, _) = deposit_reserve
+        .config
+        .fees
+        .calculate_borrow_fees(collateral_amount)?;
+    let collateral_liquidity = deposit_reserve
+        .collateral_exchange_rate()?
+        .collateral_to_liquidity(
+            collateral_amount
+                .checked_sub(origination_fee)
+                .ok_or(ErrorCode::InvalidAmount)?,
+        )?;
+    let leverage = (risk_params.max_leverage as u64)
+        .checked_sub(1)
+        .ok_or(ErrorCode::InvalidRiskParams)?;
+    collateral_liquidity
+        .checked_mul(leverage)
+        .ok_or_else(|| ErrorCode::InvalidAmount.into())
+}
+
+/// Lending accounts a position borrowed through, checked against the position and each other.
+struct PositionLoan {
+    obligation: Obligation,
+    deposit_reserve: Reserve,
+    borrow_reserve: Reserve,
+}
+
+impl PositionLoan {
+    fn load(
+        position: &Position,
+        lending_program: &AccountInfo,
+        obligation: &AccountInfo,
+        deposit_reserve: &AccountInfo,
+        borrow_reserve: &AccountInfo,
+    ) -> Result<Self> {
+        if obligation.key != &position.obligation || obligation.owner != lending_program.key {
+            return Err(ErrorCode::InvalidObligation.into());
+        }
+        if deposit_reserve.owner != lending_program.key
+            || borrow_reserve.owner != lending_program.key
+        {
+            return Err(ErrorCode::InvalidReserve.into());
+        }
+        let mut loan = PositionLoan {
+            obligation: Obligation::unpack(&obligation.try_borrow_data()?)?,
+            deposit_reserve: Reserve::unpack(&deposit_reserve.try_borrow_data()?)?,
+            borrow_reserve: Reserve::unpack(&borrow_reserve.try_borrow_data()?)?,
+        };
+        if &loan.obligation.collateral_reserve != deposit_reserve.key
+            || &loan.obligation.borrow_reserve != borrow_reserve.key
+        {
+            return Err(ErrorCode::InvalidReserve.into());
+        }
+        loan.obligation
+            .accrue_interest(loan.borrow_reserve.cumulative_borrow_rate_wads)?;
+        Ok(loan)
+    }
+
+    /// Obligation collateral as liquidity of the deposit reserve, the token the position holds.
+    fn collateral(&self) -> Result<Decimal> {
+        self.deposit_reserve
+            .collateral_exchange_rate()?
+            .decimal_collateral_to_liquidity(self.obligation.deposited_collateral_tokens.into())
+    }
+
+    /// Borrowed tokens owed, including interest accrued up to the borrow reserve's last update.
+    fn debt(&self) -> Decimal {
+        self.obligation.borrowed_liquidity_wads
+    }
+
+    /// Average price of the pair, quote per base, over the `liquidation_twap_slots` of the
+    /// reserve priced off the market, as the lending program judges its liquidations. Fails with
+    /// `TwapRequired` if that reserve doesn't average prices.
+    fn twap(
+        &self,
+        token_pair: &TokenPair,
+        lending_program: &AccountInfo,
+        price_accumulator: Option<&AccountInfo>,
+        slot: u64,
+    ) -> Result<Decimal> {
+        let market_reserve = if self.borrow_reserve.dex_market.is_some() {
+            &self.borrow_reserve
+        } else {
+            &self.deposit_reserve
+        };
+        let twap_slots = market_reserve.config.liquidation_twap_slots;
+        if twap_slots == 0 {
+            return Err(ErrorCode::TwapRequired.into());
+        }
+        let price_accumulator_info = price_accumulator.ok_or(ErrorCode::InvalidPriceAccumulator)?;
+        if price_accumulator_info.owner != lending_program.key {
+            return Err(ErrorCode::InvalidPriceAccumulator.into());
+        }
+        let price_accumulator =
+            PriceAccumulator::unpack(&price_accumulator_info.try_borrow_data()?)?;
+        if price_accumulator.lending_market != market_reserve.lending_market
+            || market_reserve.dex_market != COption::Some(price_accumulator.market)
+            || price_accumulator.base_mint != market_reserve.liquidity.mint_pubkey
+        {
+            return Err(ErrorCode::InvalidPriceAccumulator.into());
+        }
+        let twap = price_accumulator.twap(slot, twap_slots)?;
+
+        // Accumulators price the reserve's token in the lending market's quote token
+        let (base, quote) = (&price_accumulator.base_mint, &price_accumulator.quote_mint);
+        if &token_pair.first_token == base && &token_pair.second_token == quote {
+            Ok(twap)
+        } else if &token_pair.first_token == quote && &token_pair.second_token == base {
+            Ok(Decimal::one().try_div(twap)?)
+        } else {
+            Err(ErrorCode::InvalidPriceAccumulator.into())
+        }
+    }
+}
+
+/// Transfers `fee_bps` of `amount` out of `vault`, split between the fee vaults by the shares in
+/// `fees`. Returns the fee charged.
+#[allow(clippy::too_many_arguments)]
+fn charge_fee<'info>(
+    fees: &FeeConfig,
+    fee_bps: u16,
+    amount: u64,
+    vault: &CpiAccount<'info, TokenAccount>,
+    fee_vaults: &FeeVaults<'info>,
+    vault_signer: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    signer: &[&[&[u8]]],
+) -> Result<u64> {
+    let fee = ((amount as u128) * (fee_bps as u128) / 10_000) as u64;
+    if fee == 0 {
+        return Ok(0);
+    }
+    let stakers_fee = fee * fees.stakers_share as u64 / 100;
+    let insurance_fee = fee * fees.insurance_share as u64 / 100;
+    // Rounding dust goes to the community fund
+    let community_fee = fee - stakers_fee - insurance_fee;
+
+    let splits = [
+        (&fee_vaults.stakers, &fees.stakers_fee_owner, stakers_fee),
+        (
+            &fee_vaults.insurance,
+            &fees.insurance_fee_owner,
+            insurance_fee,
+        ),
+        (
+            &fee_vaults.community,
+            &fees.community_fee_owner,
+            community_fee,
+        ),
+    ];
+    for (fee_vault, owner, split) in splits.iter() {
+        if &fee_vault.owner != *owner || fee_vault.mint != vault.mint {
+            return Err(ErrorCode::InvalidFeeVault.into());
+        }
+        if *split == 0 {
+            continue;
+        }
+        let cpi_accounts = Transfer {
+            from: vault.to_account_info(),
+            to: fee_vault.to_account_info(),
+            authority: vault_signer.clone(),
+        };
+        let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer);
+        token::transfer(cpi_ctx, *split)?;
+    }
+    Ok(fee)
+}
+
+/// Has the treasury pay `amount` of bad debt from its insurance fund into `loaned_vault`, signed by
+/// this program's coverage authority.
+fn pay_from_insurance<'info>(
+    coverage: &InsuranceCoverage<'info>,
+    loaned_vault: &CpiAccount<'info, TokenAccount>,
+    token_program: &AccountInfo<'info>,
+    clock: &Sysvar<'info, Clock>,
+    program_id: &Pubkey,
+    amount: u64,
+) -> Result<()> {
+    let (coverage_authority, nonce) =
+        Pubkey::find_program_address(&[treasury::COVERAGE_AUTHORITY_SEED], program_id);
+    if coverage.coverage_authority.key != &coverage_authority {
+        return Err(ErrorCode::InvalidCoverageAuthority.into());
+    }
+    let loaned_vault = loaned_vault.to_account_info();
+    let clock = clock.to_account_info();
+    let instruction = Instruction {
+        program_id: *coverage.treasury_program.key,
+        accounts: treasury::accounts::CoverBadDebt {
+            treasury: *coverage.treasury.key,
+            vault: *coverage.treasury_vault.key,
+            coverage_authority,
+            insurance: *coverage.insurance.key,
+            destination: *loaned_vault.key,
+            treasury_signer: *coverage.treasury_signer.key,
+            token_program: *token_program.key,
+            clock: *clock.key,
+        }
+        .to_account_metas(None),
+        data: treasury::instruction::CoverBadDebt { amount }.data(),
+    };
+    invoke_signed(
+        &instruction,
+        &[
+            coverage.treasury.clone(),
+            coverage.treasury_vault.clone(),
+            coverage.coverage_authority.clone(),
+            coverage.insurance.clone(),
+            loaned_vault.clone(),
+            coverage.treasury_signer.clone(),
+            token_program.clone(),
+            clock,
+            coverage.treasury_program.clone(),
+        ],
+        &[&[treasury::COVERAGE_AUTHORITY_SEED, &[nonce]]],
+    )?;
+    Ok(())
+}
+
+/// Reads the token balance of a vault, including changes made by CPIs earlier in the instruction.
+fn vault_amount<'info>(vault: &AccountInfo<'info>) -> Result<u64> {
+    let vault: CpiAccount<'info, TokenAccount> = CpiAccount::try_from(vault)?;
+    Ok(vault.amount)
 }
 
 #[error]
@@ -544,10 +2002,357 @@ pub enum ErrorCode {
     WithdrawDisabled,
     #[msg("Unauthorized to update the program state.")]
     StateUnauthorized,
-    #[msg("Cannot add token pair, program already has maximum.")]
-    MaxTokenPairs,
     #[msg("Token pair does not exist in allowed set.")]
     InvalidTokenPair,
     #[msg("Token pair already exists.")]
     TokenPairAlreadyExists,
+    #[msg("Margin account has no open position.")]
+    NoOpenPosition,
+    #[msg("Vault does not belong to the open position.")]
+    InvalidPositionVault,
+    #[msg("Swap proceeds do not cover the repaid share of the loan.")]
+    InsufficientProceeds,
+    #[msg("Reserve is not owned by the lending program.")]
+    InvalidReserve,
+    #[msg("Borrowed token does not match the position direction.")]
+    InvalidDirection,
+    #[msg("Invalid token pair risk parameters.")]
+    InvalidRiskParams,
+    #[msg("Token pair is disabled for new positions.")]
+    TokenPairDisabled,
+    #[msg("Loan would exceed the token pair's maximum open interest.")]
+    OpenInterestExceeded,
+    #[msg("Position exceeds the token pair's maximum leverage.")]
+    LeverageExceeded,
+    #[msg("Token pair has open positions.")]
+    TokenPairInUse,
+    #[msg("Fees exceed the maximum or shares do not add up to 100.")]
+    InvalidFeeConfig,
+    #[msg("Fee vault has the wrong owner or mint.")]
+    InvalidFeeVault,
+    #[msg("Obligation does not belong to the position.")]
+    InvalidObligation,
+    #[msg("Price accumulator does not price the token pair.")]
+    InvalidPriceAccumulator,
+    #[msg("Position is above the maintenance margin.")]
+    PositionHealthy,
+    #[msg("Coverage authority must be the program signer derived from the coverage seed.")]
+    InvalidCoverageAuthority,
+    #[msg("Position has no bad debt to cover.")]
+    NoBadDebt,
+    #[msg("Swap pool is not the token pair's pool.")]
+    InvalidSwapPool,
+    #[msg("Reserve priced off the market must set liquidation_twap_slots.")]
+    TwapRequired,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spl_token_lending::state::{ReserveCollateral, ReserveLiquidity};
+
+    fn position(direction: Direction) -> Position {
+        Position {
+            loan_amount: 0,
+            direction,
+            token_pair: Pubkey::default(),
+            obligation: Pubkey::default(),
+            loaned_vault: Pubkey::new_unique(),
+            collateral_vault: Some(Pubkey::new_unique()),
+            status: Status::Locked,
+            max_held_amount: u64::MAX,
+            withdrawable_amount: 0,
+            bad_debt: 0,
+        }
+    }
+
+    fn risk_params(maintenance_margin: u8) -> RiskParams {
+        RiskParams {
+            max_leverage: 3,
+            maintenance_margin,
+            liquidation_fee: 5,
+            max_slippage: 10,
+            max_open_interest: u64::MAX,
+        }
+    }
+
+    #[test]
+    fn long_health() {
+        // 100 base deposited, 200 quote borrowed and traded for 25 base
+        let position = position(Direction::Long);
+        let risk_params = risk_params(20);
+        let held = Decimal::from(125u64);
+        let debt = Decimal::from(200u64);
+
+        // 125 * 10 * 80% / 200
+        assert_eq!(
+            position
+                .health_factor(held, debt, Decimal::from(10u64), &risk_params)
+                .unwrap(),
+            Some(Decimal::from(5u64))
+        );
+        // 200 / (125 * 80%)
+        let liquidation_price = position
+            .liquidation_price(held, debt, &risk_params)
+            .unwrap();
+        assert_eq!(liquidation_price, Some(Decimal::from(2u64)));
+        assert_eq!(
+            position
+                .health_factor(held, debt, liquidation_price.unwrap(), &risk_params)
+                .unwrap(),
+            Some(Decimal::one())
+        );
+        assert_eq!(
+            position
+                .liquidation_price(Decimal::zero(), debt, &risk_params)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            position
+                .unrealized_pnl(25, debt, Decimal::from(10u64))
+                .unwrap(),
+            50
+        );
+        // 25 base sold for 250 quote
+        assert_eq!(position.trade_price(25, 250).unwrap(), Decimal::from(10u64));
+    }
+
+    #[test]
+    fn short_health() {
+        // 1000 quote deposited, 20 base borrowed and sold for 250 quote
+        let position = position(Direction::Short);
+        let risk_params = risk_params(20);
+        let held = Decimal::from(1_250u64);
+        let debt = Decimal::from(20u64);
+
+        // 1250 * 80% / (20 * 10)
+        assert_eq!(
+            position
+                .health_factor(held, debt, Decimal::from(10u64), &risk_params)
+                .unwrap(),
+            Some(Decimal::from(5u64))
+        );
+        // 1250 * 80% / 20
+        let liquidation_price = position
+            .liquidation_price(held, debt, &risk_params)
+            .unwrap();
+        assert_eq!(liquidation_price, Some(Decimal::from(50u64)));
+        assert_eq!(
+            position
+                .health_factor(held, debt, liquidation_price.unwrap(), &risk_params)
+                .unwrap(),
+            Some(Decimal::one())
+        );
+        assert_eq!(
+            position
+                .unrealized_pnl(250, debt, Decimal::from(10u64))
+                .unwrap(),
+            50
+        );
+        assert_eq!(
+            position
+                .unrealized_pnl(250, debt, Decimal::from(15u64))
+                .unwrap(),
+            -50
+        );
+        // 250 quote sold for 20 base
+        assert_eq!(
+            position.trade_price(250, 20).unwrap(),
+            Decimal::from(25u64).try_div(2).unwrap()
+        );
+    }
+
+    #[test]
+    fn health_without_debt() {
+        for direction in [Direction::Long, Direction::Short].iter() {
+            let position = position(*direction);
+            let risk_params = risk_params(20);
+            let held = Decimal::from(100u64);
+            assert_eq!(
+                position
+                    .health_factor(held, Decimal::zero(), Decimal::one(), &risk_params)
+                    .unwrap(),
+                None
+            );
+            assert_eq!(
+                position
+                    .liquidation_price(held, Decimal::zero(), &risk_params)
+                    .unwrap(),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn max_held_amount_checks_leverage() {
+        let reserve = Reserve {
+            liquidity: ReserveLiquidity {
+                available_amount: 1_000_000,
+                ..ReserveLiquidity::default()
+            },
+            collateral: ReserveCollateral {
+                mint_total_supply: 1_000_000,
+                ..ReserveCollateral::default()
+            },
+            ..Reserve::default()
+        };
+        // At 3x the position may hold twice the collateral on top of it
+        assert_eq!(
+            max_held_amount(&reserve, 100, &risk_params(20)).unwrap(),
+            200
+        );
+        let no_leverage = RiskParams {
+            max_leverage: 0,
+            ..risk_params(20)
+        };
+        assert!(max_held_amount(&reserve, 100, &no_leverage).is_err());
+    }
+
+    #[test]
+    fn partial_reduce() {
+        // 100 base deposited, 200 quote borrowed and traded for 20 base. Half of the held base
+        // is sold for 120 quote at a price of 12, repaying 100 and leaving 20 over.
+        let mut position = position(Direction::Long);
+        position.loan_amount = 200;
+        let risk_params = risk_params(20);
+        // 10 base left plus the 50 base of collateral still in the obligation
+        let held = Decimal::from(60u64);
+        let debt = Decimal::from(100u64);
+        position
+            .reduce(100, 20, held, debt, Decimal::from(12u64), &risk_params)
+            .unwrap();
+        assert_eq!(position.loan_amount, 100);
+        assert!(position.status == Status::Locked);
+        assert_eq!(position.withdrawable_amount, 20);
+    }
+
+    #[test]
+    fn reduce_keeps_maintenance_margin() {
+        let mut position = position(Direction::Long);
+        position.loan_amount = 200;
+        let risk_params = risk_params(20);
+        // At a price of 2 the 60 base left are worth 120 quote and the 100 quote debt needs
+        // 100 / 80% = 125, so only 5 of the 10 quote over can be paid out
+        let held = Decimal::from(60u64);
+        let debt = Decimal::from(100u64);
+        position
+            .reduce(100, 10, held, debt, Decimal::from(2u64), &risk_params)
+            .unwrap();
+        assert_eq!(position.loan_amount, 100);
+        assert_eq!(position.withdrawable_amount, 5);
+
+        // Nothing is released once the rest is under the maintenance margin
+        position
+            .reduce(50, 10, held, debt, Decimal::one(), &risk_params)
+            .unwrap();
+        assert_eq!(position.withdrawable_amount, 5);
+    }
+
+    #[test]
+    fn full_close() {
+        let mut position = position(Direction::Short);
+        position.loan_amount = 20;
+        let risk_params = risk_params(20);
+        position
+            .reduce(
+                20,
+                30,
+                Decimal::zero(),
+                Decimal::zero(),
+                Decimal::from(10u64),
+                &risk_params,
+            )
+            .unwrap();
+        assert_eq!(position.loan_amount, 0);
+        assert!(position.status == Status::Available);
+        assert_eq!(position.withdrawable_amount, 30);
+
+        // Closed positions can withdraw anything from any vault
+        position.withdraw(&Pubkey::new_unique(), 1_000).unwrap();
+        assert!(position
+            .reduce(
+                1,
+                0,
+                Decimal::zero(),
+                Decimal::zero(),
+                Decimal::one(),
+                &risk_params
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_over_withdraw() {
+        let mut position = position(Direction::Long);
+        position.loan_amount = 100;
+        position.withdrawable_amount = 20;
+        let loaned_vault = position.loaned_vault;
+        let collateral_vault = position.collateral_vault.unwrap();
+
+        assert!(position.withdraw(&loaned_vault, 21).is_err());
+        assert!(position.withdraw(&collateral_vault, 1).is_err());
+        position.withdraw(&loaned_vault, 20).unwrap();
+        assert_eq!(position.withdrawable_amount, 0);
+        assert!(position.withdraw(&loaned_vault, 1).is_err());
+    }
+
+    #[test]
+    fn bad_debt_is_debt_the_holdings_do_not_cover() {
+        let price = Decimal::from(2u64);
+
+        // 10 base tokens are worth 20 quote tokens against a 30 quote debt
+        let long = position(Direction::Long);
+        assert_eq!(
+            long.bad_debt(10u64.into(), 30u64.into(), price).unwrap(),
+            10
+        );
+        assert_eq!(long.bad_debt(15u64.into(), 30u64.into(), price).unwrap(), 0);
+
+        // 100 quote tokens against a 60 base debt worth 120 quote tokens
+        let short = position(Direction::Short);
+        assert_eq!(
+            short.bad_debt(100u64.into(), 60u64.into(), price).unwrap(),
+            10
+        );
+        assert_eq!(
+            short.bad_debt(120u64.into(), 60u64.into(), price).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn sales_stay_within_slippage_of_the_average_price() {
+        let risk_params = risk_params(20);
+        let price = Decimal::from(4u64);
+
+        // 25 base are worth 100 quote, 90 after 10% slippage
+        let long = position(Direction::Long);
+        assert_eq!(
+            long.minimum_sale_proceeds(25, price, &risk_params).unwrap(),
+            90
+        );
+        // 100 quote are worth 25 base, 22.5 after 10% slippage
+        let short = position(Direction::Short);
+        assert_eq!(
+            short
+                .minimum_sale_proceeds(100, price, &risk_params)
+                .unwrap(),
+            22
+        );
+    }
+
+    #[test]
+    fn repay_leaves_room_for_close_fee() {
+        let fees = |close_fee_bps| FeeConfig {
+            close_fee_bps,
+            ..FeeConfig::default()
+        };
+        assert_eq!(fees(100).max_repay_amount(1_010), 1_000);
+        assert_eq!(fees(100).max_repay_amount(1_009), 999);
+        assert_eq!(fees(0).max_repay_amount(500), 500);
+        let repay_amount = fees(FeeConfig::MAX_FEE_BPS).max_repay_amount(u64::MAX);
+        let fee = (repay_amount as u128 * FeeConfig::MAX_FEE_BPS as u128 / 10_000) as u64;
+        assert!(repay_amount.checked_add(fee).is_some());
+    }
 }
@@ -0,0 +1,748 @@
+#![feature(proc_macro_hygiene)]
+
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, Burn, MintTo, TokenAccount, Transfer};
+use solana_program::program::invoke_signed;
+use solana_program::program_option::COption;
+use solana_program::program_pack::Pack;
+
+/// Scale of `StakePool::reward_per_share`.
+pub const REWARD_PRECISION: u128 = 1_000_000_000_000;
+/// Seconds in a year, used to apply the annual inflation rate.
+pub const SECONDS_PER_YEAR: u128 = 365 * 24 * 60 * 60;
+/// Most unstakes a staker can have in cooldown at once.
+pub const MAX_PENDING_UNSTAKES: usize = 8;
+
+/// Stakers lock the protocol token in exchange for a stake receipt token. Receipt token accounts
+/// are kept frozen by the pool, so a stake and its governance voting weight stay with the
+/// `Staker`. Stakes earn minted inflation and deposited fees through a reward per share
+/// accumulator.
+#[program]
+pub mod staking {
+    use super::*;
+
+    /// Initialize a stake pool for `native_mint`, whose mint authority must already be the pool
+    /// signer so inflation can be minted. The pool signer must also be the mint and freeze
+    /// authority of the receipt mint.
+    pub fn create_pool(
+        ctx: Context<CreatePool>,
+        authority: Pubkey,
+        config: PoolConfig,
+        nonce: u8,
+    ) -> ProgramResult {
+        config.validate()?;
+        let pool_key = ctx.accounts.pool.to_account_info().key;
+        let signer = Pubkey::create_program_address(&[pool_key.as_ref(), &[nonce]], ctx.program_id)
+            .map_err(|_| ErrorCode::InvalidProgramAddress)?;
+        let native_mint = ctx.accounts.stake_vault.mint;
+        if ctx.accounts.stake_vault.owner != signer || ctx.accounts.reward_vault.owner != signer {
+            return Err(ErrorCode::InvalidVault.into());
+        }
+        if ctx.accounts.reward_vault.mint != native_mint {
+            return Err(ErrorCode::InvalidVault.into());
+        }
+        let native = spl_token::state::Mint::unpack(&ctx.accounts.native_mint.try_borrow_data()?)?;
+        let receipt =
+            spl_token::state::Mint::unpack(&ctx.accounts.receipt_mint.try_borrow_data()?)?;
+        if ctx.accounts.native_mint.key != &native_mint
+            || native.mint_authority != COption::Some(signer)
+            || receipt.mint_authority != COption::Some(signer)
+            || receipt.freeze_authority != COption::Some(signer)
+            || receipt.supply != 0
+            || receipt.decimals != native.decimals
+        {
+            return Err(ErrorCode::InvalidMint.into());
+        }
+
+        let pool = &mut ctx.accounts.pool;
+        pool.authority = authority;
+        pool.native_mint = native_mint;
+        pool.receipt_mint = *ctx.accounts.receipt_mint.key;
+        pool.stake_vault = *ctx.accounts.stake_vault.to_account_info().key;
+        pool.reward_vault = *ctx.accounts.reward_vault.to_account_info().key;
+        pool.config = config;
+        pool.total_staked = 0;
+        pool.reward_per_share = 0;
+        pool.last_update_ts = ctx.accounts.clock.unix_timestamp;
+        pool.nonce = nonce;
+
+        Ok(())
+    }
+
+    /// Initialize the account tracking an owner's stake in a pool.
+    pub fn create_staker(ctx: Context<CreateStaker>, owner: Pubkey) -> ProgramResult {
+        let staker = &mut ctx.accounts.staker;
+        staker.pool = *ctx.accounts.pool.to_account_info().key;
+        staker.owner = owner;
+
+        Ok(())
+    }
+
+    /// Stake native tokens, receiving the same amount of stake receipt tokens in the owner's
+    /// frozen receipt token account.
+    pub fn stake(ctx: Context<Stake>, amount: u64) -> ProgramResult {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        update_pool(&mut ctx.accounts.pool_accounts)?;
+        let pool = &ctx.accounts.pool_accounts.pool;
+        ctx.accounts.staker.settle_rewards(pool)?;
+
+        token::transfer(ctx.accounts.into_transfer_context(), amount)?;
+        let seeds = &[
+            ctx.accounts
+                .pool_accounts
+                .pool
+                .to_account_info()
+                .key
+                .as_ref(),
+            &[ctx.accounts.pool_accounts.pool.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let receipts = ReceiptAccounts {
+            receipt_tokens: &ctx.accounts.receipt_tokens,
+            receipt_mint: &ctx.accounts.receipt_mint,
+            pool_signer: &ctx.accounts.pool_accounts.pool_signer,
+            token_program: &ctx.accounts.pool_accounts.token_program,
+        };
+        receipts.thaw(signer)?;
+        token::mint_to(
+            ctx.accounts.into_mint_to_context().with_signer(signer),
+            amount,
+        )?;
+        receipts.freeze(signer)?;
+
+        let pool = &mut ctx.accounts.pool_accounts.pool;
+        pool.total_staked = pool
+            .total_staked
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let staker = &mut ctx.accounts.staker;
+        staker.staked = staker
+            .staked
+            .checked_add(amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        staker.reward_debt = staker.accrued(pool)?;
+
+        Ok(())
+    }
+
+    /// Burn stake receipt tokens and start the cooldown of the matching stake. The stake stops
+    /// earning rewards and counting as voting weight immediately. Each unstake has its own
+    /// cooldown, up to `MAX_PENDING_UNSTAKES` at a time.
+    pub fn start_unstake(ctx: Context<StartUnstake>, amount: u64) -> ProgramResult {
+        if amount == 0 || amount > ctx.accounts.staker.staked {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        update_pool(&mut ctx.accounts.pool_accounts)?;
+        let pool = &ctx.accounts.pool_accounts.pool;
+        ctx.accounts.staker.settle_rewards(pool)?;
+
+        let seeds = &[
+            ctx.accounts
+                .pool_accounts
+                .pool
+                .to_account_info()
+                .key
+                .as_ref(),
+            &[ctx.accounts.pool_accounts.pool.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let receipts = ReceiptAccounts {
+            receipt_tokens: &ctx.accounts.receipt_tokens,
+            receipt_mint: &ctx.accounts.receipt_mint,
+            pool_signer: &ctx.accounts.pool_accounts.pool_signer,
+            token_program: &ctx.accounts.pool_accounts.token_program,
+        };
+        receipts.thaw(signer)?;
+        token::burn((&*ctx.accounts).into(), amount)?;
+        receipts.freeze(signer)?;
+
+        let pool = &mut ctx.accounts.pool_accounts.pool;
+        pool.total_staked -= amount;
+        let cooldown_ends_at = ctx
+            .accounts
+            .clock
+            .unix_timestamp
+            .checked_add(pool.config.cooldown)
+            .ok_or(ErrorCode::MathOverflow)?;
+        let staker = &mut ctx.accounts.staker;
+        staker.staked -= amount;
+        staker.reward_debt = staker.accrued(pool)?;
+        staker.queue_unstake(amount, cooldown_ends_at)?;
+
+        Ok(())
+    }
+
+    /// Withdraw every unstake whose cooldown has ended.
+    pub fn finish_unstake(ctx: Context<Withdraw>) -> ProgramResult {
+        if ctx.accounts.vault.to_account_info().key != &ctx.accounts.pool.stake_vault {
+            return Err(ErrorCode::InvalidVault.into());
+        }
+        let now = ctx.accounts.clock.unix_timestamp;
+        let amount = ctx.accounts.staker.take_unstaked(now)?;
+
+        let seeds = &[
+            ctx.accounts.pool.to_account_info().key.as_ref(),
+            &[ctx.accounts.pool.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+        token::transfer(cpi_ctx, amount)?;
+
+        Ok(())
+    }
+
+    /// Add native tokens to the rewards of current stakers. Protocol fees are converted to the
+    /// native token before being deposited.
+    pub fn deposit_rewards(ctx: Context<DepositRewards>, amount: u64) -> ProgramResult {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        update_pool(&mut ctx.accounts.pool_accounts)?;
+        if ctx.accounts.pool_accounts.pool.total_staked == 0 {
+            return Err(ErrorCode::NoStake.into());
+        }
+
+        token::transfer(ctx.accounts.into(), amount)?;
+        ctx.accounts.pool_accounts.pool.distribute(amount)?;
+
+        Ok(())
+    }
+
+    /// Transfer the rewards earned by a stake to its owner.
+    pub fn claim(ctx: Context<Claim>) -> ProgramResult {
+        update_pool(&mut ctx.accounts.pool_accounts)?;
+        let pool = &ctx.accounts.pool_accounts.pool;
+        ctx.accounts.staker.settle_rewards(pool)?;
+        let amount = ctx.accounts.staker.pending_rewards;
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+
+        let seeds = &[
+            ctx.accounts
+                .pool_accounts
+                .pool
+                .to_account_info()
+                .key
+                .as_ref(),
+            &[ctx.accounts.pool_accounts.pool.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+        token::transfer(cpi_ctx, amount)?;
+
+        ctx.accounts.staker.pending_rewards = 0;
+
+        Ok(())
+    }
+
+    /// Mint inflation accrued since the last update. Permissionless, the same update runs as
+    /// part of every instruction that changes stakes.
+    pub fn update(ctx: Context<UpdatePool>) -> ProgramResult {
+        update_pool(ctx.accounts)
+    }
+
+    /// Update the pool config, signed by the pool authority (the governance signer).
+    pub fn set_config(ctx: Context<SetConfig>, config: PoolConfig) -> ProgramResult {
+        config.validate()?;
+        // Inflation up to now accrues at the previous rate
+        update_pool(&mut ctx.accounts.pool_accounts)?;
+        ctx.accounts.pool_accounts.pool.config = config;
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CreatePool<'info> {
+    #[account(init)]
+    pool: ProgramAccount<'info, StakePool>,
+    native_mint: AccountInfo<'info>,
+    receipt_mint: AccountInfo<'info>,
+    stake_vault: CpiAccount<'info, TokenAccount>,
+    reward_vault: CpiAccount<'info, TokenAccount>,
+    clock: Sysvar<'info, Clock>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct CreateStaker<'info> {
+    pool: ProgramAccount<'info, StakePool>,
+    #[account(init)]
+    staker: ProgramAccount<'info, Staker>,
+    rent: Sysvar<'info, Rent>,
+}
+
+/// Accounts needed to mint inflation into the reward vault.
+#[derive(Accounts)]
+pub struct UpdatePool<'info> {
+    #[account(mut)]
+    pool: ProgramAccount<'info, StakePool>,
+    #[account(mut, "native_mint.key == &pool.native_mint")]
+    native_mint: AccountInfo<'info>,
+    #[account(mut, "reward_vault.key == &pool.reward_vault")]
+    reward_vault: AccountInfo<'info>,
+    #[account(seeds = [pool.to_account_info().key.as_ref(), &[pool.nonce]])]
+    pool_signer: AccountInfo<'info>,
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct Stake<'info> {
+    pool_accounts: UpdatePool<'info>,
+    #[account(mut, "&staker.pool == pool_accounts.pool.to_account_info().key")]
+    staker: ProgramAccount<'info, Staker>,
+    #[account(signer, "owner.key == &staker.owner")]
+    owner: AccountInfo<'info>,
+    /// Owner token account the native tokens are staked from.
+    #[account(mut)]
+    native_tokens: AccountInfo<'info>,
+    /// Owner token account receiving the stake receipt tokens.
+    #[account(
+        mut,
+        "receipt_tokens.owner == staker.owner",
+        "receipt_tokens.mint == pool_accounts.pool.receipt_mint"
+    )]
+    receipt_tokens: CpiAccount<'info, TokenAccount>,
+    #[account(mut, "stake_vault.key == &pool_accounts.pool.stake_vault")]
+    stake_vault: AccountInfo<'info>,
+    #[account(mut, "receipt_mint.key == &pool_accounts.pool.receipt_mint")]
+    receipt_mint: AccountInfo<'info>,
+}
+
+impl<'info> Stake<'info> {
+    fn into_transfer_context<'a, 'b, 'c>(&self) -> CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: self.native_tokens.clone(),
+            to: self.stake_vault.clone(),
+            authority: self.owner.clone(),
+        };
+        CpiContext::new(self.pool_accounts.token_program.clone(), cpi_accounts)
+    }
+
+    fn into_mint_to_context<'a, 'b, 'c>(&self) -> CpiContext<'a, 'b, 'c, 'info, MintTo<'info>> {
+        let cpi_accounts = MintTo {
+            mint: self.receipt_mint.clone(),
+            to: self.receipt_tokens.to_account_info(),
+            authority: self.pool_accounts.pool_signer.clone(),
+        };
+        CpiContext::new(self.pool_accounts.token_program.clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct StartUnstake<'info> {
+    pool_accounts: UpdatePool<'info>,
+    #[account(mut, "&staker.pool == pool_accounts.pool.to_account_info().key")]
+    staker: ProgramAccount<'info, Staker>,
+    #[account(signer, "owner.key == &staker.owner")]
+    owner: AccountInfo<'info>,
+    /// Owner token account the stake receipt tokens are burned from.
+    #[account(mut, "receipt_tokens.owner == staker.owner")]
+    receipt_tokens: CpiAccount<'info, TokenAccount>,
+    #[account(mut, "receipt_mint.key == &pool_accounts.pool.receipt_mint")]
+    receipt_mint: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+impl<'a, 'b, 'c, 'info> From<&StartUnstake<'info>> for CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+    fn from(accounts: &StartUnstake<'info>) -> CpiContext<'a, 'b, 'c, 'info, Burn<'info>> {
+        let cpi_accounts = Burn {
+            mint: accounts.receipt_mint.clone(),
+            to: accounts.receipt_tokens.to_account_info(),
+            authority: accounts.owner.clone(),
+        };
+        CpiContext::new(accounts.pool_accounts.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// Transfers tokens held by the pool to a staker.
+#[derive(Accounts)]
+pub struct Withdraw<'info> {
+    pool: ProgramAccount<'info, StakePool>,
+    #[account(mut, has_one = pool, has_one = owner)]
+    staker: ProgramAccount<'info, Staker>,
+    #[account(signer)]
+    owner: AccountInfo<'info>,
+    #[account(mut)]
+    vault: CpiAccount<'info, TokenAccount>,
+    /// Token account receiving the tokens.
+    #[account(mut)]
+    destination: AccountInfo<'info>,
+    #[account(seeds = [pool.to_account_info().key.as_ref(), &[pool.nonce]])]
+    pool_signer: AccountInfo<'info>,
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+impl<'a, 'b, 'c, 'info> From<&Withdraw<'info>> for CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+    fn from(accounts: &Withdraw<'info>) -> CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: accounts.vault.to_account_info(),
+            to: accounts.destination.clone(),
+            authority: accounts.pool_signer.clone(),
+        };
+        CpiContext::new(accounts.token_program.clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct DepositRewards<'info> {
+    pool_accounts: UpdatePool<'info>,
+    #[account(signer)]
+    depositor: AccountInfo<'info>,
+    /// Depositor token account the rewards are taken from.
+    #[account(mut)]
+    source: AccountInfo<'info>,
+}
+
+impl<'a, 'b, 'c, 'info> From<&mut DepositRewards<'info>>
+    for CpiContext<'a, 'b, 'c, 'info, Transfer<'info>>
+{
+    fn from(
+        accounts: &mut DepositRewards<'info>,
+    ) -> CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: accounts.source.clone(),
+            to: accounts.pool_accounts.reward_vault.clone(),
+            authority: accounts.depositor.clone(),
+        };
+        CpiContext::new(accounts.pool_accounts.token_program.clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct Claim<'info> {
+    pool_accounts: UpdatePool<'info>,
+    #[account(mut, "&staker.pool == pool_accounts.pool.to_account_info().key")]
+    staker: ProgramAccount<'info, Staker>,
+    #[account(signer, "owner.key == &staker.owner")]
+    owner: AccountInfo<'info>,
+    /// Token account receiving the rewards.
+    #[account(mut)]
+    destination: AccountInfo<'info>,
+}
+
+impl<'a, 'b, 'c, 'info> From<&Claim<'info>> for CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+    fn from(accounts: &Claim<'info>) -> CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: accounts.pool_accounts.reward_vault.clone(),
+            to: accounts.destination.clone(),
+            authority: accounts.pool_accounts.pool_signer.clone(),
+        };
+        CpiContext::new(accounts.pool_accounts.token_program.clone(), cpi_accounts)
+    }
+}
+
+#[derive(Accounts)]
+pub struct SetConfig<'info> {
+    pool_accounts: UpdatePool<'info>,
+    #[account(signer, "authority.key == &pool_accounts.pool.authority")]
+    authority: AccountInfo<'info>,
+}
+
+/// Stake pool of the protocol token.
+#[account]
+pub struct StakePool {
+    /// Key allowed to change the pool config, the governance signer.
+    pub authority: Pubkey,
+    /// Protocol token that is staked and paid as rewards.
+    pub native_mint: Pubkey,
+    /// Stake receipt token, minted 1:1 for staked tokens and used as governance voting weight.
+    pub receipt_mint: Pubkey,
+    /// Holds staked tokens, including stakes in cooldown.
+    pub stake_vault: Pubkey,
+    /// Holds minted inflation and deposited fees until they are claimed.
+    pub reward_vault: Pubkey,
+    pub config: PoolConfig,
+    /// Tokens currently earning rewards, excluding stakes in cooldown.
+    pub total_staked: u64,
+    /// Rewards earned per staked token since the pool was created, scaled by `REWARD_PRECISION`.
+    pub reward_per_share: u128,
+    /// Unix timestamp inflation was last minted at.
+    pub last_update_ts: i64,
+    /// nonce for program derived address
+    pub nonce: u8,
+}
+
+impl StakePool {
+    /// Adds `amount` of rewards to the accumulator, shared between the current stake.
+    fn distribute(&mut self, amount: u64) -> Result<()> {
+        let increase = (amount as u128)
+            .checked_mul(REWARD_PRECISION)
+            .ok_or(ErrorCode::MathOverflow)?
+            / self.total_staked as u128;
+        self.reward_per_share = self
+            .reward_per_share
+            .checked_add(increase)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Inflation accrued over `elapsed` seconds. The rate applies to the whole supply but is only
+    /// paid to stakers, boosting their rate by `supply / total_staked`.
+    pub fn inflation(&self, supply: u64, elapsed: i64) -> Result<u64> {
+        if elapsed <= 0 || self.total_staked == 0 {
+            return Ok(0);
+        }
+        let inflation = (supply as u128)
+            .checked_mul(self.config.inflation_rate_bps as u128)
+            .and_then(|value| value.checked_mul(elapsed as u128))
+            .ok_or(ErrorCode::MathOverflow)?
+            / (10_000 * SECONDS_PER_YEAR);
+        Ok(inflation as u64)
+    }
+}
+
+/// Governance controlled parameters of a stake pool.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PoolConfig {
+    /// Annual inflation as a rate on the total supply of the native token, in basis points.
+    pub inflation_rate_bps: u16,
+    /// Seconds between starting and finishing an unstake.
+    pub cooldown: i64,
+}
+
+impl PoolConfig {
+    fn validate(&self) -> Result<()> {
+        if self.inflation_rate_bps > 10_000 || self.cooldown < 0 {
+            return Err(ErrorCode::InvalidConfig.into());
+        }
+        Ok(())
+    }
+}
+
+/// Stake of an owner in a pool.
+#[account]
+pub struct Staker {
+    pub pool: Pubkey,
+    pub owner: Pubkey,
+    /// Tokens earning rewards. Equals the stake receipt tokens minted to the owner.
+    pub staked: u64,
+    /// `staked * reward_per_share` when rewards were last settled, scaled by `REWARD_PRECISION`.
+    pub reward_debt: u128,
+    /// Settled rewards that have not been claimed yet.
+    pub pending_rewards: u64,
+    /// Unstakes in cooldown, at most `MAX_PENDING_UNSTAKES`.
+    pub pending_unstakes: Vec<PendingUnstake>,
+}
+
+/// Tokens unstaked by a single `start_unstake`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct PendingUnstake {
+    pub amount: u64,
+    /// Unix timestamp the tokens can be withdrawn at.
+    pub available_at: i64,
+}
+
+impl Staker {
+    /// Rewards of the current stake since the pool was created, scaled by `REWARD_PRECISION`.
+    fn accrued(&self, pool: &StakePool) -> Result<u128> {
+        (self.staked as u128)
+            .checked_mul(pool.reward_per_share)
+            .ok_or_else(|| ErrorCode::MathOverflow.into())
+    }
+
+    /// Moves rewards accrued since the last settlement into `pending_rewards`.
+    fn settle_rewards(&mut self, pool: &StakePool) -> Result<()> {
+        let accrued = self.accrued(pool)?;
+        let earned = (accrued - self.reward_debt) / REWARD_PRECISION;
+        self.pending_rewards = self
+            .pending_rewards
+            .checked_add(earned as u64)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.reward_debt = accrued;
+        Ok(())
+    }
+
+    /// Voting weight of the stake in governance.
+    pub fn stake_weight(&self) -> u64 {
+        self.staked
+    }
+
+    /// Starts the cooldown of `amount`, withdrawable at `available_at`.
+    fn queue_unstake(&mut self, amount: u64, available_at: i64) -> Result<()> {
+        if self.pending_unstakes.len() >= MAX_PENDING_UNSTAKES {
+            return Err(ErrorCode::TooManyPendingUnstakes.into());
+        }
+        self.pending_unstakes.push(PendingUnstake {
+            amount,
+            available_at,
+        });
+        Ok(())
+    }
+
+    /// Removes the unstakes whose cooldown has ended at `now` and returns their total.
+    fn take_unstaked(&mut self, now: i64) -> Result<u64> {
+        if self.pending_unstakes.is_empty() {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        let mut amount: u64 = 0;
+        for unstake in self.pending_unstakes.iter() {
+            if unstake.available_at <= now {
+                amount = amount
+                    .checked_add(unstake.amount)
+                    .ok_or(ErrorCode::MathOverflow)?;
+            }
+        }
+        if amount == 0 {
+            return Err(ErrorCode::CooldownNotElapsed.into());
+        }
+        self.pending_unstakes
+            .retain(|unstake| unstake.available_at > now);
+        Ok(amount)
+    }
+}
+
+/// A receipt token account with the accounts needed to thaw and freeze it.
+struct ReceiptAccounts<'a, 'info> {
+    receipt_tokens: &'a CpiAccount<'info, TokenAccount>,
+    receipt_mint: &'a AccountInfo<'info>,
+    pool_signer: &'a AccountInfo<'info>,
+    token_program: &'a AccountInfo<'info>,
+}
+
+impl<'a, 'info> ReceiptAccounts<'a, 'info> {
+    /// Thaws the receipt token account so receipts can be minted or burned. New accounts aren't
+    /// frozen yet.
+    fn thaw(&self, signer: &[&[&[u8]]]) -> Result<()> {
+        if !self.receipt_tokens.is_frozen() {
+            return Ok(());
+        }
+        let instruction = spl_token::instruction::thaw_account(
+            self.token_program.key,
+            self.receipt_tokens.to_account_info().key,
+            self.receipt_mint.key,
+            self.pool_signer.key,
+            &[],
+        )?;
+        invoke_signed(&instruction, &self.account_infos(), signer)
+    }
+
+    /// Freezes the receipt token account again, so receipts can't leave the staker.
+    fn freeze(&self, signer: &[&[&[u8]]]) -> Result<()> {
+        let instruction = spl_token::instruction::freeze_account(
+            self.token_program.key,
+            self.receipt_tokens.to_account_info().key,
+            self.receipt_mint.key,
+            self.pool_signer.key,
+            &[],
+        )?;
+        invoke_signed(&instruction, &self.account_infos(), signer)
+    }
+
+    fn account_infos(&self) -> [AccountInfo<'info>; 4] {
+        [
+            self.receipt_tokens.to_account_info(),
+            self.receipt_mint.clone(),
+            self.pool_signer.clone(),
+            self.token_program.clone(),
+        ]
+    }
+}
+
+/// Mints the inflation accrued since the last update into the reward vault and adds it to the
+/// reward accumulator.
+fn update_pool(accounts: &mut UpdatePool) -> Result<()> {
+    let now = accounts.clock.unix_timestamp;
+    let elapsed = now - accounts.pool.last_update_ts;
+    let supply = spl_token::state::Mint::unpack(&accounts.native_mint.try_borrow_data()?)?.supply;
+    let inflation = accounts.pool.inflation(supply, elapsed)?;
+    accounts.pool.last_update_ts = now;
+    if inflation == 0 {
+        return Ok(());
+    }
+
+    let seeds = &[
+        accounts.pool.to_account_info().key.as_ref(),
+        &[accounts.pool.nonce],
+    ];
+    let signer = &[&seeds[..]];
+    let cpi_accounts = MintTo {
+        mint: accounts.native_mint.clone(),
+        to: accounts.reward_vault.clone(),
+        authority: accounts.pool_signer.clone(),
+    };
+    let cpi_ctx = CpiContext::new_with_signer(accounts.token_program.clone(), cpi_accounts, signer);
+    token::mint_to(cpi_ctx, inflation)?;
+
+    accounts.pool.distribute(inflation)
+}
+
+#[error]
+pub enum ErrorCode {
+    #[msg("Invalid program address. Did you provide the correct nonce?")]
+    InvalidProgramAddress,
+    #[msg("Vault must be owned by the pool signer and hold the native token.")]
+    InvalidVault,
+    #[msg("Mint authority must be the pool signer.")]
+    InvalidMint,
+    #[msg("Invalid pool config.")]
+    InvalidConfig,
+    #[msg("Amount has to be greater than 0.")]
+    InvalidAmount,
+    #[msg("Unstake cooldown has not elapsed.")]
+    CooldownNotElapsed,
+    #[msg("Pool has no stake to distribute rewards to.")]
+    NoStake,
+    #[msg("Math operation overflow.")]
+    MathOverflow,
+    #[msg("Too many unstakes in cooldown, finish one first.")]
+    TooManyPendingUnstakes,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn staker() -> Staker {
+        Staker {
+            pool: Pubkey::new_unique(),
+            owner: Pubkey::new_unique(),
+            staked: 0,
+            reward_debt: 0,
+            pending_rewards: 0,
+            pending_unstakes: vec![],
+        }
+    }
+
+    #[test]
+    fn unstakes_keep_their_own_cooldown() {
+        let mut staker = staker();
+        staker.queue_unstake(100, 10).unwrap();
+        staker.queue_unstake(50, 20).unwrap();
+
+        assert!(staker.take_unstaked(9).is_err());
+        assert_eq!(staker.take_unstaked(10).unwrap(), 100);
+        assert_eq!(
+            staker.pending_unstakes,
+            vec![PendingUnstake {
+                amount: 50,
+                available_at: 20
+            }]
+        );
+        assert!(staker.take_unstaked(19).is_err());
+        assert_eq!(staker.take_unstaked(25).unwrap(), 50);
+        assert!(staker.pending_unstakes.is_empty());
+        assert!(staker.take_unstaked(25).is_err());
+    }
+
+    #[test]
+    fn limits_pending_unstakes() {
+        let mut staker = staker();
+        for i in 0..MAX_PENDING_UNSTAKES {
+            staker.queue_unstake(1, i as i64).unwrap();
+        }
+        assert!(staker.queue_unstake(1, 0).is_err());
+        assert_eq!(
+            staker.take_unstaked(MAX_PENDING_UNSTAKES as i64).unwrap(),
+            MAX_PENDING_UNSTAKES as u64
+        );
+        staker.queue_unstake(1, 0).unwrap();
+    }
+}
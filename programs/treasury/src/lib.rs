@@ -0,0 +1,819 @@
+#![feature(proc_macro_hygiene)]
+
+use anchor_lang::prelude::*;
+use anchor_spl::token::{self, TokenAccount, Transfer};
+use entropy_program_utils::create_pda_account;
+use solana_program::program::invoke;
+use solana_program::program_pack::Pack;
+
+solana_program::declare_id!("Treasury11111111111111111111111111111111111");
+
+/// Seed of the margin program signer allowed to cover bad debt, see
+/// `InsuranceConfig::coverage_authority`.
+pub const COVERAGE_AUTHORITY_SEED: &[u8] = b"insurance-coverage";
+
+/// Seeds of the token accounts of a vault, see `TreasuryVault::token_account_seeds`.
+pub const INTAKE_SEED: &[u8] = b"intake";
+pub const INSURANCE_SEED: &[u8] = b"insurance";
+pub const COMMUNITY_SEED: &[u8] = b"community";
+
+/// The treasury holds the insurance and community funds. Fees are paid into an intake vault per
+/// mint and swept into the two funds, filling the insurance fund up to a threshold first. The
+/// community fund can only be spent by the treasury authority, the governance signer.
+#[program]
+pub mod treasury {
+    use super::*;
+
+    /// Initialize a treasury controlled by `governance`. The authority is the governance signer,
+    /// the address `governance_program` derives from the governance account, so vaults can only
+    /// be configured and funds spent by executing a passed proposal.
+    pub fn create_treasury(
+        ctx: Context<CreateTreasury>,
+        governance: Pubkey,
+        governance_program: Pubkey,
+        nonce: u8,
+    ) -> ProgramResult {
+        let treasury_key = ctx.accounts.treasury.to_account_info().key;
+        Pubkey::create_program_address(&[treasury_key.as_ref(), &[nonce]], ctx.program_id)
+            .map_err(|_| ErrorCode::InvalidProgramAddress)?;
+        let (authority, _) =
+            Pubkey::find_program_address(&[governance.as_ref()], &governance_program);
+
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.authority = authority;
+        treasury.governance = governance;
+        treasury.governance_program = governance_program;
+        treasury.nonce = nonce;
+
+        Ok(())
+    }
+
+    /// Add the vaults holding one mint. The vault lives at the program address derived from the
+    /// treasury and mint, so each mint has a single vault, and its three token accounts are
+    /// created at the addresses derived from the treasury, mint and their kind, owned by the
+    /// treasury signer. The authority pays for the accounts.
+    pub fn add_vault(ctx: Context<AddVault>, config: VaultConfig) -> ProgramResult {
+        config.validate()?;
+        let treasury_key = *ctx.accounts.treasury.to_account_info().key;
+        let mint = *ctx.accounts.mint.key;
+        let vault_info = &ctx.accounts.vault;
+        let seeds = TreasuryVault::signer_seeds(&treasury_key, &mint, &[]);
+        let (vault_address, nonce) = Pubkey::find_program_address(&seeds[..2], ctx.program_id);
+        if vault_info.key != &vault_address {
+            return Err(ErrorCode::InvalidVault.into());
+        }
+        // Anyone can send lamports to the address, so only an account owned by this program or
+        // holding data is an existing vault.
+        if vault_info.owner == ctx.program_id || !vault_info.data_is_empty() {
+            return Err(ErrorCode::VaultAlreadyExists.into());
+        }
+
+        let accounts = &ctx.accounts;
+        let vault = TreasuryVault {
+            treasury: treasury_key,
+            mint,
+            intake: create_token_account(accounts, &accounts.intake, INTAKE_SEED, ctx.program_id)?,
+            insurance: create_token_account(
+                accounts,
+                &accounts.insurance,
+                INSURANCE_SEED,
+                ctx.program_id,
+            )?,
+            community: create_token_account(
+                accounts,
+                &accounts.community,
+                COMMUNITY_SEED,
+                ctx.program_id,
+            )?,
+            config,
+            insurance_config: InsuranceConfig::default(),
+            pending_insurance_config: None,
+            insurance_config_available_at: 0,
+            outflow_epoch: 0,
+            epoch_outflow: 0,
+        };
+        let mut data = Vec::new();
+        vault.try_serialize(&mut data)?;
+
+        let nonce_seed = [nonce];
+        let seeds = TreasuryVault::signer_seeds(&treasury_key, &mint, &nonce_seed);
+        create_pda_account(
+            &accounts.authority,
+            vault_info,
+            &accounts.system_program,
+            &accounts.rent,
+            vault.space()?,
+            ctx.program_id,
+            &seeds,
+        )?;
+        vault_info.try_borrow_mut_data()?[..data.len()].copy_from_slice(&data);
+
+        Ok(())
+    }
+
+    /// Update the insurance threshold and community share of a vault.
+    pub fn set_vault_config(ctx: Context<SetVaultConfig>, config: VaultConfig) -> ProgramResult {
+        config.validate()?;
+        ctx.accounts.vault.config = config;
+
+        Ok(())
+    }
+
+    /// Split the fees paid into the intake vault between the insurance and community funds.
+    /// Permissionless, anyone can crank it.
+    pub fn sweep(ctx: Context<Sweep>) -> ProgramResult {
+        let amount = ctx.accounts.intake.amount;
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        let (insurance_amount, community_amount) = ctx
+            .accounts
+            .vault
+            .config
+            .split_fees(amount, ctx.accounts.insurance.amount);
+
+        let seeds = &[
+            ctx.accounts.treasury.to_account_info().key.as_ref(),
+            &[ctx.accounts.treasury.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let token_program = ctx.accounts.token_program.clone();
+        let intake = ctx.accounts.intake.to_account_info();
+        let splits = [
+            (ctx.accounts.insurance.to_account_info(), insurance_amount),
+            (ctx.accounts.community.to_account_info(), community_amount),
+        ];
+        for (fund, split) in splits.iter() {
+            if *split == 0 {
+                continue;
+            }
+            let cpi_accounts = Transfer {
+                from: intake.clone(),
+                to: fund.clone(),
+                authority: ctx.accounts.treasury_signer.clone(),
+            };
+            let cpi_ctx = CpiContext::new_with_signer(token_program.clone(), cpi_accounts, signer);
+            token::transfer(cpi_ctx, *split)?;
+        }
+
+        Ok(())
+    }
+
+    /// Spend from the community fund. Only the treasury authority, the governance signer, can
+    /// spend, so spending requires a passed spend proposal.
+    pub fn spend(ctx: Context<Spend>, amount: u64) -> ProgramResult {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+
+        let seeds = &[
+            ctx.accounts.treasury.to_account_info().key.as_ref(),
+            &[ctx.accounts.treasury.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let cpi_ctx = CpiContext::from(&*ctx.accounts).with_signer(signer);
+        token::transfer(cpi_ctx, amount)?;
+
+        Ok(())
+    }
+
+    /// Queue new withdrawal limits for a vault's insurance fund, applicable once the fund's
+    /// current withdrawal delay has passed, so limits can't be loosened to skip the delay.
+    /// Queueing again replaces the pending config and restarts the delay.
+    pub fn queue_insurance_config(
+        ctx: Context<QueueInsuranceConfig>,
+        config: InsuranceConfig,
+    ) -> ProgramResult {
+        config.validate()?;
+        let now = ctx.accounts.clock.unix_timestamp;
+        ctx.accounts.vault.queue_insurance_config(config, now)
+    }
+
+    /// Apply a queued insurance config whose delay has passed. Permissionless, anyone can crank
+    /// it.
+    pub fn apply_insurance_config(ctx: Context<ApplyInsuranceConfig>) -> ProgramResult {
+        let now = ctx.accounts.clock.unix_timestamp;
+        ctx.accounts.vault.apply_insurance_config(now)
+    }
+
+    /// Queue a withdrawal from the insurance fund, executable once the fund's withdrawal delay
+    /// has passed. Only the treasury authority, the governance signer, can queue withdrawals.
+    pub fn queue_insurance_withdrawal(
+        ctx: Context<QueueInsuranceWithdrawal>,
+        amount: u64,
+    ) -> ProgramResult {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        let available_at = ctx
+            .accounts
+            .clock
+            .unix_timestamp
+            .checked_add(ctx.accounts.vault.insurance_config.withdrawal_delay)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        let withdrawal = &mut ctx.accounts.withdrawal;
+        withdrawal.vault = *ctx.accounts.vault.to_account_info().key;
+        withdrawal.destination = *ctx.accounts.destination.key;
+        withdrawal.amount = amount;
+        withdrawal.withdrawn = 0;
+        withdrawal.available_at = available_at;
+        withdrawal.cancelled = false;
+
+        Ok(())
+    }
+
+    /// Cancel a queued withdrawal.
+    pub fn cancel_insurance_withdrawal(ctx: Context<CancelInsuranceWithdrawal>) -> ProgramResult {
+        ctx.accounts.withdrawal.cancelled = true;
+
+        Ok(())
+    }
+
+    /// Pay out a queued withdrawal whose delay has passed. Permissionless, anyone can crank it.
+    /// Withdrawals larger than what is left of the epoch's outflow limit are paid out over
+    /// several epochs.
+    pub fn execute_insurance_withdrawal(ctx: Context<ExecuteInsuranceWithdrawal>) -> ProgramResult {
+        let withdrawal = &ctx.accounts.withdrawal;
+        if withdrawal.cancelled {
+            return Err(ErrorCode::WithdrawalCancelled.into());
+        }
+        if ctx.accounts.clock.unix_timestamp < withdrawal.available_at {
+            return Err(ErrorCode::WithdrawalDelayNotElapsed.into());
+        }
+        let remaining = withdrawal.amount - withdrawal.withdrawn;
+        if remaining == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        let amount = ctx
+            .accounts
+            .vault
+            .available_outflow(ctx.accounts.clock.epoch)
+            .min(remaining)
+            .min(ctx.accounts.insurance.amount);
+        if amount == 0 {
+            return Err(ErrorCode::OutflowLimitReached.into());
+        }
+
+        let seeds = &[
+            ctx.accounts.treasury.to_account_info().key.as_ref(),
+            &[ctx.accounts.treasury.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.insurance.to_account_info(),
+            to: ctx.accounts.destination.clone(),
+            authority: ctx.accounts.treasury_signer.clone(),
+        };
+        let cpi_ctx =
+            CpiContext::new_with_signer(ctx.accounts.token_program.clone(), cpi_accounts, signer);
+        token::transfer(cpi_ctx, amount)?;
+
+        ctx.accounts
+            .vault
+            .record_outflow(ctx.accounts.clock.epoch, amount);
+        ctx.accounts.withdrawal.withdrawn += amount;
+
+        Ok(())
+    }
+
+    /// Cover bad debt from the insurance fund without waiting for the withdrawal delay. Must be
+    /// signed by the fund's coverage authority, the margin program's signer derived from
+    /// `COVERAGE_AUTHORITY_SEED`, which the margin program uses when liquidating a position
+    /// whose collateral doesn't cover its loan. Coverage still counts towards the epoch's
+    /// outflow limit, so a faulty caller can't drain the fund.
+    pub fn cover_bad_debt(ctx: Context<CoverBadDebt>, amount: u64) -> ProgramResult {
+        if amount == 0 {
+            return Err(ErrorCode::InvalidAmount.into());
+        }
+        let coverage_authority = ctx.accounts.vault.insurance_config.coverage_authority();
+        if coverage_authority != Some(*ctx.accounts.coverage_authority.key) {
+            return Err(ErrorCode::Unauthorized.into());
+        }
+        let epoch = ctx.accounts.clock.epoch;
+        if amount > ctx.accounts.vault.available_outflow(epoch) {
+            return Err(ErrorCode::OutflowLimitReached.into());
+        }
+
+        let seeds = &[
+            ctx.accounts.treasury.to_account_info().key.as_ref(),
+            &[ctx.accounts.treasury.nonce],
+        ];
+        let signer = &[&seeds[..]];
+        let cpi_accounts = Transfer {
+            from: ctx.accounts.insurance.to_account_info(),
+            to: ctx.accounts.destination.clone(),
+            authority: ctx.accounts.treasury_signer.clone(),
+        };
+        let cpi_ctx =
+            CpiContext::new_with_signer(ctx.accounts.token_program.clone(), cpi_accounts, signer);
+        token::transfer(cpi_ctx, amount)?;
+
+        ctx.accounts.vault.record_outflow(epoch, amount);
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CreateTreasury<'info> {
+    #[account(init)]
+    treasury: ProgramAccount<'info, Treasury>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct AddVault<'info> {
+    #[account(has_one = authority)]
+    treasury: ProgramAccount<'info, Treasury>,
+    /// Treasury authority, also pays for the vault accounts.
+    #[account(signer, mut)]
+    authority: AccountInfo<'info>,
+    /// Program derived address of the treasury and mint, see `TreasuryVault::signer_seeds`.
+    #[account(mut)]
+    vault: AccountInfo<'info>,
+    mint: AccountInfo<'info>,
+    /// Token accounts created at the program derived addresses of the treasury, mint and kind,
+    /// see `TreasuryVault::token_account_seeds`.
+    #[account(mut)]
+    intake: AccountInfo<'info>,
+    #[account(mut)]
+    insurance: AccountInfo<'info>,
+    #[account(mut)]
+    community: AccountInfo<'info>,
+    #[account(seeds = [treasury.to_account_info().key.as_ref(), &[treasury.nonce]])]
+    treasury_signer: AccountInfo<'info>,
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+    #[account("system_program.key == &solana_program::system_program::ID")]
+    system_program: AccountInfo<'info>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct SetVaultConfig<'info> {
+    #[account(has_one = authority)]
+    treasury: ProgramAccount<'info, Treasury>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    #[account(mut, has_one = treasury)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+}
+
+#[derive(Accounts)]
+pub struct QueueInsuranceConfig<'info> {
+    #[account(has_one = authority)]
+    treasury: ProgramAccount<'info, Treasury>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    #[account(mut, has_one = treasury)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+    clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct ApplyInsuranceConfig<'info> {
+    #[account(mut)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+    clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct Sweep<'info> {
+    treasury: ProgramAccount<'info, Treasury>,
+    #[account(has_one = treasury, has_one = intake, has_one = insurance, has_one = community)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+    #[account(mut)]
+    intake: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    insurance: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    community: CpiAccount<'info, TokenAccount>,
+    #[account(seeds = [treasury.to_account_info().key.as_ref(), &[treasury.nonce]])]
+    treasury_signer: AccountInfo<'info>,
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Spend<'info> {
+    #[account(has_one = authority)]
+    treasury: ProgramAccount<'info, Treasury>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    #[account(has_one = treasury, has_one = community)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+    #[account(mut)]
+    community: CpiAccount<'info, TokenAccount>,
+    /// Token account receiving the spent funds.
+    #[account(mut)]
+    destination: AccountInfo<'info>,
+    #[account(seeds = [treasury.to_account_info().key.as_ref(), &[treasury.nonce]])]
+    treasury_signer: AccountInfo<'info>,
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct QueueInsuranceWithdrawal<'info> {
+    #[account(has_one = authority)]
+    treasury: ProgramAccount<'info, Treasury>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    #[account(has_one = treasury)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+    #[account(init)]
+    withdrawal: ProgramAccount<'info, InsuranceWithdrawal>,
+    /// Token account the withdrawal is paid to.
+    destination: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+    rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct CancelInsuranceWithdrawal<'info> {
+    #[account(has_one = authority)]
+    treasury: ProgramAccount<'info, Treasury>,
+    #[account(signer)]
+    authority: AccountInfo<'info>,
+    #[account(has_one = treasury)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+    #[account(mut, has_one = vault)]
+    withdrawal: ProgramAccount<'info, InsuranceWithdrawal>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteInsuranceWithdrawal<'info> {
+    treasury: ProgramAccount<'info, Treasury>,
+    #[account(mut, has_one = treasury, has_one = insurance)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+    #[account(mut, has_one = vault, has_one = destination)]
+    withdrawal: ProgramAccount<'info, InsuranceWithdrawal>,
+    #[account(mut)]
+    insurance: CpiAccount<'info, TokenAccount>,
+    #[account(mut)]
+    destination: AccountInfo<'info>,
+    #[account(seeds = [treasury.to_account_info().key.as_ref(), &[treasury.nonce]])]
+    treasury_signer: AccountInfo<'info>,
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+#[derive(Accounts)]
+pub struct CoverBadDebt<'info> {
+    treasury: ProgramAccount<'info, Treasury>,
+    #[account(mut, has_one = treasury, has_one = insurance)]
+    vault: ProgramAccount<'info, TreasuryVault>,
+    /// Program derived signer of the margin program covering the bad debt.
+    #[account(signer)]
+    coverage_authority: AccountInfo<'info>,
+    #[account(mut)]
+    insurance: CpiAccount<'info, TokenAccount>,
+    /// Token account of the loss being covered, the loaned vault of the liquidated position,
+    /// which the margin program repays the reserve's liquidity supply from.
+    #[account(mut)]
+    destination: AccountInfo<'info>,
+    #[account(seeds = [treasury.to_account_info().key.as_ref(), &[treasury.nonce]])]
+    treasury_signer: AccountInfo<'info>,
+    #[account("token_program.key == &token::ID")]
+    token_program: AccountInfo<'info>,
+    clock: Sysvar<'info, Clock>,
+}
+
+/// Creates the token account of the vault holding `accounts.mint` at the program derived address
+/// of its `kind`, owned by the treasury signer.
+fn create_token_account<'info>(
+    accounts: &AddVault<'info>,
+    token_account: &AccountInfo<'info>,
+    kind: &[u8],
+    program_id: &Pubkey,
+) -> Result<Pubkey> {
+    let treasury_key = accounts.treasury.to_account_info().key;
+    let seeds = TreasuryVault::token_account_seeds(treasury_key, accounts.mint.key, kind, &[]);
+    let (address, nonce) = Pubkey::find_program_address(&seeds[..3], program_id);
+    if token_account.key != &address {
+        return Err(ErrorCode::InvalidVault.into());
+    }
+    let nonce_seed = [nonce];
+    let seeds =
+        TreasuryVault::token_account_seeds(treasury_key, accounts.mint.key, kind, &nonce_seed);
+    create_pda_account(
+        &accounts.authority,
+        token_account,
+        &accounts.system_program,
+        &accounts.rent,
+        spl_token::state::Account::LEN,
+        &token::ID,
+        &seeds,
+    )?;
+    invoke(
+        &spl_token::instruction::initialize_account(
+            &token::ID,
+            token_account.key,
+            accounts.mint.key,
+            accounts.treasury_signer.key,
+        )?,
+        &[
+            token_account.clone(),
+            accounts.mint.clone(),
+            accounts.treasury_signer.clone(),
+            accounts.rent.to_account_info(),
+            accounts.token_program.clone(),
+        ],
+    )?;
+    Ok(address)
+}
+
+impl<'a, 'b, 'c, 'info> From<&Spend<'info>> for CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+    fn from(accounts: &Spend<'info>) -> CpiContext<'a, 'b, 'c, 'info, Transfer<'info>> {
+        let cpi_accounts = Transfer {
+            from: accounts.community.to_account_info(),
+            to: accounts.destination.clone(),
+            authority: accounts.treasury_signer.clone(),
+        };
+        CpiContext::new(accounts.token_program.clone(), cpi_accounts)
+    }
+}
+
+/// Treasury of the protocol. Its program derived signer owns every vault.
+#[account]
+pub struct Treasury {
+    /// Key allowed to configure vaults and spend the community fund, the governance signer.
+    pub authority: Pubkey,
+    /// Governance account whose signer is the authority.
+    pub governance: Pubkey,
+    /// Program of the governance account, which derives the authority.
+    pub governance_program: Pubkey,
+    /// nonce for program derived address
+    pub nonce: u8,
+}
+
+/// Insurance and community funds of a single mint.
+#[account]
+pub struct TreasuryVault {
+    pub treasury: Pubkey,
+    pub mint: Pubkey,
+    /// Receives fees until they are swept into the funds. Set as the insurance and community
+    /// fee destination of the margin program.
+    pub intake: Pubkey,
+    /// Insurance fund, covers bad debt of liquidated positions.
+    pub insurance: Pubkey,
+    /// Community fund, spent through governance spend proposals.
+    pub community: Pubkey,
+    pub config: VaultConfig,
+    /// Limits on withdrawals from the insurance fund.
+    pub insurance_config: InsuranceConfig,
+    /// Insurance config queued to replace `insurance_config`.
+    pub pending_insurance_config: Option<InsuranceConfig>,
+    /// Unix timestamp the pending insurance config can be applied at.
+    pub insurance_config_available_at: i64,
+    /// Epoch `epoch_outflow` was recorded in.
+    pub outflow_epoch: u64,
+    /// Insurance fund outflow during `outflow_epoch`.
+    pub epoch_outflow: u64,
+}
+
+impl TreasuryVault {
+    /// Seeds of the vault's program derived address, one per treasury and mint.
+    pub fn signer_seeds<'a>(
+        treasury: &'a Pubkey,
+        mint: &'a Pubkey,
+        nonce: &'a [u8],
+    ) -> [&'a [u8]; 3] {
+        [treasury.as_ref(), mint.as_ref(), nonce]
+    }
+
+    /// Seeds of the program derived address of the vault's token account of `kind`, one of
+    /// `INTAKE_SEED`, `INSURANCE_SEED` and `COMMUNITY_SEED`.
+    pub fn token_account_seeds<'a>(
+        treasury: &'a Pubkey,
+        mint: &'a Pubkey,
+        kind: &'a [u8],
+        nonce: &'a [u8],
+    ) -> [&'a [u8]; 4] {
+        [treasury.as_ref(), mint.as_ref(), kind, nonce]
+    }
+
+    /// Size of the vault account, with room for a queued insurance config.
+    fn space(&self) -> Result<usize> {
+        let mut vault = self.clone();
+        vault.pending_insurance_config = Some(self.insurance_config);
+        let mut data = Vec::new();
+        vault.try_serialize(&mut data)?;
+        Ok(data.len())
+    }
+
+    /// Insurance fund outflow still allowed in `epoch`.
+    fn available_outflow(&self, epoch: u64) -> u64 {
+        let used = if epoch == self.outflow_epoch {
+            self.epoch_outflow
+        } else {
+            0
+        };
+        self.insurance_config
+            .max_outflow_per_epoch
+            .saturating_sub(used)
+    }
+
+    fn queue_insurance_config(&mut self, config: InsuranceConfig, now: i64) -> Result<()> {
+        self.insurance_config_available_at = now
+            .checked_add(self.insurance_config.withdrawal_delay)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.pending_insurance_config = Some(config);
+        Ok(())
+    }
+
+    fn apply_insurance_config(&mut self, now: i64) -> Result<()> {
+        let config = self
+            .pending_insurance_config
+            .ok_or(ErrorCode::NoPendingConfig)?;
+        if now < self.insurance_config_available_at {
+            return Err(ErrorCode::WithdrawalDelayNotElapsed.into());
+        }
+        self.insurance_config = config;
+        self.pending_insurance_config = None;
+        Ok(())
+    }
+
+    fn record_outflow(&mut self, epoch: u64, amount: u64) {
+        if epoch != self.outflow_epoch {
+            self.outflow_epoch = epoch;
+            self.epoch_outflow = 0;
+        }
+        self.epoch_outflow += amount;
+    }
+}
+
+/// Limits on withdrawals from an insurance fund. Outflow is disabled until governance sets a
+/// per-epoch limit. Changes are queued behind the current withdrawal delay.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct InsuranceConfig {
+    /// Seconds between queueing a withdrawal and being able to execute it.
+    pub withdrawal_delay: i64,
+    /// Most tokens, including bad debt coverage, that can leave the fund in an epoch.
+    pub max_outflow_per_epoch: u64,
+    /// Margin program whose signer can cover bad debt without a delay, none if default.
+    pub margin_program: Pubkey,
+}
+
+impl InsuranceConfig {
+    fn validate(&self) -> Result<()> {
+        if self.withdrawal_delay < 0 {
+            return Err(ErrorCode::InvalidConfig.into());
+        }
+        Ok(())
+    }
+
+    /// Signer allowed to cover bad debt, derived from `COVERAGE_AUTHORITY_SEED` and the margin
+    /// program.
+    pub fn coverage_authority(&self) -> Option<Pubkey> {
+        if self.margin_program == Pubkey::default() {
+            return None;
+        }
+        let (authority, _) =
+            Pubkey::find_program_address(&[COVERAGE_AUTHORITY_SEED], &self.margin_program);
+        Some(authority)
+    }
+}
+
+/// Withdrawal from an insurance fund waiting for its delay or outflow limit.
+#[account]
+pub struct InsuranceWithdrawal {
+    pub vault: Pubkey,
+    /// Token account the withdrawal is paid to.
+    pub destination: Pubkey,
+    pub amount: u64,
+    /// Amount paid out so far.
+    pub withdrawn: u64,
+    /// Unix timestamp the withdrawal can be executed from.
+    pub available_at: i64,
+    pub cancelled: bool,
+}
+
+/// How swept fees are split between the funds of a mint.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct VaultConfig {
+    /// Insurance fund balance, in the vault's mint, above which all fees go to the community fund.
+    pub insurance_threshold: u64,
+    /// Percentage of fees sent to the community fund while the insurance fund is below the
+    /// threshold.
+    pub community_share: u8,
+}
+
+impl VaultConfig {
+    fn validate(&self) -> Result<()> {
+        if self.community_share > 100 {
+            return Err(ErrorCode::InvalidConfig.into());
+        }
+        Ok(())
+    }
+
+    /// Splits `amount` of fees into `(insurance, community)` amounts given the insurance fund's
+    /// current balance. The insurance fund is never filled past the threshold.
+    pub fn split_fees(&self, amount: u64, insurance_balance: u64) -> (u64, u64) {
+        if insurance_balance >= self.insurance_threshold {
+            return (0, amount);
+        }
+        let community = (amount as u128 * self.community_share as u128 / 100) as u64;
+        let insurance = amount - community;
+        let shortfall = self.insurance_threshold - insurance_balance;
+        if insurance > shortfall {
+            (shortfall, amount - shortfall)
+        } else {
+            (insurance, community)
+        }
+    }
+}
+
+#[error]
+pub enum ErrorCode {
+    #[msg("Invalid program address. Did you provide the correct nonce?")]
+    InvalidProgramAddress,
+    #[msg("Vault accounts must be at the program derived addresses of the treasury and mint.")]
+    InvalidVault,
+    #[msg("Invalid vault config.")]
+    InvalidConfig,
+    #[msg("Amount has to be greater than 0.")]
+    InvalidAmount,
+    #[msg("Math operation overflow.")]
+    MathOverflow,
+    #[msg("Withdrawal has been cancelled.")]
+    WithdrawalCancelled,
+    #[msg("Withdrawal delay has not elapsed.")]
+    WithdrawalDelayNotElapsed,
+    #[msg("Insurance fund outflow limit for the epoch has been reached.")]
+    OutflowLimitReached,
+    #[msg("Signer is not the coverage authority of the insurance fund.")]
+    Unauthorized,
+    #[msg("No insurance config is queued.")]
+    NoPendingConfig,
+    #[msg("Treasury already has a vault for this mint.")]
+    VaultAlreadyExists,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vault(withdrawal_delay: i64) -> TreasuryVault {
+        TreasuryVault {
+            treasury: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            intake: Pubkey::new_unique(),
+            insurance: Pubkey::new_unique(),
+            community: Pubkey::new_unique(),
+            config: VaultConfig::default(),
+            insurance_config: InsuranceConfig {
+                withdrawal_delay,
+                max_outflow_per_epoch: 100,
+                margin_program: Pubkey::default(),
+            },
+            pending_insurance_config: None,
+            insurance_config_available_at: 0,
+            outflow_epoch: 0,
+            epoch_outflow: 0,
+        }
+    }
+
+    #[test]
+    fn config_changes_wait_for_the_withdrawal_delay() {
+        let mut vault = vault(100);
+        let config = InsuranceConfig {
+            withdrawal_delay: 0,
+            max_outflow_per_epoch: u64::MAX,
+            margin_program: Pubkey::new_unique(),
+        };
+        assert!(vault.apply_insurance_config(0).is_err());
+
+        vault.queue_insurance_config(config, 10).unwrap();
+        assert!(vault.apply_insurance_config(109).is_err());
+        assert_eq!(vault.available_outflow(0), 100);
+        vault.apply_insurance_config(110).unwrap();
+        assert_eq!(vault.available_outflow(0), u64::MAX);
+        assert!(vault.pending_insurance_config.is_none());
+        assert!(vault.apply_insurance_config(110).is_err());
+    }
+
+    #[test]
+    fn vault_space_fits_a_queued_insurance_config() {
+        let mut vault = vault(0);
+        let space = vault.space().unwrap();
+        let config = vault.insurance_config;
+        vault.queue_insurance_config(config, 0).unwrap();
+        let mut data = Vec::new();
+        vault.try_serialize(&mut data).unwrap();
+        assert_eq!(data.len(), space);
+    }
+
+    #[test]
+    fn coverage_authority_is_the_margin_program_signer() {
+        let mut config = vault(0).insurance_config;
+        assert_eq!(config.coverage_authority(), None);
+
+        config.margin_program = Pubkey::new_unique();
+        let (signer, _) =
+            Pubkey::find_program_address(&[COVERAGE_AUTHORITY_SEED], &config.margin_program);
+        assert_eq!(config.coverage_authority(), Some(signer));
+    }
+}
@@ -0,0 +1,319 @@
+//! Position analytics computed from account snapshots, without touching the cluster.
+//!
+//! Prices are quote per base, in token base units, like the price taken by `Position`'s value
+//! methods. A long position deposits the base token and borrows the quote token, a short position
+//! deposits the quote token and borrows the base token.
+
+use crate::error::{Result, SdkError};
+use margin_account::{Direction, Position, RiskParams};
+use solana_program::clock::Slot;
+use spl_token_lending::{
+    math::{Decimal, TryAdd, TryDiv, TrySub},
+    state::{Obligation, Reserve},
+};
+
+/// Price level of an order book
+#[derive(Clone, Debug, PartialEq)]
+pub struct Level {
+    /// Quote per base
+    pub price: Decimal,
+    /// Base tokens offered at `price`
+    pub quantity: u64,
+}
+
+/// Snapshot of the market a position trades on
+#[derive(Clone, Debug, PartialEq)]
+pub enum Market {
+    /// Order book levels, best first
+    OrderBook { bids: Vec<Level>, asks: Vec<Level> },
+    /// Constant product pool balances
+    Pool { base_amount: u64, quote_amount: u64 },
+}
+
+impl Market {
+    /// Price a position is marked at: what closing it would trade at on the top of the book,
+    /// or the pool's spot price
+    pub fn mark_price(&self, direction: Direction) -> Result<Decimal> {
+        match self {
+            Market::OrderBook { bids, asks } => {
+                // Longs close by selling base into the bids, shorts by buying it from the asks
+                let side = match direction {
+                    Direction::Long => bids,
+                    Direction::Short => asks,
+                };
+                side.first()
+                    .map(|level| level.price)
+                    .ok_or(SdkError::EmptyMarket)
+            }
+            Market::Pool {
+                base_amount,
+                quote_amount,
+            } => {
+                if *base_amount == 0 {
+                    return Err(SdkError::EmptyMarket);
+                }
+                Ok(Decimal::from(*quote_amount).try_div(*base_amount)?)
+            }
+        }
+    }
+}
+
+/// Accounts and balances describing an open position
+pub struct PositionSnapshot<'a> {
+    pub position: &'a Position,
+    /// Risk parameters of the position's token pair
+    pub risk_params: &'a RiskParams,
+    /// Balance of the position's collateral vault
+    pub held_amount: u64,
+    pub obligation: &'a Obligation,
+    pub deposit_reserve: &'a Reserve,
+    pub borrow_reserve: &'a Reserve,
+    /// Slot interest is accrued to, at least the reserves' last update slot
+    pub slot: Slot,
+}
+
+/// Analytics of a position
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionAnalytics {
+    /// Price the position was valued at
+    pub price: Decimal,
+    /// Borrowed tokens owed, including interest
+    pub debt: Decimal,
+    /// Interest accrued on the loan since it was taken out, in borrowed tokens
+    pub accrued_interest: Decimal,
+    /// Liquidity value of the obligation's collateral, in deposited tokens
+    pub collateral: Decimal,
+    /// Held and collateral value, net of the maintenance margin, over the debt value, as
+    /// computed by `Position::health_factor`. `None` without debt.
+    pub health_factor: Option<Decimal>,
+    /// Price at which the health factor reaches one, from `Position::liquidation_price`
+    pub liquidation_price: Option<Decimal>,
+    /// Held tokens minus debt, in quote tokens
+    pub unrealized_pnl: i128,
+}
+
+/// Compute the analytics of a position valued on `market`
+pub fn analyze(snapshot: &PositionSnapshot, market: &Market) -> Result<PositionAnalytics> {
+    let position = snapshot.position;
+    let price = market.mark_price(position.direction)?;
+
+    let mut deposit_reserve = snapshot.deposit_reserve.clone();
+    let mut borrow_reserve = snapshot.borrow_reserve.clone();
+    accrue_to(&mut deposit_reserve, snapshot.slot)?;
+    accrue_to(&mut borrow_reserve, snapshot.slot)?;
+
+    let mut obligation = snapshot.obligation.clone();
+    obligation.accrue_interest(borrow_reserve.cumulative_borrow_rate_wads)?;
+    let debt = obligation.borrowed_liquidity_wads;
+    let principal = Decimal::from(obligation.margin_borrowed_amount);
+    let accrued_interest = if debt > principal {
+        debt.try_sub(principal)?
+    } else {
+        Decimal::zero()
+    };
+
+    let collateral = deposit_reserve
+        .collateral_exchange_rate()?
+        .decimal_collateral_to_liquidity(obligation.deposited_collateral_tokens.into())?;
+    // The collateral is the same token as the held tokens
+    let held = collateral.try_add(snapshot.held_amount.into())?;
+    let health_factor = position.health_factor(held, debt, price, snapshot.risk_params)?;
+    let liquidation_price = position.liquidation_price(held, debt, snapshot.risk_params)?;
+    let unrealized_pnl = position.unrealized_pnl(snapshot.held_amount, debt, price)?;
+
+    Ok(PositionAnalytics {
+        price,
+        debt,
+        accrued_interest,
+        collateral,
+        health_factor,
+        liquidation_price,
+        unrealized_pnl,
+    })
+}
+
+fn accrue_to(reserve: &mut Reserve, slot: Slot) -> Result<()> {
+    if slot > reserve.last_update_slot {
+        reserve.accrue_interest(slot)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use margin_account::Status;
+    use solana_program::pubkey::Pubkey;
+    use spl_token_lending::state::{
+        BorrowRateCurve, ReserveCollateral, ReserveConfig, ReserveLiquidity,
+    };
+
+    fn reserve() -> Reserve {
+        Reserve {
+            cumulative_borrow_rate_wads: Decimal::one(),
+            liquidity: ReserveLiquidity {
+                available_amount: 1_000_000,
+                ..ReserveLiquidity::default()
+            },
+            collateral: ReserveCollateral {
+                mint_total_supply: 1_000_000,
+                ..ReserveCollateral::default()
+            },
+            config: ReserveConfig {
+                borrow_rate_curve: BorrowRateCurve::from_single_kink(0, 0, 0, 0),
+                ..ReserveConfig::default()
+            },
+            ..Reserve::default()
+        }
+    }
+
+    fn obligation(collateral: u64, debt: u64) -> Obligation {
+        Obligation {
+            deposited_collateral_tokens: collateral,
+            cumulative_borrow_rate_wads: Decimal::one(),
+            borrowed_liquidity_wads: Decimal::from(debt),
+            margin_borrowed_amount: debt,
+            ..Obligation::default()
+        }
+    }
+
+    fn position(direction: Direction, loan_amount: u64) -> Position {
+        Position {
+            loan_amount,
+            direction,
+            token_pair: Pubkey::default(),
+            obligation: Pubkey::default(),
+            loaned_vault: Pubkey::default(),
+            collateral_vault: Some(Pubkey::default()),
+            status: Status::Locked,
+            max_held_amount: u64::MAX,
+            withdrawable_amount: 0,
+            bad_debt: 0,
+        }
+    }
+
+    fn risk_params() -> RiskParams {
+        RiskParams {
+            max_leverage: 3,
+            maintenance_margin: 20,
+            liquidation_fee: 5,
+            max_slippage: 10,
+            max_open_interest: u64::MAX,
+        }
+    }
+
+    #[test]
+    fn long_position() {
+        // 100 base deposited, 200 quote borrowed and traded for 25 base at a price of 8
+        let position = position(Direction::Long, 200);
+        let obligation = obligation(100, 200);
+        let reserve = reserve();
+        let snapshot = PositionSnapshot {
+            position: &position,
+            risk_params: &risk_params(),
+            held_amount: 25,
+            obligation: &obligation,
+            deposit_reserve: &reserve,
+            borrow_reserve: &reserve,
+            slot: 0,
+        };
+        let market = Market::Pool {
+            base_amount: 1_000,
+            quote_amount: 12_000,
+        };
+
+        let analytics = analyze(&snapshot, &market).unwrap();
+        assert_eq!(analytics.price, Decimal::from(12u64));
+        assert_eq!(analytics.accrued_interest, Decimal::zero());
+        // (25 + 100) * 12 * 80% / 200
+        assert_eq!(analytics.health_factor, Some(Decimal::from(6u64)));
+        // 200 / ((25 + 100) * 80%)
+        assert_eq!(analytics.liquidation_price, Some(Decimal::from(2u64)));
+        // 25 * 12 - 200
+        assert_eq!(analytics.unrealized_pnl, 100);
+    }
+
+    #[test]
+    fn short_position() {
+        // 1000 quote deposited, 20 base borrowed and sold for 250 quote at a price of 12.5
+        let position = position(Direction::Short, 20);
+        let obligation = obligation(1_000, 20);
+        let reserve = reserve();
+        let snapshot = PositionSnapshot {
+            position: &position,
+            risk_params: &risk_params(),
+            held_amount: 250,
+            obligation: &obligation,
+            deposit_reserve: &reserve,
+            borrow_reserve: &reserve,
+            slot: 0,
+        };
+        let market = Market::OrderBook {
+            bids: vec![Level {
+                price: Decimal::from(7u64),
+                quantity: 10,
+            }],
+            asks: vec![Level {
+                price: Decimal::from(8u64),
+                quantity: 10,
+            }],
+        };
+
+        let analytics = analyze(&snapshot, &market).unwrap();
+        assert_eq!(analytics.price, Decimal::from(8u64));
+        // (250 + 1000) * 80% / (20 * 8)
+        assert_eq!(
+            analytics.health_factor,
+            Some(Decimal::from(625u64).try_div(100).unwrap())
+        );
+        // (250 + 1000) * 80% / 20
+        assert_eq!(analytics.liquidation_price, Some(Decimal::from(50u64)));
+        // 250 - 20 * 8
+        assert_eq!(analytics.unrealized_pnl, 90);
+    }
+
+    #[test]
+    fn accrues_interest_to_slot() {
+        let position = position(Direction::Long, 200);
+        let obligation = obligation(100, 200);
+        let mut borrow_reserve = reserve();
+        borrow_reserve.liquidity.borrowed_amount_wads = Decimal::from(500_000u64);
+        borrow_reserve.config.optimal_utilization_rate = 80;
+        borrow_reserve.config.borrow_rate_curve = BorrowRateCurve::from_single_kink(80, 0, 10, 10);
+        let deposit_reserve = reserve();
+        let snapshot = PositionSnapshot {
+            position: &position,
+            risk_params: &risk_params(),
+            held_amount: 25,
+            obligation: &obligation,
+            deposit_reserve: &deposit_reserve,
+            borrow_reserve: &borrow_reserve,
+            slot: 100_000_000,
+        };
+        let market = Market::Pool {
+            base_amount: 1,
+            quote_amount: 10,
+        };
+
+        let analytics = analyze(&snapshot, &market).unwrap();
+        assert!(analytics.accrued_interest > Decimal::zero());
+        assert_eq!(
+            analytics.debt,
+            Decimal::from(200u64)
+                .try_add(analytics.accrued_interest)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn empty_market() {
+        let market = Market::OrderBook {
+            bids: vec![],
+            asks: vec![],
+        };
+        assert!(matches!(
+            market.mark_price(Direction::Long),
+            Err(SdkError::EmptyMarket)
+        ));
+    }
+}
@@ -0,0 +1,163 @@
+//! Typed account loaders over an RPC client
+
+use crate::{
+    error::{Result, SdkError},
+    pda,
+};
+use anchor_lang::AccountDeserialize;
+use margin_account::{margin_account::State, MarginAccount, TokenPair};
+use solana_client::rpc_client::RpcClient;
+use solana_program::{
+    program_pack::{IsInitialized, Pack},
+    pubkey::Pubkey,
+};
+use solana_sdk::account::Account;
+use spl_token_lending::state::{LendingMarket, Obligation, Reserve};
+
+/// RPC client of the lending and margin programs
+pub struct Client {
+    rpc: RpcClient,
+    lending_program_id: Pubkey,
+    margin_program_id: Pubkey,
+}
+
+impl Client {
+    /// Create a client of the programs deployed at the given ids
+    pub fn new(rpc: RpcClient, lending_program_id: Pubkey, margin_program_id: Pubkey) -> Self {
+        Self {
+            rpc,
+            lending_program_id,
+            margin_program_id,
+        }
+    }
+
+    /// Underlying RPC client, to send transactions
+    pub fn rpc(&self) -> &RpcClient {
+        &self.rpc
+    }
+
+    /// Lending program id
+    pub fn lending_program_id(&self) -> &Pubkey {
+        &self.lending_program_id
+    }
+
+    /// Margin program id
+    pub fn margin_program_id(&self) -> &Pubkey {
+        &self.margin_program_id
+    }
+
+    /// Fetch a lending market
+    pub fn lending_market(&self, pubkey: &Pubkey) -> Result<LendingMarket> {
+        self.unpack(pubkey, &self.lending_program_id)
+    }
+
+    /// Fetch a reserve
+    pub fn reserve(&self, pubkey: &Pubkey) -> Result<Reserve> {
+        self.unpack(pubkey, &self.lending_program_id)
+    }
+
+    /// Fetch an obligation
+    pub fn obligation(&self, pubkey: &Pubkey) -> Result<Obligation> {
+        self.unpack(pubkey, &self.lending_program_id)
+    }
+
+    /// Fetch every reserve of a lending market
+    pub fn reserves(&self, lending_market: &Pubkey) -> Result<Vec<(Pubkey, Reserve)>> {
+        Ok(self
+            .unpack_all::<Reserve>(&self.lending_program_id)?
+            .into_iter()
+            .filter(|(_, reserve)| &reserve.lending_market == lending_market)
+            .collect())
+    }
+
+    /// Fetch every obligation of the lending program
+    pub fn obligations(&self) -> Result<Vec<(Pubkey, Obligation)>> {
+        self.unpack_all(&self.lending_program_id)
+    }
+
+    /// Authority of a lending market's token accounts
+    pub fn lending_market_authority(&self, lending_market: &Pubkey) -> Result<Pubkey> {
+        let market = self.lending_market(lending_market)?;
+        Ok(pda::lending_market_authority(
+            lending_market,
+            market.bump_seed,
+            &self.lending_program_id,
+        )?)
+    }
+
+    /// Fetch the margin program state
+    pub fn margin_state(&self) -> Result<State> {
+        let pubkey = pda::margin_state(&self.margin_program_id);
+        self.deserialize(&pubkey, &self.margin_program_id)
+    }
+
+    /// Fetch a margin account
+    pub fn margin_account(&self, pubkey: &Pubkey) -> Result<MarginAccount> {
+        self.deserialize(pubkey, &self.margin_program_id)
+    }
+
+    /// Fetch every margin account of a trader
+    pub fn margin_accounts(&self, trader: &Pubkey) -> Result<Vec<(Pubkey, MarginAccount)>> {
+        let mut margin_accounts = Vec::new();
+        for (pubkey, account) in self.rpc.get_program_accounts(&self.margin_program_id)? {
+            // Other account types fail the discriminator check
+            if let Ok(margin_account) = MarginAccount::try_deserialize(&mut account.data.as_slice())
+            {
+                if &margin_account.trader == trader {
+                    margin_accounts.push((pubkey, margin_account));
+                }
+            }
+        }
+        Ok(margin_accounts)
+    }
+
+    /// Owner of a margin account's vaults
+    pub fn margin_vault_signer(&self, margin_account: &Pubkey) -> Result<Pubkey> {
+        let account = self.margin_account(margin_account)?;
+        Ok(pda::margin_vault_signer(
+            margin_account,
+            account.nonce,
+            &self.margin_program_id,
+        )?)
+    }
+
+    /// Fetch the whitelisted pair of two mints, given in either order
+    pub fn token_pair(&self, first_token: &Pubkey, second_token: &Pubkey) -> Result<TokenPair> {
+        let (pubkey, _) = pda::find_token_pair(first_token, second_token, &self.margin_program_id);
+        self.deserialize(&pubkey, &self.margin_program_id)
+    }
+
+    /// Fetch an account, failing with `AccountNotFound` if it doesn't exist
+    fn account(&self, pubkey: &Pubkey) -> Result<Account> {
+        self.rpc
+            .get_account_with_commitment(pubkey, self.rpc.commitment())?
+            .value
+            .ok_or(SdkError::AccountNotFound(*pubkey))
+    }
+
+    fn unpack<T: Pack + IsInitialized>(&self, pubkey: &Pubkey, owner: &Pubkey) -> Result<T> {
+        let account = self.account(pubkey)?;
+        if &account.owner != owner {
+            return Err(SdkError::InvalidOwner(*pubkey, *owner));
+        }
+        Ok(T::unpack(&account.data)?)
+    }
+
+    fn unpack_all<T: Pack + IsInitialized>(&self, owner: &Pubkey) -> Result<Vec<(Pubkey, T)>> {
+        Ok(self
+            .rpc
+            .get_program_accounts(owner)?
+            .into_iter()
+            .filter(|(_, account)| account.data.len() == T::LEN)
+            .filter_map(|(pubkey, account)| Some((pubkey, T::unpack(&account.data).ok()?)))
+            .collect())
+    }
+
+    fn deserialize<T: AccountDeserialize>(&self, pubkey: &Pubkey, owner: &Pubkey) -> Result<T> {
+        let account = self.account(pubkey)?;
+        if &account.owner != owner {
+            return Err(SdkError::InvalidOwner(*pubkey, *owner));
+        }
+        Ok(T::try_deserialize(&mut account.data.as_slice())?)
+    }
+}
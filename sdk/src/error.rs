@@ -0,0 +1,34 @@
+//! Error types
+
+use solana_client::client_error::ClientError;
+use solana_program::{program_error::ProgramError, pubkey::Pubkey, pubkey::PubkeyError};
+use thiserror::Error;
+
+/// Errors that may be returned by the SDK
+#[derive(Error, Debug)]
+pub enum SdkError {
+    /// RPC request failed
+    #[error("RPC request failed: {0}")]
+    Client(#[from] ClientError),
+    /// Account data could not be decoded
+    #[error("Failed to decode account: {0}")]
+    Decode(#[from] ProgramError),
+    /// Program address could not be derived from the given seeds
+    #[error("Invalid program address seeds: {0}")]
+    Pubkey(#[from] PubkeyError),
+    /// Account does not exist
+    #[error("Account {0} not found")]
+    AccountNotFound(Pubkey),
+    /// Account is not owned by the expected program
+    #[error("Account {0} is not owned by program {1}")]
+    InvalidOwner(Pubkey, Pubkey),
+    /// Market snapshot has no liquidity to price a position against
+    #[error("Market has no liquidity")]
+    EmptyMarket,
+    /// Data is not a base58 or base64 encoded transaction, or the transaction is malformed
+    #[error("Invalid transaction")]
+    InvalidTransaction,
+}
+
+/// Result type of the SDK
+pub type Result<T> = std::result::Result<T, SdkError>;
@@ -0,0 +1,1061 @@
+//! Human readable decoding of transactions sent to the lending and margin programs, to debug
+//! failed transactions.
+//!
+//! Accounts are labelled with the roles documented on `LendingInstruction` and the fields of the
+//! margin program's `Accounts` structs, and custom error codes are mapped back to the messages of
+//! `LendingError` and the margin `ErrorCode`.
+
+use crate::error::{Result, SdkError};
+use anchor_lang::{AnchorDeserialize, InstructionData};
+use margin_account::{instruction, Direction, ErrorCode, FeeConfig, RiskParams};
+use num_traits::FromPrimitive;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+use solana_sdk::{sanitize::Sanitize, transaction::Transaction};
+use spl_token_lending::{
+    error::LendingError,
+    instruction::{describe_instruction, LendingInstruction},
+};
+use std::fmt;
+
+const ANCHOR_DISCRIMINATOR_LEN: usize = 8;
+
+/// Instruction decoded by an [Explainer](struct.Explainer.html)
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExplainedInstruction {
+    pub program_id: Pubkey,
+    /// Name of the program, `None` for programs other than lending and margin
+    pub program: Option<&'static str>,
+    /// Instruction and its arguments, or why the data could not be decoded
+    pub description: String,
+    pub accounts: Vec<ExplainedAccount>,
+}
+
+/// Account of an instruction with its role
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExplainedAccount {
+    pub pubkey: Pubkey,
+    /// Role of the account, `None` for accounts beyond the documented ones
+    pub role: Option<String>,
+    pub is_signer: bool,
+    pub is_writable: bool,
+}
+
+impl fmt::Display for ExplainedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.program {
+            Some(program) => writeln!(f, "{} ({})", self.description, program)?,
+            None => writeln!(f, "{} (program {})", self.description, self.program_id)?,
+        }
+        for (index, account) in self.accounts.iter().enumerate() {
+            let flags = match (account.is_signer, account.is_writable) {
+                (true, true) => " [signer, writable]",
+                (true, false) => " [signer]",
+                (false, true) => " [writable]",
+                (false, false) => "",
+            };
+            writeln!(
+                f,
+                "  {:>2}. {:<44} {}{}",
+                index,
+                account.role.as_deref().unwrap_or("?"),
+                account.pubkey,
+                flags
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes instructions and errors of the lending and margin programs deployed at given ids
+pub struct Explainer {
+    lending_program_id: Pubkey,
+    margin_program_id: Option<Pubkey>,
+}
+
+impl Explainer {
+    /// Create an explainer. Without a margin program id, margin instructions are left undecoded.
+    pub fn new(lending_program_id: Pubkey, margin_program_id: Option<Pubkey>) -> Self {
+        Self {
+            lending_program_id,
+            margin_program_id,
+        }
+    }
+
+    /// Decode every instruction of a transaction. Fails on transactions whose instructions point
+    /// at accounts the message does not have.
+    pub fn explain_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Vec<ExplainedInstruction>> {
+        transaction
+            .sanitize()
+            .map_err(|_| SdkError::InvalidTransaction)?;
+        let message = &transaction.message;
+        Ok(message
+            .instructions
+            .iter()
+            .map(|compiled| {
+                let accounts = compiled
+                    .accounts
+                    .iter()
+                    .map(|&index| {
+                        let index = index as usize;
+                        AccountMeta {
+                            pubkey: message.account_keys[index],
+                            is_signer: message.is_signer(index),
+                            is_writable: message.is_writable(index),
+                        }
+                    })
+                    .collect();
+                self.explain_instruction(&Instruction {
+                    program_id: message.account_keys[compiled.program_id_index as usize],
+                    accounts,
+                    data: compiled.data.clone(),
+                })
+            })
+            .collect())
+    }
+
+    /// Decode an instruction
+    pub fn explain_instruction(&self, instruction: &Instruction) -> ExplainedInstruction {
+        let (program, description, roles) = if instruction.program_id == self.lending_program_id {
+            let roles = LendingInstruction::unpack(&instruction.data)
+                .map(|lending_instruction| lending_account_roles(&lending_instruction))
+                .unwrap_or_default();
+            (
+                Some("lending"),
+                describe_instruction(&instruction.data),
+                roles,
+            )
+        } else if Some(instruction.program_id) == self.margin_program_id {
+            let (description, roles) = describe_margin_instruction(&instruction.data);
+            (Some("margin"), description, roles)
+        } else {
+            (
+                None,
+                format!("Unknown instruction ({} bytes)", instruction.data.len()),
+                vec![],
+            )
+        };
+
+        let accounts = instruction
+            .accounts
+            .iter()
+            .enumerate()
+            .map(|(index, meta)| ExplainedAccount {
+                pubkey: meta.pubkey,
+                role: roles.get(index).cloned(),
+                is_signer: meta.is_signer,
+                is_writable: meta.is_writable,
+            })
+            .collect();
+
+        ExplainedInstruction {
+            program_id: instruction.program_id,
+            program,
+            description,
+            accounts,
+        }
+    }
+
+    /// Message of a custom error `code` returned by `program_id`
+    pub fn explain_error(&self, program_id: &Pubkey, code: u32) -> Option<String> {
+        if *program_id == self.lending_program_id {
+            lending_error_message(code)
+        } else if Some(*program_id) == self.margin_program_id {
+            margin_error_message(code)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decode a bincode serialized transaction, encoded in base58 or base64. Malformed transactions,
+/// such as ones referencing accounts out of range, are rejected.
+pub fn decode_transaction(encoded: &str) -> Result<Transaction> {
+    let encoded = encoded.trim();
+    let candidates = vec![
+        bs58::decode(encoded).into_vec().ok(),
+        base64::decode(encoded).ok(),
+    ];
+    candidates
+        .into_iter()
+        .flatten()
+        .find_map(|bytes| bincode::deserialize::<Transaction>(&bytes).ok())
+        .filter(|transaction| transaction.sanitize().is_ok())
+        .ok_or(SdkError::InvalidTransaction)
+}
+
+/// Message of a custom error code of the lending program
+pub fn lending_error_message(code: u32) -> Option<String> {
+    LendingError::from_u32(code).map(|error| error.to_string())
+}
+
+/// Message of a custom error code of the margin program
+pub fn margin_error_message(code: u32) -> Option<String> {
+    margin_errors().into_iter().find_map(|error| {
+        let message = error.to_string();
+        match ProgramError::from(error) {
+            ProgramError::Custom(error_code) if error_code == code => Some(message),
+            _ => None,
+        }
+    })
+}
+
+/// Every margin program error, in declaration order
+fn margin_errors() -> Vec<ErrorCode> {
+    vec![
+        ErrorCode::InvalidProgramAddress,
+        ErrorCode::InvalidVaultOwner,
+        ErrorCode::InvalidAmount,
+        ErrorCode::AccountInUse,
+        ErrorCode::WithdrawDisabled,
+        ErrorCode::StateUnauthorized,
+        ErrorCode::InvalidTokenPair,
+        ErrorCode::TokenPairAlreadyExists,
+        ErrorCode::NoOpenPosition,
+        ErrorCode::InvalidPositionVault,
+        ErrorCode::InsufficientProceeds,
+        ErrorCode::InvalidReserve,
+        ErrorCode::InvalidDirection,
+        ErrorCode::InvalidRiskParams,
+        ErrorCode::TokenPairDisabled,
+        ErrorCode::OpenInterestExceeded,
+        ErrorCode::LeverageExceeded,
+        ErrorCode::TokenPairInUse,
+        ErrorCode::InvalidFeeConfig,
+        ErrorCode::InvalidFeeVault,
+        ErrorCode::InvalidObligation,
+        ErrorCode::InvalidPriceAccumulator,
+        ErrorCode::PositionHealthy,
+        ErrorCode::InvalidCoverageAuthority,
+        ErrorCode::NoBadDebt,
+        ErrorCode::InvalidSwapPool,
+        ErrorCode::TwapRequired,
+    ]
+}
+
+/// Roles of the accounts of a lending instruction, as documented on `LendingInstruction`
+fn lending_account_roles(instruction: &LendingInstruction) -> Vec<String> {
+    let roles: &[&str] = match instruction {
+        LendingInstruction::InitLendingMarket { .. } => &[
+            "lending_market",
+            "quote_token_mint",
+            "rent",
+            "token_program",
+        ],
+        LendingInstruction::InitReserve { .. } => &[
+            "source_liquidity",
+            "destination_collateral",
+            "reserve",
+            "reserve_liquidity_mint",
+            "reserve_liquidity_supply",
+            "reserve_collateral_mint",
+            "reserve_collateral_supply",
+            "reserve_collateral_fees_receiver",
+            "lending_market",
+            "lending_market_owner",
+            "lending_market_authority",
+            "user_transfer_authority",
+            "clock",
+            "rent",
+            "token_program",
+            "dex_market",
+        ],
+        LendingInstruction::InitObligation => &[
+            "deposit_reserve",
+            "borrow_reserve",
+            "obligation",
+            "obligation_token_mint",
+            "obligation_token_output",
+            "obligation_token_owner",
+            "lending_market",
+            "lending_market_authority",
+            "clock",
+            "rent",
+            "token_program",
+        ],
+        LendingInstruction::DepositReserveLiquidity { .. } => &[
+            "source_liquidity",
+            "destination_collateral",
+            "reserve",
+            "reserve_liquidity_supply",
+            "reserve_collateral_mint",
+            "lending_market",
+            "lending_market_authority",
+            "user_transfer_authority",
+            "clock",
+            "token_program",
+        ],
+        LendingInstruction::WithdrawReserveLiquidity { .. } => &[
+            "source_collateral",
+            "destination_liquidity",
+            "reserve",
+            "reserve_collateral_mint",
+            "reserve_liquidity_supply",
+            "lending_market",
+            "lending_market_authority",
+            "user_transfer_authority",
+            "token_program",
+        ],
+        LendingInstruction::BorrowReserveLiquidity { .. }
+        | LendingInstruction::MarginBorrowReserveLiquidity { .. } => &[
+            "source_collateral",
+            "destination_liquidity",
+            "deposit_reserve",
+            "deposit_reserve_collateral_supply",
+            "deposit_reserve_collateral_fees_receiver",
+            "borrow_reserve",
+            "borrow_reserve_liquidity_supply",
+            "obligation",
+            "obligation_token_mint",
+            "obligation_token_output",
+            "lending_market",
+            "lending_market_authority",
+            "user_transfer_authority",
+            "dex_market",
+            "dex_market_bids",
+            "dex_market_asks",
+            "clock",
+            "token_program",
+            "deposit_reserve_collateral_host_fee_receiver",
+        ],
+        LendingInstruction::RepayReserveLiquidity { .. } => &[
+            "source_liquidity",
+            "destination_collateral",
+            "repay_reserve",
+            "repay_reserve_liquidity_supply",
+            "withdraw_reserve",
+            "withdraw_reserve_collateral_supply",
+            "obligation",
+            "obligation_token_mint",
+            "obligation_token_input",
+            "lending_market",
+            "lending_market_authority",
+            "user_transfer_authority",
+            "clock",
+            "token_program",
+        ],
+        LendingInstruction::LiquidateObligation { .. } => &[
+            "source_liquidity",
+            "destination_collateral",
+            "repay_reserve",
+            "repay_reserve_liquidity_supply",
+            "withdraw_reserve",
+            "withdraw_reserve_collateral_supply",
+            "obligation",
+            "lending_market",
+            "lending_market_authority",
+            "user_transfer_authority",
+            "dex_market",
+            "dex_market_bids",
+            "dex_market_asks",
+            "clock",
+            "token_program",
+            "price_accumulator",
+        ],
+        LendingInstruction::AccrueReserveInterest { rate_histories } => {
+            let mut roles = vec!["clock".to_string()];
+            for &has_rate_history in rate_histories {
+                roles.push("reserve".to_string());
+                if has_rate_history {
+                    roles.push("rate_history".to_string());
+                }
+            }
+            return roles;
+        }
+        LendingInstruction::SetReserveConfig { .. } => {
+            &["reserve", "lending_market", "lending_market_owner", "clock"]
+        }
+        LendingInstruction::InitPriceAccumulator { .. } => &[
+            "price_accumulator",
+            "reserve",
+            "lending_market",
+            "lending_market_owner",
+            "rent",
+            "clock",
+            "market",
+            "bids_or_token_a",
+            "asks_or_token_b",
+        ],
+        LendingInstruction::UpdatePriceAccumulator => &[
+            "price_accumulator",
+            "clock",
+            "market",
+            "bids_or_token_a",
+            "asks_or_token_b",
+        ],
+        LendingInstruction::InitRateHistory { .. } => &[
+            "rate_history",
+            "reserve",
+            "lending_market",
+            "lending_market_owner",
+            "rent",
+        ],
+        LendingInstruction::WithdrawReserveFees => &[
+            "reserve",
+            "reserve_liquidity_supply",
+            "destination_liquidity",
+            "lending_market",
+            "lending_market_authority",
+            "lending_market_owner",
+            "token_program",
+        ],
+    };
+    roles.iter().map(|role| role.to_string()).collect()
+}
+
+/// Margin instruction with the roles of its accounts, the fields of its `Accounts` struct.
+/// Methods of the program `State` take the state account first.
+struct MarginInstruction {
+    discriminator: Vec<u8>,
+    name: &'static str,
+    accounts: &'static [&'static str],
+    /// Deserializes and describes the arguments
+    describe: fn(&mut &[u8]) -> Option<String>,
+}
+
+impl MarginInstruction {
+    fn new(
+        template: impl InstructionData,
+        name: &'static str,
+        accounts: &'static [&'static str],
+        describe: fn(&mut &[u8]) -> Option<String>,
+    ) -> Self {
+        Self {
+            discriminator: template.data()[..ANCHOR_DISCRIMINATOR_LEN].to_vec(),
+            name,
+            accounts,
+            describe,
+        }
+    }
+}
+
+fn margin_instructions() -> Vec<MarginInstruction> {
+    let pubkey = Pubkey::default();
+    vec![
+        MarginInstruction::new(
+            instruction::state::New {},
+            "New",
+            &[
+                "payer",
+                "state",
+                "base",
+                "system_program",
+                "program",
+                "authority",
+            ],
+            |_| Some(String::new()),
+        ),
+        MarginInstruction::new(
+            instruction::state::AddTokenPair {
+                first_token: pubkey,
+                second_token: pubkey,
+                risk_params: RiskParams::default(),
+                nonce: 0,
+            },
+            "AddTokenPair",
+            &[
+                "state",
+                "authority",
+                "token_pair",
+                "swap_pool",
+                "rent",
+                "system_program",
+            ],
+            |args| {
+                let i = instruction::state::AddTokenPair::deserialize(args).ok()?;
+                Some(format!(
+                    "first_token: {}, second_token: {}, risk_params: {}, nonce: {}",
+                    i.first_token,
+                    i.second_token,
+                    describe_risk_params(&i.risk_params),
+                    i.nonce
+                ))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::state::RemoveTokenPair {},
+            "RemoveTokenPair",
+            &["state", "authority", "token_pair"],
+            |_| Some(String::new()),
+        ),
+        MarginInstruction::new(
+            instruction::state::SetRiskParams {
+                risk_params: RiskParams::default(),
+            },
+            "SetRiskParams",
+            &["state", "authority", "token_pair"],
+            |args| {
+                let i = instruction::state::SetRiskParams::deserialize(args).ok()?;
+                Some(format!(
+                    "risk_params: {}",
+                    describe_risk_params(&i.risk_params)
+                ))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::state::SetPairEnabled { enabled: false },
+            "SetPairEnabled",
+            &["state", "authority", "token_pair"],
+            |args| {
+                let i = instruction::state::SetPairEnabled::deserialize(args).ok()?;
+                Some(format!("enabled: {}", i.enabled))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::state::SetFees {
+                fees: FeeConfig::default(),
+            },
+            "SetFees",
+            &["state", "authority"],
+            |args| {
+                let i = instruction::state::SetFees::deserialize(args).ok()?;
+                Some(format!("fees: {}", describe_fees(&i.fees)))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::state::SetAuthority {
+                new_authority: pubkey,
+            },
+            "SetAuthority",
+            &["state", "authority"],
+            |args| {
+                let i = instruction::state::SetAuthority::deserialize(args).ok()?;
+                Some(format!("new_authority: {}", i.new_authority))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::Initialize {
+                trader: pubkey,
+                nonce: 0,
+            },
+            "Initialize",
+            &["margin_account", "rent"],
+            |args| {
+                let i = instruction::Initialize::deserialize(args).ok()?;
+                Some(format!("trader: {}, nonce: {}", i.trader, i.nonce))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::InitObligation {},
+            "InitObligation",
+            &[
+                "lending_program",
+                "deposit_reserve",
+                "borrow_reserve",
+                "obligation",
+                "obligation_token_mint",
+                "obligation_token_output",
+                "obligation_token_owner",
+                "lending_market",
+                "lending_market_authority",
+                "clock",
+                "rent",
+                "token_program",
+            ],
+            |_| Some(String::new()),
+        ),
+        MarginInstruction::new(
+            instruction::TradeAmm {
+                amount_in: 0,
+                minimum_amount_out: 0,
+            },
+            "TradeAmm",
+            &[
+                "trader",
+                "swap_program",
+                "swap_info",
+                "swap_authority",
+                "source",
+                "swap_source",
+                "swap_dest",
+                "pool_mint",
+                "pool_fee",
+                "host_fee",
+                "margin_account",
+                "token_pair",
+                "source_vault",
+                "destination_vault",
+                "vault_signer",
+                "token_program",
+            ],
+            |args| {
+                let i = instruction::TradeAmm::deserialize(args).ok()?;
+                Some(format!(
+                    "amount_in: {}, minimum_amount_out: {}",
+                    i.amount_in, i.minimum_amount_out
+                ))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::Repay { amount: 0 },
+            "Repay",
+            &[
+                "lending_program",
+                "source_liquidity_acc",
+                "destination_coll_account",
+                "repay_reserve_account",
+                "repay_reserve_spl_acccount",
+                "withdraw_reserve",
+                "withdraw_reserve_collateral",
+                "obligation",
+                "obligation_mint",
+                "obligation_input",
+                "lending_market",
+                "derived_lending_authority",
+                "loan_vault",
+                "margin_account",
+                "token_pair",
+                "vault_signer",
+                "state",
+                "fee_vaults.stakers",
+                "fee_vaults.insurance",
+                "fee_vaults.community",
+                "token_program",
+                "clock",
+            ],
+            |args| {
+                let i = instruction::Repay::deserialize(args).ok()?;
+                Some(format!("amount: {}", i.amount))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::ReducePosition {
+                amount_in: 0,
+                minimum_amount_out: 0,
+            },
+            "ReducePosition",
+            &[
+                "trader",
+                "swap_program",
+                "swap_info",
+                "swap_authority",
+                "swap_source",
+                "swap_dest",
+                "pool_mint",
+                "pool_fee",
+                "host_fee",
+                "lending_program",
+                "destination_coll_account",
+                "repay_reserve_account",
+                "repay_reserve_spl_acccount",
+                "withdraw_reserve",
+                "withdraw_reserve_collateral",
+                "obligation",
+                "obligation_mint",
+                "obligation_input",
+                "lending_market",
+                "derived_lending_authority",
+                "margin_account",
+                "token_pair",
+                "collateral_vault",
+                "loaned_vault",
+                "vault_signer",
+                "state",
+                "fee_vaults.stakers",
+                "fee_vaults.insurance",
+                "fee_vaults.community",
+                "token_program",
+                "clock",
+                "price_accumulator",
+            ],
+            |args| {
+                let i = instruction::ReducePosition::deserialize(args).ok()?;
+                Some(format!(
+                    "amount_in: {}, minimum_amount_out: {}",
+                    i.amount_in, i.minimum_amount_out
+                ))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::Liquidate {
+                minimum_amount_out: 0,
+            },
+            "Liquidate",
+            &[
+                "liquidator",
+                "liquidator_account",
+                "swap_program",
+                "swap_info",
+                "swap_authority",
+                "swap_source",
+                "swap_dest",
+                "pool_mint",
+                "pool_fee",
+                "host_fee",
+                "lending_program",
+                "destination_coll_account",
+                "repay_reserve_account",
+                "repay_reserve_spl_acccount",
+                "withdraw_reserve",
+                "withdraw_reserve_collateral",
+                "obligation",
+                "obligation_mint",
+                "obligation_input",
+                "lending_market",
+                "derived_lending_authority",
+                "margin_account",
+                "token_pair",
+                "collateral_vault",
+                "loaned_vault",
+                "vault_signer",
+                "state",
+                "fee_vaults.stakers",
+                "fee_vaults.insurance",
+                "fee_vaults.community",
+                "token_program",
+                "clock",
+                "price_accumulator",
+            ],
+            |args| {
+                let i = instruction::Liquidate::deserialize(args).ok()?;
+                Some(format!("minimum_amount_out: {}", i.minimum_amount_out))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::CoverBadDebt {},
+            "CoverBadDebt",
+            &[
+                "margin_account",
+                "token_pair",
+                "loaned_vault",
+                "vault_signer",
+                "lending_program",
+                "destination_coll_account",
+                "repay_reserve_account",
+                "repay_reserve_spl_acccount",
+                "withdraw_reserve",
+                "withdraw_reserve_collateral",
+                "obligation",
+                "obligation_mint",
+                "obligation_input",
+                "lending_market",
+                "derived_lending_authority",
+                "insurance_coverage.treasury_program",
+                "insurance_coverage.treasury",
+                "insurance_coverage.treasury_vault",
+                "insurance_coverage.insurance",
+                "insurance_coverage.treasury_signer",
+                "insurance_coverage.coverage_authority",
+                "token_program",
+                "clock",
+            ],
+            |_| Some(String::new()),
+        ),
+        MarginInstruction::new(
+            instruction::Borrow {
+                loan_amount: 0,
+                collateral_amount: 0,
+                direction: Direction::Long,
+            },
+            "Borrow",
+            &[
+                "token_pair",
+                "lending_program",
+                "source_collateral",
+                "deposit_reserve",
+                "deposit_reserve_collateral_supply",
+                "deposit_reserve_collateral_fees_receiver",
+                "borrow_reserve",
+                "borrow_reserve_liquidity_supply",
+                "lending_market",
+                "lending_market_authority",
+                "obligation",
+                "obligation_token_mint",
+                "obligation_token_output",
+                "dex_market",
+                "dex_market_bids",
+                "dex_market_asks",
+                "vault_signer",
+                "loaned_vault",
+                "margin_account",
+                "state",
+                "fee_vaults.stakers",
+                "fee_vaults.insurance",
+                "fee_vaults.community",
+                "token_program",
+            ],
+            |args| {
+                let i = instruction::Borrow::deserialize(args).ok()?;
+                let direction = match i.direction {
+                    Direction::Long => "Long",
+                    Direction::Short => "Short",
+                };
+                Some(format!(
+                    "loan_amount: {}, collateral_amount: {}, direction: {}",
+                    i.loan_amount, i.collateral_amount, direction
+                ))
+            },
+        ),
+        MarginInstruction::new(
+            instruction::Withdraw { amount: 0 },
+            "Withdraw",
+            &[
+                "authority",
+                "user_token_account",
+                "margin_account",
+                "vault",
+                "vault_signer",
+                "token_program",
+            ],
+            |args| {
+                let i = instruction::Withdraw::deserialize(args).ok()?;
+                Some(format!("amount: {}", i.amount))
+            },
+        ),
+    ]
+}
+
+/// Decode margin instruction data into a description and the roles of its accounts
+fn describe_margin_instruction(data: &[u8]) -> (String, Vec<String>) {
+    if data.len() < ANCHOR_DISCRIMINATOR_LEN {
+        return ("Malformed margin instruction data".to_string(), vec![]);
+    }
+    let (discriminator, args) = data.split_at(ANCHOR_DISCRIMINATOR_LEN);
+    let instruction = match margin_instructions()
+        .into_iter()
+        .find(|instruction| instruction.discriminator == discriminator)
+    {
+        Some(instruction) => instruction,
+        None => {
+            return (
+                format!("Unknown margin instruction {:02x?}", discriminator),
+                vec![],
+            )
+        }
+    };
+
+    let roles = instruction
+        .accounts
+        .iter()
+        .map(|role| role.to_string())
+        .collect();
+    let mut rest = args;
+    let description = match (instruction.describe)(&mut rest) {
+        Some(description) if rest.is_empty() => {
+            if description.is_empty() {
+                instruction.name.to_string()
+            } else {
+                format!("{} {{ {} }}", instruction.name, description)
+            }
+        }
+        _ => format!(
+            "{} with malformed data ({} bytes): {:02x?}",
+            instruction.name,
+            args.len(),
+            args
+        ),
+    };
+    (description, roles)
+}
+
+fn describe_risk_params(risk_params: &RiskParams) -> String {
+    format!(
+        "RiskParams {{ max_leverage: {}, maintenance_margin: {}, liquidation_fee: {}, \
+         max_slippage: {}, max_open_interest: {} }}",
+        risk_params.max_leverage,
+        risk_params.maintenance_margin,
+        risk_params.liquidation_fee,
+        risk_params.max_slippage,
+        risk_params.max_open_interest
+    )
+}
+
+fn describe_fees(fees: &FeeConfig) -> String {
+    format!(
+        "FeeConfig {{ open_fee_bps: {}, close_fee_bps: {}, stakers_share: {}, \
+         insurance_share: {}, community_share: {}, stakers_fee_owner: {}, \
+         insurance_fee_owner: {}, community_fee_owner: {} }}",
+        fees.open_fee_bps,
+        fees.close_fee_bps,
+        fees.stakers_share,
+        fees.insurance_share,
+        fees.community_share,
+        fees.stakers_fee_owner,
+        fees.insurance_fee_owner,
+        fees.community_fee_owner
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::margin;
+    use margin_account::accounts;
+    use solana_sdk::{
+        message::Message,
+        signature::{Keypair, Signer},
+    };
+    use spl_token_lending::instruction::accrue_reserve_interest;
+
+    fn explainer() -> (Explainer, Pubkey, Pubkey) {
+        let lending_program_id = Pubkey::new_unique();
+        let margin_program_id = Pubkey::new_unique();
+        (
+            Explainer::new(lending_program_id, Some(margin_program_id)),
+            lending_program_id,
+            margin_program_id,
+        )
+    }
+
+    fn withdraw(margin_program_id: Pubkey, authority: Pubkey) -> Instruction {
+        margin::withdraw(
+            margin_program_id,
+            accounts::Withdraw {
+                authority,
+                user_token_account: Pubkey::new_unique(),
+                margin_account: Pubkey::new_unique(),
+                vault: Pubkey::new_unique(),
+                vault_signer: Pubkey::new_unique(),
+                token_program: Pubkey::new_unique(),
+            },
+            42,
+        )
+    }
+
+    #[test]
+    fn explains_transaction() {
+        let (explainer, lending_program_id, margin_program_id) = explainer();
+        let payer = Keypair::new();
+        let reserves = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let message = Message::new(
+            &[
+                accrue_reserve_interest(lending_program_id, reserves.clone()),
+                withdraw(margin_program_id, payer.pubkey()),
+            ],
+            Some(&payer.pubkey()),
+        );
+        let transaction = Transaction::new_unsigned(message);
+
+        let explained = explainer.explain_transaction(&transaction).unwrap();
+        assert_eq!(explained.len(), 2);
+
+        assert_eq!(explained[0].program, Some("lending"));
+        assert_eq!(
+            explained[0].description,
+            "AccrueReserveInterest { rate_histories: [false, false] }"
+        );
+        let roles: Vec<_> = explained[0]
+            .accounts
+            .iter()
+            .map(|account| account.role.as_deref())
+            .collect();
+        assert_eq!(roles, vec![Some("clock"), Some("reserve"), Some("reserve")]);
+        assert_eq!(explained[0].accounts[1].pubkey, reserves[0]);
+        assert!(explained[0].accounts[1].is_writable);
+
+        assert_eq!(explained[1].program, Some("margin"));
+        assert_eq!(explained[1].description, "Withdraw { amount: 42 }");
+        let authority = &explained[1].accounts[0];
+        assert_eq!(authority.role.as_deref(), Some("authority"));
+        assert_eq!(authority.pubkey, payer.pubkey());
+        assert!(authority.is_signer);
+    }
+
+    #[test]
+    fn explains_malformed_margin_data() {
+        let (explainer, _, margin_program_id) = explainer();
+        let mut instruction = withdraw(margin_program_id, Pubkey::new_unique());
+        instruction.data.pop();
+        let explained = explainer.explain_instruction(&instruction);
+        assert!(explained
+            .description
+            .starts_with("Withdraw with malformed data (7 bytes)"));
+
+        instruction.data = vec![0; 8];
+        let explained = explainer.explain_instruction(&instruction);
+        assert!(explained
+            .description
+            .starts_with("Unknown margin instruction"));
+        assert!(explained
+            .accounts
+            .iter()
+            .all(|account| account.role.is_none()));
+    }
+
+    #[test]
+    fn labels_every_margin_instruction() {
+        let instructions = margin_instructions();
+        for (index, instruction) in instructions.iter().enumerate() {
+            assert!(instructions[index + 1..]
+                .iter()
+                .all(|other| other.discriminator != instruction.discriminator));
+        }
+        let (explainer, _, margin_program_id) = explainer();
+        let explained =
+            explainer.explain_instruction(&withdraw(margin_program_id, Pubkey::default()));
+        assert_eq!(
+            explained.accounts.len(),
+            instructions
+                .iter()
+                .find(|instruction| instruction.name == "Withdraw")
+                .unwrap()
+                .accounts
+                .len()
+        );
+    }
+
+    #[test]
+    fn explains_errors() {
+        let (explainer, lending_program_id, margin_program_id) = explainer();
+        assert_eq!(
+            explainer.explain_error(&lending_program_id, 0).as_deref(),
+            Some("Failed to unpack instruction data")
+        );
+        let code = match ProgramError::from(ErrorCode::InvalidAmount) {
+            ProgramError::Custom(code) => code,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            explainer.explain_error(&margin_program_id, code),
+            Some(ErrorCode::InvalidAmount.to_string())
+        );
+        assert_eq!(explainer.explain_error(&Pubkey::new_unique(), 0), None);
+    }
+
+    #[test]
+    fn decodes_encoded_transactions() {
+        let payer = Keypair::new();
+        let message = Message::new(
+            &[withdraw(Pubkey::new_unique(), payer.pubkey())],
+            Some(&payer.pubkey()),
+        );
+        let transaction = Transaction::new_unsigned(message);
+        let bytes = bincode::serialize(&transaction).unwrap();
+
+        assert_eq!(
+            decode_transaction(&bs58::encode(&bytes).into_string()).unwrap(),
+            transaction
+        );
+        assert_eq!(
+            decode_transaction(&base64::encode(&bytes)).unwrap(),
+            transaction
+        );
+        assert!(decode_transaction("not a transaction").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_transactions() {
+        let (explainer, _, margin_program_id) = explainer();
+        let payer = Keypair::new();
+        let message = Message::new(
+            &[withdraw(margin_program_id, payer.pubkey())],
+            Some(&payer.pubkey()),
+        );
+        let mut transaction = Transaction::new_unsigned(message);
+        transaction.message.instructions[0].accounts[0] = 200;
+
+        assert!(explainer.explain_transaction(&transaction).is_err());
+        let bytes = bincode::serialize(&transaction).unwrap();
+        assert!(decode_transaction(&base64::encode(&bytes)).is_err());
+
+        transaction.message.instructions[0].accounts[0] = 0;
+        transaction.message.instructions[0].program_id_index = 200;
+        assert!(explainer.explain_transaction(&transaction).is_err());
+    }
+}
@@ -0,0 +1,6 @@
+//! Lending program instruction builders
+//!
+//! The lending crate already exposes a builder for each of its instructions, they are
+//! re-exported here so consumers only need this crate.
+
+pub use spl_token_lending::instruction::*;
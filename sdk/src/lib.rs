@@ -0,0 +1,13 @@
+//! Off-chain SDK for the lending and margin programs: typed account loaders, program address
+//! helpers, instruction builders, position analytics and a transaction explainer
+
+pub mod analytics;
+pub mod client;
+pub mod error;
+pub mod explain;
+pub mod lending;
+pub mod margin;
+pub mod pda;
+
+pub use client::Client;
+pub use error::SdkError;
@@ -0,0 +1,269 @@
+//! Margin program instruction builders
+//!
+//! Builders take the anchor generated account structs of `margin_account::accounts`, whose
+//! fields are the instruction's account addresses, and the instruction arguments. Methods of the
+//! program `State` are passed the state account first, which the builders add.
+
+use crate::pda;
+use anchor_lang::{InstructionData, ToAccountMetas};
+use margin_account::{accounts, instruction, Direction, FeeConfig, RiskParams};
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+
+/// Creates the program `State` with `authority` as the whitelist authority
+pub fn new_state(program_id: Pubkey, payer: Pubkey, authority: Pubkey) -> Instruction {
+    let (base, _) = Pubkey::find_program_address(&[], &program_id);
+    let mut account_metas = vec![
+        AccountMeta::new(payer, true),
+        AccountMeta::new(pda::margin_state(&program_id), false),
+        AccountMeta::new_readonly(base, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(program_id, false),
+    ];
+    account_metas.extend(accounts::Auth { authority }.to_account_metas(None));
+    Instruction {
+        program_id,
+        accounts: account_metas,
+        data: instruction::state::New {}.data(),
+    }
+}
+
+/// Whitelists a token pair trading through `swap_pool`. `token_pair` and `nonce` come from
+/// `pda::find_token_pair`.
+pub fn add_token_pair(
+    program_id: Pubkey,
+    authority: Pubkey,
+    first_token: Pubkey,
+    second_token: Pubkey,
+    swap_pool: Pubkey,
+    risk_params: RiskParams,
+) -> Instruction {
+    let (token_pair, nonce) = pda::find_token_pair(&first_token, &second_token, &program_id);
+    state_instruction(
+        program_id,
+        accounts::AddTokenPair {
+            authority,
+            token_pair,
+            swap_pool,
+            rent: sysvar::rent::id(),
+            system_program: system_program::id(),
+        },
+        instruction::state::AddTokenPair {
+            first_token,
+            second_token,
+            risk_params,
+            nonce,
+        },
+    )
+}
+
+/// Removes a token pair from the whitelist
+pub fn remove_token_pair(program_id: Pubkey, authority: Pubkey, token_pair: Pubkey) -> Instruction {
+    state_instruction(
+        program_id,
+        accounts::UpdateTokenPair {
+            authority,
+            token_pair,
+        },
+        instruction::state::RemoveTokenPair {},
+    )
+}
+
+/// Updates the risk parameters of a whitelisted token pair
+pub fn set_risk_params(
+    program_id: Pubkey,
+    authority: Pubkey,
+    token_pair: Pubkey,
+    risk_params: RiskParams,
+) -> Instruction {
+    state_instruction(
+        program_id,
+        accounts::UpdateTokenPair {
+            authority,
+            token_pair,
+        },
+        instruction::state::SetRiskParams { risk_params },
+    )
+}
+
+/// Enables or disables opening positions on a whitelisted token pair
+pub fn set_pair_enabled(
+    program_id: Pubkey,
+    authority: Pubkey,
+    token_pair: Pubkey,
+    enabled: bool,
+) -> Instruction {
+    state_instruction(
+        program_id,
+        accounts::UpdateTokenPair {
+            authority,
+            token_pair,
+        },
+        instruction::state::SetPairEnabled { enabled },
+    )
+}
+
+/// Updates the position fees
+pub fn set_fees(program_id: Pubkey, authority: Pubkey, fees: FeeConfig) -> Instruction {
+    state_instruction(
+        program_id,
+        accounts::Auth { authority },
+        instruction::state::SetFees { fees },
+    )
+}
+
+/// Hands the whitelist over to `new_authority`
+pub fn set_authority(program_id: Pubkey, authority: Pubkey, new_authority: Pubkey) -> Instruction {
+    state_instruction(
+        program_id,
+        accounts::Auth { authority },
+        instruction::state::SetAuthority { new_authority },
+    )
+}
+
+/// Initializes a margin account created in the same transaction. `nonce` comes from
+/// `pda::find_margin_vault_signer`.
+pub fn initialize(
+    program_id: Pubkey,
+    margin_account: Pubkey,
+    trader: Pubkey,
+    nonce: u8,
+) -> Instruction {
+    program_instruction(
+        program_id,
+        accounts::Initialize {
+            margin_account,
+            rent: sysvar::rent::id(),
+        },
+        instruction::Initialize { trader, nonce },
+    )
+}
+
+/// Initializes the lending obligation backing a margin account's positions
+pub fn init_obligation(program_id: Pubkey, accounts: accounts::InitObligation) -> Instruction {
+    program_instruction(program_id, accounts, instruction::InitObligation {})
+}
+
+/// Borrows against deposited collateral to open a position
+pub fn borrow(
+    program_id: Pubkey,
+    accounts: accounts::Borrow,
+    loan_amount: u64,
+    collateral_amount: u64,
+    direction: Direction,
+) -> Instruction {
+    program_instruction(
+        program_id,
+        accounts,
+        instruction::Borrow {
+            loan_amount,
+            collateral_amount,
+            direction,
+        },
+    )
+}
+
+/// Swaps between a margin account's vaults
+pub fn trade_amm(
+    program_id: Pubkey,
+    accounts: accounts::TradeAmm,
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Instruction {
+    program_instruction(
+        program_id,
+        accounts,
+        instruction::TradeAmm {
+            amount_in,
+            minimum_amount_out,
+        },
+    )
+}
+
+/// Repays the loan of an open position
+pub fn repay(program_id: Pubkey, accounts: accounts::Repay, amount: u64) -> Instruction {
+    program_instruction(program_id, accounts, instruction::Repay { amount })
+}
+
+/// Sells part of a position and repays the same share of its loan. `price_accumulator` is as in
+/// `liquidate`.
+pub fn reduce_position(
+    program_id: Pubkey,
+    accounts: accounts::ReducePosition,
+    price_accumulator: Pubkey,
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Instruction {
+    let instruction = program_instruction(
+        program_id,
+        accounts,
+        instruction::ReducePosition {
+            amount_in,
+            minimum_amount_out,
+        },
+    );
+    with_price_accumulator(instruction, price_accumulator)
+}
+
+/// Liquidates a position under its pair's maintenance margin. `price_accumulator` is the lending
+/// program's accumulator of the reserve priced off the market.
+pub fn liquidate(
+    program_id: Pubkey,
+    accounts: accounts::Liquidate,
+    price_accumulator: Pubkey,
+    minimum_amount_out: u64,
+) -> Instruction {
+    let instruction = program_instruction(
+        program_id,
+        accounts,
+        instruction::Liquidate { minimum_amount_out },
+    );
+    with_price_accumulator(instruction, price_accumulator)
+}
+
+/// Repays the bad debt a liquidation left on a position from the treasury's insurance fund
+pub fn cover_bad_debt(program_id: Pubkey, accounts: accounts::CoverBadDebt) -> Instruction {
+    program_instruction(program_id, accounts, instruction::CoverBadDebt {})
+}
+
+/// Withdraws from a margin account's vault
+pub fn withdraw(program_id: Pubkey, accounts: accounts::Withdraw, amount: u64) -> Instruction {
+    program_instruction(program_id, accounts, instruction::Withdraw { amount })
+}
+
+fn program_instruction(
+    program_id: Pubkey,
+    accounts: impl ToAccountMetas,
+    data: impl InstructionData,
+) -> Instruction {
+    Instruction {
+        program_id,
+        accounts: accounts.to_account_metas(None),
+        data: data.data(),
+    }
+}
+
+/// Appends the price accumulator, read from the remaining accounts
+fn with_price_accumulator(mut instruction: Instruction, price_accumulator: Pubkey) -> Instruction {
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(price_accumulator, false));
+    instruction
+}
+
+fn state_instruction(
+    program_id: Pubkey,
+    accounts: impl ToAccountMetas,
+    data: impl InstructionData,
+) -> Instruction {
+    let mut account_metas = vec![AccountMeta::new(pda::margin_state(&program_id), false)];
+    account_metas.extend(accounts.to_account_metas(None));
+    Instruction {
+        program_id,
+        accounts: account_metas,
+        data: data.data(),
+    }
+}
@@ -0,0 +1,141 @@
+//! Program derived addresses of the lending, margin and treasury programs
+
+use margin_account::TokenPair;
+use solana_program::pubkey::{Pubkey, PubkeyError};
+use treasury::TreasuryVault;
+
+/// Seed the program state account is created with, from anchor's `#[state]` constructor
+const MARGIN_STATE_SEED: &str = "unversioned";
+
+/// Authority of a lending market's token accounts, from the market's stored `bump_seed`
+pub fn lending_market_authority(
+    lending_market: &Pubkey,
+    bump_seed: u8,
+    program_id: &Pubkey,
+) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[lending_market.as_ref(), &[bump_seed]], program_id)
+}
+
+/// Authority of a new lending market's token accounts and the bump seed to initialize it with
+pub fn find_lending_market_authority(lending_market: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[lending_market.as_ref()], program_id)
+}
+
+/// Owner of a margin account's vaults, from the account's stored `nonce`
+pub fn margin_vault_signer(
+    margin_account: &Pubkey,
+    nonce: u8,
+    program_id: &Pubkey,
+) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[margin_account.as_ref(), &[nonce]], program_id)
+}
+
+/// Owner of a new margin account's vaults and the nonce to initialize the account with
+pub fn find_margin_vault_signer(margin_account: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[margin_account.as_ref()], program_id)
+}
+
+/// Address of the whitelisted pair of two mints and its nonce. Mints can be given in either
+/// order.
+pub fn find_token_pair(
+    first_token: &Pubkey,
+    second_token: &Pubkey,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    let seeds = TokenPair::signer_seeds(first_token, second_token, &[]);
+    Pubkey::find_program_address(&seeds[..3], program_id)
+}
+
+/// Signer of the margin program allowed to cover bad debt from the treasury's insurance funds
+pub fn margin_coverage_authority(program_id: &Pubkey) -> Pubkey {
+    let (authority, _) =
+        Pubkey::find_program_address(&[treasury::COVERAGE_AUTHORITY_SEED], program_id);
+    authority
+}
+
+/// Owner of a treasury's vaults, from the treasury's stored `nonce`
+pub fn treasury_signer(
+    treasury: &Pubkey,
+    nonce: u8,
+    program_id: &Pubkey,
+) -> Result<Pubkey, PubkeyError> {
+    Pubkey::create_program_address(&[treasury.as_ref(), &[nonce]], program_id)
+}
+
+/// Treasury vault holding `mint`, one per treasury and mint
+pub fn treasury_vault(treasury: &Pubkey, mint: &Pubkey, program_id: &Pubkey) -> Pubkey {
+    let seeds = TreasuryVault::signer_seeds(treasury, mint, &[]);
+    let (vault, _) = Pubkey::find_program_address(&seeds[..2], program_id);
+    vault
+}
+
+/// Token account of `kind` of the treasury vault holding `mint`, one of
+/// `treasury::INTAKE_SEED`, `treasury::INSURANCE_SEED` and `treasury::COMMUNITY_SEED`
+pub fn treasury_token_account(
+    treasury: &Pubkey,
+    mint: &Pubkey,
+    kind: &[u8],
+    program_id: &Pubkey,
+) -> Pubkey {
+    let seeds = TreasuryVault::token_account_seeds(treasury, mint, kind, &[]);
+    let (token_account, _) = Pubkey::find_program_address(&seeds[..3], program_id);
+    token_account
+}
+
+/// Address of the margin program's `State` account
+pub fn margin_state(program_id: &Pubkey) -> Pubkey {
+    let (base, _) = Pubkey::find_program_address(&[], program_id);
+    Pubkey::create_with_seed(&base, MARGIN_STATE_SEED, program_id)
+        .expect("state seed is a valid seed")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_pair_address_ignores_mint_order() {
+        let program_id = Pubkey::new_unique();
+        let first_token = Pubkey::new_unique();
+        let second_token = Pubkey::new_unique();
+        assert_eq!(
+            find_token_pair(&first_token, &second_token, &program_id),
+            find_token_pair(&second_token, &first_token, &program_id)
+        );
+    }
+
+    #[test]
+    fn derived_authorities_match_stored_seeds() {
+        let program_id = Pubkey::new_unique();
+        let account = Pubkey::new_unique();
+
+        let (authority, bump_seed) = find_lending_market_authority(&account, &program_id);
+        assert_eq!(
+            lending_market_authority(&account, bump_seed, &program_id),
+            Ok(authority)
+        );
+
+        let (signer, nonce) = find_margin_vault_signer(&account, &program_id);
+        assert_eq!(
+            margin_vault_signer(&account, nonce, &program_id),
+            Ok(signer)
+        );
+    }
+    #[test]
+    fn treasury_vault_accounts_are_distinct() {
+        let program_id = Pubkey::new_unique();
+        let treasury = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut addresses = vec![treasury_vault(&treasury, &mint, &program_id)];
+        for kind in &[
+            treasury::INTAKE_SEED,
+            treasury::INSURANCE_SEED,
+            treasury::COMMUNITY_SEED,
+        ] {
+            addresses.push(treasury_token_account(&treasury, &mint, kind, &program_id));
+        }
+        addresses.sort();
+        addresses.dedup();
+        assert_eq!(addresses.len(), 4);
+    }
+}
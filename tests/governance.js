@@ -0,0 +1,211 @@
+const anchor = require("@project-serum/anchor");
+const serumCmn = require("@project-serum/common");
+const TokenInstructions = require("@project-serum/serum").TokenInstructions;
+const assert = require("assert");
+
+describe("governance", () => {
+  const provider = anchor.Provider.local();
+
+  // Configure the client to use the local cluster.
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.Governance;
+  const staking = anchor.workspace.Staking;
+
+  const governance = new anchor.web3.Account();
+  const pool = new anchor.web3.Account();
+  const staker = new anchor.web3.Account();
+  let governanceSigner = null;
+  let nonce = null;
+
+  const config = {
+    quorumVotes: new anchor.BN(100),
+    approvalThreshold: 50,
+    votingPeriod: new anchor.BN(2),
+    timelock: new anchor.BN(0),
+  };
+
+  it("Creates governance", async () => {
+    const [_signer, _nonce] = await anchor.web3.PublicKey.findProgramAddress(
+      [governance.publicKey.toBuffer()],
+      program.programId
+    );
+    governanceSigner = _signer;
+    nonce = _nonce;
+
+    await createStake(governanceSigner, 150);
+
+    await program.rpc.createGovernance(config, nonce, {
+      accounts: {
+        governance: governance.publicKey,
+        stakePool: pool.publicKey,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [governance],
+      instructions: [await program.account.governance.createInstruction(governance)],
+    });
+
+    const account = await program.account.governance(governance.publicKey);
+    assert.ok(account.stakingProgram.equals(staking.programId));
+    assert.ok(account.stakePool.equals(pool.publicKey));
+    assert.ok(account.config.quorumVotes.eq(config.quorumVotes));
+  });
+
+  it("Executes a passed proposal as the governance signer", async () => {
+    // Proposal updating the governance config itself
+    const newConfig = { ...config, approvalThreshold: 66 };
+    const accounts = [
+      { pubkey: governance.publicKey, isWritable: true, isSigner: false },
+      { pubkey: governanceSigner, isWritable: false, isSigner: true },
+      { pubkey: pool.publicKey, isWritable: false, isSigner: false },
+    ];
+    const data = program.coder.instruction.encode("set_config", { config: newConfig });
+
+    const proposal = new anchor.web3.Account();
+    await program.rpc.createProposal({ paramChange: {} }, program.programId, accounts, data, {
+      accounts: {
+        governance: governance.publicKey,
+        proposal: proposal.publicKey,
+        proposer: provider.wallet.publicKey,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [proposal],
+      instructions: [await program.account.proposal.createInstruction(proposal, 1000)],
+    });
+
+    // Votes are weighted by the voter's stake, once per proposal
+    const [voteRecord, voteNonce] = await anchor.web3.PublicKey.findProgramAddress(
+      [
+        Buffer.from("vote-record"),
+        proposal.publicKey.toBuffer(),
+        provider.wallet.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    const voteAccounts = {
+      governance: governance.publicKey,
+      proposal: proposal.publicKey,
+      voteRecord,
+      voter: provider.wallet.publicKey,
+      staker: staker.publicKey,
+      stakePool: pool.publicKey,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      systemProgram: anchor.web3.SystemProgram.programId,
+    };
+    await program.rpc.vote(true, voteNonce, { accounts: voteAccounts });
+    await assert.rejects(program.rpc.vote(true, voteNonce, { accounts: voteAccounts }));
+
+    let proposalAccount = await program.account.proposal(proposal.publicKey);
+    assert.ok(proposalAccount.yesVotes.toNumber() === 150);
+
+    // Wait for voting to end
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+
+    await program.rpc.executeProposal({
+      accounts: {
+        governance: governance.publicKey,
+        proposal: proposal.publicKey,
+        governanceSigner,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      },
+      remainingAccounts: accounts
+        .map((meta) =>
+          meta.pubkey.equals(governanceSigner) ? { ...meta, isSigner: false } : meta
+        )
+        .concat({ pubkey: program.programId, isWritable: false, isSigner: false }),
+    });
+
+    proposalAccount = await program.account.proposal(proposal.publicKey);
+    assert.ok(proposalAccount.executed);
+    const updated = await program.account.governance(governance.publicKey);
+    assert.ok(updated.config.approvalThreshold === 66);
+  });
+
+  // Stakes `amount` in a new pool whose authority is `authority`
+  async function createStake(authority, amount) {
+    const [poolSigner, poolNonce] = await anchor.web3.PublicKey.findProgramAddress(
+      [pool.publicKey.toBuffer()],
+      staking.programId
+    );
+    const [nativeMint, nativeTokens] = await serumCmn.createMintAndVault(
+      provider,
+      new anchor.BN(1000)
+    );
+    const receiptMint = new anchor.web3.Account();
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      anchor.web3.SystemProgram.createAccount({
+        fromPubkey: provider.wallet.publicKey,
+        newAccountPubkey: receiptMint.publicKey,
+        space: 82,
+        lamports: await provider.connection.getMinimumBalanceForRentExemption(82),
+        programId: TokenInstructions.TOKEN_PROGRAM_ID,
+      }),
+      TokenInstructions.initializeMint({
+        mint: receiptMint.publicKey,
+        decimals: 0,
+        mintAuthority: poolSigner,
+        freezeAuthority: poolSigner,
+      }),
+      TokenInstructions.setAuthority({
+        target: nativeMint,
+        currentAuthority: provider.wallet.publicKey,
+        newAuthority: poolSigner,
+        authorityType: 0,
+      })
+    );
+    await provider.send(tx, [receiptMint]);
+    const receiptTokens = await serumCmn.createTokenAccount(
+      provider,
+      receiptMint.publicKey,
+      provider.wallet.publicKey
+    );
+    const stakeVault = await serumCmn.createTokenAccount(provider, nativeMint, poolSigner);
+    const rewardVault = await serumCmn.createTokenAccount(provider, nativeMint, poolSigner);
+
+    // The cooldown outlasts voting, so a stake can't vote twice
+    const poolConfig = { inflationRateBps: 0, cooldown: new anchor.BN(60) };
+    await staking.rpc.createPool(authority, poolConfig, poolNonce, {
+      accounts: {
+        pool: pool.publicKey,
+        nativeMint,
+        receiptMint: receiptMint.publicKey,
+        stakeVault,
+        rewardVault,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [pool],
+      instructions: [await staking.account.stakePool.createInstruction(pool)],
+    });
+    await staking.rpc.createStaker(provider.wallet.publicKey, {
+      accounts: {
+        pool: pool.publicKey,
+        staker: staker.publicKey,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [staker],
+      instructions: [await staking.account.staker.createInstruction(staker, 300)],
+    });
+    await staking.rpc.stake(new anchor.BN(amount), {
+      accounts: {
+        poolAccounts: {
+          pool: pool.publicKey,
+          nativeMint,
+          rewardVault,
+          poolSigner,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        },
+        staker: staker.publicKey,
+        owner: provider.wallet.publicKey,
+        nativeTokens,
+        receiptTokens,
+        stakeVault,
+        receiptMint: receiptMint.publicKey,
+      },
+    });
+  }
+});
@@ -12,6 +12,9 @@ describe("margin-account", () => {
   const lendingProgram = new anchor.web3.PublicKey(
     "TokenLending2222222222222222222222222222222"
   );
+  const swapProgram = new anchor.web3.PublicKey(
+    "SwaPpA9LAaLfeLi3a68M4DjnLqgtticKg6CnyNwgAC8"
+  );
 
   const program = anchor.workspace.MarginAccount;
 
@@ -94,18 +97,99 @@ describe("margin-account", () => {
     };
 
     await program.state.rpc.new({ accounts });
-    await program.state.rpc.clearPairs({ accounts });
 
     let state = await program.state();
     assert.ok(state.authority.equals(provider.wallet.publicKey));
-    assert.ok(state.tokenPairs.length == 0);
+    assert.ok(state.fees.openFeeBps === 0);
+
+    // Split fees between the protocol funds
+    const fees = {
+      openFeeBps: 10,
+      closeFeeBps: 10,
+      stakersShare: 20,
+      insuranceShare: 50,
+      communityShare: 30,
+      stakersFeeOwner: provider.wallet.publicKey,
+      insuranceFeeOwner: provider.wallet.publicKey,
+      communityFeeOwner: provider.wallet.publicKey,
+    };
+    await program.state.rpc.setFees(fees, { accounts });
+    state = await program.state();
+    assert.ok(state.fees.openFeeBps === 10);
+    assert.ok(state.fees.insuranceShare === 50);
 
     // Add token pair used for test
-    await program.state.rpc.addTokenPair(collateralMint, liquidityMint, { accounts });
-    state = await program.state();
-    assert.ok(state.tokenPairs.length === 1);
-    assert.ok(state.tokenPairs[0].firstToken.equals(collateralMint));
-    assert.ok(state.tokenPairs[0].secondToken.equals(liquidityMint));
+    const [tokenPair, tokenPairNonce] = await findTokenPairAddress(
+      collateralMint,
+      liquidityMint,
+      program.programId
+    );
+    const riskParams = {
+      maxLeverage: 3,
+      maintenanceMargin: 10,
+      liquidationFee: 5,
+      maxSlippage: 5,
+      maxOpenInterest: new anchor.BN(1000000),
+    };
+    // Pool the pair's positions trade through
+    const swapPool = await createSwapPool(
+      provider,
+      swapProgram,
+      [collateralMint, collateralVault],
+      [liquidityMint, liquidityVault],
+      new anchor.BN(100000)
+    );
+    // Lamports sent to the pair's address before it is added don't block it
+    const fundTx = new anchor.web3.Transaction();
+    fundTx.add(
+      anchor.web3.SystemProgram.transfer({
+        fromPubkey: provider.wallet.publicKey,
+        toPubkey: tokenPair,
+        lamports: 1000,
+      })
+    );
+    await provider.send(fundTx);
+    await program.state.rpc.addTokenPair(collateralMint, liquidityMint, riskParams, tokenPairNonce, {
+      accounts: {
+        authority: provider.wallet.publicKey,
+        tokenPair,
+        swapPool,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      },
+    });
+    let pair = await program.account.tokenPair(tokenPair);
+    assert.ok(pair.firstToken.equals(collateralMint));
+    assert.ok(pair.secondToken.equals(liquidityMint));
+    assert.ok(pair.swapPool.equals(swapPool));
+    assert.ok(pair.riskParams.maxLeverage === 3);
+    assert.ok(pair.enabled);
+    assert.ok(pair.longOpenInterest.toNumber() === 0);
+    assert.ok(pair.shortOpenInterest.toNumber() === 0);
+
+    // The pair can't be added twice
+    await assert.rejects(
+      program.state.rpc.addTokenPair(collateralMint, liquidityMint, riskParams, tokenPairNonce, {
+        accounts: {
+          authority: provider.wallet.publicKey,
+          tokenPair,
+          swapPool,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+          systemProgram: anchor.web3.SystemProgram.programId,
+        },
+      })
+    );
+
+    // Update risk parameters and disable the pair
+    const pairAccounts = { authority: provider.wallet.publicKey, tokenPair };
+    await program.state.rpc.setRiskParams(
+      { ...riskParams, maxLeverage: 5 },
+      { accounts: pairAccounts }
+    );
+    await program.state.rpc.setPairEnabled(false, { accounts: pairAccounts });
+    pair = await program.account.tokenPair(tokenPair);
+    assert.ok(pair.riskParams.maxLeverage === 5);
+    assert.ok(!pair.enabled);
   });
 
   it("Initializes margin account", async () => {
@@ -255,6 +339,102 @@ describe("margin-account", () => {
   });
 });
 
+// Token pairs are stored at an address derived from a prefix and both mints, sorted so that
+// either order resolves to the same account.
+async function findTokenPairAddress(firstMint, secondMint, programId) {
+  const mints = [firstMint.toBuffer(), secondMint.toBuffer()].sort(Buffer.compare);
+  return await anchor.web3.PublicKey.findProgramAddress(
+    [Buffer.from("token-pair"), ...mints],
+    programId
+  );
+}
+
+// Constant product token swap pool without fees, funded with `amount` of each token from the
+// given vaults.
+async function createSwapPool(provider, swapProgram, [mintA, vaultA], [mintB, vaultB], amount) {
+  const swap = new anchor.web3.Account();
+  const [authority, nonce] = await anchor.web3.PublicKey.findProgramAddress(
+    [swap.publicKey.toBuffer()],
+    swapProgram
+  );
+  const tokenA = await serumCmn.createTokenAccount(provider, mintA, authority);
+  const tokenB = await serumCmn.createTokenAccount(provider, mintB, authority);
+  const poolMint = await serumCmn.createMint(provider, authority);
+  const poolFee = await serumCmn.createTokenAccount(provider, poolMint, provider.wallet.publicKey);
+  const poolTokens = await serumCmn.createTokenAccount(
+    provider,
+    poolMint,
+    provider.wallet.publicKey
+  );
+
+  const dataLayout = BufferLayout.struct([
+    BufferLayout.u8("instruction"),
+    BufferLayout.u8("nonce"),
+    uint64("tradeFeeNumerator"),
+    uint64("tradeFeeDenominator"),
+    uint64("ownerTradeFeeNumerator"),
+    uint64("ownerTradeFeeDenominator"),
+    uint64("ownerWithdrawFeeNumerator"),
+    uint64("ownerWithdrawFeeDenominator"),
+    uint64("hostFeeNumerator"),
+    uint64("hostFeeDenominator"),
+    BufferLayout.u8("curveType"),
+    BufferLayout.blob(32, "curveParameters"),
+  ]);
+  const data = Buffer.alloc(dataLayout.span);
+  const noFee = new anchor.BN(0);
+  dataLayout.encode(
+    {
+      instruction: 0, // Initialize instruction
+      nonce,
+      tradeFeeNumerator: noFee,
+      tradeFeeDenominator: noFee,
+      ownerTradeFeeNumerator: noFee,
+      ownerTradeFeeDenominator: noFee,
+      ownerWithdrawFeeNumerator: noFee,
+      ownerWithdrawFeeDenominator: noFee,
+      hostFeeNumerator: noFee,
+      hostFeeDenominator: noFee,
+      curveType: 0, // Constant product
+      curveParameters: Buffer.alloc(32),
+    },
+    data
+  );
+
+  const tx = new anchor.web3.Transaction();
+  tx.add(
+    TokenInstructions.transfer({
+      source: vaultA,
+      destination: tokenA,
+      amount,
+      owner: provider.wallet.publicKey,
+    }),
+    TokenInstructions.transfer({
+      source: vaultB,
+      destination: tokenB,
+      amount,
+      owner: provider.wallet.publicKey,
+    }),
+    await createSolAccountInstruction(swap, provider, swapProgram, 324, provider.wallet.publicKey),
+    new anchor.web3.TransactionInstruction({
+      keys: [
+        { pubkey: swap.publicKey, isSigner: false, isWritable: true },
+        { pubkey: authority, isSigner: false, isWritable: false },
+        { pubkey: tokenA, isSigner: false, isWritable: false },
+        { pubkey: tokenB, isSigner: false, isWritable: false },
+        { pubkey: poolMint, isSigner: false, isWritable: true },
+        { pubkey: poolFee, isSigner: false, isWritable: false },
+        { pubkey: poolTokens, isSigner: false, isWritable: true },
+        { pubkey: TokenInstructions.TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ],
+      programId: swapProgram,
+      data,
+    })
+  );
+  await provider.send(tx, [swap]);
+  return swap.publicKey;
+}
+
 async function createSolAccountInstruction(account, provider, program, size, from) {
   return anchor.web3.SystemProgram.createAccount({
     fromPubkey: from,
@@ -267,6 +447,9 @@ async function createSolAccountInstruction(account, provider, program, size, fro
   });
 }
 
+// First byte of lending instruction data, `INSTRUCTION_VERSION` in the lending program
+const LENDING_INSTRUCTION_VERSION = 1;
+
 // * ported/modified from lending frontend
 const initReserveInstruction = (
   liquidityAmount,
@@ -287,23 +470,41 @@ const initReserveInstruction = (
 
   lendingProgram,
 ) => {
+  const curvePointLayout = BufferLayout.struct([
+    BufferLayout.u16("utilizationRateBps"),
+    BufferLayout.u16("borrowRateBps"),
+  ]);
   const dataLayout = BufferLayout.struct([
+    BufferLayout.u8("version"),
     BufferLayout.u8("instruction"),
     uint64("liquidityAmount"),
     BufferLayout.u8("optimalUtilizationRate"),
     BufferLayout.u8("loanToValueRatio"),
     BufferLayout.u8("liquidationBonus"),
     BufferLayout.u8("liquidationThreshold"),
-    BufferLayout.u8("minBorrowRate"),
-    BufferLayout.u8("optimalBorrowRate"),
-    BufferLayout.u8("maxBorrowRate"),
     uint64("borrowFeeWad"),
     BufferLayout.u8("hostFeePercentage"),
+    uint64("maxMarginBorrowAmount"),
+    uint64("liquidationTwapSlots"),
+    BufferLayout.u8("borrowRateCurvePoints"),
+    BufferLayout.seq(curvePointLayout, 8, "borrowRateCurve"),
+    uint64("adaptiveRiseGainWad"),
+    uint64("adaptiveFallGainWad"),
+    BufferLayout.u16("adaptiveMinOptimalBorrowRateBps"),
+    BufferLayout.u16("adaptiveMaxOptimalBorrowRateBps"),
+    BufferLayout.u8("reserveFactor"),
+    BufferLayout.u8("priceSource"),
   ]);
+  // 0% borrow rate at no utilization, 2% at 80% and 15% when fully utilized, unused points zeroed
+  const borrowRateCurve = [[0, 0], [8000, 200], [10000, 1500]];
+  while (borrowRateCurve.length < 8) {
+    borrowRateCurve.push([0, 0]);
+  }
 
   const data = Buffer.alloc(dataLayout.span);
   dataLayout.encode(
     {
+      version: LENDING_INSTRUCTION_VERSION,
       instruction: 1, // Init reserve instruction
       // * Params taken from sol reserve config on mainnet
       optimalUtilizationRate: 80,
@@ -311,11 +512,21 @@ const initReserveInstruction = (
       loanToValueRatio: 75,
       liquidationBonus: 10,
       liquidationThreshold: 80,
-      minBorrowRate: 0,
-      optimalBorrowRate: 2,
-      maxBorrowRate: 15,
       borrowFeeWad: new anchor.BN(1_000_000_000_000),
       hostFeePercentage: 20,
+      maxMarginBorrowAmount: new anchor.BN(1_000_000_000),
+      liquidationTwapSlots: new anchor.BN(0),
+      borrowRateCurvePoints: 3,
+      borrowRateCurve: borrowRateCurve.map(([utilizationRateBps, borrowRateBps]) => ({
+        utilizationRateBps,
+        borrowRateBps,
+      })),
+      adaptiveRiseGainWad: new anchor.BN(0),
+      adaptiveFallGainWad: new anchor.BN(0),
+      adaptiveMinOptimalBorrowRateBps: 0,
+      adaptiveMaxOptimalBorrowRateBps: 0,
+      reserveFactor: 0,
+      priceSource: 0, // Dex market, unused by quote token reserves
     },
     data
   );
@@ -354,6 +565,7 @@ const initLendingMarketInstruction = (
   lendingProgram,
 ) => {
   const dataLayout = BufferLayout.struct([
+    BufferLayout.u8("version"),
     BufferLayout.u8("instruction"),
     publicKey("marketOwner"),
   ]);
@@ -361,6 +573,7 @@ const initLendingMarketInstruction = (
   const data = Buffer.alloc(dataLayout.span);
   dataLayout.encode(
     {
+      version: LENDING_INSTRUCTION_VERSION,
       instruction: 0, // Init lending market
       marketOwner: lendingMarketOwner
     },
@@ -0,0 +1,228 @@
+const anchor = require("@project-serum/anchor");
+const serumCmn = require("@project-serum/common");
+const TokenInstructions = require("@project-serum/serum").TokenInstructions;
+const assert = require("assert");
+
+describe("staking", () => {
+  const provider = anchor.Provider.local();
+
+  // Configure the client to use the local cluster.
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.Staking;
+
+  const pool = new anchor.web3.Account();
+  const staker = new anchor.web3.Account();
+  let poolSigner = null;
+  let nativeMint = null;
+  let receiptMint = null;
+  let stakeVault = null;
+  let rewardVault = null;
+  let nativeTokens = null;
+  let receiptTokens = null;
+
+  const config = {
+    inflationRateBps: 500,
+    cooldown: new anchor.BN(0),
+  };
+
+  it("Creates a stake pool", async () => {
+    const [_signer, nonce] = await anchor.web3.PublicKey.findProgramAddress(
+      [pool.publicKey.toBuffer()],
+      program.programId
+    );
+    poolSigner = _signer;
+
+    const [_nativeMint, _nativeTokens] = await serumCmn.createMintAndVault(
+      provider,
+      new anchor.BN(1000000)
+    );
+    nativeMint = _nativeMint;
+    nativeTokens = _nativeTokens;
+    receiptMint = await createReceiptMint(poolSigner);
+    receiptTokens = await serumCmn.createTokenAccount(
+      provider,
+      receiptMint,
+      provider.wallet.publicKey
+    );
+    stakeVault = await serumCmn.createTokenAccount(provider, nativeMint, poolSigner);
+    rewardVault = await serumCmn.createTokenAccount(provider, nativeMint, poolSigner);
+
+    // Inflation is minted by the pool
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      TokenInstructions.setAuthority({
+        target: nativeMint,
+        currentAuthority: provider.wallet.publicKey,
+        newAuthority: poolSigner,
+        authorityType: 0,
+      })
+    );
+    await provider.send(tx);
+
+    await program.rpc.createPool(provider.wallet.publicKey, config, nonce, {
+      accounts: {
+        pool: pool.publicKey,
+        nativeMint,
+        receiptMint,
+        stakeVault,
+        rewardVault,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [pool],
+      instructions: [await program.account.stakePool.createInstruction(pool)],
+    });
+
+    const account = await program.account.stakePool(pool.publicKey);
+    assert.ok(account.nativeMint.equals(nativeMint));
+    assert.ok(account.receiptMint.equals(receiptMint));
+    assert.ok(account.totalStaked.toNumber() === 0);
+  });
+
+  it("Stakes for receipt tokens and earns rewards", async () => {
+    await program.rpc.createStaker(provider.wallet.publicKey, {
+      accounts: {
+        pool: pool.publicKey,
+        staker: staker.publicKey,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [staker],
+      instructions: [await program.account.staker.createInstruction(staker, 300)],
+    });
+
+    const poolAccounts = {
+      pool: pool.publicKey,
+      nativeMint,
+      rewardVault,
+      poolSigner,
+      tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+    };
+    await program.rpc.stake(new anchor.BN(1000), {
+      accounts: {
+        poolAccounts,
+        staker: staker.publicKey,
+        owner: provider.wallet.publicKey,
+        nativeTokens,
+        receiptTokens,
+        stakeVault,
+        receiptMint,
+      },
+    });
+
+    const receipts = await serumCmn.getTokenAccount(provider, receiptTokens);
+    assert.ok(receipts.amount.toNumber() === 1000);
+
+    // Fees deposited are shared by the current stake
+    await program.rpc.depositRewards(new anchor.BN(500), {
+      accounts: {
+        poolAccounts,
+        depositor: provider.wallet.publicKey,
+        source: nativeTokens,
+      },
+    });
+    await program.rpc.claim({
+      accounts: {
+        poolAccounts,
+        staker: staker.publicKey,
+        owner: provider.wallet.publicKey,
+        destination: nativeTokens,
+      },
+    });
+
+    const stakerAccount = await program.account.staker(staker.publicKey);
+    assert.ok(stakerAccount.staked.toNumber() === 1000);
+    assert.ok(stakerAccount.pendingRewards.toNumber() === 0);
+    const tokens = await serumCmn.getTokenAccount(provider, nativeTokens);
+    // Staked tokens are gone, deposited fees and any inflation have been claimed back
+    assert.ok(tokens.amount.toNumber() >= 1000000 - 1000);
+  });
+
+  it("Unstakes after the cooldown", async () => {
+    const poolAccounts = {
+      pool: pool.publicKey,
+      nativeMint,
+      rewardVault,
+      poolSigner,
+      tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+    };
+    for (const amount of [300, 100]) {
+      await program.rpc.startUnstake(new anchor.BN(amount), {
+        accounts: {
+          poolAccounts,
+          staker: staker.publicKey,
+          owner: provider.wallet.publicKey,
+          receiptTokens,
+          receiptMint,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        },
+      });
+    }
+
+    let stakerAccount = await program.account.staker(staker.publicKey);
+    assert.ok(stakerAccount.staked.toNumber() === 600);
+    assert.ok(stakerAccount.pendingUnstakes.length === 2);
+
+    await program.rpc.finishUnstake({
+      accounts: {
+        pool: pool.publicKey,
+        staker: staker.publicKey,
+        owner: provider.wallet.publicKey,
+        vault: stakeVault,
+        destination: nativeTokens,
+        poolSigner,
+        tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      },
+    });
+
+    stakerAccount = await program.account.staker(staker.publicKey);
+    assert.ok(stakerAccount.pendingUnstakes.length === 0);
+    const receipts = await serumCmn.getTokenAccount(provider, receiptTokens);
+    assert.ok(receipts.amount.toNumber() === 600);
+  });
+
+  it("Keeps receipt tokens with the staker", async () => {
+    const other = await serumCmn.createTokenAccount(
+      provider,
+      receiptMint,
+      provider.wallet.publicKey
+    );
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      TokenInstructions.transfer({
+        source: receiptTokens,
+        destination: other,
+        amount: 1,
+        owner: provider.wallet.publicKey,
+      })
+    );
+    await assert.rejects(provider.send(tx));
+  });
+
+  // Receipt token accounts are frozen by the pool, so the pool signer is also the freeze
+  // authority of the receipt mint
+  async function createReceiptMint(authority) {
+    const mint = new anchor.web3.Account();
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      anchor.web3.SystemProgram.createAccount({
+        fromPubkey: provider.wallet.publicKey,
+        newAccountPubkey: mint.publicKey,
+        space: 82,
+        lamports: await provider.connection.getMinimumBalanceForRentExemption(82),
+        programId: TokenInstructions.TOKEN_PROGRAM_ID,
+      }),
+      TokenInstructions.initializeMint({
+        mint: mint.publicKey,
+        decimals: 0,
+        mintAuthority: authority,
+        freezeAuthority: authority,
+      })
+    );
+    await provider.send(tx, [mint]);
+    return mint.publicKey;
+  }
+});
@@ -0,0 +1,407 @@
+const anchor = require("@project-serum/anchor");
+const serumCmn = require("@project-serum/common");
+const TokenInstructions = require("@project-serum/serum").TokenInstructions;
+const assert = require("assert");
+
+describe("treasury", () => {
+  const provider = anchor.Provider.local();
+
+  // Configure the client to use the local cluster.
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace.Treasury;
+  const governanceProgram = anchor.workspace.Governance;
+  const staking = anchor.workspace.Staking;
+
+  const treasury = new anchor.web3.Account();
+  const governance = new anchor.web3.Account();
+  const pool = new anchor.web3.Account();
+  const staker = new anchor.web3.Account();
+  let governanceSigner = null;
+  let vault = null;
+  let treasurySigner = null;
+  let fees = null;
+  let intake = null;
+  let insurance = null;
+  let community = null;
+
+  it("Creates a treasury with vaults for a mint", async () => {
+    await createGovernance();
+    const [_signer, nonce] = await anchor.web3.PublicKey.findProgramAddress(
+      [treasury.publicKey.toBuffer()],
+      program.programId
+    );
+    treasurySigner = _signer;
+
+    await program.rpc.createTreasury(governance.publicKey, governanceProgram.programId, nonce, {
+      accounts: {
+        treasury: treasury.publicKey,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [treasury],
+      instructions: [await program.account.treasury.createInstruction(treasury)],
+    });
+    const treasuryAccount = await program.account.treasury(treasury.publicKey);
+    assert.ok(treasuryAccount.authority.equals(governanceSigner));
+
+    const [mint, _fees] = await serumCmn.createMintAndVault(provider, new anchor.BN(1000));
+    fees = _fees;
+    const seeds = [treasury.publicKey.toBuffer(), mint.toBuffer()];
+    vault = await findVaultAddress(seeds);
+    intake = await findVaultAddress(seeds.concat(Buffer.from("intake")));
+    insurance = await findVaultAddress(seeds.concat(Buffer.from("insurance")));
+    community = await findVaultAddress(seeds.concat(Buffer.from("community")));
+
+    const config = { insuranceThreshold: new anchor.BN(500), communityShare: 20 };
+    const accounts = {
+      treasury: treasury.publicKey,
+      authority: governanceSigner,
+      vault,
+      mint,
+      intake,
+      insurance,
+      community,
+      treasurySigner,
+      tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+      systemProgram: anchor.web3.SystemProgram.programId,
+      rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+    };
+    await executeAsGovernance(program.instruction.addVault(config, { accounts }));
+
+    const account = await program.account.treasuryVault(vault);
+    assert.ok(account.mint.equals(mint));
+    assert.ok(account.intake.equals(intake));
+    assert.ok(account.config.insuranceThreshold.toNumber() === 500);
+    const intakeAccount = await serumCmn.getTokenAccount(provider, intake);
+    assert.ok(intakeAccount.owner.equals(treasurySigner));
+
+    // Each mint has a single vault
+    await assert.rejects(executeAsGovernance(program.instruction.addVault(config, { accounts })));
+  });
+
+  it("Fills the insurance fund up to the threshold", async () => {
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      TokenInstructions.transfer({
+        source: fees,
+        destination: intake,
+        amount: 1000,
+        owner: provider.wallet.publicKey,
+      })
+    );
+    await provider.send(tx);
+
+    await program.rpc.sweep({
+      accounts: {
+        treasury: treasury.publicKey,
+        vault,
+        intake,
+        insurance,
+        community,
+        treasurySigner,
+        tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+      },
+    });
+
+    // 80% of fees would go to insurance, but it is capped at the threshold
+    let insuranceAccount = await serumCmn.getTokenAccount(provider, insurance);
+    let communityAccount = await serumCmn.getTokenAccount(provider, community);
+    assert.ok(insuranceAccount.amount.toNumber() === 500);
+    assert.ok(communityAccount.amount.toNumber() === 500);
+
+    // Only the governance signer can spend
+    const spendAccounts = {
+      treasury: treasury.publicKey,
+      authority: provider.wallet.publicKey,
+      vault,
+      community,
+      destination: fees,
+      treasurySigner,
+      tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+    };
+    await assert.rejects(program.rpc.spend(new anchor.BN(100), { accounts: spendAccounts }));
+    await executeAsGovernance(
+      program.instruction.spend(new anchor.BN(100), {
+        accounts: { ...spendAccounts, authority: governanceSigner },
+      }),
+      { spend: {} }
+    );
+    communityAccount = await serumCmn.getTokenAccount(provider, community);
+    assert.ok(communityAccount.amount.toNumber() === 400);
+  });
+
+  it("Limits insurance withdrawals per epoch", async () => {
+    // The vault starts without a withdrawal delay, so the first config applies immediately
+    const insuranceConfig = {
+      withdrawalDelay: new anchor.BN(0),
+      maxOutflowPerEpoch: new anchor.BN(200),
+      marginProgram: anchor.web3.PublicKey.default,
+    };
+    await executeAsGovernance(
+      program.instruction.queueInsuranceConfig(insuranceConfig, {
+        accounts: {
+          treasury: treasury.publicKey,
+          authority: governanceSigner,
+          vault,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        },
+      })
+    );
+    await program.rpc.applyInsuranceConfig({
+      accounts: {
+        vault,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      },
+    });
+
+    const withdrawal = new anchor.web3.Account();
+    const tx = new anchor.web3.Transaction();
+    tx.add(await program.account.insuranceWithdrawal.createInstruction(withdrawal));
+    await provider.send(tx, [withdrawal]);
+    await executeAsGovernance(
+      program.instruction.queueInsuranceWithdrawal(new anchor.BN(300), {
+        accounts: {
+          treasury: treasury.publicKey,
+          authority: governanceSigner,
+          vault,
+          withdrawal: withdrawal.publicKey,
+          destination: fees,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        },
+      })
+    );
+
+    const executeAccounts = {
+      treasury: treasury.publicKey,
+      vault,
+      withdrawal: withdrawal.publicKey,
+      insurance,
+      destination: fees,
+      treasurySigner,
+      tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+      clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+    };
+    await program.rpc.executeInsuranceWithdrawal({ accounts: executeAccounts });
+    const withdrawalAccount = await program.account.insuranceWithdrawal(withdrawal.publicKey);
+    assert.ok(withdrawalAccount.withdrawn.toNumber() === 200);
+
+    // The rest has to wait for the next epoch
+    await assert.rejects(
+      program.rpc.executeInsuranceWithdrawal({ accounts: executeAccounts })
+    );
+
+    // Only the margin program's signer can cover bad debt
+    await assert.rejects(
+      program.rpc.coverBadDebt(new anchor.BN(1), {
+        accounts: {
+          treasury: treasury.publicKey,
+          vault,
+          coverageAuthority: provider.wallet.publicKey,
+          insurance,
+          destination: fees,
+          treasurySigner,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        },
+      })
+    );
+  });
+
+  async function findVaultAddress(seeds) {
+    const [address, _nonce] = await anchor.web3.PublicKey.findProgramAddress(
+      seeds,
+      program.programId
+    );
+    return address;
+  }
+
+  // Creates a governance voted on by a stake of the provider wallet, whose signer pays for the
+  // accounts its proposals create
+  async function createGovernance() {
+    const [_signer, nonce] = await anchor.web3.PublicKey.findProgramAddress(
+      [governance.publicKey.toBuffer()],
+      governanceProgram.programId
+    );
+    governanceSigner = _signer;
+    await createStake(governanceSigner, 150);
+
+    const config = {
+      quorumVotes: new anchor.BN(100),
+      approvalThreshold: 50,
+      votingPeriod: new anchor.BN(2),
+      timelock: new anchor.BN(0),
+    };
+    await governanceProgram.rpc.createGovernance(config, nonce, {
+      accounts: {
+        governance: governance.publicKey,
+        stakePool: pool.publicKey,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [governance],
+      instructions: [await governanceProgram.account.governance.createInstruction(governance)],
+    });
+
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      anchor.web3.SystemProgram.transfer({
+        fromPubkey: provider.wallet.publicKey,
+        toPubkey: governanceSigner,
+        lamports: anchor.web3.LAMPORTS_PER_SOL,
+      })
+    );
+    await provider.send(tx);
+  }
+
+  // Proposes `instruction`, votes it through and executes it as the governance signer
+  async function executeAsGovernance(instruction, kind = { paramChange: {} }) {
+    const accounts = instruction.keys.map((meta) => ({
+      pubkey: meta.pubkey,
+      isWritable: meta.isWritable,
+      isSigner: meta.pubkey.equals(governanceSigner),
+    }));
+    const proposal = new anchor.web3.Account();
+    await governanceProgram.rpc.createProposal(
+      kind,
+      instruction.programId,
+      accounts,
+      instruction.data,
+      {
+        accounts: {
+          governance: governance.publicKey,
+          proposal: proposal.publicKey,
+          proposer: provider.wallet.publicKey,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        },
+        signers: [proposal],
+        instructions: [
+          await governanceProgram.account.proposal.createInstruction(proposal, 1000),
+        ],
+      }
+    );
+
+    const [voteRecord, voteNonce] = await anchor.web3.PublicKey.findProgramAddress(
+      [
+        Buffer.from("vote-record"),
+        proposal.publicKey.toBuffer(),
+        provider.wallet.publicKey.toBuffer(),
+      ],
+      governanceProgram.programId
+    );
+    await governanceProgram.rpc.vote(true, voteNonce, {
+      accounts: {
+        governance: governance.publicKey,
+        proposal: proposal.publicKey,
+        voteRecord,
+        voter: provider.wallet.publicKey,
+        staker: staker.publicKey,
+        stakePool: pool.publicKey,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        systemProgram: anchor.web3.SystemProgram.programId,
+      },
+    });
+
+    // Wait for voting to end
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+
+    await governanceProgram.rpc.executeProposal({
+      accounts: {
+        governance: governance.publicKey,
+        proposal: proposal.publicKey,
+        governanceSigner,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+      },
+      remainingAccounts: accounts
+        .map((meta) => ({ ...meta, isSigner: false }))
+        .concat({ pubkey: instruction.programId, isWritable: false, isSigner: false }),
+    });
+  }
+
+  // Stakes `amount` in a new pool whose authority is `authority`
+  async function createStake(authority, amount) {
+    const [poolSigner, poolNonce] = await anchor.web3.PublicKey.findProgramAddress(
+      [pool.publicKey.toBuffer()],
+      staking.programId
+    );
+    const [nativeMint, nativeTokens] = await serumCmn.createMintAndVault(
+      provider,
+      new anchor.BN(1000)
+    );
+    const receiptMint = new anchor.web3.Account();
+    const tx = new anchor.web3.Transaction();
+    tx.add(
+      anchor.web3.SystemProgram.createAccount({
+        fromPubkey: provider.wallet.publicKey,
+        newAccountPubkey: receiptMint.publicKey,
+        space: 82,
+        lamports: await provider.connection.getMinimumBalanceForRentExemption(82),
+        programId: TokenInstructions.TOKEN_PROGRAM_ID,
+      }),
+      TokenInstructions.initializeMint({
+        mint: receiptMint.publicKey,
+        decimals: 0,
+        mintAuthority: poolSigner,
+        freezeAuthority: poolSigner,
+      }),
+      TokenInstructions.setAuthority({
+        target: nativeMint,
+        currentAuthority: provider.wallet.publicKey,
+        newAuthority: poolSigner,
+        authorityType: 0,
+      })
+    );
+    await provider.send(tx, [receiptMint]);
+    const receiptTokens = await serumCmn.createTokenAccount(
+      provider,
+      receiptMint.publicKey,
+      provider.wallet.publicKey
+    );
+    const stakeVault = await serumCmn.createTokenAccount(provider, nativeMint, poolSigner);
+    const rewardVault = await serumCmn.createTokenAccount(provider, nativeMint, poolSigner);
+
+    // The cooldown outlasts voting, so a stake can't vote twice
+    const poolConfig = { inflationRateBps: 0, cooldown: new anchor.BN(60) };
+    await staking.rpc.createPool(authority, poolConfig, poolNonce, {
+      accounts: {
+        pool: pool.publicKey,
+        nativeMint,
+        receiptMint: receiptMint.publicKey,
+        stakeVault,
+        rewardVault,
+        clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [pool],
+      instructions: [await staking.account.stakePool.createInstruction(pool)],
+    });
+    await staking.rpc.createStaker(provider.wallet.publicKey, {
+      accounts: {
+        pool: pool.publicKey,
+        staker: staker.publicKey,
+        rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+      },
+      signers: [staker],
+      instructions: [await staking.account.staker.createInstruction(staker, 300)],
+    });
+    await staking.rpc.stake(new anchor.BN(amount), {
+      accounts: {
+        poolAccounts: {
+          pool: pool.publicKey,
+          nativeMint,
+          rewardVault,
+          poolSigner,
+          tokenProgram: TokenInstructions.TOKEN_PROGRAM_ID,
+          clock: anchor.web3.SYSVAR_CLOCK_PUBKEY,
+        },
+        staker: staker.publicKey,
+        owner: provider.wallet.publicKey,
+        nativeTokens,
+        receiptTokens,
+        stakeVault,
+        receiptMint: receiptMint.publicKey,
+      },
+    });
+  }
+});
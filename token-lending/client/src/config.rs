@@ -0,0 +1,336 @@
+//! Configuration files read by the client
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use spl_token_lending::state::{
+    AdaptiveRateConfig, BorrowRateCurve, BorrowRateCurvePoint, ReserveConfig, ReserveFees,
+    MAX_BORROW_RATE_CURVE_POINTS,
+};
+use std::{convert::TryFrom, fs, path::Path};
+
+use crate::Error;
+
+/// Write a TOML or JSON file, by extension
+pub fn save<T: Serialize>(path: &str, value: &T) -> Result<(), Error> {
+    let contents = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::to_string_pretty(value)?,
+        _ => toml::to_string_pretty(value)?,
+    };
+    fs::write(path, contents).map_err(|err| format!("Failed to write {}: {}", path, err))?;
+    Ok(())
+}
+
+/// Read a TOML or JSON file, by extension
+pub fn load<T: DeserializeOwned>(path: &str) -> Result<T, Error> {
+    let contents =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let value = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&contents)?,
+        _ => toml::from_str(&contents)?,
+    };
+    Ok(value)
+}
+
+/// Serde helpers writing pubkeys as base58 strings
+pub mod pubkey_string {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+    use solana_program::pubkey::Pubkey;
+    use std::str::FromStr;
+
+    pub fn serialize<S: Serializer>(pubkey: &Pubkey, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&pubkey.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pubkey, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Pubkey::from_str(&value).map_err(|err| D::Error::custom(format!("{}: {}", value, err)))
+    }
+
+    /// Same as the parent module for optional pubkeys
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            pubkey: &Option<Pubkey>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match pubkey {
+                Some(pubkey) => super::serialize(pubkey, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Pubkey>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|value| {
+                    Pubkey::from_str(&value)
+                        .map_err(|err| D::Error::custom(format!("{}: {}", value, err)))
+                })
+                .transpose()
+        }
+    }
+}
+
+/// Reserve configuration as written in a config file
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReserveConfigFile {
+    /// Optimal utilization rate as a percent
+    pub optimal_utilization_rate: u8,
+    /// The ratio of the loan to the value of the collateral as a percent
+    pub loan_to_value_ratio: u8,
+    /// The percent discount the liquidator gets when buying collateral
+    pub liquidation_bonus: u8,
+    /// The percent at which an obligation is considered unhealthy
+    pub liquidation_threshold: u8,
+    /// Fees charged on borrows
+    pub fees: ReserveFeesFile,
+    /// Max liquidity borrowed through margin borrows, unlimited if omitted
+    #[serde(default)]
+    pub max_margin_borrow_amount: Option<u64>,
+    /// Slots liquidations average the price over, spot price if omitted
+    #[serde(default)]
+    pub liquidation_twap_slots: u64,
+    /// Borrow APR by utilization
+    pub borrow_rate_curve: Vec<BorrowRateCurvePointFile>,
+    /// Adaptive rate model, disabled if omitted
+    #[serde(default)]
+    pub adaptive_rate: Option<AdaptiveRateConfigFile>,
+    /// Percent of borrow interest kept as fees for the lending market owner, none if omitted
+    #[serde(default)]
+    pub reserve_factor: u8,
+}
+
+/// Adaptive rate model as written in a config file
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveRateConfigFile {
+    /// Max rise of the optimal borrow rate per slot as a wad, 10^18 = 100%
+    pub rise_gain_wad: u64,
+    /// Max fall of the optimal borrow rate per slot as a wad, 10^18 = 100%
+    pub fall_gain_wad: u64,
+    /// Lowest optimal borrow rate in basis points
+    pub min_optimal_borrow_rate_bps: u16,
+    /// Highest optimal borrow rate in basis points
+    pub max_optimal_borrow_rate_bps: u16,
+}
+
+/// Borrow rate curve point as written in a config file
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BorrowRateCurvePointFile {
+    /// Utilization rate in basis points
+    pub utilization_rate_bps: u16,
+    /// Borrow APR in basis points
+    pub borrow_rate_bps: u16,
+}
+
+/// Reserve fees as written in a config file
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReserveFeesFile {
+    /// Borrow fee as a wad, 10^18 = 100%
+    pub borrow_fee_wad: u64,
+    /// Percentage of the fee going to the host account
+    pub host_fee_percentage: u8,
+}
+
+impl TryFrom<ReserveConfigFile> for ReserveConfig {
+    type Error = Error;
+
+    fn try_from(config: ReserveConfigFile) -> Result<Self, Error> {
+        let points: Vec<_> = config
+            .borrow_rate_curve
+            .iter()
+            .map(|point| BorrowRateCurvePoint {
+                utilization_rate_bps: point.utilization_rate_bps,
+                borrow_rate_bps: point.borrow_rate_bps,
+            })
+            .collect();
+        let borrow_rate_curve = BorrowRateCurve::new(&points).map_err(|_| {
+            format!(
+                "Borrow rate curve has {} points, at most {} are supported",
+                points.len(),
+                MAX_BORROW_RATE_CURVE_POINTS
+            )
+        })?;
+        if !borrow_rate_curve.is_valid() {
+            return Err(
+                "Borrow rate curve must span 0% to 100% utilization and be monotonic".into(),
+            );
+        }
+
+        Ok(Self {
+            optimal_utilization_rate: config.optimal_utilization_rate,
+            loan_to_value_ratio: config.loan_to_value_ratio,
+            liquidation_bonus: config.liquidation_bonus,
+            liquidation_threshold: config.liquidation_threshold,
+            fees: ReserveFees {
+                borrow_fee_wad: config.fees.borrow_fee_wad,
+                host_fee_percentage: config.fees.host_fee_percentage,
+            },
+            max_margin_borrow_amount: config.max_margin_borrow_amount.unwrap_or(u64::MAX),
+            liquidation_twap_slots: config.liquidation_twap_slots,
+            borrow_rate_curve,
+            adaptive_rate: config.adaptive_rate.map_or(
+                AdaptiveRateConfig::DISABLED,
+                |adaptive_rate| AdaptiveRateConfig {
+                    rise_gain_wad: adaptive_rate.rise_gain_wad,
+                    fall_gain_wad: adaptive_rate.fall_gain_wad,
+                    min_optimal_borrow_rate_bps: adaptive_rate.min_optimal_borrow_rate_bps,
+                    max_optimal_borrow_rate_bps: adaptive_rate.max_optimal_borrow_rate_bps,
+                },
+            ),
+            reserve_factor: config.reserve_factor,
+        })
+    }
+}
+
+/// Read a reserve configuration file
+pub fn load_reserve_config(path: &str) -> Result<ReserveConfig, Error> {
+    ReserveConfig::try_from(load::<ReserveConfigFile>(path)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn toml_and_json_match() {
+        let toml: ReserveConfigFile = toml::from_str(
+            r#"
+            optimal_utilization_rate = 80
+            loan_to_value_ratio = 75
+            liquidation_bonus = 5
+            liquidation_threshold = 80
+
+            [fees]
+            borrow_fee_wad = 100000000000000
+            host_fee_percentage = 20
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 0
+            borrow_rate_bps = 0
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 8000
+            borrow_rate_bps = 400
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 10000
+            borrow_rate_bps = 3000
+            "#,
+        )
+        .unwrap();
+        let json: ReserveConfigFile = serde_json::from_str(
+            r#"{
+                "optimal_utilization_rate": 80,
+                "loan_to_value_ratio": 75,
+                "liquidation_bonus": 5,
+                "liquidation_threshold": 80,
+                "fees": { "borrow_fee_wad": 100000000000000, "host_fee_percentage": 20 },
+                "borrow_rate_curve": [
+                    { "utilization_rate_bps": 0, "borrow_rate_bps": 0 },
+                    { "utilization_rate_bps": 8000, "borrow_rate_bps": 400 },
+                    { "utilization_rate_bps": 10000, "borrow_rate_bps": 3000 }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(toml, json);
+
+        let config = ReserveConfig::try_from(toml).unwrap();
+        assert_eq!(config.max_margin_borrow_amount, u64::MAX);
+        assert_eq!(config.liquidation_twap_slots, 0);
+        assert_eq!(config.fees.host_fee_percentage, 20);
+        assert_eq!(config.borrow_rate_curve.points().len(), 3);
+        assert!(!config.adaptive_rate.is_enabled());
+        assert_eq!(config.reserve_factor, 0);
+    }
+
+    #[test]
+    fn adaptive_rate() {
+        let file: ReserveConfigFile = toml::from_str(
+            r#"
+            optimal_utilization_rate = 80
+            loan_to_value_ratio = 75
+            liquidation_bonus = 5
+            liquidation_threshold = 80
+            reserve_factor = 10
+
+            [fees]
+            borrow_fee_wad = 100000000000000
+            host_fee_percentage = 20
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 0
+            borrow_rate_bps = 0
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 8000
+            borrow_rate_bps = 400
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 10000
+            borrow_rate_bps = 3000
+
+            [adaptive_rate]
+            rise_gain_wad = 2000000000
+            fall_gain_wad = 1000000000
+            min_optimal_borrow_rate_bps = 200
+            max_optimal_borrow_rate_bps = 1500
+            "#,
+        )
+        .unwrap();
+        let config = ReserveConfig::try_from(file).unwrap();
+        assert!(config.adaptive_rate.is_enabled());
+        assert_eq!(config.adaptive_rate.max_optimal_borrow_rate_bps, 1_500);
+        assert_eq!(config.reserve_factor, 10);
+    }
+
+    #[test]
+    fn borrow_rate_curve() {
+        let file: ReserveConfigFile = toml::from_str(
+            r#"
+            optimal_utilization_rate = 80
+            loan_to_value_ratio = 75
+            liquidation_bonus = 5
+            liquidation_threshold = 80
+
+            [fees]
+            borrow_fee_wad = 100000000000000
+            host_fee_percentage = 20
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 0
+            borrow_rate_bps = 0
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 8000
+            borrow_rate_bps = 400
+
+            [[borrow_rate_curve]]
+            utilization_rate_bps = 10000
+            borrow_rate_bps = 30000
+            "#,
+        )
+        .unwrap();
+        let config = ReserveConfig::try_from(file.clone()).unwrap();
+        assert_eq!(config.borrow_rate_curve.points().len(), 3);
+        assert_eq!(config.borrow_rate_curve.points()[2].borrow_rate_bps, 30_000);
+
+        let mut too_many_points = file.clone();
+        too_many_points.borrow_rate_curve =
+            vec![file.borrow_rate_curve[0]; MAX_BORROW_RATE_CURVE_POINTS + 1];
+        assert!(ReserveConfig::try_from(too_many_points).is_err());
+
+        let mut decreasing = file.clone();
+        decreasing.borrow_rate_curve[1].borrow_rate_bps = 40_000;
+        assert!(ReserveConfig::try_from(decreasing).is_err());
+
+        let mut empty = file;
+        empty.borrow_rate_curve.clear();
+        assert!(ReserveConfig::try_from(empty).is_err());
+    }
+}
@@ -0,0 +1,654 @@
+//! Lending market commands
+
+use solana_client::rpc_client::RpcClient;
+use solana_program::{program_pack::Pack, pubkey::Pubkey};
+use solana_sdk::{
+    signature::{Keypair, Signer},
+    system_instruction::create_account,
+    transaction::Transaction,
+};
+use spl_token::{
+    instruction::approve,
+    state::{Account as Token, Mint},
+};
+use spl_token_lending::{
+    dex_market::{DexMarket, ASKS_OFFSET, BIDS_OFFSET},
+    instruction::{
+        accrue_reserve_interest_with_rate_histories, init_lending_market, init_price_accumulator,
+        init_rate_history, init_reserve, set_reserve_config, update_price_accumulator,
+        withdraw_reserve_fees,
+    },
+    state::{
+        apr_to_apy, LendingMarket, Obligation, PriceAccumulator, PriceSource, RateHistory, Reserve,
+        ReserveConfig,
+    },
+    swap_pool::SwapPool,
+};
+
+use crate::{Config, Error};
+
+/// Create a lending market owned by `owner`
+pub fn create_lending_market(
+    config: &Config,
+    quote_token_mint: Pubkey,
+    owner: Pubkey,
+) -> Result<(Pubkey, LendingMarket), Error> {
+    let client = &config.rpc_client;
+    let payer = &config.payer;
+    let keypair = Keypair::new();
+    let pubkey = keypair.pubkey();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            create_account(
+                &payer.pubkey(),
+                &pubkey,
+                client.get_minimum_balance_for_rent_exemption(LendingMarket::LEN)?,
+                LendingMarket::LEN as u64,
+                &config.program_id,
+            ),
+            init_lending_market(config.program_id, pubkey, owner, quote_token_mint),
+        ],
+        Some(&payer.pubkey()),
+    );
+
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(&[payer, &keypair], recent_blockhash);
+    client.send_and_confirm_transaction(&transaction)?;
+
+    Ok((pubkey, get_lending_market(client, &pubkey)?))
+}
+
+/// Create a reserve funded with `liquidity_amount` from `liquidity_source_pubkey`, which the
+/// payer must own. `dex_market_pubkey` is the market or pool `price_source` refers to.
+#[allow(clippy::too_many_arguments)]
+pub fn create_reserve(
+    config: &Config,
+    reserve_config: ReserveConfig,
+    lending_market_pubkey: Pubkey,
+    lending_market_owner: &Keypair,
+    dex_market_pubkey: Option<Pubkey>,
+    price_source: PriceSource,
+    liquidity_source_pubkey: Pubkey,
+    liquidity_amount: Option<u64>,
+) -> Result<(Pubkey, Reserve), Error> {
+    let client = &config.rpc_client;
+    let payer = &config.payer;
+    let reserve_keypair = Keypair::new();
+    let reserve_pubkey = reserve_keypair.pubkey();
+    let collateral_mint_keypair = Keypair::new();
+    let collateral_supply_keypair = Keypair::new();
+    let collateral_fees_receiver_keypair = Keypair::new();
+    let liquidity_supply_keypair = Keypair::new();
+    let user_collateral_token_keypair = Keypair::new();
+    let user_transfer_authority = Keypair::new();
+
+    let liquidity_source_account = client.get_account(&liquidity_source_pubkey)?;
+    let liquidity_source_token = Token::unpack(&liquidity_source_account.data)?;
+    let liquidity_mint_pubkey = liquidity_source_token.mint;
+    let liquidity_amount = liquidity_amount.unwrap_or(liquidity_source_token.amount);
+
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    let token_balance = client.get_minimum_balance_for_rent_exemption(Token::LEN)?;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            create_account(
+                &payer.pubkey(),
+                &collateral_mint_keypair.pubkey(),
+                client.get_minimum_balance_for_rent_exemption(Mint::LEN)?,
+                Mint::LEN as u64,
+                &spl_token::id(),
+            ),
+            create_account(
+                &payer.pubkey(),
+                &collateral_supply_keypair.pubkey(),
+                token_balance,
+                Token::LEN as u64,
+                &spl_token::id(),
+            ),
+            create_account(
+                &payer.pubkey(),
+                &collateral_fees_receiver_keypair.pubkey(),
+                token_balance,
+                Token::LEN as u64,
+                &spl_token::id(),
+            ),
+            create_account(
+                &payer.pubkey(),
+                &liquidity_supply_keypair.pubkey(),
+                token_balance,
+                Token::LEN as u64,
+                &spl_token::id(),
+            ),
+            create_account(
+                &payer.pubkey(),
+                &user_collateral_token_keypair.pubkey(),
+                token_balance,
+                Token::LEN as u64,
+                &spl_token::id(),
+            ),
+            create_account(
+                &payer.pubkey(),
+                &reserve_pubkey,
+                client.get_minimum_balance_for_rent_exemption(Reserve::LEN)?,
+                Reserve::LEN as u64,
+                &config.program_id,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+
+    transaction.sign(
+        &vec![
+            payer,
+            &reserve_keypair,
+            &collateral_mint_keypair,
+            &collateral_supply_keypair,
+            &collateral_fees_receiver_keypair,
+            &liquidity_supply_keypair,
+            &user_collateral_token_keypair,
+        ],
+        recent_blockhash,
+    );
+
+    client.send_and_confirm_transaction(&transaction)?;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            approve(
+                &spl_token::id(),
+                &liquidity_source_pubkey,
+                &user_transfer_authority.pubkey(),
+                &payer.pubkey(),
+                &[],
+                liquidity_amount,
+            )?,
+            init_reserve(
+                config.program_id,
+                liquidity_amount,
+                reserve_config,
+                liquidity_source_pubkey,
+                user_collateral_token_keypair.pubkey(),
+                reserve_pubkey,
+                liquidity_mint_pubkey,
+                liquidity_supply_keypair.pubkey(),
+                collateral_mint_keypair.pubkey(),
+                collateral_supply_keypair.pubkey(),
+                collateral_fees_receiver_keypair.pubkey(),
+                lending_market_pubkey,
+                lending_market_owner.pubkey(),
+                user_transfer_authority.pubkey(),
+                dex_market_pubkey,
+                price_source,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+
+    transaction.sign(
+        &vec![payer, lending_market_owner, &user_transfer_authority],
+        recent_blockhash,
+    );
+
+    client.send_and_confirm_transaction(&transaction)?;
+
+    Ok((reserve_pubkey, get_reserve(client, &reserve_pubkey)?))
+}
+
+/// Replace the configuration of a reserve
+pub fn update_reserve(
+    config: &Config,
+    reserve_config: ReserveConfig,
+    reserve_pubkey: Pubkey,
+    lending_market_owner: &Keypair,
+) -> Result<Reserve, Error> {
+    let client = &config.rpc_client;
+    let payer = &config.payer;
+    let reserve = get_reserve(client, &reserve_pubkey)?;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[set_reserve_config(
+            config.program_id,
+            reserve_config,
+            reserve_pubkey,
+            reserve.lending_market,
+            lending_market_owner.pubkey(),
+        )],
+        Some(&payer.pubkey()),
+    );
+
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(&vec![payer, lending_market_owner], recent_blockhash);
+    client.send_and_confirm_transaction(&transaction)?;
+
+    get_reserve(client, &reserve_pubkey)
+}
+
+/// Withdraw the fees accumulated in a reserve to `destination`, returning the amount withdrawn
+pub fn withdraw_fees(
+    config: &Config,
+    reserve_pubkey: Pubkey,
+    lending_market_owner: &Keypair,
+    destination: Pubkey,
+) -> Result<u64, Error> {
+    let client = &config.rpc_client;
+    let payer = &config.payer;
+    let reserve = get_reserve(client, &reserve_pubkey)?;
+    let lending_market = get_lending_market(client, &reserve.lending_market)?;
+    let lending_market_authority = Pubkey::create_program_address(
+        &[reserve.lending_market.as_ref(), &[lending_market.bump_seed]],
+        &config.program_id,
+    )?;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[withdraw_reserve_fees(
+            config.program_id,
+            reserve_pubkey,
+            reserve.liquidity.supply_pubkey,
+            destination,
+            reserve.lending_market,
+            lending_market_authority,
+            lending_market_owner.pubkey(),
+        )],
+        Some(&payer.pubkey()),
+    );
+
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(&vec![payer, lending_market_owner], recent_blockhash);
+    client.send_and_confirm_transaction(&transaction)?;
+
+    let available_amount = get_reserve(client, &reserve_pubkey)?
+        .liquidity
+        .available_amount;
+    Ok(reserve.liquidity.available_amount - available_amount)
+}
+
+/// Create a price accumulator for the market `reserve_pubkey` is priced off, seeded with the
+/// market's current price
+pub fn create_price_accumulator(
+    config: &Config,
+    reserve_pubkey: Pubkey,
+    lending_market_owner: &Keypair,
+    observation_interval: u64,
+) -> Result<(Pubkey, PriceAccumulator), Error> {
+    let client = &config.rpc_client;
+    let payer = &config.payer;
+    let reserve = get_reserve(client, &reserve_pubkey)?;
+    let market = reserve
+        .dex_market
+        .ok_or("reserve is not priced off a market")?;
+    let (first, second) = market_price_accounts(client, &market, reserve.price_source)?;
+    let keypair = Keypair::new();
+    let pubkey = keypair.pubkey();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            create_account(
+                &payer.pubkey(),
+                &pubkey,
+                client.get_minimum_balance_for_rent_exemption(PriceAccumulator::LEN)?,
+                PriceAccumulator::LEN as u64,
+                &config.program_id,
+            ),
+            init_price_accumulator(
+                config.program_id,
+                observation_interval,
+                pubkey,
+                reserve_pubkey,
+                reserve.lending_market,
+                lending_market_owner.pubkey(),
+                market,
+                first,
+                second,
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(
+        &vec![payer, lending_market_owner, &keypair],
+        recent_blockhash,
+    );
+    client.send_and_confirm_transaction(&transaction)?;
+
+    Ok((pubkey, get_price_accumulator(client, &pubkey)?))
+}
+
+/// Sample the market price into a price accumulator
+pub fn crank_price_accumulator(config: &Config, pubkey: Pubkey) -> Result<PriceAccumulator, Error> {
+    let client = &config.rpc_client;
+    let payer = &config.payer;
+    let price_accumulator = get_price_accumulator(client, &pubkey)?;
+    let (first, second) = market_price_accounts(
+        client,
+        &price_accumulator.market,
+        price_accumulator.price_source,
+    )?;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[update_price_accumulator(
+            config.program_id,
+            pubkey,
+            price_accumulator.market,
+            first,
+            second,
+        )],
+        Some(&payer.pubkey()),
+    );
+
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(&[payer], recent_blockhash);
+    client.send_and_confirm_transaction(&transaction)?;
+
+    get_price_accumulator(client, &pubkey)
+}
+
+/// Bids and asks of a dex market, or token A and B accounts of a token swap pool, which price
+/// accumulators read the market price from
+fn market_price_accounts(
+    client: &RpcClient,
+    market: &Pubkey,
+    price_source: PriceSource,
+) -> Result<(Pubkey, Pubkey), Error> {
+    let data = client.get_account(market)?.data;
+    Ok(match price_source {
+        PriceSource::DexMarket => (
+            DexMarket::pubkey_at_offset(&data, BIDS_OFFSET),
+            DexMarket::pubkey_at_offset(&data, ASKS_OFFSET),
+        ),
+        PriceSource::TokenSwap => {
+            let pool = SwapPool::unpack(&data)?;
+            (pool.token_a, pool.token_b)
+        }
+    })
+}
+
+/// Create a rate history recording snapshots of `reserve_pubkey` as its interest accrues
+pub fn create_rate_history(
+    config: &Config,
+    reserve_pubkey: Pubkey,
+    lending_market_owner: &Keypair,
+    snapshot_interval: u64,
+) -> Result<(Pubkey, RateHistory), Error> {
+    let client = &config.rpc_client;
+    let payer = &config.payer;
+    let reserve = get_reserve(client, &reserve_pubkey)?;
+    let keypair = Keypair::new();
+    let pubkey = keypair.pubkey();
+
+    let mut transaction = Transaction::new_with_payer(
+        &[
+            create_account(
+                &payer.pubkey(),
+                &pubkey,
+                client.get_minimum_balance_for_rent_exemption(RateHistory::LEN)?,
+                RateHistory::LEN as u64,
+                &config.program_id,
+            ),
+            init_rate_history(
+                config.program_id,
+                snapshot_interval,
+                pubkey,
+                reserve_pubkey,
+                reserve.lending_market,
+                lending_market_owner.pubkey(),
+            ),
+        ],
+        Some(&payer.pubkey()),
+    );
+
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(
+        &vec![payer, lending_market_owner, &keypair],
+        recent_blockhash,
+    );
+    client.send_and_confirm_transaction(&transaction)?;
+
+    Ok((pubkey, get_rate_history(client, &pubkey)?))
+}
+
+/// Accrue interest on the reserve of a rate history and record a snapshot into it
+pub fn crank_rate_history(config: &Config, pubkey: Pubkey) -> Result<RateHistory, Error> {
+    let client = &config.rpc_client;
+    let payer = &config.payer;
+    let rate_history = get_rate_history(client, &pubkey)?;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[accrue_reserve_interest_with_rate_histories(
+            config.program_id,
+            vec![(rate_history.reserve, Some(pubkey))],
+        )],
+        Some(&payer.pubkey()),
+    );
+
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(&[payer], recent_blockhash);
+    client.send_and_confirm_transaction(&transaction)?;
+
+    get_rate_history(client, &pubkey)
+}
+
+/// Fetch a lending market
+pub fn get_lending_market(client: &RpcClient, pubkey: &Pubkey) -> Result<LendingMarket, Error> {
+    Ok(LendingMarket::unpack(&client.get_account(pubkey)?.data)?)
+}
+
+/// Fetch a reserve
+pub fn get_reserve(client: &RpcClient, pubkey: &Pubkey) -> Result<Reserve, Error> {
+    Ok(Reserve::unpack(&client.get_account(pubkey)?.data)?)
+}
+
+/// Fetch a price accumulator
+pub fn get_price_accumulator(
+    client: &RpcClient,
+    pubkey: &Pubkey,
+) -> Result<PriceAccumulator, Error> {
+    Ok(PriceAccumulator::unpack(&client.get_account(pubkey)?.data)?)
+}
+
+/// Fetch a rate history
+pub fn get_rate_history(client: &RpcClient, pubkey: &Pubkey) -> Result<RateHistory, Error> {
+    Ok(RateHistory::unpack(&client.get_account(pubkey)?.data)?)
+}
+
+/// Fetch an obligation
+pub fn get_obligation(client: &RpcClient, pubkey: &Pubkey) -> Result<Obligation, Error> {
+    Ok(Obligation::unpack(&client.get_account(pubkey)?.data)?)
+}
+
+/// Print a lending market
+pub fn show_lending_market(config: &Config, pubkey: &Pubkey) -> Result<(), Error> {
+    let lending_market = get_lending_market(&config.rpc_client, pubkey)?;
+    let authority = Pubkey::create_program_address(
+        &[pubkey.as_ref(), &[lending_market.bump_seed]],
+        &config.program_id,
+    )?;
+
+    println!("Lending market: {}", pubkey);
+    println!("  Owner: {}", lending_market.owner);
+    println!("  Authority: {}", authority);
+    println!("  Quote token mint: {}", lending_market.quote_token_mint);
+    println!("  Token program: {}", lending_market.token_program_id);
+    Ok(())
+}
+
+/// Print a reserve
+pub fn show_reserve(config: &Config, pubkey: &Pubkey) -> Result<(), Error> {
+    let reserve = get_reserve(&config.rpc_client, pubkey)?;
+    let reserve_config = &reserve.config;
+
+    println!("Reserve: {}", pubkey);
+    println!("  Lending market: {}", reserve.lending_market);
+    println!("  Last update slot: {}", reserve.last_update_slot);
+    if let Some(dex_market) = Option::<Pubkey>::from(reserve.dex_market) {
+        match reserve.price_source {
+            PriceSource::DexMarket => println!("  Dex market: {}", dex_market),
+            PriceSource::TokenSwap => println!("  Token swap pool: {}", dex_market),
+        }
+    }
+    println!("  Liquidity mint: {}", reserve.liquidity.mint_pubkey);
+    println!("  Liquidity supply: {}", reserve.liquidity.supply_pubkey);
+    println!(
+        "  Available liquidity: {}",
+        reserve.liquidity.available_amount
+    );
+    println!(
+        "  Borrowed liquidity: {}",
+        reserve.liquidity.borrowed_amount_wads
+    );
+    println!(
+        "  Margin borrowed liquidity: {}",
+        reserve.liquidity.margin_borrowed_amount
+    );
+    println!(
+        "  Accumulated fees: {}",
+        reserve.liquidity.accumulated_fees_wads
+    );
+    println!("  Collateral mint: {}", reserve.collateral.mint_pubkey);
+    println!("  Collateral supply: {}", reserve.collateral.supply_pubkey);
+    println!(
+        "  Collateral fees receiver: {}",
+        reserve.collateral.fees_receiver
+    );
+    println!(
+        "  Utilization rate: {}",
+        reserve.liquidity.utilization_rate()?
+    );
+    let borrow_rate = reserve.current_borrow_rate()?;
+    println!(
+        "  Borrow rate: {} (APY {})",
+        borrow_rate,
+        apr_to_apy(borrow_rate)?
+    );
+    let supply_rate = reserve.current_supply_rate()?;
+    println!(
+        "  Supply rate: {} (APY {})",
+        supply_rate,
+        apr_to_apy(supply_rate)?
+    );
+    println!("  Config:");
+    println!(
+        "    Optimal utilization rate: {}%",
+        reserve_config.optimal_utilization_rate
+    );
+    println!(
+        "    Loan to value ratio: {}%",
+        reserve_config.loan_to_value_ratio
+    );
+    println!(
+        "    Liquidation bonus: {}%",
+        reserve_config.liquidation_bonus
+    );
+    println!(
+        "    Liquidation threshold: {}%",
+        reserve_config.liquidation_threshold
+    );
+    let adaptive_rate = &reserve_config.adaptive_rate;
+    if adaptive_rate.is_enabled() {
+        println!(
+            "    Adaptive optimal borrow rate: {} ({} to {} bps, gains {} / {} wad per slot)",
+            reserve.optimal_borrow_rate()?,
+            adaptive_rate.min_optimal_borrow_rate_bps,
+            adaptive_rate.max_optimal_borrow_rate_bps,
+            adaptive_rate.rise_gain_wad,
+            adaptive_rate.fall_gain_wad
+        );
+    }
+    println!("    Borrow rate curve:");
+    for point in reserve_config.borrow_rate_curve.points() {
+        println!(
+            "      {} bps utilization: {} bps",
+            point.utilization_rate_bps, point.borrow_rate_bps
+        );
+    }
+    println!("    Borrow fee wad: {}", reserve_config.fees.borrow_fee_wad);
+    println!(
+        "    Host fee percentage: {}%",
+        reserve_config.fees.host_fee_percentage
+    );
+    println!(
+        "    Max margin borrow amount: {}",
+        reserve_config.max_margin_borrow_amount
+    );
+    println!(
+        "    Liquidation TWAP slots: {}",
+        reserve_config.liquidation_twap_slots
+    );
+    println!("    Reserve factor: {}%", reserve_config.reserve_factor);
+    Ok(())
+}
+
+/// Print a price accumulator
+pub fn show_price_accumulator(config: &Config, pubkey: &Pubkey) -> Result<(), Error> {
+    let client = &config.rpc_client;
+    let price_accumulator = get_price_accumulator(client, pubkey)?;
+
+    println!("Price accumulator: {}", pubkey);
+    println!("  Lending market: {}", price_accumulator.lending_market);
+    match price_accumulator.price_source {
+        PriceSource::DexMarket => println!("  Dex market: {}", price_accumulator.market),
+        PriceSource::TokenSwap => println!("  Token swap pool: {}", price_accumulator.market),
+    }
+    println!("  Base mint: {}", price_accumulator.base_mint);
+    println!("  Quote mint: {}", price_accumulator.quote_mint);
+    println!(
+        "  Observation interval: {} slots",
+        price_accumulator.observation_interval
+    );
+    println!("  Last update slot: {}", price_accumulator.last_update_slot);
+    println!("  Last price: {}", price_accumulator.last_price);
+    println!("  Observations: {}", price_accumulator.observations.len());
+    if let Some(oldest) = price_accumulator.observations.first() {
+        println!("  Oldest observation slot: {}", oldest.slot);
+    }
+    Ok(())
+}
+
+/// Print a rate history, oldest snapshot first
+pub fn show_rate_history(config: &Config, pubkey: &Pubkey) -> Result<(), Error> {
+    let rate_history = get_rate_history(&config.rpc_client, pubkey)?;
+
+    println!("Rate history: {}", pubkey);
+    println!("  Reserve: {}", rate_history.reserve);
+    println!(
+        "  Snapshot interval: {} slots",
+        rate_history.snapshot_interval
+    );
+    println!("  Snapshots: {}", rate_history.snapshots.len());
+    for snapshot in rate_history.iter() {
+        println!(
+            "    Slot {}: utilization {}, borrow rate {}, exchange rate {}, total supply {}",
+            snapshot.slot,
+            snapshot.utilization_rate,
+            snapshot.borrow_rate,
+            snapshot.collateral_exchange_rate,
+            snapshot.total_supply
+        );
+    }
+    Ok(())
+}
+
+/// Print an obligation
+pub fn show_obligation(config: &Config, pubkey: &Pubkey) -> Result<(), Error> {
+    let obligation = get_obligation(&config.rpc_client, pubkey)?;
+
+    println!("Obligation: {}", pubkey);
+    println!("  Collateral reserve: {}", obligation.collateral_reserve);
+    println!(
+        "  Deposited collateral: {}",
+        obligation.deposited_collateral_tokens
+    );
+    println!("  Borrow reserve: {}", obligation.borrow_reserve);
+    println!(
+        "  Borrowed liquidity: {}",
+        obligation.borrowed_liquidity_wads
+    );
+    println!(
+        "  Margin borrowed liquidity: {}",
+        obligation.margin_borrowed_amount
+    );
+    println!("  Token mint: {}", obligation.token_mint);
+    Ok(())
+}
@@ -1,304 +1,658 @@
-use solana_client::rpc_client::RpcClient;
-use solana_program::program_pack::Pack;
-use solana_sdk::{
-    pubkey::Pubkey,
-    signature::{read_keypair_file, Keypair, Signer},
-    system_instruction::create_account,
-    transaction::Transaction,
-};
-use spl_token::{
-    instruction::approve,
-    state::{Account as Token, Mint},
+mod config;
+mod lending;
+mod manifest;
+mod margin;
+
+use clap::{
+    crate_description, crate_name, crate_version, value_t, App, AppSettings, Arg, ArgMatches,
+    SubCommand,
 };
-use spl_token_lending::{
-    instruction::{init_lending_market, init_reserve},
-    state::{LendingMarket, Reserve, ReserveConfig, ReserveFees},
+use entropy_sdk::{
+    explain::{decode_transaction, Explainer},
+    Client,
 };
-use std::str::FromStr;
+use solana_client::rpc_client::RpcClient;
+use solana_program::pubkey::Pubkey;
+use solana_sdk::signature::{read_keypair_file, Keypair, Signer};
+use spl_token_lending::state::PriceSource;
+use std::{process::exit, str::FromStr};
 
-// -------- UPDATE START -------
-const KEYPAIR_PATH: &str = "/your/path";
-const SRM_TOKEN_ACCOUNT: &str = "BASE58_ADDRESS";
-const USDC_TOKEN_ACCOUNT: &str = "BASE58_ADDRESS";
-const WRAPPED_SOL_TOKEN_ACCOUNT: &str = "BASE58_ADDRESS";
-solana_program::declare_id!("TokenLend1ng1111111111111111111111111111111");
-// -------- UPDATE END ---------
+pub type Error = Box<dyn std::error::Error>;
 
-pub struct DexMarket {
-    pub name: &'static str,
-    pub pubkey: Pubkey,
+/// Settings shared by every command
+pub struct Config {
+    pub url: String,
+    pub rpc_client: RpcClient,
+    pub payer: Keypair,
+    pub program_id: Pubkey,
+    pub margin_program_id: Option<Pubkey>,
 }
 
-pub fn main() {
-    let mut client = RpcClient::new("https://api.mainnet-beta.solana.com".to_owned());
+impl Config {
+    /// New RPC client to the same cluster
+    pub fn connect(&self) -> RpcClient {
+        RpcClient::new(self.url.clone())
+    }
 
-    let payer = read_keypair_file(&format!("{}/payer.json", KEYPAIR_PATH)).unwrap();
-    let usdc_mint_pubkey =
-        Pubkey::from_str("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v").unwrap();
-
-    let sol_usdc_dex_market = DexMarket {
-        name: "sol_usdc",
-        pubkey: Pubkey::from_str("7xMDbYTCqQEcK2aM9LbetGtNFJpzKdfXzLL5juaLh4GJ").unwrap(),
-    };
-
-    let srm_usdc_dex_market = DexMarket {
-        name: "srm_usdc",
-        pubkey: Pubkey::from_str("CDdR97S8y96v3To93aKvi3nCnjUrbuVSuumw8FLvbVeg").unwrap(),
-    };
+    /// Margin program id, required by the margin commands
+    pub fn margin_program_id(&self) -> Result<Pubkey, Error> {
+        self.margin_program_id
+            .ok_or_else(|| "--margin-program-id is required".into())
+    }
 
-    let quote_token_mint = usdc_mint_pubkey;
-    let (lending_market_owner, lending_market_pubkey, _lending_market) =
-        create_lending_market(&mut client, quote_token_mint, &payer);
+    /// Account loader for both programs
+    pub fn margin_client(&self) -> Result<Client, Error> {
+        Ok(Client::new(
+            self.connect(),
+            self.program_id,
+            self.margin_program_id()?,
+        ))
+    }
+}
 
-    let usdc_liquidity_source = Pubkey::from_str(USDC_TOKEN_ACCOUNT).unwrap();
-    let usdc_reserve_config = ReserveConfig {
-        optimal_utilization_rate: 80,
-        loan_to_value_ratio: 75,
-        liquidation_bonus: 5,
-        liquidation_threshold: 80,
-        min_borrow_rate: 0,
-        optimal_borrow_rate: 4,
-        max_borrow_rate: 30,
-        fees: ReserveFees {
-            borrow_fee_wad: 100_000_000_000_000, // 1 bp
-            host_fee_percentage: 20,
-        },
-    };
+fn is_pubkey(value: String) -> Result<(), String> {
+    Pubkey::from_str(&value)
+        .map(|_| ())
+        .map_err(|err| format!("{}", err))
+}
 
-    let (usdc_reserve_pubkey, _usdc_reserve) = create_reserve(
-        &mut client,
-        usdc_reserve_config,
-        lending_market_pubkey,
-        &lending_market_owner,
-        None,
-        usdc_liquidity_source,
-        &payer,
-    );
+fn pubkey_of(matches: &ArgMatches, name: &str) -> Option<Pubkey> {
+    matches
+        .value_of(name)
+        .map(|value| Pubkey::from_str(value).unwrap())
+}
 
-    println!("Created usdc reserve with pubkey: {}", usdc_reserve_pubkey);
+fn keypair_of(matches: &ArgMatches, name: &str) -> Result<Option<Keypair>, Error> {
+    match matches.value_of(name) {
+        Some(path) => {
+            Ok(Some(read_keypair_file(path).map_err(|err| {
+                format!("Failed to read {}: {}", path, err)
+            })?))
+        }
+        None => Ok(None),
+    }
+}
 
-    let sol_liquidity_source = Pubkey::from_str(WRAPPED_SOL_TOKEN_ACCOUNT).unwrap();
-    let sol_reserve_config = ReserveConfig {
-        optimal_utilization_rate: 0,
-        loan_to_value_ratio: 75,
-        liquidation_bonus: 10,
-        liquidation_threshold: 80,
-        min_borrow_rate: 0,
-        optimal_borrow_rate: 2,
-        max_borrow_rate: 15,
-        fees: ReserveFees {
-            borrow_fee_wad: 1_000_000_000_000, // 0.01 bp
-            host_fee_percentage: 20,
-        },
-    };
+fn pubkey_arg<'a, 'b>(name: &'a str, help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name(name)
+        .long(name)
+        .value_name("PUBKEY")
+        .takes_value(true)
+        .validator(is_pubkey)
+        .help(help)
+}
 
-    let (sol_reserve_pubkey, _sol_reserve) = create_reserve(
-        &mut client,
-        sol_reserve_config,
-        lending_market_pubkey,
-        &lending_market_owner,
-        Some(sol_usdc_dex_market.pubkey),
-        sol_liquidity_source,
-        &payer,
-    );
+fn owner_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("owner")
+        .long("owner")
+        .value_name("PATH")
+        .takes_value(true)
+        .help("Lending market owner keypair [default: payer]")
+}
 
-    println!("Created sol reserve with pubkey: {}", sol_reserve_pubkey);
+fn config_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("config")
+        .long("config")
+        .value_name("PATH")
+        .takes_value(true)
+        .required(true)
+        .help("Reserve config, TOML or JSON")
+}
 
-    let srm_liquidity_source = Pubkey::from_str(SRM_TOKEN_ACCOUNT).unwrap();
-    let srm_reserve_config = ReserveConfig {
-        optimal_utilization_rate: 0,
-        loan_to_value_ratio: 75,
-        liquidation_bonus: 10,
-        liquidation_threshold: 80,
-        min_borrow_rate: 0,
-        optimal_borrow_rate: 2,
-        max_borrow_rate: 15,
-        fees: ReserveFees {
-            borrow_fee_wad: 10_000_000_000_000, // 0.1 bp
-            host_fee_percentage: 25,
-        },
-    };
+fn amount_arg<'a, 'b>(name: &'a str, help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name(name)
+        .long(name)
+        .value_name("AMOUNT")
+        .takes_value(true)
+        .required(true)
+        .help(help)
+}
 
-    let (srm_reserve_pubkey, _srm_reserve) = create_reserve(
-        &mut client,
-        srm_reserve_config,
-        lending_market_pubkey,
-        &lending_market_owner,
-        Some(srm_usdc_dex_market.pubkey),
-        srm_liquidity_source,
-        &payer,
-    );
+fn market_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("market")
+        .long("market")
+        .value_name("PATH")
+        .takes_value(true)
+        .help("Memory and swap pool accounts of the market, TOML or JSON")
+}
 
-    println!("Created srm reserve with pubkey: {}", srm_reserve_pubkey);
+fn margin_app<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("margin")
+        .about("Trade with margin accounts")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("init")
+                .about("Create a margin account for the payer")
+                .arg(
+                    pubkey_arg("mint", "Create a vault for this mint")
+                        .multiple(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("init-obligation")
+                .about("Create the obligation a margin account borrows through")
+                .arg(pubkey_arg("margin-account", "Margin account").required(true))
+                .arg(pubkey_arg("deposit-reserve", "Reserve of the collateral").required(true))
+                .arg(pubkey_arg("borrow-reserve", "Reserve of the loan").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("open")
+                .about("Borrow against collateral and trade the loan")
+                .arg(pubkey_arg("margin-account", "Margin account").required(true))
+                .arg(pubkey_arg("obligation", "Obligation of the margin account").required(true))
+                .arg(market_arg().required(true))
+                .arg(
+                    pubkey_arg(
+                        "collateral-account",
+                        "Payer token account of the collateral",
+                    )
+                    .required(true),
+                )
+                .arg(amount_arg("collateral-amount", "Collateral to deposit"))
+                .arg(amount_arg("loan-amount", "Amount to borrow"))
+                .arg(amount_arg("min-out", "Least amount the trade may return")),
+        )
+        .subcommand(
+            SubCommand::with_name("close")
+                .about("Trade a position back and repay its loan")
+                .arg(pubkey_arg("margin-account", "Margin account").required(true))
+                .arg(pubkey_arg("obligation", "Obligation of the margin account").required(true))
+                .arg(market_arg().required(true))
+                .arg(
+                    pubkey_arg(
+                        "collateral-account",
+                        "Token account receiving the collateral",
+                    )
+                    .required(true),
+                )
+                .arg(amount_arg("min-out", "Least amount the trade may return")),
+        )
+        .subcommand(
+            SubCommand::with_name("withdraw")
+                .about("Withdraw from a margin account vault")
+                .arg(pubkey_arg("margin-account", "Margin account").required(true))
+                .arg(pubkey_arg("vault", "Vault to withdraw from").required(true))
+                .arg(pubkey_arg("destination", "Token account to withdraw to").required(true))
+                .arg(amount_arg("amount", "Amount to withdraw")),
+        )
+        .subcommand(
+            SubCommand::with_name("show")
+                .about("Print a margin account and its position")
+                .arg(pubkey_arg("margin-account", "Margin account").required(true))
+                .arg(pubkey_arg("obligation", "Obligation of the margin account"))
+                .arg(market_arg()),
+        )
 }
 
-pub fn create_lending_market(
-    client: &mut RpcClient,
-    quote_token_mint: Pubkey,
-    payer: &Keypair,
-) -> (Keypair, Pubkey, LendingMarket) {
-    let owner = read_keypair_file(&format!("{}/lending_market_owner.json", KEYPAIR_PATH)).unwrap();
-    let keypair = Keypair::new();
-    let pubkey = keypair.pubkey();
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new(crate_name!())
+        .about(crate_description!())
+        .version(crate_version!())
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("url")
+                .long("url")
+                .value_name("URL")
+                .takes_value(true)
+                .global(true)
+                .default_value("http://localhost:8899")
+                .help("JSON RPC URL of the cluster"),
+        )
+        .arg(
+            Arg::with_name("keypair")
+                .long("keypair")
+                .value_name("PATH")
+                .takes_value(true)
+                .global(true)
+                .help("Fee payer keypair [default: ~/.config/solana/id.json]"),
+        )
+        .arg(
+            pubkey_arg(
+                "program-id",
+                "Lending program id [default: spl_token_lending::id()]",
+            )
+            .global(true),
+        )
+        .arg(pubkey_arg("margin-program-id", "Margin program id").global(true))
+        .subcommand(
+            SubCommand::with_name("create-market")
+                .about("Create a lending market")
+                .arg(pubkey_arg("quote-mint", "Quote token mint").required(true))
+                .arg(pubkey_arg("owner", "Lending market owner [default: payer]")),
+        )
+        .subcommand(
+            SubCommand::with_name("add-reserve")
+                .about("Add a reserve to a lending market, funded from a payer token account")
+                .arg(pubkey_arg("market", "Lending market").required(true))
+                .arg(owner_arg())
+                .arg(config_arg())
+                .arg(
+                    pubkey_arg("source", "Token account providing the initial liquidity")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("amount")
+                        .long("amount")
+                        .value_name("AMOUNT")
+                        .takes_value(true)
+                        .help("Initial liquidity [default: source balance]"),
+                )
+                .arg(pubkey_arg(
+                    "dex-market",
+                    "Serum market against the quote token, not needed for the quote reserve",
+                ))
+                .arg(
+                    pubkey_arg(
+                        "swap-pool",
+                        "Token swap pool against the quote token to price the reserve off instead",
+                    )
+                    .conflicts_with("dex-market"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("update-reserve")
+                .about("Replace the config of a reserve")
+                .arg(pubkey_arg("reserve", "Reserve").required(true))
+                .arg(owner_arg())
+                .arg(config_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("withdraw-reserve-fees")
+                .about("Withdraw the reserve factor's share of interest kept by a reserve")
+                .arg(pubkey_arg("reserve", "Reserve").required(true))
+                .arg(owner_arg())
+                .arg(
+                    pubkey_arg("destination", "Token account to withdraw the fees to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("init-price-accumulator")
+                .about("Create a price accumulator for the market a reserve is priced off")
+                .arg(pubkey_arg("reserve", "Reserve").required(true))
+                .arg(owner_arg())
+                .arg(
+                    Arg::with_name("observation-interval")
+                        .long("observation-interval")
+                        .value_name("SLOTS")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Minimum slots between two recorded observations"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("crank-price-accumulator")
+                .about("Sample the market price into a price accumulator")
+                .arg(pubkey_arg("price-accumulator", "Price accumulator").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("show-price-accumulator")
+                .about("Print a price accumulator")
+                .arg(pubkey_arg("price-accumulator", "Price accumulator").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("init-rate-history")
+                .about("Create a rate history recording snapshots of a reserve's rates")
+                .arg(pubkey_arg("reserve", "Reserve").required(true))
+                .arg(owner_arg())
+                .arg(
+                    Arg::with_name("snapshot-interval")
+                        .long("snapshot-interval")
+                        .value_name("SLOTS")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Minimum slots between two recorded snapshots"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("crank-rate-history")
+                .about("Accrue interest on a reserve and record a snapshot into its rate history")
+                .arg(pubkey_arg("rate-history", "Rate history").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("show-rate-history")
+                .about("Print a rate history")
+                .arg(pubkey_arg("rate-history", "Rate history").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("show-market")
+                .about("Print a lending market")
+                .arg(pubkey_arg("market", "Lending market").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("show-reserve")
+                .about("Print a reserve")
+                .arg(pubkey_arg("reserve", "Reserve").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("show-obligation")
+                .about("Print an obligation")
+                .arg(pubkey_arg("obligation", "Obligation").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Create or update a deployment to match a manifest")
+                .arg(
+                    Arg::with_name("manifest")
+                        .long("manifest")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Deployment manifest, TOML or JSON"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Addresses of the deployment, read back by later applies"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Print the changes without sending them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("explain")
+                .about("Decode the lending and margin instructions of a transaction")
+                .arg(
+                    Arg::with_name("transaction")
+                        .long("transaction")
+                        .value_name("DATA")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Serialized transaction, base58 or base64"),
+                )
+                .arg(
+                    Arg::with_name("error")
+                        .long("error")
+                        .value_name("CODE")
+                        .takes_value(true)
+                        .help("Custom error code the transaction failed with"),
+                )
+                .arg(
+                    Arg::with_name("instruction")
+                        .long("instruction")
+                        .value_name("INDEX")
+                        .takes_value(true)
+                        .help("Index of the instruction that failed [default: any]"),
+                ),
+        )
+        .subcommand(margin_app())
+}
 
-    let mut transaction = Transaction::new_with_payer(
-        &[
-            create_account(
-                &payer.pubkey(),
-                &pubkey,
-                client
-                    .get_minimum_balance_for_rent_exemption(LendingMarket::LEN)
-                    .unwrap(),
-                LendingMarket::LEN as u64,
-                &id(),
-            ),
-            init_lending_market(id(), pubkey, owner.pubkey(), quote_token_mint),
-        ],
-        Some(&payer.pubkey()),
-    );
+fn run(matches: &ArgMatches) -> Result<(), Error> {
+    let (command, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.unwrap();
 
-    let recent_blockhash = client.get_recent_blockhash().unwrap().0;
-    transaction.sign(&[&payer, &keypair], recent_blockhash);
-    client.send_and_confirm_transaction(&transaction).unwrap();
+    let keypair_path = match sub_matches.value_of("keypair") {
+        Some(path) => path.to_owned(),
+        None => format!(
+            "{}/.config/solana/id.json",
+            std::env::var("HOME").unwrap_or_else(|_| ".".to_owned())
+        ),
+    };
+    let url = sub_matches.value_of("url").unwrap().to_owned();
+    let config = Config {
+        rpc_client: RpcClient::new(url.clone()),
+        url,
+        payer: read_keypair_file(&keypair_path)
+            .map_err(|err| format!("Failed to read {}: {}", keypair_path, err))?,
+        program_id: pubkey_of(sub_matches, "program-id").unwrap_or_else(spl_token_lending::id),
+        margin_program_id: pubkey_of(sub_matches, "margin-program-id"),
+    };
 
-    let account = client.get_account(&pubkey).unwrap();
-    let lending_market = LendingMarket::unpack(&account.data).unwrap();
+    match command {
+        "create-market" => {
+            let quote_token_mint = pubkey_of(sub_matches, "quote-mint").unwrap();
+            let owner = pubkey_of(sub_matches, "owner").unwrap_or_else(|| config.payer.pubkey());
+            let (pubkey, _) = lending::create_lending_market(&config, quote_token_mint, owner)?;
+            println!("Created lending market: {}", pubkey);
+        }
+        "add-reserve" => {
+            let owner = keypair_of(sub_matches, "owner")?;
+            let owner = owner.as_ref().unwrap_or(&config.payer);
+            let reserve_config =
+                config::load_reserve_config(sub_matches.value_of("config").unwrap())?;
+            let amount = match sub_matches.value_of("amount") {
+                Some(_) => Some(value_t!(sub_matches, "amount", u64)?),
+                None => None,
+            };
+            let (price_market, price_source) = match pubkey_of(sub_matches, "swap-pool") {
+                Some(swap_pool) => (Some(swap_pool), PriceSource::TokenSwap),
+                None => (pubkey_of(sub_matches, "dex-market"), PriceSource::DexMarket),
+            };
+            let (pubkey, _) = lending::create_reserve(
+                &config,
+                reserve_config,
+                pubkey_of(sub_matches, "market").unwrap(),
+                owner,
+                price_market,
+                price_source,
+                pubkey_of(sub_matches, "source").unwrap(),
+                amount,
+            )?;
+            println!("Created reserve: {}", pubkey);
+        }
+        "update-reserve" => {
+            let owner = keypair_of(sub_matches, "owner")?;
+            let owner = owner.as_ref().unwrap_or(&config.payer);
+            let reserve_config =
+                config::load_reserve_config(sub_matches.value_of("config").unwrap())?;
+            let reserve_pubkey = pubkey_of(sub_matches, "reserve").unwrap();
+            lending::update_reserve(&config, reserve_config, reserve_pubkey, owner)?;
+            println!("Updated reserve: {}", reserve_pubkey);
+        }
+        "withdraw-reserve-fees" => {
+            let owner = keypair_of(sub_matches, "owner")?;
+            let owner = owner.as_ref().unwrap_or(&config.payer);
+            let amount = lending::withdraw_fees(
+                &config,
+                pubkey_of(sub_matches, "reserve").unwrap(),
+                owner,
+                pubkey_of(sub_matches, "destination").unwrap(),
+            )?;
+            println!("Withdrew reserve fees: {}", amount);
+        }
+        "init-price-accumulator" => {
+            let owner = keypair_of(sub_matches, "owner")?;
+            let owner = owner.as_ref().unwrap_or(&config.payer);
+            let observation_interval = value_t!(sub_matches, "observation-interval", u64)?;
+            let (pubkey, _) = lending::create_price_accumulator(
+                &config,
+                pubkey_of(sub_matches, "reserve").unwrap(),
+                owner,
+                observation_interval,
+            )?;
+            println!("Created price accumulator: {}", pubkey);
+        }
+        "crank-price-accumulator" => {
+            let pubkey = pubkey_of(sub_matches, "price-accumulator").unwrap();
+            let price_accumulator = lending::crank_price_accumulator(&config, pubkey)?;
+            println!(
+                "Updated price accumulator: {} at slot {}, price {}",
+                pubkey, price_accumulator.last_update_slot, price_accumulator.last_price
+            );
+        }
+        "show-price-accumulator" => lending::show_price_accumulator(
+            &config,
+            &pubkey_of(sub_matches, "price-accumulator").unwrap(),
+        )?,
+        "init-rate-history" => {
+            let owner = keypair_of(sub_matches, "owner")?;
+            let owner = owner.as_ref().unwrap_or(&config.payer);
+            let snapshot_interval = value_t!(sub_matches, "snapshot-interval", u64)?;
+            let (pubkey, _) = lending::create_rate_history(
+                &config,
+                pubkey_of(sub_matches, "reserve").unwrap(),
+                owner,
+                snapshot_interval,
+            )?;
+            println!("Created rate history: {}", pubkey);
+        }
+        "crank-rate-history" => {
+            let pubkey = pubkey_of(sub_matches, "rate-history").unwrap();
+            let rate_history = lending::crank_rate_history(&config, pubkey)?;
+            match rate_history.latest() {
+                Some(snapshot) => println!(
+                    "Updated rate history: {} at slot {}, borrow rate {}",
+                    pubkey, snapshot.slot, snapshot.borrow_rate
+                ),
+                None => println!("Updated rate history: {}", pubkey),
+            }
+        }
+        "show-rate-history" => {
+            lending::show_rate_history(&config, &pubkey_of(sub_matches, "rate-history").unwrap())?
+        }
+        "show-market" => {
+            lending::show_lending_market(&config, &pubkey_of(sub_matches, "market").unwrap())?
+        }
+        "show-reserve" => {
+            lending::show_reserve(&config, &pubkey_of(sub_matches, "reserve").unwrap())?
+        }
+        "show-obligation" => {
+            lending::show_obligation(&config, &pubkey_of(sub_matches, "obligation").unwrap())?
+        }
+        "apply" => manifest::apply(
+            &config,
+            sub_matches.value_of("manifest").unwrap(),
+            sub_matches.value_of("output").unwrap(),
+            sub_matches.is_present("dry-run"),
+        )?,
+        "explain" => {
+            let error = match sub_matches.value_of("error") {
+                Some(_) => Some(value_t!(sub_matches, "error", u32)?),
+                None => None,
+            };
+            let instruction = match sub_matches.value_of("instruction") {
+                Some(_) => Some(value_t!(sub_matches, "instruction", usize)?),
+                None => None,
+            };
+            explain(
+                &config,
+                sub_matches.value_of("transaction").unwrap(),
+                error,
+                instruction,
+            )?
+        }
+        "margin" => run_margin(&config, sub_matches)?,
+        _ => unreachable!(),
+    }
 
-    (owner, pubkey, lending_market)
+    Ok(())
 }
 
-pub fn create_reserve(
-    client: &mut RpcClient,
-    config: ReserveConfig,
-    lending_market_pubkey: Pubkey,
-    lending_market_owner: &Keypair,
-    dex_market_pubkey: Option<Pubkey>,
-    liquidity_source_pubkey: Pubkey,
-    payer: &Keypair,
-) -> (Pubkey, Reserve) {
-    let reserve_keypair = Keypair::new();
-    let reserve_pubkey = reserve_keypair.pubkey();
-    let collateral_mint_keypair = Keypair::new();
-    let collateral_supply_keypair = Keypair::new();
-    let collateral_fees_receiver_keypair = Keypair::new();
-    let liquidity_supply_keypair = Keypair::new();
-    let user_collateral_token_keypair = Keypair::new();
-    let user_transfer_authority = Keypair::new();
-
-    let liquidity_source_account = client.get_account(&liquidity_source_pubkey).unwrap();
-    let liquidity_source_token = Token::unpack(&liquidity_source_account.data).unwrap();
-    let liquidity_mint_pubkey = liquidity_source_token.mint;
+fn explain(
+    config: &Config,
+    encoded: &str,
+    error: Option<u32>,
+    failed_instruction: Option<usize>,
+) -> Result<(), Error> {
+    let explainer = Explainer::new(config.program_id, config.margin_program_id);
+    let transaction = decode_transaction(encoded)?;
+    let instructions = explainer.explain_transaction(&transaction)?;
+    for (index, instruction) in instructions.iter().enumerate() {
+        print!("#{} {}", index, instruction);
+    }
 
-    let recent_blockhash = client.get_recent_blockhash().unwrap().0;
-    let token_balance = client
-        .get_minimum_balance_for_rent_exemption(Token::LEN)
-        .unwrap();
-
-    let mut transaction = Transaction::new_with_payer(
-        &[
-            create_account(
-                &payer.pubkey(),
-                &collateral_mint_keypair.pubkey(),
-                client
-                    .get_minimum_balance_for_rent_exemption(Mint::LEN)
-                    .unwrap(),
-                Mint::LEN as u64,
-                &spl_token::id(),
-            ),
-            create_account(
-                &payer.pubkey(),
-                &collateral_supply_keypair.pubkey(),
-                token_balance,
-                Token::LEN as u64,
-                &spl_token::id(),
-            ),
-            create_account(
-                &payer.pubkey(),
-                &collateral_fees_receiver_keypair.pubkey(),
-                token_balance,
-                Token::LEN as u64,
-                &spl_token::id(),
-            ),
-            create_account(
-                &payer.pubkey(),
-                &liquidity_supply_keypair.pubkey(),
-                token_balance,
-                Token::LEN as u64,
-                &spl_token::id(),
-            ),
-            create_account(
-                &payer.pubkey(),
-                &user_collateral_token_keypair.pubkey(),
-                token_balance,
-                Token::LEN as u64,
-                &spl_token::id(),
-            ),
-            create_account(
-                &payer.pubkey(),
-                &reserve_pubkey,
-                client
-                    .get_minimum_balance_for_rent_exemption(Reserve::LEN)
-                    .unwrap(),
-                Reserve::LEN as u64,
-                &id(),
-            ),
-        ],
-        Some(&payer.pubkey()),
-    );
-
-    transaction.sign(
-        &vec![
-            payer,
-            &reserve_keypair,
-            &collateral_mint_keypair,
-            &collateral_supply_keypair,
-            &liquidity_supply_keypair,
-            &user_collateral_token_keypair,
-        ],
-        recent_blockhash,
-    );
+    if let Some(code) = error {
+        // Without the failed instruction, try the programs of every instruction
+        let candidates = match failed_instruction {
+            Some(index) => vec![instructions
+                .get(index)
+                .ok_or_else(|| format!("Transaction has no instruction {}", index))?],
+            None => instructions.iter().collect(),
+        };
+        let mut messages = candidates
+            .iter()
+            .filter_map(|instruction| {
+                let message = explainer.explain_error(&instruction.program_id, code)?;
+                Some(format!("{}: {}", instruction.program?, message))
+            })
+            .collect::<Vec<_>>();
+        messages.dedup();
+        if messages.is_empty() {
+            println!("Error {}: unknown", code);
+        }
+        for message in messages {
+            println!("Error {}: {}", code, message);
+        }
+    }
+    Ok(())
+}
 
-    client.send_and_confirm_transaction(&transaction).unwrap();
+fn run_margin(config: &Config, matches: &ArgMatches) -> Result<(), Error> {
+    let (command, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.unwrap();
+    let market = match sub_matches.value_of("market") {
+        Some(path) => Some(config::load::<margin::MarginMarket>(path)?),
+        None => None,
+    };
 
-    let mut transaction = Transaction::new_with_payer(
-        &[
-            approve(
-                &spl_token::id(),
-                &liquidity_source_pubkey,
-                &user_transfer_authority.pubkey(),
-                &payer.pubkey(),
-                &[],
-                liquidity_source_token.amount,
-            )
-            .unwrap(),
-            init_reserve(
-                id(),
-                liquidity_source_token.amount,
+    match command {
+        "init" => {
+            let mints = match sub_matches.values_of("mint") {
+                Some(values) => values
+                    .map(|value| Pubkey::from_str(value).unwrap())
+                    .collect(),
+                None => vec![],
+            };
+            let pubkey = margin::init(config, &mints)?;
+            println!("Created margin account: {}", pubkey);
+        }
+        "init-obligation" => {
+            let pubkey = margin::init_obligation(
                 config,
-                liquidity_source_pubkey,
-                user_collateral_token_keypair.pubkey(),
-                reserve_pubkey,
-                liquidity_mint_pubkey,
-                liquidity_supply_keypair.pubkey(),
-                collateral_mint_keypair.pubkey(),
-                collateral_supply_keypair.pubkey(),
-                collateral_fees_receiver_keypair.pubkey(),
-                lending_market_pubkey,
-                lending_market_owner.pubkey(),
-                user_transfer_authority.pubkey(),
-                dex_market_pubkey,
-            ),
-        ],
-        Some(&payer.pubkey()),
-    );
-
-    transaction.sign(
-        &vec![payer, &lending_market_owner, &user_transfer_authority],
-        recent_blockhash,
-    );
+                &pubkey_of(sub_matches, "margin-account").unwrap(),
+                &pubkey_of(sub_matches, "deposit-reserve").unwrap(),
+                &pubkey_of(sub_matches, "borrow-reserve").unwrap(),
+            )?;
+            println!("Created obligation: {}", pubkey);
+        }
+        "open" => margin::open(
+            config,
+            market.as_ref().unwrap(),
+            &pubkey_of(sub_matches, "margin-account").unwrap(),
+            &pubkey_of(sub_matches, "obligation").unwrap(),
+            &pubkey_of(sub_matches, "collateral-account").unwrap(),
+            value_t!(sub_matches, "collateral-amount", u64)?,
+            value_t!(sub_matches, "loan-amount", u64)?,
+            value_t!(sub_matches, "min-out", u64)?,
+        )?,
+        "close" => margin::close(
+            config,
+            market.as_ref().unwrap(),
+            &pubkey_of(sub_matches, "margin-account").unwrap(),
+            &pubkey_of(sub_matches, "obligation").unwrap(),
+            &pubkey_of(sub_matches, "collateral-account").unwrap(),
+            value_t!(sub_matches, "min-out", u64)?,
+        )?,
+        "withdraw" => margin::withdraw(
+            config,
+            &pubkey_of(sub_matches, "margin-account").unwrap(),
+            &pubkey_of(sub_matches, "vault").unwrap(),
+            &pubkey_of(sub_matches, "destination").unwrap(),
+            value_t!(sub_matches, "amount", u64)?,
+        )?,
+        "show" => margin::show(
+            config,
+            &pubkey_of(sub_matches, "margin-account").unwrap(),
+            pubkey_of(sub_matches, "obligation").as_ref(),
+            market.as_ref(),
+        )?,
+        _ => unreachable!(),
+    }
 
-    client.send_and_confirm_transaction(&transaction).unwrap();
+    Ok(())
+}
 
-    let account = client.get_account(&reserve_pubkey).unwrap();
-    (reserve_pubkey, Reserve::unpack(&account.data).unwrap())
+pub fn main() {
+    let matches = app().get_matches();
+    if let Err(err) = run(&matches) {
+        eprintln!("error: {}", err);
+        exit(1);
+    }
 }
@@ -0,0 +1,625 @@
+//! Declarative deployment of a lending market and the margin program's whitelist
+//!
+//! A manifest describes the desired lending market, reserves, margin `State` authority and
+//! whitelisted pairs. `apply` compares it with the accounts on chain and only sends the
+//! instructions creating or updating what differs, so it can be run repeatedly against the same
+//! cluster.
+
+use entropy_sdk::{margin, pda, Client, SdkError};
+use margin_account::{RiskParams, TokenPair};
+use serde::{Deserialize, Serialize};
+use solana_program::{
+    instruction::Instruction, program_option::COption, program_pack::Pack, pubkey::Pubkey,
+};
+use solana_sdk::{
+    signature::{read_keypair_file, Keypair, Signer},
+    transaction::Transaction,
+};
+use spl_token_lending::state::{PriceSource, Reserve, ReserveConfig};
+use std::{collections::BTreeMap, convert::TryFrom, path::Path};
+
+use crate::{
+    config::{self, pubkey_string, ReserveConfigFile},
+    lending, Config, Error,
+};
+
+/// Desired deployment
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Existing lending market to manage, created if omitted and not in the output file
+    #[serde(default, with = "pubkey_string::option")]
+    pub lending_market: Option<Pubkey>,
+    /// Quote token mint of the lending market
+    #[serde(with = "pubkey_string")]
+    pub quote_mint: Pubkey,
+    /// Lending market owner keypair, defaults to the payer
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Reserves of the lending market
+    #[serde(default)]
+    pub reserves: Vec<ReserveManifest>,
+    /// Margin program whitelist
+    #[serde(default)]
+    pub margin: Option<MarginManifest>,
+}
+
+/// Desired reserve, identified by its liquidity mint
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReserveManifest {
+    /// Name used for the reserve in pairs and the output file
+    pub name: String,
+    /// Liquidity mint
+    #[serde(with = "pubkey_string")]
+    pub mint: Pubkey,
+    /// Payer token account funding a new reserve
+    #[serde(default, with = "pubkey_string::option")]
+    pub source: Option<Pubkey>,
+    /// Initial liquidity of a new reserve, defaults to the source balance
+    #[serde(default)]
+    pub liquidity_amount: Option<u64>,
+    /// Serum market against the quote token, not needed for the quote reserve
+    #[serde(default, with = "pubkey_string::option")]
+    pub dex_market: Option<Pubkey>,
+    /// Token swap pool against the quote token to price the reserve off instead of `dex_market`
+    #[serde(default, with = "pubkey_string::option")]
+    pub swap_pool: Option<Pubkey>,
+    /// Reserve configuration
+    pub config: ReserveConfigFile,
+}
+
+/// Desired margin program state
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MarginManifest {
+    /// Margin program id
+    #[serde(with = "pubkey_string")]
+    pub program_id: Pubkey,
+    /// Whitelist authority keypair, defaults to the payer. Creates the state if it doesn't exist.
+    #[serde(default)]
+    pub authority: Option<String>,
+    /// Whitelisted pairs
+    #[serde(default)]
+    pub pairs: Vec<PairManifest>,
+}
+
+/// Desired whitelisted pair
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PairManifest {
+    /// Base token, a reserve name or a mint
+    pub base: String,
+    /// Quote token, a reserve name or a mint
+    pub quote: String,
+    /// Token swap pool positions on the pair trade through, needed to whitelist the pair
+    #[serde(default, with = "pubkey_string::option")]
+    pub swap_pool: Option<Pubkey>,
+    /// Risk parameters of the pair
+    pub risk_params: RiskParamsFile,
+    /// Whether new positions can be opened on the pair
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+/// Margin `RiskParams` as written in a manifest
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RiskParamsFile {
+    pub max_leverage: u8,
+    pub maintenance_margin: u8,
+    pub liquidation_fee: u8,
+    pub max_slippage: u8,
+    pub max_open_interest: u64,
+}
+
+impl From<RiskParamsFile> for RiskParams {
+    fn from(params: RiskParamsFile) -> Self {
+        Self {
+            max_leverage: params.max_leverage,
+            maintenance_margin: params.maintenance_margin,
+            liquidation_fee: params.liquidation_fee,
+            max_slippage: params.max_slippage,
+            max_open_interest: params.max_open_interest,
+        }
+    }
+}
+
+impl From<&RiskParams> for RiskParamsFile {
+    fn from(params: &RiskParams) -> Self {
+        Self {
+            max_leverage: params.max_leverage,
+            maintenance_margin: params.maintenance_margin,
+            liquidation_fee: params.liquidation_fee,
+            max_slippage: params.max_slippage,
+            max_open_interest: params.max_open_interest,
+        }
+    }
+}
+
+/// Addresses of a deployment, written after every apply
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Deployment {
+    /// Lending market
+    #[serde(default, with = "pubkey_string::option")]
+    pub lending_market: Option<Pubkey>,
+    /// Reserves by name
+    #[serde(default)]
+    pub reserves: BTreeMap<String, String>,
+    /// Margin program state
+    #[serde(default, with = "pubkey_string::option")]
+    pub margin_state: Option<Pubkey>,
+    /// Whitelisted pairs by `base/quote`
+    #[serde(default)]
+    pub token_pairs: BTreeMap<String, String>,
+}
+
+/// Change needed to bring an account in line with the manifest
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change<T> {
+    /// Account doesn't exist
+    Create,
+    /// Account exists with other values
+    Update(T),
+    /// Account matches
+    None,
+}
+
+/// Change bringing a reserve to `config`. The program cannot move a reserve to another market, so
+/// a reserve priced off a market other than `price_market` is an error.
+pub fn reserve_change(
+    existing: Option<&Reserve>,
+    config: &ReserveConfig,
+    price_market: Option<Pubkey>,
+    price_source: PriceSource,
+) -> Result<Change<ReserveConfig>, String> {
+    let reserve = match existing {
+        Some(reserve) => reserve,
+        None => return Ok(Change::Create),
+    };
+    let existing_market = match reserve.dex_market {
+        COption::Some(market) => Some(market),
+        COption::None => None,
+    };
+    if existing_market != price_market
+        || (price_market.is_some() && reserve.price_source != price_source)
+    {
+        return Err(format!(
+            "priced off {} on chain, not {}, and a reserve's market cannot be changed",
+            describe_price_market(existing_market, reserve.price_source),
+            describe_price_market(price_market, price_source)
+        ));
+    }
+    if &reserve.config != config {
+        Ok(Change::Update(*config))
+    } else {
+        Ok(Change::None)
+    }
+}
+
+fn describe_price_market(market: Option<Pubkey>, price_source: PriceSource) -> String {
+    match (market, price_source) {
+        (None, _) => "no market".to_owned(),
+        (Some(market), PriceSource::DexMarket) => format!("dex market {}", market),
+        (Some(market), PriceSource::TokenSwap) => format!("swap pool {}", market),
+    }
+}
+
+/// Changes bringing a token pair to the manifest's risk parameters and enabled flag
+pub fn token_pair_changes(
+    existing: Option<&TokenPair>,
+    pair: &PairManifest,
+) -> Vec<Change<PairUpdate>> {
+    let token_pair = match existing {
+        Some(token_pair) => token_pair,
+        None if pair.enabled => return vec![Change::Create],
+        None => return vec![Change::Create, Change::Update(PairUpdate::Enabled(false))],
+    };
+    let mut changes = Vec::new();
+    if RiskParamsFile::from(&token_pair.risk_params) != pair.risk_params {
+        changes.push(Change::Update(PairUpdate::RiskParams(pair.risk_params)));
+    }
+    if token_pair.enabled != pair.enabled {
+        changes.push(Change::Update(PairUpdate::Enabled(pair.enabled)));
+    }
+    changes
+}
+
+/// Update of an existing token pair
+#[derive(Clone, Debug, PartialEq)]
+pub enum PairUpdate {
+    /// New risk parameters
+    RiskParams(RiskParamsFile),
+    /// Enable or disable the pair
+    Enabled(bool),
+}
+
+/// Bring the cluster in line with the manifest at `manifest_path` and write the resulting
+/// addresses to `output_path`. With `dry_run` only the planned changes are printed.
+pub fn apply(
+    config: &Config,
+    manifest_path: &str,
+    output_path: &str,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let manifest: Manifest = config::load(manifest_path)?;
+    let mut deployment: Deployment = if Path::new(output_path).exists() {
+        config::load(output_path)?
+    } else {
+        Deployment::default()
+    };
+    let owner = read_optional_keypair(manifest.owner.as_deref())?;
+    let owner = owner.as_ref().unwrap_or(&config.payer);
+
+    // Lending market
+    let lending_market = match manifest.lending_market.or(deployment.lending_market) {
+        Some(pubkey) => {
+            let lending_market = lending::get_lending_market(&config.rpc_client, &pubkey)?;
+            if lending_market.quote_token_mint != manifest.quote_mint {
+                return Err(format!(
+                    "Lending market {} has quote mint {}, not {}",
+                    pubkey, lending_market.quote_token_mint, manifest.quote_mint
+                )
+                .into());
+            }
+            if lending_market.owner != owner.pubkey() {
+                return Err(format!(
+                    "Lending market {} is owned by {}, not {}",
+                    pubkey,
+                    lending_market.owner,
+                    owner.pubkey()
+                )
+                .into());
+            }
+            pubkey
+        }
+        None if dry_run => {
+            println!("create lending market");
+            Pubkey::default()
+        }
+        None => {
+            let (pubkey, _) =
+                lending::create_lending_market(config, manifest.quote_mint, owner.pubkey())?;
+            println!("created lending market {}", pubkey);
+            pubkey
+        }
+    };
+    deployment.lending_market = Some(lending_market);
+
+    // Reserves
+    let existing_reserves = reserves_by_mint(config, &lending_market)?;
+    let mut mints = BTreeMap::new();
+    for reserve in &manifest.reserves {
+        mints.insert(reserve.name.clone(), reserve.mint);
+        let reserve_config = ReserveConfig::try_from(reserve.config.clone())?;
+        let existing = existing_reserves.get(&reserve.mint);
+        let (price_market, price_source) = match (reserve.dex_market, reserve.swap_pool) {
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "Reserve {} can have a dex market or a swap pool, not both",
+                    reserve.name
+                )
+                .into())
+            }
+            (None, Some(swap_pool)) => (Some(swap_pool), PriceSource::TokenSwap),
+            (dex_market, None) => (dex_market, PriceSource::DexMarket),
+        };
+        let change = reserve_change(
+            existing.map(|(_, reserve)| reserve),
+            &reserve_config,
+            price_market,
+            price_source,
+        )
+        .map_err(|err| format!("Reserve {} is {}", reserve.name, err))?;
+        let pubkey = match (change, existing) {
+            (Change::Create, _) if dry_run => {
+                println!("create reserve {}", reserve.name);
+                continue;
+            }
+            (Change::Create, _) => {
+                let source = reserve.source.ok_or_else(|| {
+                    format!("Reserve {} needs a source to be created", reserve.name)
+                })?;
+                let (pubkey, _) = lending::create_reserve(
+                    config,
+                    reserve_config,
+                    lending_market,
+                    owner,
+                    price_market,
+                    price_source,
+                    source,
+                    reserve.liquidity_amount,
+                )?;
+                println!("created reserve {} {}", reserve.name, pubkey);
+                pubkey
+            }
+            (Change::Update(_), Some((pubkey, _))) => {
+                println!("update reserve {} {}", reserve.name, pubkey);
+                if !dry_run {
+                    lending::update_reserve(config, reserve_config, *pubkey, owner)?;
+                }
+                *pubkey
+            }
+            (_, Some((pubkey, _))) => *pubkey,
+            (_, None) => unreachable!(),
+        };
+        deployment
+            .reserves
+            .insert(reserve.name.clone(), pubkey.to_string());
+    }
+
+    // Margin whitelist
+    if let Some(margin_manifest) = &manifest.margin {
+        apply_margin(
+            config,
+            margin_manifest,
+            &mints,
+            owner,
+            &mut deployment,
+            dry_run,
+        )?;
+    }
+
+    if !dry_run {
+        config::save(output_path, &deployment)?;
+        println!("wrote {}", output_path);
+    }
+    Ok(())
+}
+
+fn apply_margin(
+    config: &Config,
+    manifest: &MarginManifest,
+    mints: &BTreeMap<String, Pubkey>,
+    owner: &Keypair,
+    deployment: &mut Deployment,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let program_id = manifest.program_id;
+    let authority = read_optional_keypair(manifest.authority.as_deref())?;
+    let authority = authority.as_ref().unwrap_or(owner);
+    let client = Client::new(config.connect(), config.program_id, program_id);
+
+    let state_pubkey = pda::margin_state(&program_id);
+    match client.margin_state() {
+        Ok(state) if state.authority != authority.pubkey() => {
+            return Err(format!(
+                "Margin state authority is {}, not {}",
+                state.authority,
+                authority.pubkey()
+            )
+            .into())
+        }
+        Ok(_) => {}
+        Err(SdkError::AccountNotFound(_)) => {
+            println!("create margin state {}", state_pubkey);
+            if !dry_run {
+                send(
+                    config,
+                    &[margin::new_state(
+                        program_id,
+                        config.payer.pubkey(),
+                        authority.pubkey(),
+                    )],
+                    &[authority],
+                )?;
+            }
+        }
+        Err(err) => return Err(err.into()),
+    }
+    deployment.margin_state = Some(state_pubkey);
+
+    for pair in &manifest.pairs {
+        let base = resolve_mint(mints, &pair.base)?;
+        let quote = resolve_mint(mints, &pair.quote)?;
+        let name = format!("{}/{}", pair.base, pair.quote);
+        let (pubkey, _) = pda::find_token_pair(&base, &quote, &program_id);
+        let existing = match client.token_pair(&base, &quote) {
+            Ok(token_pair) => Some(token_pair),
+            Err(SdkError::AccountNotFound(_)) => None,
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(token_pair) = &existing {
+            if token_pair.first_token != base {
+                return Err(
+                    format!("Pair {} is whitelisted with the tokens reversed", name).into(),
+                );
+            }
+        }
+
+        let mut instructions = Vec::new();
+        for change in token_pair_changes(existing.as_ref(), pair) {
+            let instruction = match change {
+                Change::Create => {
+                    println!("whitelist pair {}", name);
+                    let swap_pool = pair
+                        .swap_pool
+                        .ok_or_else(|| format!("Pair {} needs a swap_pool", name))?;
+                    margin::add_token_pair(
+                        program_id,
+                        authority.pubkey(),
+                        base,
+                        quote,
+                        swap_pool,
+                        pair.risk_params.into(),
+                    )
+                }
+                Change::Update(PairUpdate::RiskParams(risk_params)) => {
+                    println!("update risk params of pair {}", name);
+                    margin::set_risk_params(
+                        program_id,
+                        authority.pubkey(),
+                        pubkey,
+                        risk_params.into(),
+                    )
+                }
+                Change::Update(PairUpdate::Enabled(enabled)) => {
+                    println!("set pair {} enabled: {}", name, enabled);
+                    margin::set_pair_enabled(program_id, authority.pubkey(), pubkey, enabled)
+                }
+                Change::None => continue,
+            };
+            instructions.push(instruction);
+        }
+        if !dry_run && !instructions.is_empty() {
+            send(config, &instructions, &[authority])?;
+        }
+        deployment.token_pairs.insert(name, pubkey.to_string());
+    }
+
+    Ok(())
+}
+
+/// Reserves of a lending market by liquidity mint
+fn reserves_by_mint(
+    config: &Config,
+    lending_market: &Pubkey,
+) -> Result<BTreeMap<Pubkey, (Pubkey, Reserve)>, Error> {
+    let mut reserves = BTreeMap::new();
+    for (pubkey, account) in config.rpc_client.get_program_accounts(&config.program_id)? {
+        if account.data.len() != Reserve::LEN {
+            continue;
+        }
+        if let Ok(reserve) = Reserve::unpack(&account.data) {
+            if &reserve.lending_market == lending_market {
+                reserves.insert(reserve.liquidity.mint_pubkey, (pubkey, reserve));
+            }
+        }
+    }
+    Ok(reserves)
+}
+
+fn resolve_mint(mints: &BTreeMap<String, Pubkey>, token: &str) -> Result<Pubkey, Error> {
+    match mints.get(token) {
+        Some(mint) => Ok(*mint),
+        None => token
+            .parse()
+            .map_err(|_| format!("{} is neither a reserve name nor a mint", token).into()),
+    }
+}
+
+fn read_optional_keypair(path: Option<&str>) -> Result<Option<Keypair>, Error> {
+    match path {
+        Some(path) => {
+            Ok(Some(read_keypair_file(path).map_err(|err| {
+                format!("Failed to read {}: {}", path, err)
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn send(config: &Config, instructions: &[Instruction], signers: &[&Keypair]) -> Result<(), Error> {
+    let client = &config.rpc_client;
+    let mut transaction = Transaction::new_with_payer(instructions, Some(&config.payer.pubkey()));
+    let mut all_signers = vec![&config.payer];
+    all_signers.extend(signers.iter().copied());
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(&all_signers, recent_blockhash);
+    client.send_and_confirm_transaction(&transaction)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pair(enabled: bool) -> PairManifest {
+        PairManifest {
+            base: "sol".to_owned(),
+            quote: "usdc".to_owned(),
+            swap_pool: None,
+            risk_params: RiskParamsFile {
+                max_leverage: 3,
+                maintenance_margin: 10,
+                liquidation_fee: 5,
+                max_slippage: 5,
+                max_open_interest: 1_000,
+            },
+            enabled,
+        }
+    }
+
+    #[test]
+    fn example_manifest_parses() {
+        let manifest: Manifest = config::load("config/mainnet.toml").unwrap();
+        assert_eq!(manifest.reserves.len(), 3);
+        assert_eq!(manifest.margin.unwrap().pairs.len(), 2);
+    }
+
+    #[test]
+    fn reserve_changes() {
+        let config = ReserveConfig {
+            loan_to_value_ratio: 75,
+            ..ReserveConfig::default()
+        };
+        let dex_market = Pubkey::new_unique();
+        let mut reserve = Reserve {
+            config,
+            dex_market: COption::Some(dex_market),
+            ..Reserve::default()
+        };
+        let change = |reserve: Option<&Reserve>, market, source| {
+            reserve_change(reserve, &config, market, source)
+        };
+        assert_eq!(
+            change(None, Some(dex_market), PriceSource::DexMarket),
+            Ok(Change::Create)
+        );
+        assert_eq!(
+            change(Some(&reserve), Some(dex_market), PriceSource::DexMarket),
+            Ok(Change::None)
+        );
+
+        reserve.config.loan_to_value_ratio = 60;
+        assert_eq!(
+            change(Some(&reserve), Some(dex_market), PriceSource::DexMarket),
+            Ok(Change::Update(config))
+        );
+
+        // moving a reserve to another market is not supported
+        assert!(change(
+            Some(&reserve),
+            Some(Pubkey::new_unique()),
+            PriceSource::DexMarket
+        )
+        .is_err());
+        assert!(change(Some(&reserve), Some(dex_market), PriceSource::TokenSwap).is_err());
+        assert!(change(Some(&reserve), None, PriceSource::DexMarket).is_err());
+    }
+
+    #[test]
+    fn pair_changes() {
+        assert_eq!(token_pair_changes(None, &pair(true)), vec![Change::Create]);
+        assert_eq!(
+            token_pair_changes(None, &pair(false)),
+            vec![Change::Create, Change::Update(PairUpdate::Enabled(false))]
+        );
+
+        let mut token_pair = TokenPair {
+            first_token: Pubkey::default(),
+            second_token: Pubkey::default(),
+            swap_pool: Pubkey::default(),
+            risk_params: pair(true).risk_params.into(),
+            enabled: true,
+            long_open_interest: 0,
+            short_open_interest: 0,
+            nonce: 0,
+        };
+        assert!(token_pair_changes(Some(&token_pair), &pair(true)).is_empty());
+
+        token_pair.risk_params.max_leverage = 2;
+        token_pair.enabled = false;
+        assert_eq!(
+            token_pair_changes(Some(&token_pair), &pair(true)),
+            vec![
+                Change::Update(PairUpdate::RiskParams(pair(true).risk_params)),
+                Change::Update(PairUpdate::Enabled(true)),
+            ]
+        );
+    }
+}
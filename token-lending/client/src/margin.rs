@@ -0,0 +1,729 @@
+//! Margin account commands
+
+use entropy_sdk::{
+    analytics::{self, Market, PositionSnapshot},
+    margin as margin_instruction, pda, Client,
+};
+use margin_account::{accounts, Direction, FeeConfig, MarginAccount, Status};
+use serde::{Deserialize, Serialize};
+use solana_client::{rpc_client::RpcClient, rpc_request::TokenAccountsFilter};
+use solana_program::{
+    instruction::Instruction, program_option::COption, program_pack::Pack, pubkey::Pubkey, sysvar,
+};
+use solana_sdk::{
+    signature::{Keypair, Signer},
+    system_instruction::create_account,
+    transaction::Transaction,
+};
+use spl_token::{
+    instruction::{approve, initialize_account},
+    state::{Account as Token, Mint},
+};
+use spl_token_lending::{
+    dex_market::{DexMarket, ASKS_OFFSET, BIDS_OFFSET},
+    instruction::accrue_reserve_interest,
+    state::{Obligation, PriceSource, Reserve},
+    swap_pool,
+};
+
+use crate::{config::pubkey_string, Config, Error};
+
+/// Space of a margin account: discriminator, trader, optional position and nonce
+const MARGIN_ACCOUNT_LEN: usize = 8 + 32 + (1 + 123) + 1;
+
+/// Accounts shared by the positions of a market, read from a TOML or JSON file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MarginMarket {
+    /// Swap pool positions trade on
+    pub swap: SwapPool,
+}
+
+/// Token swap pool accounts
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SwapPool {
+    #[serde(with = "pubkey_string")]
+    pub program_id: Pubkey,
+    #[serde(with = "pubkey_string")]
+    pub pool: Pubkey,
+    #[serde(with = "pubkey_string")]
+    pub authority: Pubkey,
+    #[serde(with = "pubkey_string")]
+    pub token_a: Pubkey,
+    #[serde(with = "pubkey_string")]
+    pub token_b: Pubkey,
+    #[serde(with = "pubkey_string")]
+    pub pool_mint: Pubkey,
+    #[serde(with = "pubkey_string")]
+    pub pool_fee: Pubkey,
+    /// Host fee account, defaults to the pool fee account
+    #[serde(default, with = "pubkey_string::option")]
+    pub host_fee: Option<Pubkey>,
+}
+
+/// Lending accounts behind a margin account's obligation
+struct PositionAccounts {
+    obligation: Obligation,
+    lending_market_authority: Pubkey,
+    deposit_reserve: Reserve,
+    borrow_reserve: Reserve,
+    token_pair_pubkey: Pubkey,
+    direction: Direction,
+    vault_signer: Pubkey,
+}
+
+impl PositionAccounts {
+    fn load(
+        config: &Config,
+        client: &Client,
+        margin_account_pubkey: &Pubkey,
+        margin_account: &MarginAccount,
+        obligation_pubkey: &Pubkey,
+    ) -> Result<Self, Error> {
+        let obligation = client.obligation(obligation_pubkey)?;
+        let deposit_reserve = client.reserve(&obligation.collateral_reserve)?;
+        let borrow_reserve = client.reserve(&obligation.borrow_reserve)?;
+        let lending_market_authority =
+            client.lending_market_authority(&deposit_reserve.lending_market)?;
+
+        let deposit_mint = deposit_reserve.liquidity.mint_pubkey;
+        let borrow_mint = borrow_reserve.liquidity.mint_pubkey;
+        let (token_pair_pubkey, _) =
+            pda::find_token_pair(&deposit_mint, &borrow_mint, client.margin_program_id());
+        let token_pair = client.token_pair(&deposit_mint, &borrow_mint)?;
+
+        // Longs borrow the quote of the pair, shorts the base
+        let direction = if token_pair.second_token == borrow_mint {
+            Direction::Long
+        } else {
+            Direction::Short
+        };
+        let vault_signer = pda::margin_vault_signer(
+            margin_account_pubkey,
+            margin_account.nonce,
+            &config.margin_program_id()?,
+        )?;
+
+        Ok(Self {
+            obligation,
+            lending_market_authority,
+            deposit_reserve,
+            borrow_reserve,
+            token_pair_pubkey,
+            direction,
+            vault_signer,
+        })
+    }
+}
+
+/// Create a margin account for the payer, with a vault owned by the account's signer for each
+/// of `mints`
+pub fn init(config: &Config, mints: &[Pubkey]) -> Result<Pubkey, Error> {
+    let client = &config.rpc_client;
+    let program_id = config.margin_program_id()?;
+    let margin_account = Keypair::new();
+    let (vault_signer, nonce) =
+        pda::find_margin_vault_signer(&margin_account.pubkey(), &program_id);
+
+    let mut instructions = vec![
+        create_account(
+            &config.payer.pubkey(),
+            &margin_account.pubkey(),
+            client.get_minimum_balance_for_rent_exemption(MARGIN_ACCOUNT_LEN)?,
+            MARGIN_ACCOUNT_LEN as u64,
+            &program_id,
+        ),
+        margin_instruction::initialize(
+            program_id,
+            margin_account.pubkey(),
+            config.payer.pubkey(),
+            nonce,
+        ),
+    ];
+    let vaults = mints.iter().map(|_| Keypair::new()).collect::<Vec<_>>();
+    let token_balance = client.get_minimum_balance_for_rent_exemption(Token::LEN)?;
+    for (vault, mint) in vaults.iter().zip(mints) {
+        instructions.push(create_account(
+            &config.payer.pubkey(),
+            &vault.pubkey(),
+            token_balance,
+            Token::LEN as u64,
+            &spl_token::id(),
+        ));
+        instructions.push(initialize_account(
+            &spl_token::id(),
+            &vault.pubkey(),
+            mint,
+            &vault_signer,
+        )?);
+    }
+
+    let mut signers = vec![&margin_account];
+    signers.extend(vaults.iter());
+    send(config, &instructions, &signers)?;
+
+    println!("Vault signer: {}", vault_signer);
+    for (vault, mint) in vaults.iter().zip(mints) {
+        println!("Vault for {}: {}", mint, vault.pubkey());
+    }
+    Ok(margin_account.pubkey())
+}
+
+/// Create the lending obligation a margin account borrows through
+pub fn init_obligation(
+    config: &Config,
+    margin_account_pubkey: &Pubkey,
+    deposit_reserve_pubkey: &Pubkey,
+    borrow_reserve_pubkey: &Pubkey,
+) -> Result<Pubkey, Error> {
+    let rpc_client = &config.rpc_client;
+    let client = config.margin_client()?;
+    let program_id = config.margin_program_id()?;
+    let margin_account = client.margin_account(margin_account_pubkey)?;
+    let vault_signer =
+        pda::margin_vault_signer(margin_account_pubkey, margin_account.nonce, &program_id)?;
+    let deposit_reserve = client.reserve(deposit_reserve_pubkey)?;
+
+    let obligation = Keypair::new();
+    let obligation_token_mint = Keypair::new();
+    let obligation_token_output = Keypair::new();
+    let token_balance = rpc_client.get_minimum_balance_for_rent_exemption(Token::LEN)?;
+    let instructions = [
+        create_account(
+            &config.payer.pubkey(),
+            &obligation.pubkey(),
+            rpc_client.get_minimum_balance_for_rent_exemption(Obligation::LEN)?,
+            Obligation::LEN as u64,
+            &config.program_id,
+        ),
+        create_account(
+            &config.payer.pubkey(),
+            &obligation_token_mint.pubkey(),
+            rpc_client.get_minimum_balance_for_rent_exemption(Mint::LEN)?,
+            Mint::LEN as u64,
+            &spl_token::id(),
+        ),
+        create_account(
+            &config.payer.pubkey(),
+            &obligation_token_output.pubkey(),
+            token_balance,
+            Token::LEN as u64,
+            &spl_token::id(),
+        ),
+        margin_instruction::init_obligation(
+            program_id,
+            accounts::InitObligation {
+                lending_program: config.program_id,
+                deposit_reserve: *deposit_reserve_pubkey,
+                borrow_reserve: *borrow_reserve_pubkey,
+                obligation: obligation.pubkey(),
+                obligation_token_mint: obligation_token_mint.pubkey(),
+                obligation_token_output: obligation_token_output.pubkey(),
+                obligation_token_owner: vault_signer,
+                lending_market: deposit_reserve.lending_market,
+                lending_market_authority: client
+                    .lending_market_authority(&deposit_reserve.lending_market)?,
+                clock: sysvar::clock::id(),
+                rent: sysvar::rent::id(),
+                token_program: spl_token::id(),
+            },
+        ),
+    ];
+    send(
+        config,
+        &instructions,
+        &[
+            &obligation,
+            &obligation_token_mint,
+            &obligation_token_output,
+        ],
+    )?;
+
+    Ok(obligation.pubkey())
+}
+
+/// Open a position: borrow against `collateral_amount` of the payer's `collateral_account` and
+/// swap the loan into the deposit reserve's token
+#[allow(clippy::too_many_arguments)]
+pub fn open(
+    config: &Config,
+    market: &MarginMarket,
+    margin_account_pubkey: &Pubkey,
+    obligation_pubkey: &Pubkey,
+    collateral_account: &Pubkey,
+    collateral_amount: u64,
+    loan_amount: u64,
+    minimum_amount_out: u64,
+) -> Result<(), Error> {
+    let client = config.margin_client()?;
+    let program_id = config.margin_program_id()?;
+    let margin_account = client.margin_account(margin_account_pubkey)?;
+    let position = PositionAccounts::load(
+        config,
+        &client,
+        margin_account_pubkey,
+        &margin_account,
+        obligation_pubkey,
+    )?;
+    let state = client.margin_state()?;
+
+    let borrow_mint = position.borrow_reserve.liquidity.mint_pubkey;
+    let deposit_mint = position.deposit_reserve.liquidity.mint_pubkey;
+    let loaned_vault = token_account(&config.rpc_client, &position.vault_signer, &borrow_mint)?;
+    let collateral_vault =
+        token_account(&config.rpc_client, &position.vault_signer, &deposit_mint)?;
+    let obligation_token_output = token_account(
+        &config.rpc_client,
+        &position.vault_signer,
+        &position.obligation.token_mint,
+    )?;
+    let (dex_market, dex_market_bids, dex_market_asks) = price_accounts(
+        &config.rpc_client,
+        &position.deposit_reserve,
+        &position.borrow_reserve,
+    )?;
+
+    // The vault signer moves the collateral into the reserve
+    let borrow = [
+        approve(
+            &spl_token::id(),
+            collateral_account,
+            &position.vault_signer,
+            &config.payer.pubkey(),
+            &[],
+            collateral_amount,
+        )?,
+        accrue_reserve_interest(
+            config.program_id,
+            vec![
+                position.obligation.collateral_reserve,
+                position.obligation.borrow_reserve,
+            ],
+        ),
+        margin_instruction::borrow(
+            program_id,
+            accounts::Borrow {
+                token_pair: position.token_pair_pubkey,
+                lending_program: config.program_id,
+                source_collateral: *collateral_account,
+                deposit_reserve: position.obligation.collateral_reserve,
+                deposit_reserve_collateral_supply: position
+                    .deposit_reserve
+                    .collateral
+                    .supply_pubkey,
+                deposit_reserve_collateral_fees_receiver: position
+                    .deposit_reserve
+                    .collateral
+                    .fees_receiver,
+                borrow_reserve: position.obligation.borrow_reserve,
+                borrow_reserve_liquidity_supply: position.borrow_reserve.liquidity.supply_pubkey,
+                lending_market: position.deposit_reserve.lending_market,
+                lending_market_authority: position.lending_market_authority,
+                obligation: *obligation_pubkey,
+                obligation_token_mint: position.obligation.token_mint,
+                obligation_token_output,
+                dex_market,
+                dex_market_bids,
+                dex_market_asks,
+                vault_signer: position.vault_signer,
+                loaned_vault,
+                margin_account: *margin_account_pubkey,
+                state: pda::margin_state(&program_id),
+                fee_vaults: fee_vaults(&config.rpc_client, &state.fees, &borrow_mint)?,
+                token_program: spl_token::id(),
+            },
+            loan_amount,
+            collateral_amount,
+            position.direction,
+        ),
+    ];
+    send(config, &borrow, &[])?;
+    println!("Borrowed {} into {}", loan_amount, loaned_vault);
+
+    // Trade whatever is left of the loan after the open fee
+    let amount_in = token_balance(&config.rpc_client, &loaned_vault)?;
+    let trade = trade_amm(
+        config,
+        market,
+        margin_account_pubkey,
+        &position,
+        loaned_vault,
+        collateral_vault,
+        amount_in,
+        minimum_amount_out,
+    )?;
+    send(config, &[trade], &[])?;
+    println!(
+        "Opened {} position, holding {} in {}",
+        direction_name(position.direction),
+        token_balance(&config.rpc_client, &collateral_vault)?,
+        collateral_vault
+    );
+    Ok(())
+}
+
+/// Close a position: swap the held tokens back and repay the loan, returning the obligation's
+/// collateral to the payer's `collateral_account`
+pub fn close(
+    config: &Config,
+    market: &MarginMarket,
+    margin_account_pubkey: &Pubkey,
+    obligation_pubkey: &Pubkey,
+    collateral_account: &Pubkey,
+    minimum_amount_out: u64,
+) -> Result<(), Error> {
+    let client = config.margin_client()?;
+    let program_id = config.margin_program_id()?;
+    let margin_account = client.margin_account(margin_account_pubkey)?;
+    let open_position = margin_account
+        .position
+        .clone()
+        .filter(|position| position.status == Status::Locked)
+        .ok_or("Margin account has no open position")?;
+    let collateral_vault = open_position
+        .collateral_vault
+        .ok_or("Position has not traded its loan yet")?;
+    let position = PositionAccounts::load(
+        config,
+        &client,
+        margin_account_pubkey,
+        &margin_account,
+        obligation_pubkey,
+    )?;
+    let state = client.margin_state()?;
+
+    let amount_in = token_balance(&config.rpc_client, &collateral_vault)?;
+    let trade = trade_amm(
+        config,
+        market,
+        margin_account_pubkey,
+        &position,
+        collateral_vault,
+        open_position.loaned_vault,
+        amount_in,
+        minimum_amount_out,
+    )?;
+    send(config, &[trade], &[])?;
+
+    let obligation_input = token_account(
+        &config.rpc_client,
+        &position.vault_signer,
+        &position.obligation.token_mint,
+    )?;
+    let repay = [
+        accrue_reserve_interest(
+            config.program_id,
+            vec![
+                position.obligation.collateral_reserve,
+                position.obligation.borrow_reserve,
+            ],
+        ),
+        margin_instruction::repay(
+            program_id,
+            accounts::Repay {
+                lending_program: config.program_id,
+                source_liquidity_acc: open_position.loaned_vault,
+                destination_coll_account: *collateral_account,
+                repay_reserve_account: position.obligation.borrow_reserve,
+                repay_reserve_spl_acccount: position.borrow_reserve.liquidity.supply_pubkey,
+                withdraw_reserve: position.obligation.collateral_reserve,
+                withdraw_reserve_collateral: position.deposit_reserve.collateral.supply_pubkey,
+                obligation: *obligation_pubkey,
+                obligation_mint: position.obligation.token_mint,
+                obligation_input,
+                lending_market: position.deposit_reserve.lending_market,
+                derived_lending_authority: position.lending_market_authority,
+                loan_vault: open_position.loaned_vault,
+                margin_account: *margin_account_pubkey,
+                token_pair: position.token_pair_pubkey,
+                vault_signer: position.vault_signer,
+                state: pda::margin_state(&program_id),
+                fee_vaults: fee_vaults(
+                    &config.rpc_client,
+                    &state.fees,
+                    &position.borrow_reserve.liquidity.mint_pubkey,
+                )?,
+                token_program: spl_token::id(),
+                clock: sysvar::clock::id(),
+            },
+            open_position.loan_amount,
+        ),
+    ];
+    send(config, &repay, &[])?;
+    println!(
+        "Closed position, {} left in {}",
+        token_balance(&config.rpc_client, &open_position.loaned_vault)?,
+        open_position.loaned_vault
+    );
+    Ok(())
+}
+
+/// Withdraw `amount` from one of a margin account's vaults to `destination`
+pub fn withdraw(
+    config: &Config,
+    margin_account_pubkey: &Pubkey,
+    vault: &Pubkey,
+    destination: &Pubkey,
+    amount: u64,
+) -> Result<(), Error> {
+    let client = config.margin_client()?;
+    let program_id = config.margin_program_id()?;
+    let margin_account = client.margin_account(margin_account_pubkey)?;
+    let vault_signer =
+        pda::margin_vault_signer(margin_account_pubkey, margin_account.nonce, &program_id)?;
+
+    let instruction = margin_instruction::withdraw(
+        program_id,
+        accounts::Withdraw {
+            authority: config.payer.pubkey(),
+            user_token_account: *destination,
+            margin_account: *margin_account_pubkey,
+            vault: *vault,
+            vault_signer,
+            token_program: spl_token::id(),
+        },
+        amount,
+    );
+    send(config, &[instruction], &[])
+}
+
+/// Print a margin account's position. With its obligation and market, also prices the position
+/// at the swap pool's spot price to show its health, liquidation price, PnL and interest.
+pub fn show(
+    config: &Config,
+    margin_account_pubkey: &Pubkey,
+    obligation_pubkey: Option<&Pubkey>,
+    market: Option<&MarginMarket>,
+) -> Result<(), Error> {
+    let client = config.margin_client()?;
+    let margin_account = client.margin_account(margin_account_pubkey)?;
+
+    println!("Margin account: {}", margin_account_pubkey);
+    println!("  Trader: {}", margin_account.trader);
+    let position = match &margin_account.position {
+        Some(position) => position,
+        None => {
+            println!("  No position");
+            return Ok(());
+        }
+    };
+    let status = match position.status {
+        Status::Locked => "open",
+        Status::Available => "closed",
+    };
+    println!(
+        "  Position: {} ({})",
+        direction_name(position.direction),
+        status
+    );
+    println!("  Token pair: {}", position.token_pair);
+    println!("  Loan: {}", position.loan_amount);
+    println!("  Loaned vault: {}", position.loaned_vault);
+    println!("  Withdrawable: {}", position.withdrawable_amount);
+    if position.bad_debt > 0 {
+        println!("  Bad debt: {}", position.bad_debt);
+    }
+    let collateral_vault = match position.collateral_vault {
+        Some(collateral_vault) => collateral_vault,
+        None => return Ok(()),
+    };
+    let held_amount = token_balance(&config.rpc_client, &collateral_vault)?;
+    println!("  Collateral vault: {}", collateral_vault);
+    println!("  Held: {} (max {})", held_amount, position.max_held_amount);
+
+    let (obligation_pubkey, market) = match (obligation_pubkey, market) {
+        (Some(obligation_pubkey), Some(market)) => (obligation_pubkey, market),
+        _ => return Ok(()),
+    };
+    let obligation = client.obligation(obligation_pubkey)?;
+    let deposit_reserve = client.reserve(&obligation.collateral_reserve)?;
+    let borrow_reserve = client.reserve(&obligation.borrow_reserve)?;
+    // Longs hold the base token, shorts owe it
+    let base_mint = match position.direction {
+        Direction::Long => deposit_reserve.liquidity.mint_pubkey,
+        Direction::Short => borrow_reserve.liquidity.mint_pubkey,
+    };
+    let (base_amount, quote_amount) = pool_balances(&config.rpc_client, &market.swap, &base_mint)?;
+    let token_pair = client.token_pair(
+        &deposit_reserve.liquidity.mint_pubkey,
+        &borrow_reserve.liquidity.mint_pubkey,
+    )?;
+    let snapshot = PositionSnapshot {
+        position,
+        risk_params: &token_pair.risk_params,
+        held_amount,
+        obligation: &obligation,
+        deposit_reserve: &deposit_reserve,
+        borrow_reserve: &borrow_reserve,
+        slot: config.rpc_client.get_slot()?,
+    };
+    let analytics = analytics::analyze(
+        &snapshot,
+        &Market::Pool {
+            base_amount,
+            quote_amount,
+        },
+    )?;
+    println!("  Spot price: {}", analytics.price);
+    println!("  Debt: {}", analytics.debt);
+    println!("  Accrued interest: {}", analytics.accrued_interest);
+    println!("  Collateral: {}", analytics.collateral);
+    match analytics.health_factor {
+        Some(health_factor) => println!("  Health factor: {}", health_factor),
+        None => println!("  Health factor: no debt"),
+    }
+    match analytics.liquidation_price {
+        Some(price) => println!("  Liquidation price: {}", price),
+        None => println!("  Liquidation price: none"),
+    }
+    println!("  Unrealized PnL: {}", analytics.unrealized_pnl);
+    Ok(())
+}
+
+/// Base and quote balances of the pool
+fn pool_balances(
+    client: &RpcClient,
+    swap: &SwapPool,
+    base_mint: &Pubkey,
+) -> Result<(u64, u64), Error> {
+    let token_a = Token::unpack(&client.get_account(&swap.token_a)?.data)?;
+    let token_b = Token::unpack(&client.get_account(&swap.token_b)?.data)?;
+    if token_a.mint == *base_mint {
+        Ok((token_a.amount, token_b.amount))
+    } else {
+        Ok((token_b.amount, token_a.amount))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn trade_amm(
+    config: &Config,
+    market: &MarginMarket,
+    margin_account_pubkey: &Pubkey,
+    position: &PositionAccounts,
+    source_vault: Pubkey,
+    destination_vault: Pubkey,
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Result<Instruction, Error> {
+    let program_id = config.margin_program_id()?;
+    let swap = &market.swap;
+    let source_mint = Token::unpack(&config.rpc_client.get_account(&source_vault)?.data)?.mint;
+    let token_a_mint = Token::unpack(&config.rpc_client.get_account(&swap.token_a)?.data)?.mint;
+    let (swap_source, swap_dest) = if token_a_mint == source_mint {
+        (swap.token_a, swap.token_b)
+    } else {
+        (swap.token_b, swap.token_a)
+    };
+
+    Ok(margin_instruction::trade_amm(
+        program_id,
+        accounts::TradeAmm {
+            trader: config.payer.pubkey(),
+            swap_program: swap.program_id,
+            swap_info: swap.pool,
+            swap_authority: swap.authority,
+            source: source_vault,
+            swap_source,
+            swap_dest,
+            pool_mint: swap.pool_mint,
+            pool_fee: swap.pool_fee,
+            host_fee: swap.host_fee.unwrap_or(swap.pool_fee),
+            margin_account: *margin_account_pubkey,
+            token_pair: position.token_pair_pubkey,
+            source_vault,
+            destination_vault,
+            vault_signer: position.vault_signer,
+            token_program: spl_token::id(),
+        },
+        amount_in,
+        minimum_amount_out,
+    ))
+}
+
+/// Token accounts receiving the fee shares of `mint`
+fn fee_vaults(
+    client: &RpcClient,
+    fees: &FeeConfig,
+    mint: &Pubkey,
+) -> Result<accounts::FeeVaults, Error> {
+    Ok(accounts::FeeVaults {
+        stakers: token_account(client, &fees.stakers_fee_owner, mint)?,
+        insurance: token_account(client, &fees.insurance_fee_owner, mint)?,
+        community: token_account(client, &fees.community_fee_owner, mint)?,
+    })
+}
+
+/// Accounts the lending program prices a borrow with: the dex market and its bids and asks, or
+/// the token swap pool and its token A and B accounts
+fn price_accounts(
+    client: &RpcClient,
+    deposit_reserve: &Reserve,
+    borrow_reserve: &Reserve,
+) -> Result<(Pubkey, Pubkey, Pubkey), Error> {
+    let (dex_market, price_source) = match (deposit_reserve.dex_market, borrow_reserve.dex_market) {
+        (COption::Some(pubkey), _) => (pubkey, deposit_reserve.price_source),
+        (_, COption::Some(pubkey)) => (pubkey, borrow_reserve.price_source),
+        _ => return Err("One reserve must have a dex market".into()),
+    };
+    let data = client.get_account(&dex_market)?.data;
+    match price_source {
+        PriceSource::DexMarket => Ok((
+            dex_market,
+            DexMarket::pubkey_at_offset(&data, BIDS_OFFSET),
+            DexMarket::pubkey_at_offset(&data, ASKS_OFFSET),
+        )),
+        PriceSource::TokenSwap => {
+            let pool = swap_pool::SwapPool::unpack(&data)?;
+            Ok((dex_market, pool.token_a, pool.token_b))
+        }
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Long => "long",
+        Direction::Short => "short",
+    }
+}
+
+/// First token account of `mint` owned by `owner`
+fn token_account(client: &RpcClient, owner: &Pubkey, mint: &Pubkey) -> Result<Pubkey, Error> {
+    let accounts = client.get_token_accounts_by_owner(owner, TokenAccountsFilter::Mint(*mint))?;
+    let account = accounts
+        .first()
+        .ok_or_else(|| format!("No token account of {} owned by {}", mint, owner))?;
+    Ok(account.pubkey.parse()?)
+}
+
+fn token_balance(client: &RpcClient, pubkey: &Pubkey) -> Result<u64, Error> {
+    Ok(Token::unpack(&client.get_account(pubkey)?.data)?.amount)
+}
+
+fn send(config: &Config, instructions: &[Instruction], signers: &[&Keypair]) -> Result<(), Error> {
+    let client = &config.rpc_client;
+    let mut transaction = Transaction::new_with_payer(instructions, Some(&config.payer.pubkey()));
+    let mut all_signers = vec![&config.payer];
+    all_signers.extend(signers.iter().copied());
+    let recent_blockhash = client.get_recent_blockhash()?.0;
+    transaction.sign(&all_signers, recent_blockhash);
+    client.send_and_confirm_transaction(&transaction)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn example_market_parses() {
+        let market: MarginMarket = config::load("config/sol-usdc-market.toml").unwrap();
+        assert_eq!(market.swap.host_fee, None);
+        assert_eq!(
+            market.swap.program_id.to_string(),
+            "SwaPpA9LAaLfeLi3a68M4DjnLqgtticKg6CnyNwgAC8"
+        );
+    }
+}
@@ -5,10 +5,8 @@ use crate::{
     math::{Decimal, TryAdd, TryDiv, TryMul, TrySub},
     state::TokenConverter,
 };
-use arrayref::{array_refs, mut_array_refs};
-use serum_dex::critbit::{Slab, SlabView};
 use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
-use std::{cell::RefMut, convert::TryFrom};
+use std::{cell::Ref, convert::TryFrom};
 
 /// Side of the dex market order book
 #[derive(Clone, Copy, PartialEq)]
@@ -24,6 +22,17 @@ enum Currency {
     Quote,
 }
 
+impl Side {
+    /// Child of a critbit inner node holding the better prices: asks are filled from the lowest
+    /// price up and bids from the highest down
+    fn best_child(&self) -> usize {
+        match self {
+            Side::Ask => 0,
+            Side::Bid => 1,
+        }
+    }
+}
+
 impl Currency {
     fn opposite(&self) -> Self {
         match self {
@@ -112,18 +121,26 @@ impl<'a> TokenConverter for TradeSimulator<'a> {
 }
 
 impl<'a> TradeSimulator<'a> {
-    /// Create a new TradeSimulator
+    /// Create a new TradeSimulator over the order book side the trade fills against
     pub fn new(
         dex_market_info: &AccountInfo,
-        dex_market_orders: &AccountInfo,
-        memory: &'a AccountInfo,
+        dex_market_bids: &'a AccountInfo,
+        dex_market_asks: &'a AccountInfo,
         quote_token_mint: &'a Pubkey,
         buy_token_mint: &'a Pubkey,
         sell_token_mint: &'a Pubkey,
     ) -> Result<Self, ProgramError> {
         let dex_market = DexMarket::new(dex_market_info);
-        let orders = DexMarketOrders::new(&dex_market, dex_market_orders, memory)?;
-        let orders_side = orders.side;
+        // Buying the base token takes asks, buying the quote token takes bids
+        let orders_side = if buy_token_mint == quote_token_mint {
+            Side::Bid
+        } else {
+            Side::Ask
+        };
+        let orders = match orders_side {
+            Side::Bid => DexMarketOrders::new(&dex_market, dex_market_bids, Side::Bid)?,
+            Side::Ask => DexMarketOrders::new(&dex_market, dex_market_asks, Side::Ask)?,
+        };
 
         Ok(Self {
             dex_market,
@@ -203,51 +220,92 @@ impl<'a> TradeSimulator<'a> {
     }
 }
 
-/// Dex market order account info
+const SLAB_HEADER_LEN: usize = 32;
+const SLAB_ROOT_NODE_OFFSET: usize = 20;
+const SLAB_LEAF_COUNT_OFFSET: usize = 24;
+const SLAB_NODE_LEN: usize = 72;
+const INNER_NODE_TAG: u32 = 1;
+const LEAF_NODE_TAG: u32 = 2;
+const NODE_KEY_OFFSET: usize = 8;
+const INNER_NODE_CHILDREN_OFFSET: usize = 24;
+const LEAF_NODE_QUANTITY_OFFSET: usize = 56;
+
+/// Node of a critbit slab
+enum SlabNode {
+    Inner([u32; 2]),
+    Leaf(Order),
+}
+
+/// Dex market order book side, read in place. Orders are the leaves of the side's critbit tree
+/// keyed by price, so walking the tree in order visits them from the best price.
 struct DexMarketOrders<'a> {
-    heap: Option<RefMut<'a, Slab>>,
+    slab: Ref<'a, [u8]>,
     side: Side,
+    /// Nodes left to walk, the one holding the next best orders on top
+    stack: Vec<u32>,
 }
 
 impl<'a> DexMarketOrders<'a> {
     /// Create a new DexMarketOrders
     fn new(
         dex_market: &DexMarket,
-        orders: &AccountInfo,
-        memory: &'a AccountInfo,
+        orders: &'a AccountInfo,
+        side: Side,
     ) -> Result<Self, ProgramError> {
-        let side = match orders.key {
-            key if key == &dex_market.bids => Side::Bid,
-            key if key == &dex_market.asks => Side::Ask,
-            _ => return Err(LendingError::DexInvalidOrderBookSide.into()),
+        let orders_key = match side {
+            Side::Bid => &dex_market.bids,
+            Side::Ask => &dex_market.asks,
         };
-
-        if memory.data_len() < orders.data_len() {
-            return Err(LendingError::MemoryTooSmall.into());
+        if orders.key != orders_key {
+            return Err(LendingError::DexInvalidOrderBookSide.into());
         }
 
-        let mut memory_data = memory.data.borrow_mut();
-        fast_copy(&orders.data.borrow(), &mut memory_data);
-        let heap = Some(RefMut::map(memory_data, |bytes| {
-            // strip padding and header
+        // strip padding and header
+        let slab = Ref::map(orders.data.borrow(), |bytes| {
             let start = 5 + 8;
-            let end = bytes.len() - 7;
-            Slab::new(&mut bytes[start..end])
-        }));
+            let end = bytes.len().saturating_sub(7);
+            bytes.get(start..end).unwrap_or(&[])
+        });
+        if slab.len() < SLAB_HEADER_LEN {
+            return Err(LendingError::TradeSimulationError.into());
+        }
 
-        Ok(Self { heap, side })
+        let mut stack = Vec::new();
+        if u64_at(&slab, SLAB_LEAF_COUNT_OFFSET) > 0 {
+            stack.push(u32_at(&slab, SLAB_ROOT_NODE_OFFSET));
+        }
+        Ok(Self { slab, side, stack })
     }
 
-    fn best_order_price(&mut self) -> Option<u64> {
-        let side = self.side;
-        self.heap.as_mut().and_then(|heap| {
-            let handle = match side {
-                Side::Bid => heap.find_max(),
-                Side::Ask => heap.find_min(),
-            }?;
+    fn best_order_price(&self) -> Option<u64> {
+        let mut handle = *self.stack.last()?;
+        loop {
+            match self.node(handle)? {
+                SlabNode::Inner(children) => handle = children[self.side.best_child()],
+                SlabNode::Leaf(order) => return Some(order.price),
+            }
+        }
+    }
 
-            Some(heap.get_mut(handle)?.as_leaf_mut()?.price().get())
-        })
+    fn node(&self, handle: u32) -> Option<SlabNode> {
+        let start = SLAB_HEADER_LEN.checked_add((handle as usize).checked_mul(SLAB_NODE_LEN)?)?;
+        let node = self.slab.get(start..start + SLAB_NODE_LEN)?;
+        match u32_at(node, 0) {
+            INNER_NODE_TAG => Some(SlabNode::Inner([
+                u32_at(node, INNER_NODE_CHILDREN_OFFSET),
+                u32_at(node, INNER_NODE_CHILDREN_OFFSET + 4),
+            ])),
+            LEAF_NODE_TAG => {
+                let key = u128::from_le_bytes(
+                    <[u8; 16]>::try_from(&node[NODE_KEY_OFFSET..NODE_KEY_OFFSET + 16]).unwrap(),
+                );
+                Some(SlabNode::Leaf(Order {
+                    price: (key >> 64) as u64,
+                    quantity: u64_at(node, LEAF_NODE_QUANTITY_OFFSET),
+                }))
+            }
+            _ => None,
+        }
     }
 }
 
@@ -255,25 +313,37 @@ impl Iterator for DexMarketOrders<'_> {
     type Item = Order;
 
     fn next(&mut self) -> Option<Order> {
-        let leaf_node = match self.side {
-            Side::Bid => self.heap.as_mut().and_then(|heap| heap.remove_max()),
-            Side::Ask => self.heap.as_mut().and_then(|heap| heap.remove_min()),
-        }?;
-
-        Some(Order {
-            price: leaf_node.price().get(),
-            quantity: leaf_node.quantity(),
-        })
+        while let Some(handle) = self.stack.pop() {
+            match self.node(handle)? {
+                SlabNode::Inner(children) => {
+                    let best_child = self.side.best_child();
+                    self.stack.push(children[1 - best_child]);
+                    self.stack.push(children[best_child]);
+                }
+                SlabNode::Leaf(order) => return Some(order),
+            }
+        }
+        None
     }
 }
 
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(<[u8; 4]>::try_from(&data[offset..offset + 4]).unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(<[u8; 8]>::try_from(&data[offset..offset + 8]).unwrap())
+}
+
 /// Offset for dex market base mint
 pub const BASE_MINT_OFFSET: usize = 6;
 /// Offset for dex market quote mint
 pub const QUOTE_MINT_OFFSET: usize = 10;
 
-const BIDS_OFFSET: usize = 35;
-const ASKS_OFFSET: usize = 39;
+/// Offset for dex market bids
+pub const BIDS_OFFSET: usize = 35;
+/// Offset for dex market asks
+pub const ASKS_OFFSET: usize = 39;
 
 /// Dex market info
 pub struct DexMarket {
@@ -327,19 +397,123 @@ impl DexMarket {
     }
 }
 
-/// A more efficient `copy_from_slice` implementation.
-fn fast_copy(mut src: &[u8], mut dst: &mut [u8]) {
-    const COPY_SIZE: usize = 512;
-    while src.len() >= COPY_SIZE {
-        #[allow(clippy::ptr_offset_with_cast)]
-        let (src_word, src_rem) = array_refs![src, COPY_SIZE; ..;];
-        #[allow(clippy::ptr_offset_with_cast)]
-        let (dst_word, dst_rem) = mut_array_refs![dst, COPY_SIZE; ..;];
-        *dst_word = *src_word;
-        src = src_rem;
-        dst = dst_rem;
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn inner(children: [u32; 2]) -> Vec<u8> {
+        let mut node = vec![0; SLAB_NODE_LEN];
+        node[..4].copy_from_slice(&INNER_NODE_TAG.to_le_bytes());
+        node[INNER_NODE_CHILDREN_OFFSET..INNER_NODE_CHILDREN_OFFSET + 4]
+            .copy_from_slice(&children[0].to_le_bytes());
+        node[INNER_NODE_CHILDREN_OFFSET + 4..INNER_NODE_CHILDREN_OFFSET + 8]
+            .copy_from_slice(&children[1].to_le_bytes());
+        node
+    }
+
+    fn leaf(price: u64, quantity: u64) -> Vec<u8> {
+        let mut node = vec![0; SLAB_NODE_LEN];
+        node[..4].copy_from_slice(&LEAF_NODE_TAG.to_le_bytes());
+        let key = (price as u128) << 64;
+        node[NODE_KEY_OFFSET..NODE_KEY_OFFSET + 16].copy_from_slice(&key.to_le_bytes());
+        node[LEAF_NODE_QUANTITY_OFFSET..LEAF_NODE_QUANTITY_OFFSET + 8]
+            .copy_from_slice(&quantity.to_le_bytes());
+        node
     }
-    unsafe {
-        std::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), src.len());
+
+    /// Order book side account holding `nodes`, with the tree rooted at the first node
+    fn orders_data(nodes: &[Vec<u8>]) -> Vec<u8> {
+        let leaf_count = nodes
+            .iter()
+            .filter(|node| u32_at(node, 0) == LEAF_NODE_TAG)
+            .count() as u64;
+        let mut header = vec![0; SLAB_HEADER_LEN];
+        header[SLAB_LEAF_COUNT_OFFSET..SLAB_LEAF_COUNT_OFFSET + 8]
+            .copy_from_slice(&leaf_count.to_le_bytes());
+
+        let mut data = vec![0; 5 + 8];
+        data.extend_from_slice(&header);
+        for node in nodes {
+            data.extend_from_slice(node);
+        }
+        data.extend_from_slice(&[0; 7]);
+        data
+    }
+
+    /// Best price and the prices in the order they fill, reading `side` of a book holding `nodes`
+    fn walk(side: Side, nodes: &[Vec<u8>]) -> (Option<u64>, Vec<u64>) {
+        let dex_market = DexMarket {
+            bids: Pubkey::new_unique(),
+            asks: Pubkey::new_unique(),
+            base_lots: 1,
+            quote_lots: 1,
+        };
+        let key = match side {
+            Side::Bid => dex_market.bids,
+            Side::Ask => dex_market.asks,
+        };
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = orders_data(nodes);
+        let orders_info = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+
+        let orders = DexMarketOrders::new(&dex_market, &orders_info, side).unwrap();
+        let best_order_price = orders.best_order_price();
+        (best_order_price, orders.map(|order| order.price).collect())
+    }
+
+    #[test]
+    fn walks_orders_by_price() {
+        let nodes = vec![
+            inner([1, 2]),
+            leaf(10, 1),
+            inner([3, 4]),
+            leaf(20, 2),
+            leaf(30, 3),
+        ];
+        assert_eq!(walk(Side::Ask, &nodes), (Some(10), vec![10, 20, 30]));
+        assert_eq!(walk(Side::Bid, &nodes), (Some(30), vec![30, 20, 10]));
+        assert_eq!(walk(Side::Ask, &[]), (None, vec![]));
+    }
+
+    #[test]
+    fn reads_orders_in_place() {
+        let dex_market = DexMarket {
+            bids: Pubkey::new_unique(),
+            asks: Pubkey::new_unique(),
+            base_lots: 1,
+            quote_lots: 1,
+        };
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = orders_data(&[leaf(10, 1)]);
+        let expected_data = data.clone();
+        let orders_info = AccountInfo::new(
+            &dex_market.bids,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+
+        assert_eq!(
+            DexMarketOrders::new(&dex_market, &orders_info, Side::Ask).err(),
+            Some(LendingError::DexInvalidOrderBookSide.into())
+        );
+        let orders = DexMarketOrders::new(&dex_market, &orders_info, Side::Bid).unwrap();
+        assert_eq!(orders.count(), 1);
+        assert_eq!(*orders_info.data.borrow(), &expected_data[..]);
     }
 }
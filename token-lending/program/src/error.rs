@@ -125,6 +125,28 @@ pub enum LendingError {
     /// Token burn failed
     #[error("Token burn failed")]
     TokenBurnFailed,
+
+    // 35
+    /// Margin borrow limit reached
+    #[error("Reserve margin borrow limit exceeded")]
+    MarginBorrowLimitExceeded,
+    /// Invalid token swap pool
+    #[error("Invalid token swap pool")]
+    InvalidSwapPool,
+    /// Token swap pool mints do not match the reserve mints
+    #[error("Token swap pool mints do not match")]
+    SwapPoolMintMismatch,
+    /// Token swap pool curve is not constant product
+    #[error("Token swap pool curve type is not supported")]
+    UnsupportedSwapCurve,
+
+    // 40
+    /// Price accumulator was not updated within the averaging window
+    #[error("Price accumulator needs to be updated")]
+    PriceAccumulatorStale,
+    /// Price accumulator observations do not cover the averaging window
+    #[error("Price history does not cover the averaging window")]
+    PriceHistoryUnavailable,
 }
 
 impl From<LendingError> for ProgramError {
@@ -2,20 +2,19 @@
 
 use crate::{
     error::LendingError,
-    state::{ReserveConfig, ReserveFees},
+    state::{PriceSource, ReserveConfig},
 };
-use num_derive::{FromPrimitive, ToPrimitive};
-use num_traits::{FromPrimitive, ToPrimitive};
+use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     instruction::{AccountMeta, Instruction},
+    msg,
     program_error::ProgramError,
     pubkey::Pubkey,
     sysvar,
 };
-use std::{convert::TryInto, mem::size_of};
 
 /// Describe how the borrow input amount should be treated
-#[derive(Clone, Copy, Debug, PartialEq, FromPrimitive, ToPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
 pub enum BorrowAmountType {
     /// Treat amount as amount of liquidity to borrow
     LiquidityBorrowAmount,
@@ -25,8 +24,14 @@ pub enum BorrowAmountType {
     MarginBorrowAmount,
 }
 
+/// Version of the instruction encoding, the first byte of instruction data
+pub const INSTRUCTION_VERSION: u8 = 1;
+
 /// Instructions supported by the lending program.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// Instructions are Borsh encoded after the `INSTRUCTION_VERSION` byte, tagged by their variant
+/// index. New instructions must be added at the end so the tags of existing ones never change.
+#[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
 pub enum LendingInstruction {
     /// Initializes a new lending market.
     ///
@@ -57,12 +62,15 @@ pub enum LendingInstruction {
     ///   12 `[]` Clock sysvar
     ///   13 `[]` Rent sysvar
     ///   14 '[]` Token program id
-    ///   15 `[optional]` Serum DEX market account. Not required for quote currency reserves. Must be initialized and match quote and base currency.
+    ///   15 `[optional]` Serum DEX market or spl-token-swap pool account, matching `price_source`.
+    ///                     Not required for quote currency reserves. Must be initialized and match quote and base currency.
     InitReserve {
         /// Initial amount of liquidity to deposit into the new reserve
         liquidity_amount: u64,
         /// Reserve configuration values
         config: ReserveConfig,
+        /// Kind of market the reserve is priced off
+        price_source: PriceSource,
     },
 
     /// Initializes a new loan obligation.
@@ -133,9 +141,9 @@ pub enum LendingInstruction {
     ///   10 `[]` Lending market account.
     ///   11 `[]` Derived lending market authority.
     ///   12 `[]` User transfer authority ($authority).
-    ///   13 `[]` Dex market
-    ///   14 `[]` Dex market order book side
-    ///   15 `[]` Temporary memory
+    ///   13 `[]` Dex market, or token swap pool for reserves priced off a pool
+    ///   14 `[]` Dex market bids, or pool token A account
+    ///   15 `[]` Dex market asks, or pool token B account
     ///   16 `[]` Clock sysvar
     ///   17 '[]` Token program id
     ///   18 `[optional, writable]` Deposit reserve collateral host fee receiver account.
@@ -147,37 +155,6 @@ pub enum LendingInstruction {
         amount_type: BorrowAmountType,
     },
 
-    /// Borrow tokens from a reserve. The number of borrowed tokens
-    /// is calculated by market price. There is no debt obligation
-    /// because the loan is given to a partially protocol controlled account.
-    ///
-    ///   0. `[writable]` Source collateral token account, minted by deposit reserve collateral mint,
-    ///                     $authority can transfer $collateral_amount
-    ///   1. `[writable]` Destination liquidity token account, minted by borrow reserve liquidity mint
-    ///   2. `[]` Deposit reserve account.
-    ///   3. `[writable]` Deposit reserve collateral supply SPL Token account
-    ///   4. `[writable]` Deposit reserve collateral fee receiver account.
-    ///                     Must be the fee account specified at InitReserve.
-    ///   5. `[writable]` Borrow reserve account.
-    ///   6. `[writable]` Borrow reserve liquidity supply SPL Token account
-    ///   10 `[]` Lending market account.
-    ///   11 `[]` Derived lending market authority.
-    ///   12 `[]` User transfer authority ($authority).
-    ///   13 `[]` Dex market
-    ///   14 `[]` Dex market order book side
-    ///   15 `[]` Temporary memory
-    ///   16 `[]` Clock sysvar
-    ///   17 '[]` Token program id
-    ///   18 `[optional, writable]` Deposit reserve collateral host fee receiver account.
-    MarginBorrowReserveLiquidity {
-        // TODO: slippage constraint
-        /// Amount whose usage depends on `amount_type`
-        collateral_amount: u64,
-        /// amount
-        loan_amount: u64,
-        /// Describe how the amount should be treated
-        amount_type: BorrowAmountType,
-    },
     /// Repay loaned tokens to a reserve and receive collateral tokens. The obligation balance
     /// will be recalculated for interest.
     ///
@@ -214,11 +191,13 @@ pub enum LendingInstruction {
     ///   7. `[]` Lending market account.
     ///   8. `[]` Derived lending market authority.
     ///   9. `[]` User transfer authority ($authority).
-    ///   10 `[]` Dex market
-    ///   11 `[]` Dex market order book side
-    ///   12 `[]` Temporary memory
+    ///   10 `[]` Dex market, or token swap pool for reserves priced off a pool
+    ///   11 `[]` Dex market bids, or pool token A account
+    ///   12 `[]` Dex market asks, or pool token B account
     ///   13 `[]` Clock sysvar
     ///   14 `[]` Token program id
+    ///   15 `[]` Price accumulator of the dex market or pool. Required when the reserve priced
+    ///             off the market sets `liquidation_twap_slots`.
     LiquidateObligation {
         /// Amount of loan to repay
         liquidity_amount: u64,
@@ -228,199 +207,179 @@ pub enum LendingInstruction {
     ///
     ///   0. `[]` Clock sysvar
     ///   1. `[writable]` Reserve account.
-    ///   .. `[writable]` Additional reserve accounts.
-    AccrueReserveInterest,
+    ///   .. `[writable]` Rate history account of the preceding reserve, if flagged.
+    ///   .. `[writable]` Additional reserve accounts, each followed by its rate history if flagged.
+    AccrueReserveInterest {
+        /// Whether each reserve, in order, is followed by its rate history
+        rate_histories: Vec<bool>,
+    },
+
+    /// Borrow tokens from a reserve. The number of borrowed tokens
+    /// is calculated by market price. There is no debt obligation
+    /// because the loan is given to a partially protocol controlled account.
+    ///
+    ///   0. `[writable]` Source collateral token account, minted by deposit reserve collateral mint,
+    ///                     $authority can transfer $collateral_amount
+    ///   1. `[writable]` Destination liquidity token account, minted by borrow reserve liquidity mint
+    ///   2. `[]` Deposit reserve account.
+    ///   3. `[writable]` Deposit reserve collateral supply SPL Token account
+    ///   4. `[writable]` Deposit reserve collateral fee receiver account.
+    ///                     Must be the fee account specified at InitReserve.
+    ///   5. `[writable]` Borrow reserve account.
+    ///   6. `[writable]` Borrow reserve liquidity supply SPL Token account
+    ///   7. `[writable]` Obligation
+    ///   8. `[writable]` Obligation token mint
+    ///   9. `[writable]` Obligation token output
+    ///   10 `[]` Lending market account.
+    ///   11 `[]` Derived lending market authority.
+    ///   12 `[]` User transfer authority ($authority).
+    ///   13 `[]` Dex market, or token swap pool for reserves priced off a pool
+    ///   14 `[]` Dex market bids, or pool token A account
+    ///   15 `[]` Dex market asks, or pool token B account
+    ///   16 `[]` Clock sysvar
+    ///   17 '[]` Token program id
+    ///   18 `[optional, writable]` Deposit reserve collateral host fee receiver account.
+    MarginBorrowReserveLiquidity {
+        // TODO: slippage constraint
+        /// Amount whose usage depends on `amount_type`
+        collateral_amount: u64,
+        /// amount
+        loan_amount: u64,
+        /// Describe how the amount should be treated
+        amount_type: BorrowAmountType,
+    },
+
+    /// Updates the configuration of a reserve. Accrues interest at the previous rates first.
+    ///
+    ///   0. `[writable]` Reserve account.
+    ///   1. `[]` Lending market account.
+    ///   2. `[signer]` Lending market owner.
+    ///   3. `[]` Clock sysvar
+    SetReserveConfig {
+        /// New reserve configuration values
+        config: ReserveConfig,
+    },
+
+    /// Initializes a price accumulator for the dex market or token swap pool a reserve is
+    /// priced off. The accumulator takes its first sample from the market, which later samples
+    /// are clamped against, and samples the market on each UpdatePriceAccumulator. Its
+    /// observations must span the reserve's liquidation averaging window.
+    ///
+    ///   0. `[writable]` Price accumulator account - uninitialized.
+    ///   1. `[]` Reserve account, priced off the market.
+    ///   2. `[]` Lending market account.
+    ///   3. `[signer]` Lending market owner.
+    ///   4. `[]` Rent sysvar
+    ///   5. `[]` Clock sysvar
+    ///   6. `[]` Dex market, or token swap pool
+    ///   7. `[]` Dex market bids, or pool token A account
+    ///   8. `[]` Dex market asks, or pool token B account
+    InitPriceAccumulator {
+        /// Minimum slots between two recorded observations
+        observation_interval: u64,
+    },
+
+    /// Samples the market price into a price accumulator. Only the first update of a slot is
+    /// recorded and each sample may only move a bounded amount per slot from the previous one,
+    /// so anyone may crank it.
+    ///
+    ///   0. `[writable]` Price accumulator account.
+    ///   1. `[]` Clock sysvar
+    ///   2. `[]` Dex market, or token swap pool
+    ///   3. `[]` Dex market bids, or pool token A account
+    ///   4. `[]` Dex market asks, or pool token B account
+    UpdatePriceAccumulator,
+
+    /// Initializes a rate history for a reserve. AccrueReserveInterest records a snapshot of the
+    /// reserve's rates into it when passed after the reserve.
+    ///
+    ///   0. `[writable]` Rate history account - uninitialized.
+    ///   1. `[]` Reserve account.
+    ///   2. `[]` Lending market account.
+    ///   3. `[signer]` Lending market owner.
+    ///   4. `[]` Rent sysvar
+    InitRateHistory {
+        /// Minimum slots between two recorded snapshots
+        snapshot_interval: u64,
+    },
+
+    /// Withdraws the reserve factor's share of borrow interest accumulated in a reserve, as far
+    /// as the available liquidity covers it.
+    ///
+    ///   0. `[writable]` Reserve account.
+    ///   1. `[writable]` Reserve liquidity supply SPL Token account.
+    ///   2. `[writable]` Destination liquidity token account.
+    ///   3. `[]` Lending market account.
+    ///   4. `[]` Derived lending market authority.
+    ///   5. `[signer]` Lending market owner.
+    ///   6. '[]` Token program id
+    WithdrawReserveFees,
 }
 
 impl LendingInstruction {
     /// Unpacks a byte buffer into a [LendingInstruction](enum.LendingInstruction.html).
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let (&tag, rest) = input
+        let (&version, rest) = input
             .split_first()
             .ok_or(LendingError::InstructionUnpackError)?;
-        Ok(match tag {
-            0 => {
-                let (market_owner, _rest) = Self::unpack_pubkey(rest)?;
-                Self::InitLendingMarket { market_owner }
-            }
-            1 => {
-                let (liquidity_amount, rest) = Self::unpack_u64(rest)?;
-                let (optimal_utilization_rate, rest) = Self::unpack_u8(rest)?;
-                let (loan_to_value_ratio, rest) = Self::unpack_u8(rest)?;
-                let (liquidation_bonus, rest) = Self::unpack_u8(rest)?;
-                let (liquidation_threshold, rest) = Self::unpack_u8(rest)?;
-                let (min_borrow_rate, rest) = Self::unpack_u8(rest)?;
-                let (optimal_borrow_rate, rest) = Self::unpack_u8(rest)?;
-                let (max_borrow_rate, rest) = Self::unpack_u8(rest)?;
-                let (borrow_fee_wad, rest) = Self::unpack_u64(rest)?;
-                let (host_fee_percentage, _rest) = Self::unpack_u8(rest)?;
-                Self::InitReserve {
-                    liquidity_amount,
-                    config: ReserveConfig {
-                        optimal_utilization_rate,
-                        loan_to_value_ratio,
-                        liquidation_bonus,
-                        liquidation_threshold,
-                        min_borrow_rate,
-                        optimal_borrow_rate,
-                        max_borrow_rate,
-                        fees: ReserveFees {
-                            borrow_fee_wad,
-                            host_fee_percentage,
-                        },
-                    },
-                }
-            }
-            2 => Self::InitObligation,
-            3 => {
-                let (liquidity_amount, _rest) = Self::unpack_u64(rest)?;
-                Self::DepositReserveLiquidity { liquidity_amount }
-            }
-            4 => {
-                let (collateral_amount, _rest) = Self::unpack_u64(rest)?;
-                Self::WithdrawReserveLiquidity { collateral_amount }
-            }
-            5 => {
-                let (amount, rest) = Self::unpack_u64(rest)?;
-                let (amount_type, _rest) = Self::unpack_u8(rest)?;
-                let amount_type = BorrowAmountType::from_u8(amount_type)
-                    .ok_or(LendingError::InstructionUnpackError)?;
-                Self::BorrowReserveLiquidity {
-                    amount,
-                    amount_type,
-                }
-            }
-            6 => {
-                let (liquidity_amount, _rest) = Self::unpack_u64(rest)?;
-                Self::RepayReserveLiquidity { liquidity_amount }
-            }
-            7 => {
-                let (liquidity_amount, _rest) = Self::unpack_u64(rest)?;
-                Self::LiquidateObligation { liquidity_amount }
-            }
-            8 => Self::AccrueReserveInterest,
-            _ => return Err(LendingError::InstructionUnpackError.into()),
-        })
-    }
-
-    fn unpack_u64(input: &[u8]) -> Result<(u64, &[u8]), ProgramError> {
-        if input.len() >= 8 {
-            let (amount, rest) = input.split_at(8);
-            let amount = amount
-                .get(..8)
-                .and_then(|slice| slice.try_into().ok())
-                .map(u64::from_le_bytes)
-                .ok_or(LendingError::InstructionUnpackError)?;
-            Ok((amount, rest))
-        } else {
-            Err(LendingError::InstructionUnpackError.into())
+        if version != INSTRUCTION_VERSION {
+            msg!("Unsupported instruction version {}", version);
+            return Err(LendingError::InstructionUnpackError.into());
         }
+        Self::try_from_slice(rest).map_err(|_| LendingError::InstructionUnpackError.into())
     }
 
-    fn unpack_u8(input: &[u8]) -> Result<(u8, &[u8]), ProgramError> {
-        if !input.is_empty() {
-            let (amount, rest) = input.split_at(1);
-            let amount = amount
-                .get(..1)
-                .and_then(|slice| slice.try_into().ok())
-                .map(u8::from_le_bytes)
-                .ok_or(LendingError::InstructionUnpackError)?;
-            Ok((amount, rest))
-        } else {
-            Err(LendingError::InstructionUnpackError.into())
-        }
+    /// Packs a [LendingInstruction](enum.LendingInstruction.html) into a byte buffer.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut data = vec![INSTRUCTION_VERSION];
+        // Borsh only fails when the writer does, and writing to a `Vec` cannot fail
+        self.serialize(&mut data)
+            .expect("serializing into a Vec is infallible");
+        data
     }
+}
 
-    fn unpack_pubkey(input: &[u8]) -> Result<(Pubkey, &[u8]), ProgramError> {
-        if input.len() >= 32 {
-            let (key, rest) = input.split_at(32);
-            let pk = Pubkey::new(key);
-            Ok((pk, rest))
-        } else {
-            Err(LendingError::InstructionUnpackError.into())
-        }
-    }
+/// Names of the instructions, indexed by tag
+pub const INSTRUCTION_NAMES: [&str; 15] = [
+    "InitLendingMarket",
+    "InitReserve",
+    "InitObligation",
+    "DepositReserveLiquidity",
+    "WithdrawReserveLiquidity",
+    "BorrowReserveLiquidity",
+    "RepayReserveLiquidity",
+    "LiquidateObligation",
+    "AccrueReserveInterest",
+    "MarginBorrowReserveLiquidity",
+    "SetReserveConfig",
+    "InitPriceAccumulator",
+    "UpdatePriceAccumulator",
+    "InitRateHistory",
+    "WithdrawReserveFees",
+];
 
-    /// Packs a [LendingInstruction](enum.LendingInstruction.html) into a byte buffer.
-    pub fn pack(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(size_of::<Self>());
-        match *self {
-            Self::InitLendingMarket { market_owner } => {
-                buf.push(0);
-                buf.extend_from_slice(market_owner.as_ref());
-            }
-            Self::InitReserve {
-                liquidity_amount,
-                config:
-                    ReserveConfig {
-                        optimal_utilization_rate,
-                        loan_to_value_ratio,
-                        liquidation_bonus,
-                        liquidation_threshold,
-                        min_borrow_rate,
-                        optimal_borrow_rate,
-                        max_borrow_rate,
-                        fees:
-                            ReserveFees {
-                                borrow_fee_wad,
-                                host_fee_percentage,
-                            },
-                    },
-            } => {
-                buf.push(1);
-                buf.extend_from_slice(&liquidity_amount.to_le_bytes());
-                buf.extend_from_slice(&optimal_utilization_rate.to_le_bytes());
-                buf.extend_from_slice(&loan_to_value_ratio.to_le_bytes());
-                buf.extend_from_slice(&liquidation_bonus.to_le_bytes());
-                buf.extend_from_slice(&liquidation_threshold.to_le_bytes());
-                buf.extend_from_slice(&min_borrow_rate.to_le_bytes());
-                buf.extend_from_slice(&optimal_borrow_rate.to_le_bytes());
-                buf.extend_from_slice(&max_borrow_rate.to_le_bytes());
-                buf.extend_from_slice(&borrow_fee_wad.to_le_bytes());
-                buf.extend_from_slice(&host_fee_percentage.to_le_bytes());
-            }
-            Self::InitObligation => {
-                buf.push(2);
-            }
-            Self::DepositReserveLiquidity { liquidity_amount } => {
-                buf.push(3);
-                buf.extend_from_slice(&liquidity_amount.to_le_bytes());
-            }
-            Self::WithdrawReserveLiquidity { collateral_amount } => {
-                buf.push(4);
-                buf.extend_from_slice(&collateral_amount.to_le_bytes());
-            }
-            Self::BorrowReserveLiquidity {
-                amount,
-                amount_type,
-            } => {
-                buf.push(5);
-                buf.extend_from_slice(&amount.to_le_bytes());
-                buf.extend_from_slice(&amount_type.to_u8().unwrap().to_le_bytes());
-            }
-            Self::RepayReserveLiquidity { liquidity_amount } => {
-                buf.push(6);
-                buf.extend_from_slice(&liquidity_amount.to_le_bytes());
-            }
-            Self::LiquidateObligation { liquidity_amount } => {
-                buf.push(7);
-                buf.extend_from_slice(&liquidity_amount.to_le_bytes());
-            }
-            Self::AccrueReserveInterest => {
-                buf.push(8);
-            }
-            Self::MarginBorrowReserveLiquidity {
-                collateral_amount,
-                loan_amount,
-                amount_type,
-            } => {
-                buf.push(9);
-                buf.extend_from_slice(&collateral_amount.to_le_bytes());
-                buf.extend_from_slice(&loan_amount.to_le_bytes());
-                buf.extend_from_slice(&amount_type.to_u8().unwrap().to_le_bytes());
-            }
+/// Describes raw instruction data for debugging, including data that fails to unpack
+pub fn describe_instruction(input: &[u8]) -> String {
+    if let Ok(instruction) = LendingInstruction::unpack(input) {
+        return format!("{:?}", instruction);
+    }
+    match input.split_first() {
+        None => "Empty instruction data".to_string(),
+        Some((&version, _)) if version != INSTRUCTION_VERSION => {
+            format!("Unsupported instruction version {}", version)
         }
-        buf
+        Some((_, data)) => match data.split_first() {
+            None => "Instruction data without a tag".to_string(),
+            Some((&tag, rest)) => match INSTRUCTION_NAMES.get(tag as usize) {
+                Some(name) => format!(
+                    "{} with malformed data ({} bytes): {:02x?}",
+                    name,
+                    rest.len(),
+                    rest
+                ),
+                None => format!("Unknown instruction tag {}", tag),
+            },
+        },
     }
 }
 
@@ -464,6 +423,7 @@ pub fn init_reserve(
     lending_market_owner_pubkey: Pubkey,
     user_transfer_authority_pubkey: Pubkey,
     dex_market_pubkey: Option<Pubkey>,
+    price_source: PriceSource,
 ) -> Instruction {
     let (lending_market_authority_pubkey, _bump_seed) =
         Pubkey::find_program_address(&[&lending_market_pubkey.to_bytes()[..32]], &program_id);
@@ -495,6 +455,7 @@ pub fn init_reserve(
         data: LendingInstruction::InitReserve {
             liquidity_amount,
             config,
+            price_source,
         }
         .pack(),
     }
@@ -618,8 +579,8 @@ pub fn borrow_reserve_liquidity(
     obligation_token_mint_pubkey: Pubkey,
     obligation_token_output_pubkey: Pubkey,
     dex_market_pubkey: Pubkey,
-    dex_market_order_book_side_pubkey: Pubkey,
-    memory_pubkey: Pubkey,
+    dex_market_bids_pubkey: Pubkey,
+    dex_market_asks_pubkey: Pubkey,
     deposit_reserve_collateral_host_pubkey: Option<Pubkey>,
 ) -> Instruction {
     let mut accounts = vec![
@@ -637,8 +598,8 @@ pub fn borrow_reserve_liquidity(
         AccountMeta::new_readonly(lending_market_authority_pubkey, false),
         AccountMeta::new_readonly(user_transfer_authority_pubkey, true),
         AccountMeta::new_readonly(dex_market_pubkey, false),
-        AccountMeta::new_readonly(dex_market_order_book_side_pubkey, false),
-        AccountMeta::new_readonly(memory_pubkey, false),
+        AccountMeta::new_readonly(dex_market_bids_pubkey, false),
+        AccountMeta::new_readonly(dex_market_asks_pubkey, false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
         AccountMeta::new_readonly(spl_token::id(), false),
     ];
@@ -680,8 +641,8 @@ pub fn margin_borrow_reserve_liquidity(
     obligation_token_mint_pubkey: Pubkey,
     obligation_token_output_pubkey: Pubkey,
     dex_market_pubkey: Pubkey,
-    dex_market_order_book_side_pubkey: Pubkey,
-    memory_pubkey: Pubkey,
+    dex_market_bids_pubkey: Pubkey,
+    dex_market_asks_pubkey: Pubkey,
     deposit_reserve_collateral_host_pubkey: Option<Pubkey>,
 ) -> Instruction {
     let mut accounts = vec![
@@ -699,8 +660,8 @@ pub fn margin_borrow_reserve_liquidity(
         AccountMeta::new_readonly(lending_market_authority_pubkey, false),
         AccountMeta::new_readonly(user_transfer_authority_pubkey, true),
         AccountMeta::new_readonly(dex_market_pubkey, false),
-        AccountMeta::new_readonly(dex_market_order_book_side_pubkey, false),
-        AccountMeta::new_readonly(memory_pubkey, false),
+        AccountMeta::new_readonly(dex_market_bids_pubkey, false),
+        AccountMeta::new_readonly(dex_market_asks_pubkey, false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
         AccountMeta::new_readonly(spl_token::id(), false),
     ];
@@ -778,43 +739,387 @@ pub fn liquidate_obligation(
     lending_market_authority_pubkey: Pubkey,
     user_transfer_authority_pubkey: Pubkey,
     dex_market_pubkey: Pubkey,
-    dex_market_order_book_side_pubkey: Pubkey,
-    memory_pubkey: Pubkey,
+    dex_market_bids_pubkey: Pubkey,
+    dex_market_asks_pubkey: Pubkey,
+    price_accumulator_pubkey: Option<Pubkey>,
 ) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(source_liquidity_pubkey, false),
+        AccountMeta::new(destination_collateral_pubkey, false),
+        AccountMeta::new(repay_reserve_pubkey, false),
+        AccountMeta::new(repay_reserve_liquidity_supply_pubkey, false),
+        AccountMeta::new_readonly(withdraw_reserve_pubkey, false),
+        AccountMeta::new(withdraw_reserve_collateral_supply_pubkey, false),
+        AccountMeta::new(obligation_pubkey, false),
+        AccountMeta::new_readonly(lending_market_pubkey, false),
+        AccountMeta::new_readonly(lending_market_authority_pubkey, false),
+        AccountMeta::new_readonly(user_transfer_authority_pubkey, true),
+        AccountMeta::new_readonly(dex_market_pubkey, false),
+        AccountMeta::new_readonly(dex_market_bids_pubkey, false),
+        AccountMeta::new_readonly(dex_market_asks_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+    if let Some(price_accumulator_pubkey) = price_accumulator_pubkey {
+        accounts.push(AccountMeta::new_readonly(price_accumulator_pubkey, false));
+    }
     Instruction {
         program_id,
-        accounts: vec![
-            AccountMeta::new(source_liquidity_pubkey, false),
-            AccountMeta::new(destination_collateral_pubkey, false),
-            AccountMeta::new(repay_reserve_pubkey, false),
-            AccountMeta::new(repay_reserve_liquidity_supply_pubkey, false),
-            AccountMeta::new_readonly(withdraw_reserve_pubkey, false),
-            AccountMeta::new(withdraw_reserve_collateral_supply_pubkey, false),
-            AccountMeta::new(obligation_pubkey, false),
-            AccountMeta::new_readonly(lending_market_pubkey, false),
-            AccountMeta::new_readonly(lending_market_authority_pubkey, false),
-            AccountMeta::new_readonly(user_transfer_authority_pubkey, true),
-            AccountMeta::new_readonly(dex_market_pubkey, false),
-            AccountMeta::new_readonly(dex_market_order_book_side_pubkey, false),
-            AccountMeta::new_readonly(memory_pubkey, false),
-            AccountMeta::new_readonly(sysvar::clock::id(), false),
-            AccountMeta::new_readonly(spl_token::id(), false),
-        ],
+        accounts,
         data: LendingInstruction::LiquidateObligation { liquidity_amount }.pack(),
     }
 }
 
 /// Creates an `AccrueReserveInterest` instruction
 pub fn accrue_reserve_interest(program_id: Pubkey, reserve_pubkeys: Vec<Pubkey>) -> Instruction {
-    let mut accounts = vec![AccountMeta::new_readonly(sysvar::clock::id(), false)];
-    accounts.extend(
+    accrue_reserve_interest_with_rate_histories(
+        program_id,
         reserve_pubkeys
             .into_iter()
-            .map(|reserve_pubkey| AccountMeta::new(reserve_pubkey, false)),
-    );
+            .map(|reserve_pubkey| (reserve_pubkey, None))
+            .collect(),
+    )
+}
+
+/// Creates an `AccrueReserveInterest` instruction recording rate snapshots of the reserves
+/// given a rate history
+pub fn accrue_reserve_interest_with_rate_histories(
+    program_id: Pubkey,
+    reserves: Vec<(Pubkey, Option<Pubkey>)>,
+) -> Instruction {
+    let mut accounts = vec![AccountMeta::new_readonly(sysvar::clock::id(), false)];
+    let mut rate_histories = Vec::with_capacity(reserves.len());
+    for (reserve_pubkey, rate_history_pubkey) in reserves {
+        accounts.push(AccountMeta::new(reserve_pubkey, false));
+        rate_histories.push(rate_history_pubkey.is_some());
+        if let Some(rate_history_pubkey) = rate_history_pubkey {
+            accounts.push(AccountMeta::new(rate_history_pubkey, false));
+        }
+    }
+    Instruction {
+        program_id,
+        accounts,
+        data: LendingInstruction::AccrueReserveInterest { rate_histories }.pack(),
+    }
+}
+
+/// Creates a 'SetReserveConfig' instruction.
+pub fn set_reserve_config(
+    program_id: Pubkey,
+    config: ReserveConfig,
+    reserve_pubkey: Pubkey,
+    lending_market_pubkey: Pubkey,
+    lending_market_owner: Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id,
+        accounts: vec![
+            AccountMeta::new(reserve_pubkey, false),
+            AccountMeta::new_readonly(lending_market_pubkey, false),
+            AccountMeta::new_readonly(lending_market_owner, true),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+        ],
+        data: LendingInstruction::SetReserveConfig { config }.pack(),
+    }
+}
+
+/// Creates an 'InitPriceAccumulator' instruction.
+#[allow(clippy::too_many_arguments)]
+pub fn init_price_accumulator(
+    program_id: Pubkey,
+    observation_interval: u64,
+    price_accumulator_pubkey: Pubkey,
+    reserve_pubkey: Pubkey,
+    lending_market_pubkey: Pubkey,
+    lending_market_owner: Pubkey,
+    market_pubkey: Pubkey,
+    bids_or_token_a_pubkey: Pubkey,
+    asks_or_token_b_pubkey: Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id,
+        accounts: vec![
+            AccountMeta::new(price_accumulator_pubkey, false),
+            AccountMeta::new_readonly(reserve_pubkey, false),
+            AccountMeta::new_readonly(lending_market_pubkey, false),
+            AccountMeta::new_readonly(lending_market_owner, true),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new_readonly(market_pubkey, false),
+            AccountMeta::new_readonly(bids_or_token_a_pubkey, false),
+            AccountMeta::new_readonly(asks_or_token_b_pubkey, false),
+        ],
+        data: LendingInstruction::InitPriceAccumulator {
+            observation_interval,
+        }
+        .pack(),
+    }
+}
+
+/// Creates an 'UpdatePriceAccumulator' instruction.
+pub fn update_price_accumulator(
+    program_id: Pubkey,
+    price_accumulator_pubkey: Pubkey,
+    market_pubkey: Pubkey,
+    bids_or_token_a_pubkey: Pubkey,
+    asks_or_token_b_pubkey: Pubkey,
+) -> Instruction {
+    let accounts = vec![
+        AccountMeta::new(price_accumulator_pubkey, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(market_pubkey, false),
+        AccountMeta::new_readonly(bids_or_token_a_pubkey, false),
+        AccountMeta::new_readonly(asks_or_token_b_pubkey, false),
+    ];
     Instruction {
         program_id,
         accounts,
-        data: LendingInstruction::AccrueReserveInterest.pack(),
+        data: LendingInstruction::UpdatePriceAccumulator.pack(),
+    }
+}
+
+/// Creates an 'InitRateHistory' instruction.
+pub fn init_rate_history(
+    program_id: Pubkey,
+    snapshot_interval: u64,
+    rate_history_pubkey: Pubkey,
+    reserve_pubkey: Pubkey,
+    lending_market_pubkey: Pubkey,
+    lending_market_owner: Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id,
+        accounts: vec![
+            AccountMeta::new(rate_history_pubkey, false),
+            AccountMeta::new_readonly(reserve_pubkey, false),
+            AccountMeta::new_readonly(lending_market_pubkey, false),
+            AccountMeta::new_readonly(lending_market_owner, true),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: LendingInstruction::InitRateHistory { snapshot_interval }.pack(),
+    }
+}
+
+/// Creates a 'WithdrawReserveFees' instruction.
+pub fn withdraw_reserve_fees(
+    program_id: Pubkey,
+    reserve_pubkey: Pubkey,
+    reserve_liquidity_supply_pubkey: Pubkey,
+    destination_liquidity_pubkey: Pubkey,
+    lending_market_pubkey: Pubkey,
+    lending_market_authority_pubkey: Pubkey,
+    lending_market_owner: Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id,
+        accounts: vec![
+            AccountMeta::new(reserve_pubkey, false),
+            AccountMeta::new(reserve_liquidity_supply_pubkey, false),
+            AccountMeta::new(destination_liquidity_pubkey, false),
+            AccountMeta::new_readonly(lending_market_pubkey, false),
+            AccountMeta::new_readonly(lending_market_authority_pubkey, false),
+            AccountMeta::new_readonly(lending_market_owner, true),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+        data: LendingInstruction::WithdrawReserveFees.pack(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{AdaptiveRateConfig, BorrowRateCurve, BorrowRateCurvePoint, ReserveFees};
+
+    /// Tag of each instruction. The match has no wildcard so that adding a variant fails to
+    /// compile until it is covered here and in `instructions`.
+    fn tag(instruction: &LendingInstruction) -> u8 {
+        match instruction {
+            LendingInstruction::InitLendingMarket { .. } => 0,
+            LendingInstruction::InitReserve { .. } => 1,
+            LendingInstruction::InitObligation => 2,
+            LendingInstruction::DepositReserveLiquidity { .. } => 3,
+            LendingInstruction::WithdrawReserveLiquidity { .. } => 4,
+            LendingInstruction::BorrowReserveLiquidity { .. } => 5,
+            LendingInstruction::RepayReserveLiquidity { .. } => 6,
+            LendingInstruction::LiquidateObligation { .. } => 7,
+            LendingInstruction::AccrueReserveInterest { .. } => 8,
+            LendingInstruction::MarginBorrowReserveLiquidity { .. } => 9,
+            LendingInstruction::SetReserveConfig { .. } => 10,
+            LendingInstruction::InitPriceAccumulator { .. } => 11,
+            LendingInstruction::UpdatePriceAccumulator => 12,
+            LendingInstruction::InitRateHistory { .. } => 13,
+            LendingInstruction::WithdrawReserveFees => 14,
+        }
+    }
+
+    fn config() -> ReserveConfig {
+        ReserveConfig {
+            optimal_utilization_rate: 80,
+            loan_to_value_ratio: 75,
+            liquidation_bonus: 5,
+            liquidation_threshold: 80,
+            fees: ReserveFees {
+                borrow_fee_wad: 100_000_000_000_000,
+                host_fee_percentage: 20,
+            },
+            max_margin_borrow_amount: 1_000_000,
+            liquidation_twap_slots: 150,
+            borrow_rate_curve: BorrowRateCurve::new(&[
+                BorrowRateCurvePoint {
+                    utilization_rate_bps: 0,
+                    borrow_rate_bps: 100,
+                },
+                BorrowRateCurvePoint {
+                    utilization_rate_bps: 10_000,
+                    borrow_rate_bps: 3_000,
+                },
+            ])
+            .unwrap(),
+            adaptive_rate: AdaptiveRateConfig {
+                rise_gain_wad: 2_000_000_000,
+                fall_gain_wad: 1_000_000_000,
+                min_optimal_borrow_rate_bps: 200,
+                max_optimal_borrow_rate_bps: 1_500,
+            },
+            reserve_factor: 10,
+        }
+    }
+
+    fn instructions() -> Vec<LendingInstruction> {
+        vec![
+            LendingInstruction::InitLendingMarket {
+                market_owner: Pubkey::new_unique(),
+            },
+            LendingInstruction::InitReserve {
+                liquidity_amount: 1,
+                config: config(),
+                price_source: PriceSource::TokenSwap,
+            },
+            LendingInstruction::InitObligation,
+            LendingInstruction::DepositReserveLiquidity {
+                liquidity_amount: 2,
+            },
+            LendingInstruction::WithdrawReserveLiquidity {
+                collateral_amount: 3,
+            },
+            LendingInstruction::BorrowReserveLiquidity {
+                amount: 4,
+                amount_type: BorrowAmountType::CollateralDepositAmount,
+            },
+            LendingInstruction::RepayReserveLiquidity {
+                liquidity_amount: 5,
+            },
+            LendingInstruction::LiquidateObligation {
+                liquidity_amount: 6,
+            },
+            LendingInstruction::AccrueReserveInterest {
+                rate_histories: vec![true, false],
+            },
+            LendingInstruction::MarginBorrowReserveLiquidity {
+                collateral_amount: 7,
+                loan_amount: 8,
+                amount_type: BorrowAmountType::MarginBorrowAmount,
+            },
+            LendingInstruction::SetReserveConfig { config: config() },
+            LendingInstruction::InitPriceAccumulator {
+                observation_interval: 9,
+            },
+            LendingInstruction::UpdatePriceAccumulator,
+            LendingInstruction::InitRateHistory {
+                snapshot_interval: 10,
+            },
+            LendingInstruction::WithdrawReserveFees,
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let instructions = instructions();
+        assert_eq!(instructions.len(), INSTRUCTION_NAMES.len());
+        for (expected_tag, instruction) in instructions.iter().enumerate() {
+            let data = instruction.pack();
+            assert_eq!(tag(instruction), expected_tag as u8);
+            assert_eq!(data[0], INSTRUCTION_VERSION);
+            assert_eq!(data[1], expected_tag as u8);
+            assert_eq!(LendingInstruction::unpack(&data).unwrap(), *instruction);
+            assert!(format!("{:?}", instruction).starts_with(INSTRUCTION_NAMES[expected_tag]));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        for instruction in instructions() {
+            let mut data = instruction.pack();
+            data.push(0);
+            assert!(LendingInstruction::unpack(&data).is_err());
+            data.truncate(data.len() - 2);
+            if !data.is_empty() {
+                assert!(LendingInstruction::unpack(&data).is_err());
+            }
+        }
+        assert!(LendingInstruction::unpack(&[]).is_err());
+        assert!(LendingInstruction::unpack(&[INSTRUCTION_VERSION]).is_err());
+        assert!(
+            LendingInstruction::unpack(&[INSTRUCTION_VERSION, INSTRUCTION_NAMES.len() as u8])
+                .is_err()
+        );
+        // Out of range borrow amount type
+        assert!(LendingInstruction::unpack(&[1, 5, 0, 0, 0, 0, 0, 0, 0, 0, 3]).is_err());
+        // Unknown version
+        let mut data = LendingInstruction::InitObligation.pack();
+        data[0] = INSTRUCTION_VERSION + 1;
+        assert!(LendingInstruction::unpack(&data).is_err());
+    }
+
+    #[test]
+    fn layout() {
+        let data = LendingInstruction::MarginBorrowReserveLiquidity {
+            collateral_amount: 1,
+            loan_amount: 2,
+            amount_type: BorrowAmountType::MarginBorrowAmount,
+        }
+        .pack();
+        assert_eq!(
+            data,
+            vec![1, 9, 1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 2]
+        );
+
+        let data = LendingInstruction::SetReserveConfig { config: config() }.pack();
+        let mut expected = vec![1, 10, 80, 75, 5, 80];
+        expected.extend_from_slice(&100_000_000_000_000u64.to_le_bytes());
+        expected.push(20);
+        expected.extend_from_slice(&1_000_000u64.to_le_bytes());
+        expected.extend_from_slice(&150u64.to_le_bytes());
+        // borrow rate curve: point count, then all eight points with unused ones zeroed
+        expected.push(2);
+        for value in &[0u16, 100, 10_000, 3_000] {
+            expected.extend_from_slice(&value.to_le_bytes());
+        }
+        expected.extend_from_slice(&[0; 24]);
+        expected.extend_from_slice(&2_000_000_000u64.to_le_bytes());
+        expected.extend_from_slice(&1_000_000_000u64.to_le_bytes());
+        expected.extend_from_slice(&200u16.to_le_bytes());
+        expected.extend_from_slice(&1_500u16.to_le_bytes());
+        expected.push(10);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn describe() {
+        assert_eq!(
+            describe_instruction(&[1, 3, 100, 0, 0, 0, 0, 0, 0, 0]),
+            "DepositReserveLiquidity { liquidity_amount: 100 }"
+        );
+        assert_eq!(
+            describe_instruction(&[1, 6, 1, 2]),
+            "RepayReserveLiquidity with malformed data (2 bytes): [01, 02]"
+        );
+        assert_eq!(describe_instruction(&[1, 42]), "Unknown instruction tag 42");
+        assert_eq!(
+            describe_instruction(&[2, 3]),
+            "Unsupported instruction version 2"
+        );
+        assert_eq!(describe_instruction(&[1]), "Instruction data without a tag");
+        assert_eq!(describe_instruction(&[]), "Empty instruction data");
     }
 }
@@ -9,6 +9,8 @@ pub mod instruction;
 pub mod math;
 pub mod processor;
 pub mod state;
+pub mod swap_pool;
+pub mod twap;
 
 // Export current sdk types for downstream users building with a different sdk version
 pub use solana_program;
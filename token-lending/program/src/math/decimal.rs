@@ -94,6 +94,23 @@ impl Decimal {
             .ok_or(LendingError::MathOverflow)?;
         Ok(u64::try_from(ceil_val).map_err(|_| LendingError::MathOverflow)?)
     }
+
+    /// Calculates base^exp, without squaring the base past the highest bit of the exponent
+    pub fn try_pow(&self, mut exp: u64) -> Result<Decimal, ProgramError> {
+        let mut base = *self;
+        let mut ret = Self::one();
+        while exp > 0 {
+            if exp % 2 != 0 {
+                ret = ret.try_mul(base)?;
+            }
+            exp /= 2;
+            if exp > 0 {
+                base = base.try_mul(base)?;
+            }
+        }
+
+        Ok(ret)
+    }
 }
 
 impl fmt::Display for Decimal {
@@ -211,4 +228,14 @@ mod test {
     fn test_scaler() {
         assert_eq!(U192::exp10(SCALE), Decimal::wad());
     }
+
+    #[test]
+    fn checked_pow() {
+        assert_eq!(Decimal::one(), Decimal::one().try_pow(u64::MAX).unwrap());
+        assert_eq!(
+            Decimal::from(3u64).try_pow(5).unwrap(),
+            Decimal::from(243u64)
+        );
+        assert_eq!(Decimal::from(7u64).try_pow(0).unwrap(), Decimal::one());
+    }
 }
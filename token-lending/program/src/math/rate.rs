@@ -3,7 +3,7 @@
 //! Usages and their ranges include:
 //!   - Collateral exchange ratio <= 5.0
 //!   - Loan to value ratio <= 0.9
-//!   - Max borrow rate <= 2.56
+//!   - Max borrow rate <= 6.5535
 //!   - Percentages <= 1.0
 //!
 //! Rates are internally scaled by a WAD (10^18) to preserve
@@ -61,6 +61,11 @@ impl Rate {
         Self(U128::from(percent as u64 * PERCENT_SCALER))
     }
 
+    /// Create scaled decimal from basis points value
+    pub fn from_bps(bps: u16) -> Self {
+        Self(U128::from(bps as u64 * BPS_SCALER))
+    }
+
     /// Return raw scaled value
     pub fn to_scaled_val(&self) -> u128 {
         self.0.as_u128()
@@ -4,11 +4,15 @@ use crate::{
     dex_market::{DexMarket, TradeSimulator, BASE_MINT_OFFSET, QUOTE_MINT_OFFSET},
     error::LendingError,
     instruction::{BorrowAmountType, LendingInstruction},
-    math::{Decimal, TryAdd, WAD},
+    math::{Decimal, TryAdd, TryDiv, WAD},
     state::{
-        LendingMarket, LiquidateResult, NewObligationParams, NewReserveParams, Obligation,
-        RepayResult, Reserve, ReserveCollateral, ReserveConfig, ReserveLiquidity, PROGRAM_VERSION,
+        LendingMarket, LiquidateResult, NewObligationParams, NewPriceAccumulatorParams,
+        NewReserveParams, Obligation, PriceAccumulator, PriceSource, RateHistory, RateSnapshot,
+        RepayResult, Reserve, ReserveCollateral, ReserveConfig, ReserveLiquidity, TokenConverter,
+        PROGRAM_VERSION,
     },
+    swap_pool::{SwapPool, SwapSimulator},
+    twap::TwapConverter,
 };
 use num_traits::FromPrimitive;
 use solana_program::{
@@ -41,9 +45,10 @@ pub fn process_instruction(
         LendingInstruction::InitReserve {
             liquidity_amount,
             config,
+            price_source,
         } => {
             msg!("Instruction: Init Reserve");
-            process_init_reserve(program_id, liquidity_amount, config, accounts)
+            process_init_reserve(program_id, liquidity_amount, config, price_source, accounts)
         }
         LendingInstruction::InitObligation => {
             msg!("Instruction: Init Obligation");
@@ -86,9 +91,31 @@ pub fn process_instruction(
             msg!("Instruction: Liquidate");
             process_liquidate(program_id, liquidity_amount, accounts)
         }
-        LendingInstruction::AccrueReserveInterest => {
+        LendingInstruction::AccrueReserveInterest { rate_histories } => {
             msg!("Instruction: Accrue Interest");
-            process_accrue_interest(program_id, accounts)
+            process_accrue_interest(program_id, &rate_histories, accounts)
+        }
+        LendingInstruction::SetReserveConfig { config } => {
+            msg!("Instruction: Set Reserve Config");
+            process_set_reserve_config(program_id, config, accounts)
+        }
+        LendingInstruction::InitPriceAccumulator {
+            observation_interval,
+        } => {
+            msg!("Instruction: Init Price Accumulator");
+            process_init_price_accumulator(program_id, observation_interval, accounts)
+        }
+        LendingInstruction::UpdatePriceAccumulator => {
+            msg!("Instruction: Update Price Accumulator");
+            process_update_price_accumulator(program_id, accounts)
+        }
+        LendingInstruction::InitRateHistory { snapshot_interval } => {
+            msg!("Instruction: Init Rate History");
+            process_init_rate_history(program_id, snapshot_interval, accounts)
+        }
+        LendingInstruction::WithdrawReserveFees => {
+            msg!("Instruction: Withdraw Reserve Fees");
+            process_withdraw_reserve_fees(program_id, accounts)
         }
     }
 }
@@ -127,46 +154,14 @@ fn process_init_reserve(
     program_id: &Pubkey,
     liquidity_amount: u64,
     config: ReserveConfig,
+    price_source: PriceSource,
     accounts: &[AccountInfo],
 ) -> ProgramResult {
     if liquidity_amount == 0 {
         msg!("Reserve must be initialized with liquidity");
         return Err(LendingError::InvalidAmount.into());
     }
-    if config.optimal_utilization_rate > 100 {
-        msg!("Optimal utilization rate must be in range [0, 100]");
-        return Err(LendingError::InvalidConfig.into());
-    }
-    if config.loan_to_value_ratio >= 100 {
-        msg!("Loan to value ratio must be in range [0, 100)");
-        return Err(LendingError::InvalidConfig.into());
-    }
-    if config.liquidation_bonus > 100 {
-        msg!("Liquidation bonus must be in range [0, 100]");
-        return Err(LendingError::InvalidConfig.into());
-    }
-    if config.liquidation_threshold <= config.loan_to_value_ratio
-        || config.liquidation_threshold > 100
-    {
-        msg!("Liquidation threshold must be in range (LTV, 100]");
-        return Err(LendingError::InvalidConfig.into());
-    }
-    if config.optimal_borrow_rate < config.min_borrow_rate {
-        msg!("Optimal borrow rate must be >= min borrow rate");
-        return Err(LendingError::InvalidConfig.into());
-    }
-    if config.optimal_borrow_rate > config.max_borrow_rate {
-        msg!("Optimal borrow rate must be <= max borrow rate");
-        return Err(LendingError::InvalidConfig.into());
-    }
-    if config.fees.borrow_fee_wad >= WAD {
-        msg!("Borrow fee must be in range [0, 1_000_000_000_000_000_000)");
-        return Err(LendingError::InvalidConfig.into());
-    }
-    if config.fees.host_fee_percentage > 100 {
-        msg!("Host fee percentage must be in range [0, 100]");
-        return Err(LendingError::InvalidConfig.into());
-    }
+    validate_reserve_config(&config)?;
 
     let account_info_iter = &mut accounts.iter();
     let source_liquidity_info = next_account_info(account_info_iter)?;
@@ -216,13 +211,28 @@ fn process_init_reserve(
         }
 
         let dex_market_data = &dex_market_info.data.borrow();
-        let market_quote_mint = DexMarket::pubkey_at_offset(&dex_market_data, QUOTE_MINT_OFFSET);
-        if lending_market.quote_token_mint != market_quote_mint {
-            return Err(LendingError::DexMarketMintMismatch.into());
-        }
-        let market_base_mint = DexMarket::pubkey_at_offset(&dex_market_data, BASE_MINT_OFFSET);
-        if reserve_liquidity_mint_info.key != &market_base_mint {
-            return Err(LendingError::DexMarketMintMismatch.into());
+        match price_source {
+            PriceSource::DexMarket => {
+                let market_quote_mint =
+                    DexMarket::pubkey_at_offset(&dex_market_data, QUOTE_MINT_OFFSET);
+                if lending_market.quote_token_mint != market_quote_mint {
+                    return Err(LendingError::DexMarketMintMismatch.into());
+                }
+                let market_base_mint =
+                    DexMarket::pubkey_at_offset(&dex_market_data, BASE_MINT_OFFSET);
+                if reserve_liquidity_mint_info.key != &market_base_mint {
+                    return Err(LendingError::DexMarketMintMismatch.into());
+                }
+            }
+            PriceSource::TokenSwap => {
+                let swap_pool = SwapPool::unpack(&dex_market_data)?;
+                if !swap_pool.trades(
+                    reserve_liquidity_mint_info.key,
+                    &lending_market.quote_token_mint,
+                ) {
+                    return Err(LendingError::SwapPoolMintMismatch.into());
+                }
+            }
         }
 
         COption::Some(*dex_market_info.key)
@@ -261,6 +271,7 @@ fn process_init_reserve(
         collateral: reserve_collateral_info,
         liquidity: reserve_liquidity_info,
         dex_market,
+        price_source,
         config,
     });
     let collateral_amount = reserve.deposit_liquidity(liquidity_amount)?;
@@ -627,16 +638,11 @@ fn process_borrow(
     let lending_market_authority_info = next_account_info(account_info_iter)?;
     let user_transfer_authority_info = next_account_info(account_info_iter)?;
     let dex_market_info = next_account_info(account_info_iter)?;
-    let dex_market_orders_info = next_account_info(account_info_iter)?;
-    let memory = next_account_info(account_info_iter)?;
+    let dex_market_bids_info = next_account_info(account_info_iter)?;
+    let dex_market_asks_info = next_account_info(account_info_iter)?;
     let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
     let token_program_id = next_account_info(account_info_iter)?;
 
-    // Ensure memory is owned by this program so that we don't have to zero it out
-    if memory.owner != program_id {
-        return Err(LendingError::InvalidAccountOwner.into());
-    }
-
     let lending_market = LendingMarket::unpack(&lending_market_info.data.borrow())?;
     if lending_market_info.owner != program_id {
         return Err(LendingError::InvalidAccountOwner.into());
@@ -743,10 +749,11 @@ fn process_borrow(
     assert_last_update_slot(&deposit_reserve, clock.slot)?;
     obligation.accrue_interest(borrow_reserve.cumulative_borrow_rate_wads)?;
 
-    let trade_simulator = TradeSimulator::new(
+    let trade_simulator = ReserveConverter::new(
+        loan_price_source(&borrow_reserve, &deposit_reserve)?,
         dex_market_info,
-        dex_market_orders_info,
-        memory,
+        dex_market_bids_info,
+        dex_market_asks_info,
         &lending_market.quote_token_mint,
         &borrow_reserve.liquidity.mint_pubkey,
         &deposit_reserve.liquidity.mint_pubkey,
@@ -868,16 +875,11 @@ pub fn process_margin_borrow(
     let lending_market_authority_info = next_account_info(account_info_iter)?;
     let user_transfer_authority_info = next_account_info(account_info_iter)?;
     let dex_market_info = next_account_info(account_info_iter)?;
-    let dex_market_orders_info = next_account_info(account_info_iter)?;
-    let memory = next_account_info(account_info_iter)?;
+    let dex_market_bids_info = next_account_info(account_info_iter)?;
+    let dex_market_asks_info = next_account_info(account_info_iter)?;
     let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
     let token_program_id = next_account_info(account_info_iter)?;
 
-    // Ensure memory is owned by this program so that we don't have to zero it out
-    if memory.owner != program_id {
-        return Err(LendingError::InvalidAccountOwner.into());
-    }
-
     let lending_market = LendingMarket::unpack(&lending_market_info.data.borrow())?;
     if lending_market_info.owner != program_id {
         return Err(LendingError::InvalidAccountOwner.into());
@@ -984,10 +986,11 @@ pub fn process_margin_borrow(
     assert_last_update_slot(&deposit_reserve, clock.slot)?;
     obligation.accrue_interest(borrow_reserve.cumulative_borrow_rate_wads)?;
 
-    let trade_simulator = TradeSimulator::new(
+    let trade_simulator = ReserveConverter::new(
+        loan_price_source(&borrow_reserve, &deposit_reserve)?,
         dex_market_info,
-        dex_market_orders_info,
-        memory,
+        dex_market_bids_info,
+        dex_market_asks_info,
         &lending_market.quote_token_mint,
         &borrow_reserve.liquidity.mint_pubkey,
         &deposit_reserve.liquidity.mint_pubkey,
@@ -1001,10 +1004,17 @@ pub fn process_margin_borrow(
         &borrow_reserve.liquidity.mint_pubkey,
     )?;
 
-    borrow_reserve.liquidity.borrow(loan.borrow_amount)?;
+    borrow_reserve.liquidity.margin_borrow(
+        loan.borrow_amount,
+        borrow_reserve.config.max_margin_borrow_amount,
+    )?;
     obligation.borrowed_liquidity_wads = obligation
         .borrowed_liquidity_wads
         .try_add(Decimal::from(loan.borrow_amount))?;
+    obligation.margin_borrowed_amount = obligation
+        .margin_borrowed_amount
+        .checked_add(loan.borrow_amount)
+        .ok_or(LendingError::MathOverflow)?;
     obligation.deposited_collateral_tokens += loan.collateral_amount;
 
     Obligation::pack(obligation, &mut obligation_info.data.borrow_mut())?;
@@ -1186,10 +1196,14 @@ fn process_repay(
         decimal_repay_amount,
         collateral_withdraw_amount,
         obligation_token_amount,
+        margin_release_amount,
     } = obligation.repay(liquidity_amount, obligation_mint.supply)?;
     repay_reserve
         .liquidity
         .repay(integer_repay_amount, decimal_repay_amount)?;
+    repay_reserve
+        .liquidity
+        .release_margin_borrow(margin_release_amount);
 
     Reserve::pack(repay_reserve, &mut repay_reserve_info.data.borrow_mut())?;
     Obligation::pack(obligation, &mut obligation_info.data.borrow_mut())?;
@@ -1259,16 +1273,11 @@ fn process_liquidate(
     let lending_market_authority_info = next_account_info(account_info_iter)?;
     let user_transfer_authority_info = next_account_info(account_info_iter)?;
     let dex_market_info = next_account_info(account_info_iter)?;
-    let dex_market_orders_info = next_account_info(account_info_iter)?;
-    let memory = next_account_info(account_info_iter)?;
+    let dex_market_bids_info = next_account_info(account_info_iter)?;
+    let dex_market_asks_info = next_account_info(account_info_iter)?;
     let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
     let token_program_id = next_account_info(account_info_iter)?;
 
-    // Ensure memory is owned by this program so that we don't have to zero it out
-    if memory.owner != program_id {
-        return Err(LendingError::InvalidAccountOwner.into());
-    }
-
     let lending_market = LendingMarket::unpack(&lending_market_info.data.borrow())?;
     if lending_market_info.owner != program_id {
         return Err(LendingError::InvalidAccountOwner.into());
@@ -1356,10 +1365,41 @@ fn process_liquidate(
     assert_last_update_slot(&withdraw_reserve, clock.slot)?;
     obligation.accrue_interest(repay_reserve.cumulative_borrow_rate_wads)?;
 
-    let trade_simulator = TradeSimulator::new(
+    // the reserve priced off the market decides whether health is judged at an average price
+    let market_reserve = if repay_reserve.dex_market.is_some() {
+        &repay_reserve
+    } else {
+        &withdraw_reserve
+    };
+    let twap_slots = market_reserve.config.liquidation_twap_slots;
+    let base_token_mint = market_reserve.liquidity.mint_pubkey;
+    let twap = if twap_slots > 0 {
+        let price_accumulator_info = next_account_info(account_info_iter)?;
+        let price_accumulator = PriceAccumulator::unpack(&price_accumulator_info.data.borrow())?;
+        if price_accumulator_info.owner != program_id {
+            return Err(LendingError::InvalidAccountOwner.into());
+        }
+        if &price_accumulator.lending_market != lending_market_info.key
+            || &price_accumulator.market != dex_market_info.key
+            || price_accumulator.base_mint != base_token_mint
+        {
+            msg!("Invalid price accumulator account");
+            return Err(LendingError::InvalidAccountInput.into());
+        }
+        if twap_slots > price_accumulator.max_twap_slots() {
+            msg!("Price accumulator observations are too far apart for the averaging window");
+            return Err(LendingError::PriceHistoryUnavailable.into());
+        }
+        Some(price_accumulator.twap(clock.slot, twap_slots)?)
+    } else {
+        None
+    };
+
+    let trade_simulator = ReserveConverter::new(
+        loan_price_source(&repay_reserve, &withdraw_reserve)?,
         dex_market_info,
-        dex_market_orders_info,
-        memory,
+        dex_market_bids_info,
+        dex_market_asks_info,
         &lending_market.quote_token_mint,
         &withdraw_reserve.liquidity.mint_pubkey,
         &repay_reserve.liquidity.mint_pubkey,
@@ -1369,14 +1409,26 @@ fn process_liquidate(
         withdraw_amount,
         repay_amount,
         settle_amount,
-    } = withdraw_reserve.liquidate_obligation(
-        &obligation,
-        liquidity_amount,
-        &repay_reserve.liquidity.mint_pubkey,
-        trade_simulator,
-    )?;
+    } = match twap {
+        Some(twap) => withdraw_reserve.liquidate_obligation(
+            &obligation,
+            liquidity_amount,
+            &repay_reserve.liquidity.mint_pubkey,
+            TwapConverter::new(trade_simulator, twap, &base_token_mint),
+        )?,
+        None => withdraw_reserve.liquidate_obligation(
+            &obligation,
+            liquidity_amount,
+            &repay_reserve.liquidity.mint_pubkey,
+            trade_simulator,
+        )?,
+    };
 
+    let margin_release_amount = obligation.release_margin_borrow(settle_amount)?;
     repay_reserve.liquidity.repay(repay_amount, settle_amount)?;
+    repay_reserve
+        .liquidity
+        .release_margin_borrow(margin_release_amount);
     Reserve::pack(repay_reserve, &mut repay_reserve_info.data.borrow_mut())?;
 
     obligation.liquidate(settle_amount, withdraw_amount)?;
@@ -1416,22 +1468,446 @@ fn process_liquidate(
 }
 
 #[inline(never)] // avoid stack frame limit
-fn process_accrue_interest(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+fn process_accrue_interest(
+    program_id: &Pubkey,
+    rate_histories: &[bool],
+    accounts: &[AccountInfo],
+) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
-    for reserve_info in account_info_iter {
+    for &has_rate_history in rate_histories {
+        let reserve_info = next_account_info(account_info_iter)?;
         let mut reserve = Reserve::unpack(&reserve_info.data.borrow())?;
         if reserve_info.owner != program_id {
             return Err(LendingError::InvalidAccountOwner.into());
         }
 
         reserve.accrue_interest(clock.slot)?;
+
+        if has_rate_history {
+            let rate_history_info = next_account_info(account_info_iter)?;
+            let mut rate_history = RateHistory::unpack(&rate_history_info.data.borrow())?;
+            if rate_history_info.owner != program_id {
+                return Err(LendingError::InvalidAccountOwner.into());
+            }
+            if &rate_history.reserve != reserve_info.key {
+                msg!("Invalid rate history reserve account");
+                return Err(LendingError::InvalidAccountInput.into());
+            }
+
+            if rate_history.record(RateSnapshot::new(&reserve)?) {
+                RateHistory::pack(rate_history, &mut rate_history_info.data.borrow_mut())?;
+            }
+        }
+
         Reserve::pack(reserve, &mut reserve_info.data.borrow_mut())?;
     }
+    if account_info_iter.next().is_some() {
+        msg!("More accounts than the reserves and rate histories in the instruction data");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+
+    Ok(())
+}
+
+fn process_set_reserve_config(
+    program_id: &Pubkey,
+    config: ReserveConfig,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    validate_reserve_config(&config)?;
+
+    let account_info_iter = &mut accounts.iter();
+    let reserve_info = next_account_info(account_info_iter)?;
+    let lending_market_info = next_account_info(account_info_iter)?;
+    let lending_market_owner_info = next_account_info(account_info_iter)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+
+    let mut reserve = Reserve::unpack(&reserve_info.data.borrow())?;
+    if reserve_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &reserve.lending_market != lending_market_info.key {
+        msg!("Invalid reserve lending market account");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+
+    let lending_market = LendingMarket::unpack(&lending_market_info.data.borrow())?;
+    if lending_market_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &lending_market.owner != lending_market_owner_info.key {
+        return Err(LendingError::InvalidMarketOwner.into());
+    }
+    if !lending_market_owner_info.is_signer {
+        return Err(LendingError::InvalidSigner.into());
+    }
+
+    // interest up to now accrues at the previous rates
+    reserve.accrue_interest(clock.slot)?;
+    reserve.set_config(config);
+    Reserve::pack(reserve, &mut reserve_info.data.borrow_mut())?;
+
+    Ok(())
+}
+
+fn process_init_price_accumulator(
+    program_id: &Pubkey,
+    observation_interval: u64,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let price_accumulator_info = next_account_info(account_info_iter)?;
+    let reserve_info = next_account_info(account_info_iter)?;
+    let lending_market_info = next_account_info(account_info_iter)?;
+    let lending_market_owner_info = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let bids_or_token_a_info = next_account_info(account_info_iter)?;
+    let asks_or_token_b_info = next_account_info(account_info_iter)?;
+
+    let lending_market = LendingMarket::unpack(&lending_market_info.data.borrow())?;
+    if lending_market_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &lending_market.owner != lending_market_owner_info.key {
+        return Err(LendingError::InvalidMarketOwner.into());
+    }
+    if !lending_market_owner_info.is_signer {
+        return Err(LendingError::InvalidSigner.into());
+    }
+
+    let reserve = Reserve::unpack(&reserve_info.data.borrow())?;
+    if reserve_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &reserve.lending_market != lending_market_info.key {
+        msg!("Invalid reserve lending market account");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+    let market = match reserve.dex_market {
+        COption::Some(market) => market,
+        COption::None => {
+            msg!("Reserve is not priced off a market");
+            return Err(LendingError::InvalidAccountInput.into());
+        }
+    };
+
+    if price_accumulator_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    assert_rent_exempt(rent, price_accumulator_info)?;
+    assert_uninitialized::<PriceAccumulator>(price_accumulator_info)?;
+
+    if market_info.key != &market {
+        msg!("Invalid market account");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+
+    let mut price_accumulator = PriceAccumulator::new(NewPriceAccumulatorParams {
+        lending_market: *lending_market_info.key,
+        market,
+        price_source: reserve.price_source,
+        base_mint: reserve.liquidity.mint_pubkey,
+        quote_mint: lending_market.quote_token_mint,
+        observation_interval,
+    });
+    if reserve.config.liquidation_twap_slots > price_accumulator.max_twap_slots() {
+        msg!(
+            "Observation interval is too short to cover the reserve's liquidation averaging window"
+        );
+        return Err(LendingError::InvalidConfig.into());
+    }
+    // The first sample isn't clamped, so it is taken here, signed by the market owner, rather
+    // than by whoever sends the first update
+    let price = market_price(
+        &price_accumulator,
+        market_info,
+        bids_or_token_a_info,
+        asks_or_token_b_info,
+    )?;
+    price_accumulator.update(clock.slot, price)?;
+    PriceAccumulator::pack(
+        price_accumulator,
+        &mut price_accumulator_info.data.borrow_mut(),
+    )?;
+
+    Ok(())
+}
+
+fn process_init_rate_history(
+    program_id: &Pubkey,
+    snapshot_interval: u64,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let rate_history_info = next_account_info(account_info_iter)?;
+    let reserve_info = next_account_info(account_info_iter)?;
+    let lending_market_info = next_account_info(account_info_iter)?;
+    let lending_market_owner_info = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    let lending_market = LendingMarket::unpack(&lending_market_info.data.borrow())?;
+    if lending_market_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &lending_market.owner != lending_market_owner_info.key {
+        return Err(LendingError::InvalidMarketOwner.into());
+    }
+    if !lending_market_owner_info.is_signer {
+        return Err(LendingError::InvalidSigner.into());
+    }
+
+    let reserve = Reserve::unpack(&reserve_info.data.borrow())?;
+    if reserve_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &reserve.lending_market != lending_market_info.key {
+        msg!("Invalid reserve lending market account");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+
+    if rate_history_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    assert_rent_exempt(rent, rate_history_info)?;
+    assert_uninitialized::<RateHistory>(rate_history_info)?;
+
+    let rate_history = RateHistory::new(*reserve_info.key, snapshot_interval);
+    RateHistory::pack(rate_history, &mut rate_history_info.data.borrow_mut())?;
 
     Ok(())
 }
 
+#[inline(never)] // avoid stack frame limit
+fn process_update_price_accumulator(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let price_accumulator_info = next_account_info(account_info_iter)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let market_info = next_account_info(account_info_iter)?;
+    let bids_or_token_a_info = next_account_info(account_info_iter)?;
+    let asks_or_token_b_info = next_account_info(account_info_iter)?;
+
+    let mut price_accumulator = PriceAccumulator::unpack(&price_accumulator_info.data.borrow())?;
+    if price_accumulator_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &price_accumulator.market != market_info.key {
+        msg!("Invalid market account");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+    if price_accumulator.observations.is_empty() {
+        msg!("Price accumulator was not seeded when initialized");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+
+    let price = market_price(
+        &price_accumulator,
+        market_info,
+        bids_or_token_a_info,
+        asks_or_token_b_info,
+    )?;
+    price_accumulator.update(clock.slot, price)?;
+    PriceAccumulator::pack(
+        price_accumulator,
+        &mut price_accumulator_info.data.borrow_mut(),
+    )?;
+
+    Ok(())
+}
+
+/// Current price of a price accumulator's market, the mid price of a dex market or the spot
+/// price of a token swap pool
+fn process_withdraw_reserve_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let reserve_info = next_account_info(account_info_iter)?;
+    let reserve_liquidity_supply_info = next_account_info(account_info_iter)?;
+    let destination_liquidity_info = next_account_info(account_info_iter)?;
+    let lending_market_info = next_account_info(account_info_iter)?;
+    let lending_market_authority_info = next_account_info(account_info_iter)?;
+    let lending_market_owner_info = next_account_info(account_info_iter)?;
+    let token_program_id = next_account_info(account_info_iter)?;
+
+    let lending_market = LendingMarket::unpack(&lending_market_info.data.borrow())?;
+    if lending_market_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &lending_market.owner != lending_market_owner_info.key {
+        return Err(LendingError::InvalidMarketOwner.into());
+    }
+    if !lending_market_owner_info.is_signer {
+        return Err(LendingError::InvalidSigner.into());
+    }
+    if &lending_market.token_program_id != token_program_id.key {
+        return Err(LendingError::InvalidTokenProgram.into());
+    }
+
+    let mut reserve = Reserve::unpack(&reserve_info.data.borrow())?;
+    if reserve_info.owner != program_id {
+        return Err(LendingError::InvalidAccountOwner.into());
+    }
+    if &reserve.lending_market != lending_market_info.key {
+        msg!("Invalid reserve lending market account");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+    if &reserve.liquidity.supply_pubkey != reserve_liquidity_supply_info.key {
+        msg!("Invalid reserve liquidity supply account");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+    if &reserve.liquidity.supply_pubkey == destination_liquidity_info.key {
+        msg!("Cannot use reserve liquidity supply as destination account input");
+        return Err(LendingError::InvalidAccountInput.into());
+    }
+
+    let withdraw_amount = reserve.liquidity.withdraw_fees()?;
+    Reserve::pack(reserve, &mut reserve_info.data.borrow_mut())?;
+
+    let authority_signer_seeds = &[
+        lending_market_info.key.as_ref(),
+        &[lending_market.bump_seed],
+    ];
+    let lending_market_authority_pubkey =
+        Pubkey::create_program_address(authority_signer_seeds, program_id)?;
+    if lending_market_authority_info.key != &lending_market_authority_pubkey {
+        return Err(LendingError::InvalidMarketAuthority.into());
+    }
+
+    spl_token_transfer(TokenTransferParams {
+        source: reserve_liquidity_supply_info.clone(),
+        destination: destination_liquidity_info.clone(),
+        amount: withdraw_amount,
+        authority: lending_market_authority_info.clone(),
+        authority_signer_seeds,
+        token_program: token_program_id.clone(),
+    })?;
+
+    Ok(())
+}
+
+fn market_price(
+    price_accumulator: &PriceAccumulator,
+    market_info: &AccountInfo,
+    bids_or_token_a_info: &AccountInfo,
+    asks_or_token_b_info: &AccountInfo,
+) -> Result<Decimal, ProgramError> {
+    let base_mint = &price_accumulator.base_mint;
+    let quote_mint = &price_accumulator.quote_mint;
+    match price_accumulator.price_source {
+        PriceSource::DexMarket => {
+            let bid = TradeSimulator::new(
+                market_info,
+                bids_or_token_a_info,
+                asks_or_token_b_info,
+                quote_mint,
+                quote_mint,
+                base_mint,
+            )?
+            .best_price(base_mint)?;
+            let ask = TradeSimulator::new(
+                market_info,
+                bids_or_token_a_info,
+                asks_or_token_b_info,
+                quote_mint,
+                base_mint,
+                quote_mint,
+            )?
+            .best_price(base_mint)?;
+            bid.try_add(ask)?.try_div(2)
+        }
+        PriceSource::TokenSwap => SwapSimulator::new(
+            market_info,
+            bids_or_token_a_info,
+            asks_or_token_b_info,
+            base_mint,
+            quote_mint,
+        )?
+        .spot_price(base_mint),
+    }
+}
+
+/// Token converter for the market a pair of reserves is priced off
+enum ReserveConverter<'a> {
+    DexMarket(TradeSimulator<'a>),
+    TokenSwap(SwapSimulator<'a>),
+}
+
+impl<'a> ReserveConverter<'a> {
+    /// Create a converter from the three market accounts of an instruction, which are the dex
+    /// market and its bids and asks, or the token swap pool and its token A and B accounts
+    fn new(
+        price_source: PriceSource,
+        market_info: &AccountInfo,
+        second_market_info: &'a AccountInfo,
+        third_market_info: &'a AccountInfo,
+        quote_token_mint: &'a Pubkey,
+        buy_token_mint: &'a Pubkey,
+        sell_token_mint: &'a Pubkey,
+    ) -> Result<Self, ProgramError> {
+        match price_source {
+            PriceSource::DexMarket => Ok(ReserveConverter::DexMarket(TradeSimulator::new(
+                market_info,
+                second_market_info,
+                third_market_info,
+                quote_token_mint,
+                buy_token_mint,
+                sell_token_mint,
+            )?)),
+            PriceSource::TokenSwap => Ok(ReserveConverter::TokenSwap(SwapSimulator::new(
+                market_info,
+                second_market_info,
+                third_market_info,
+                buy_token_mint,
+                sell_token_mint,
+            )?)),
+        }
+    }
+}
+
+impl<'a> TokenConverter for ReserveConverter<'a> {
+    fn best_price(&mut self, token_mint: &Pubkey) -> Result<Decimal, ProgramError> {
+        match self {
+            ReserveConverter::DexMarket(converter) => converter.best_price(token_mint),
+            ReserveConverter::TokenSwap(converter) => converter.best_price(token_mint),
+        }
+    }
+
+    fn convert(
+        self,
+        from_amount: Decimal,
+        from_token_mint: &Pubkey,
+    ) -> Result<Decimal, ProgramError> {
+        match self {
+            ReserveConverter::DexMarket(converter) => {
+                converter.convert(from_amount, from_token_mint)
+            }
+            ReserveConverter::TokenSwap(converter) => {
+                converter.convert(from_amount, from_token_mint)
+            }
+        }
+    }
+}
+
+/// Price source of the reserve pair of a loan, taken from the reserve that has a market
+fn loan_price_source(
+    reserve: &Reserve,
+    other_reserve: &Reserve,
+) -> Result<PriceSource, ProgramError> {
+    match (reserve.dex_market, other_reserve.dex_market) {
+        (COption::Some(_), COption::Some(_))
+            if reserve.price_source != other_reserve.price_source =>
+        {
+            msg!("Reserve price sources do not match");
+            Err(LendingError::InvalidAccountInput.into())
+        }
+        (COption::Some(_), _) => Ok(reserve.price_source),
+        _ => Ok(other_reserve.price_source),
+    }
+}
+
 impl PrintProgramError for LendingError {
     fn print<E>(&self)
     where
@@ -1441,6 +1917,77 @@ impl PrintProgramError for LendingError {
     }
 }
 
+fn validate_reserve_config(config: &ReserveConfig) -> ProgramResult {
+    if config.optimal_utilization_rate > 100 {
+        msg!("Optimal utilization rate must be in range [0, 100]");
+        return Err(LendingError::InvalidConfig.into());
+    }
+    if config.loan_to_value_ratio >= 100 {
+        msg!("Loan to value ratio must be in range [0, 100)");
+        return Err(LendingError::InvalidConfig.into());
+    }
+    if config.liquidation_bonus > 100 {
+        msg!("Liquidation bonus must be in range [0, 100]");
+        return Err(LendingError::InvalidConfig.into());
+    }
+    if config.liquidation_threshold <= config.loan_to_value_ratio
+        || config.liquidation_threshold > 100
+    {
+        msg!("Liquidation threshold must be in range (LTV, 100]");
+        return Err(LendingError::InvalidConfig.into());
+    }
+    if !config.borrow_rate_curve.is_valid() {
+        msg!("Borrow rate curve must span 0% to 100% utilization and be monotonic");
+        return Err(LendingError::InvalidConfig.into());
+    }
+    let adaptive_rate = &config.adaptive_rate;
+    if adaptive_rate.is_enabled() {
+        let kink = match config.optimal_point() {
+            Some(kink) => kink,
+            None => {
+                msg!(
+                    "Adaptive rates need a borrow rate curve point at the optimal utilization rate"
+                );
+                return Err(LendingError::InvalidConfig.into());
+            }
+        };
+        if adaptive_rate.min_optimal_borrow_rate_bps > adaptive_rate.max_optimal_borrow_rate_bps {
+            msg!("Min optimal borrow rate must be <= max optimal borrow rate");
+            return Err(LendingError::InvalidConfig.into());
+        }
+        // the moved point must keep the curve's rates from decreasing
+        let points = config.borrow_rate_curve.points();
+        let below = points
+            .iter()
+            .rev()
+            .find(|point| point.utilization_rate_bps < kink.utilization_rate_bps);
+        let above = points
+            .iter()
+            .find(|point| point.utilization_rate_bps > kink.utilization_rate_bps);
+        let below_rate_bps = below.map_or(0, |point| point.borrow_rate_bps);
+        let above_rate_bps = above.map_or(u16::MAX, |point| point.borrow_rate_bps);
+        if adaptive_rate.min_optimal_borrow_rate_bps < below_rate_bps
+            || adaptive_rate.max_optimal_borrow_rate_bps > above_rate_bps
+        {
+            msg!("Optimal borrow rate bounds must be within the neighbouring curve point rates");
+            return Err(LendingError::InvalidConfig.into());
+        }
+    }
+    if config.reserve_factor > 100 {
+        msg!("Reserve factor must be in range [0, 100]");
+        return Err(LendingError::InvalidConfig.into());
+    }
+    if config.fees.borrow_fee_wad >= WAD {
+        msg!("Borrow fee must be in range [0, 1_000_000_000_000_000_000)");
+        return Err(LendingError::InvalidConfig.into());
+    }
+    if config.fees.host_fee_percentage > 100 {
+        msg!("Host fee percentage must be in range [0, 100]");
+        return Err(LendingError::InvalidConfig.into());
+    }
+    Ok(())
+}
+
 fn assert_rent_exempt(rent: &Rent, account_info: &AccountInfo) -> ProgramResult {
     if !rent.is_exempt(account_info.lamports(), account_info.data_len()) {
         msg!(&rent.minimum_balance(account_info.data_len()).to_string());
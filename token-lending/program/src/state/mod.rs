@@ -2,13 +2,17 @@
 
 mod lending_market;
 mod obligation;
+mod price_accumulator;
+mod rate_history;
 mod reserve;
 
 pub use lending_market::*;
 pub use obligation::*;
+pub use price_accumulator::*;
+pub use rate_history::*;
 pub use reserve::*;
 
-use crate::math::{Decimal, WAD};
+use crate::math::{Decimal, Rate, TryAdd, TryDiv, TryMul, TrySub, WAD};
 use arrayref::{array_refs, mut_array_refs};
 use solana_program::{
     clock::{DEFAULT_TICKS_PER_SECOND, DEFAULT_TICKS_PER_SLOT, SECONDS_PER_DAY},
@@ -16,6 +20,7 @@ use solana_program::{
     program_option::COption,
     pubkey::Pubkey,
 };
+use std::convert::TryFrom;
 
 /// Collateral tokens are initially valued at a ratio of 5:1 (collateral:liquidity)
 pub const INITIAL_COLLATERAL_RATIO: u64 = 5;
@@ -32,6 +37,50 @@ pub const UNINITIALIZED_VERSION: u8 = 0;
 pub const SLOTS_PER_YEAR: u64 =
     DEFAULT_TICKS_PER_SECOND / DEFAULT_TICKS_PER_SLOT * SECONDS_PER_DAY * 365;
 
+/// Newton iterations solving for the slot rate of an annual percentage yield, ample for yields
+/// within the range of borrow rates
+const APY_TO_APR_ITERATIONS: usize = 32;
+
+/// Annual percentage yield of an annual percentage rate compounded every slot, the way interest
+/// accrues on reserves
+pub fn apr_to_apy(apr: Rate) -> Result<Decimal, ProgramError> {
+    Decimal::one()
+        .try_add(Decimal::from(apr).try_div(SLOTS_PER_YEAR)?)?
+        .try_pow(SLOTS_PER_YEAR)?
+        .try_sub(Decimal::one())
+}
+
+/// Annual percentage rate that compounded every slot yields an annual percentage yield
+pub fn apy_to_apr(apy: Decimal) -> Result<Rate, ProgramError> {
+    let target = Decimal::one().try_add(apy)?;
+    // Newton's method on the slot rate. Compounding is convex, so starting above the root keeps
+    // every step above it. ln(1 + apy) is at most both the yield and the number of doublings it
+    // takes to reach it, and the lower of the two keeps compounding far from overflowing.
+    let mut doublings = 0u64;
+    let mut doubled = Decimal::one();
+    while doubled < target {
+        doubled = doubled.try_mul(2)?;
+        doublings += 1;
+    }
+    let mut slot_rate = apy.min(Decimal::from(doublings)).try_div(SLOTS_PER_YEAR)?;
+    for _ in 0..APY_TO_APR_ITERATIONS {
+        let growth = Decimal::one().try_add(slot_rate)?;
+        let compounded = growth.try_pow(SLOTS_PER_YEAR)?;
+        if compounded <= target {
+            break;
+        }
+        let step = compounded
+            .try_sub(target)?
+            .try_mul(growth)?
+            .try_div(compounded.try_mul(SLOTS_PER_YEAR)?)?;
+        if step == Decimal::zero() {
+            break;
+        }
+        slot_rate = slot_rate.try_sub(step)?;
+    }
+    Rate::try_from(slot_rate.try_mul(SLOTS_PER_YEAR)?)
+}
+
 /// Token converter
 pub trait TokenConverter {
     /// Return best price for specified token
@@ -90,4 +139,26 @@ mod test {
             INITIAL_COLLATERAL_RATE
         );
     }
+
+    #[test]
+    fn apr_apy_conversions() {
+        assert_eq!(apr_to_apy(Rate::zero()).unwrap(), Decimal::zero());
+        assert_eq!(apy_to_apr(Decimal::zero()).unwrap(), Rate::zero());
+
+        // compounding every slot approaches continuous compounding, e^apr - 1
+        let apy = apr_to_apy(Rate::from_percent(100)).unwrap();
+        assert!(apy > Decimal::from_scaled_val(1_718_281_000_000_000_000));
+        assert!(apy < Decimal::from_scaled_val(1_718_282_000_000_000_000));
+
+        for bps in [1, 400, 3_000, 25_500, 65_535].iter() {
+            let apr = Rate::from_bps(*bps);
+            let round_trip = apy_to_apr(apr_to_apy(apr).unwrap()).unwrap();
+            let error = if round_trip > apr {
+                round_trip.try_sub(apr).unwrap()
+            } else {
+                apr.try_sub(round_trip).unwrap()
+            };
+            assert!(error < Rate::from_scaled_val(1_000_000_000), "{} bps", bps);
+        }
+    }
 }
@@ -29,6 +29,8 @@ pub struct Obligation {
     pub borrow_reserve: Pubkey,
     /// Mint address of the tokens for this obligation
     pub token_mint: Pubkey,
+    /// Amount of tokens borrowed through margin borrows, excluding interest
+    pub margin_borrowed_amount: u64,
 }
 
 impl Obligation {
@@ -49,6 +51,7 @@ impl Obligation {
             borrowed_liquidity_wads: Decimal::zero(),
             borrow_reserve,
             token_mint,
+            margin_borrowed_amount: 0,
         }
     }
 
@@ -101,6 +104,26 @@ impl Obligation {
         Ok(())
     }
 
+    /// Release the share of the margin borrowed amount settled by `settle_amount`. Must be called
+    /// before the settled amount is subtracted from the borrowed liquidity.
+    pub fn release_margin_borrow(&mut self, settle_amount: Decimal) -> Result<u64, ProgramError> {
+        if self.margin_borrowed_amount == 0 {
+            return Ok(0);
+        }
+
+        let release_amount = if settle_amount >= self.borrowed_liquidity_wads {
+            self.margin_borrowed_amount
+        } else {
+            settle_amount
+                .try_div(self.borrowed_liquidity_wads)?
+                .try_mul(self.margin_borrowed_amount)?
+                .try_ceil_u64()?
+                .min(self.margin_borrowed_amount)
+        };
+        self.margin_borrowed_amount -= release_amount;
+        Ok(release_amount)
+    }
+
     /// Liquidate part of obligation
     pub fn liquidate(&mut self, settle_amount: Decimal, withdraw_amount: u64) -> ProgramResult {
         self.borrowed_liquidity_wads = self.borrowed_liquidity_wads.try_sub(settle_amount)?;
@@ -123,6 +146,7 @@ impl Obligation {
             return Err(LendingError::ObligationEmpty.into());
         }
 
+        let margin_release_amount = self.release_margin_borrow(decimal_repay_amount)?;
         let repay_pct: Decimal = decimal_repay_amount.try_div(self.borrowed_liquidity_wads)?;
         let collateral_withdraw_amount = {
             let withdraw_amount: Decimal = repay_pct.try_mul(self.deposited_collateral_tokens)?;
@@ -148,6 +172,7 @@ impl Obligation {
             obligation_token_amount,
             decimal_repay_amount,
             integer_repay_amount,
+            margin_release_amount,
         })
     }
 }
@@ -162,6 +187,8 @@ pub struct RepayResult {
     pub decimal_repay_amount: Decimal,
     /// Amount that will be repaid as u64
     pub integer_repay_amount: u64,
+    /// Amount of margin borrows released from the borrow reserve
+    pub margin_release_amount: u64,
 }
 
 /// Create new obligation
@@ -199,8 +226,9 @@ impl Pack for Obligation {
             borrowed_liquidity_wads,
             borrow_reserve,
             token_mint,
+            margin_borrowed_amount,
             _padding,
-        ) = array_refs![input, 1, 8, 32, 16, 16, 32, 32, 128];
+        ) = array_refs![input, 1, 8, 32, 16, 16, 32, 32, 8, 120];
         Ok(Self {
             version: u8::from_le_bytes(*version),
             deposited_collateral_tokens: u64::from_le_bytes(*deposited_collateral_tokens),
@@ -209,6 +237,7 @@ impl Pack for Obligation {
             borrowed_liquidity_wads: unpack_decimal(borrowed_liquidity_wads),
             borrow_reserve: Pubkey::new_from_array(*borrow_reserve),
             token_mint: Pubkey::new_from_array(*token_mint),
+            margin_borrowed_amount: u64::from_le_bytes(*margin_borrowed_amount),
         })
     }
 
@@ -222,8 +251,9 @@ impl Pack for Obligation {
             borrowed_liquidity_wads,
             borrow_reserve,
             token_mint,
+            margin_borrowed_amount,
             _padding,
-        ) = mut_array_refs![output, 1, 8, 32, 16, 16, 32, 32, 128];
+        ) = mut_array_refs![output, 1, 8, 32, 16, 16, 32, 32, 8, 120];
 
         *version = self.version.to_le_bytes();
         *deposited_collateral_tokens = self.deposited_collateral_tokens.to_le_bytes();
@@ -232,6 +262,7 @@ impl Pack for Obligation {
         pack_decimal(self.borrowed_liquidity_wads, borrowed_liquidity_wads);
         borrow_reserve.copy_from_slice(self.borrow_reserve.as_ref());
         token_mint.copy_from_slice(self.token_mint.as_ref());
+        *margin_borrowed_amount = self.margin_borrowed_amount.to_le_bytes();
     }
 }
 
@@ -274,6 +305,28 @@ mod test {
         );
     }
 
+    #[test]
+    fn obligation_release_margin_borrow() {
+        let mut obligation = Obligation {
+            borrowed_liquidity_wads: Decimal::from(100u64),
+            margin_borrowed_amount: 80,
+            ..Obligation::default()
+        };
+
+        assert_eq!(
+            obligation.release_margin_borrow(Decimal::from(25u64)),
+            Ok(20)
+        );
+        assert_eq!(obligation.margin_borrowed_amount, 60);
+
+        // settling the full balance releases the remainder
+        assert_eq!(
+            obligation.release_margin_borrow(Decimal::from(100u64)),
+            Ok(60)
+        );
+        assert_eq!(obligation.margin_borrowed_amount, 0);
+    }
+
     // Creates rates (r1, r2) where 0 < r1 <= r2 <= 100*r1
     prop_compose! {
         fn cumulative_rates()(rate in 1..=u128::MAX)(
@@ -0,0 +1,449 @@
+use super::*;
+use crate::{
+    error::LendingError,
+    math::{Decimal, Rate, TryAdd, TryDiv, TryMul, TrySub},
+};
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use num_traits::FromPrimitive;
+use solana_program::{
+    clock::Slot,
+    entrypoint::ProgramResult,
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack, Sealed},
+    pubkey::Pubkey,
+};
+use std::convert::TryFrom;
+
+/// Number of cumulative price observations kept by a price accumulator
+pub const MAX_PRICE_OBSERVATIONS: usize = 64;
+
+/// Most a sampled price may move away from the previous sample per slot elapsed, in basis points
+pub const MAX_PRICE_CHANGE_BPS_PER_SLOT: u16 = 10;
+
+/// Cumulative price of a market at a slot
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PriceObservation {
+    /// Slot of the observation
+    pub slot: Slot,
+    /// Price accumulator's cumulative price at `slot`
+    pub cumulative_price_wads: u128,
+}
+
+/// Time weighted price accumulator of a dex market or token swap pool.
+///
+/// Prices are quote per base, in token base units. The cumulative price is the sum of each
+/// sampled price times the slots it was in effect, as a wad that wraps on overflow like Uniswap's
+/// accumulators; only differences between two observations are meaningful.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PriceAccumulator {
+    /// Version of the struct
+    pub version: u8,
+    /// Lending market address
+    pub lending_market: Pubkey,
+    /// Dex market or token swap pool address
+    pub market: Pubkey,
+    /// Kind of market `market` refers to
+    pub price_source: PriceSource,
+    /// Base token mint, the liquidity mint of the reserve priced off the market
+    pub base_mint: Pubkey,
+    /// Quote token mint of the lending market
+    pub quote_mint: Pubkey,
+    /// Minimum slots between two recorded observations
+    pub observation_interval: Slot,
+    /// Slot the price was last sampled at
+    pub last_update_slot: Slot,
+    /// Price sampled at `last_update_slot`
+    pub last_price: Decimal,
+    /// Cumulative price at `last_update_slot`
+    pub cumulative_price_wads: u128,
+    /// Recorded observations, oldest first
+    pub observations: Vec<PriceObservation>,
+}
+
+impl PriceAccumulator {
+    /// Initialize a new price accumulator
+    pub fn new(params: NewPriceAccumulatorParams) -> Self {
+        let NewPriceAccumulatorParams {
+            lending_market,
+            market,
+            price_source,
+            base_mint,
+            quote_mint,
+            observation_interval,
+        } = params;
+
+        Self {
+            version: PROGRAM_VERSION,
+            lending_market,
+            market,
+            price_source,
+            base_mint,
+            quote_mint,
+            observation_interval,
+            ..Self::default()
+        }
+    }
+
+    /// Sample the market price. The price in effect since the previous sample is accumulated
+    /// first, and only the first sample of a slot is taken so a price moved later in the slot
+    /// is not recorded.
+    ///
+    /// A sample sees the market as it stands within the sending transaction, which may move the
+    /// market and restore it around the update. Samples are therefore clamped to within
+    /// `MAX_PRICE_CHANGE_BPS_PER_SLOT` of the previous one per slot elapsed, so such a sample can
+    /// only drag the average along slowly. The first sample has nothing to be clamped against
+    /// and is taken as is, which is why `InitPriceAccumulator` takes it.
+    pub fn update(&mut self, slot: Slot, price: Decimal) -> ProgramResult {
+        if self.observations.is_empty() {
+            self.last_update_slot = slot;
+            self.last_price = price;
+            self.record_observation();
+            return Ok(());
+        }
+        if slot <= self.last_update_slot {
+            return Ok(());
+        }
+
+        let price = self.clamp_price(slot, price)?;
+        self.cumulative_price_wads = self.cumulative_price_at(slot)?;
+        self.last_update_slot = slot;
+        self.last_price = price;
+
+        let last_observation_slot = self.observations.last().map_or(0, |o| o.slot);
+        if slot.saturating_sub(last_observation_slot) >= self.observation_interval {
+            self.record_observation();
+        }
+        Ok(())
+    }
+
+    /// Longest averaging window the observations are sure to cover once the buffer is full, as
+    /// consecutive observations are at least `observation_interval` and one slot apart
+    pub fn max_twap_slots(&self) -> Slot {
+        (MAX_PRICE_OBSERVATIONS as Slot - 1).saturating_mul(self.observation_interval.max(1))
+    }
+
+    /// Average price over at least the last `window` slots, measured from the newest observation
+    /// that old. Fails if the price was not sampled in the last `window` slots or the window
+    /// reaches further back than the recorded observations.
+    pub fn twap(&self, slot: Slot, window: Slot) -> Result<Decimal, ProgramError> {
+        if self.observations.is_empty() || slot.saturating_sub(self.last_update_slot) > window {
+            return Err(LendingError::PriceAccumulatorStale.into());
+        }
+
+        let window_start = slot
+            .checked_sub(window)
+            .ok_or(LendingError::PriceHistoryUnavailable)?;
+        let observation = self
+            .observations
+            .iter()
+            .rev()
+            .find(|observation| observation.slot <= window_start)
+            .ok_or(LendingError::PriceHistoryUnavailable)?;
+
+        let elapsed = slot - observation.slot;
+        if elapsed == 0 {
+            return Ok(self.last_price);
+        }
+        let cumulative_price_wads = self
+            .cumulative_price_at(slot)?
+            .wrapping_sub(observation.cumulative_price_wads);
+        Decimal::from_scaled_val(cumulative_price_wads).try_div(elapsed)
+    }
+
+    fn clamp_price(&self, slot: Slot, price: Decimal) -> Result<Decimal, ProgramError> {
+        let elapsed = slot
+            .saturating_sub(self.last_update_slot)
+            .min(u32::MAX as Slot);
+        let max_change = self
+            .last_price
+            .try_mul(Rate::from_bps(MAX_PRICE_CHANGE_BPS_PER_SLOT))?
+            .try_mul(elapsed)?;
+        let max_price = self.last_price.try_add(max_change)?;
+        let min_price = self
+            .last_price
+            .try_sub(max_change)
+            .unwrap_or_else(|_| Decimal::zero());
+        Ok(price.max(min_price).min(max_price))
+    }
+
+    fn cumulative_price_at(&self, slot: Slot) -> Result<u128, ProgramError> {
+        let elapsed = slot.saturating_sub(self.last_update_slot) as u128;
+        let accumulated = self.last_price.to_scaled_val()?.wrapping_mul(elapsed);
+        Ok(self.cumulative_price_wads.wrapping_add(accumulated))
+    }
+
+    fn record_observation(&mut self) {
+        if self.observations.len() == MAX_PRICE_OBSERVATIONS {
+            self.observations.remove(0);
+        }
+        self.observations.push(PriceObservation {
+            slot: self.last_update_slot,
+            cumulative_price_wads: self.cumulative_price_wads,
+        });
+    }
+}
+
+/// Create a new price accumulator
+pub struct NewPriceAccumulatorParams {
+    /// Lending market address
+    pub lending_market: Pubkey,
+    /// Dex market or token swap pool address
+    pub market: Pubkey,
+    /// Kind of market `market` refers to
+    pub price_source: PriceSource,
+    /// Base token mint
+    pub base_mint: Pubkey,
+    /// Quote token mint
+    pub quote_mint: Pubkey,
+    /// Minimum slots between two recorded observations
+    pub observation_interval: Slot,
+}
+
+impl Sealed for PriceAccumulator {}
+impl IsInitialized for PriceAccumulator {
+    fn is_initialized(&self) -> bool {
+        self.version != UNINITIALIZED_VERSION
+    }
+}
+
+const OBSERVATION_LEN: usize = 24;
+const OBSERVATIONS_LEN: usize = OBSERVATION_LEN * MAX_PRICE_OBSERVATIONS;
+const PRICE_ACCUMULATOR_LEN: usize = 1843;
+impl Pack for PriceAccumulator {
+    const LEN: usize = 1843;
+
+    /// Unpacks a byte buffer into a [PriceAccumulator](struct.PriceAccumulator.html).
+    fn unpack_from_slice(input: &[u8]) -> Result<Self, ProgramError> {
+        let input = array_ref![input, 0, PRICE_ACCUMULATOR_LEN];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (
+            version,
+            lending_market,
+            market,
+            price_source,
+            base_mint,
+            quote_mint,
+            observation_interval,
+            last_update_slot,
+            last_price,
+            cumulative_price_wads,
+            observation_count,
+            observations,
+            _padding,
+        ) = array_refs![
+            input,
+            1,
+            32,
+            32,
+            1,
+            32,
+            32,
+            8,
+            8,
+            16,
+            16,
+            1,
+            OBSERVATIONS_LEN,
+            128
+        ];
+        let version = u8::from_le_bytes(*version);
+        if version > PROGRAM_VERSION {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let observation_count = u8::from_le_bytes(*observation_count) as usize;
+        if observation_count > MAX_PRICE_OBSERVATIONS {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let observations = observations
+            .chunks(OBSERVATION_LEN)
+            .take(observation_count)
+            .map(|observation| {
+                let (slot, cumulative_price_wads) = observation.split_at(8);
+                PriceObservation {
+                    slot: u64::from_le_bytes(<[u8; 8]>::try_from(slot).unwrap()),
+                    cumulative_price_wads: u128::from_le_bytes(
+                        <[u8; 16]>::try_from(cumulative_price_wads).unwrap(),
+                    ),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            version,
+            lending_market: Pubkey::new_from_array(*lending_market),
+            market: Pubkey::new_from_array(*market),
+            price_source: PriceSource::from_u8(price_source[0])
+                .ok_or(ProgramError::InvalidAccountData)?,
+            base_mint: Pubkey::new_from_array(*base_mint),
+            quote_mint: Pubkey::new_from_array(*quote_mint),
+            observation_interval: u64::from_le_bytes(*observation_interval),
+            last_update_slot: u64::from_le_bytes(*last_update_slot),
+            last_price: unpack_decimal(last_price),
+            cumulative_price_wads: u128::from_le_bytes(*cumulative_price_wads),
+            observations,
+        })
+    }
+
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        let output = array_mut_ref![output, 0, PRICE_ACCUMULATOR_LEN];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (
+            version,
+            lending_market,
+            market,
+            price_source,
+            base_mint,
+            quote_mint,
+            observation_interval,
+            last_update_slot,
+            last_price,
+            cumulative_price_wads,
+            observation_count,
+            observations,
+            _padding,
+        ) = mut_array_refs![
+            output,
+            1,
+            32,
+            32,
+            1,
+            32,
+            32,
+            8,
+            8,
+            16,
+            16,
+            1,
+            OBSERVATIONS_LEN,
+            128
+        ];
+        *version = self.version.to_le_bytes();
+        lending_market.copy_from_slice(self.lending_market.as_ref());
+        market.copy_from_slice(self.market.as_ref());
+        price_source[0] = self.price_source as u8;
+        base_mint.copy_from_slice(self.base_mint.as_ref());
+        quote_mint.copy_from_slice(self.quote_mint.as_ref());
+        *observation_interval = self.observation_interval.to_le_bytes();
+        *last_update_slot = self.last_update_slot.to_le_bytes();
+        pack_decimal(self.last_price, last_price);
+        *cumulative_price_wads = self.cumulative_price_wads.to_le_bytes();
+
+        *observation_count = (self.observations.len() as u8).to_le_bytes();
+        for (dst, observation) in observations
+            .chunks_mut(OBSERVATION_LEN)
+            .zip(self.observations.iter())
+        {
+            let (slot, cumulative_price_wads) = dst.split_at_mut(8);
+            slot.copy_from_slice(&observation.slot.to_le_bytes());
+            cumulative_price_wads.copy_from_slice(&observation.cumulative_price_wads.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_accumulator(observation_interval: Slot) -> PriceAccumulator {
+        PriceAccumulator::new(NewPriceAccumulatorParams {
+            lending_market: Pubkey::new_unique(),
+            market: Pubkey::new_unique(),
+            price_source: PriceSource::TokenSwap,
+            base_mint: Pubkey::new_unique(),
+            quote_mint: Pubkey::new_unique(),
+            observation_interval,
+        })
+    }
+
+    #[test]
+    fn time_weighted_price() {
+        let mut accumulator = new_accumulator(10);
+        accumulator.update(100, Decimal::from(100u64)).unwrap();
+        accumulator.update(110, Decimal::from(101u64)).unwrap();
+        // Moving the price later in a slot is ignored
+        accumulator.update(110, Decimal::from(1_000u64)).unwrap();
+        accumulator.update(130, Decimal::from(102u64)).unwrap();
+
+        // 10 slots at 100 and 20 at 101, then 10 more at 102
+        assert_eq!(accumulator.twap(140, 40).unwrap(), Decimal::from(101u64));
+        // Measured from the observation at 110: 20 slots at 101 and 10 at 102
+        assert_eq!(
+            accumulator.twap(140, 25).unwrap(),
+            Decimal::from(304u64).try_div(3).unwrap()
+        );
+        assert_eq!(
+            accumulator.twap(140, 41),
+            Err(LendingError::PriceHistoryUnavailable.into())
+        );
+        assert_eq!(
+            accumulator.twap(200, 60),
+            Err(LendingError::PriceAccumulatorStale.into())
+        );
+    }
+
+    #[test]
+    fn clamped_price_change() {
+        let mut accumulator = new_accumulator(0);
+        accumulator.update(0, Decimal::from(100u64)).unwrap();
+
+        // a sample moved tenfold only moves 0.1% in one slot
+        accumulator.update(1, Decimal::from(1_000u64)).unwrap();
+        let price = Decimal::from(1_001u64).try_div(10).unwrap();
+        assert_eq!(accumulator.last_price, price);
+
+        // and 1% down over ten slots
+        accumulator.update(11, Decimal::zero()).unwrap();
+        let max_change = price.try_div(100).unwrap();
+        assert_eq!(accumulator.last_price, price.try_sub(max_change).unwrap());
+
+        // prices within the bound are taken as sampled
+        accumulator.update(21, Decimal::from(99u64)).unwrap();
+        assert_eq!(accumulator.last_price, Decimal::from(99u64));
+    }
+
+    #[test]
+    fn max_twap_slots() {
+        assert_eq!(new_accumulator(10).max_twap_slots(), 630);
+        assert_eq!(new_accumulator(0).max_twap_slots(), 63);
+    }
+
+    #[test]
+    fn observation_interval() {
+        let mut accumulator = new_accumulator(10);
+        for slot in 0..25 {
+            accumulator.update(slot, Decimal::one()).unwrap();
+        }
+        let slots: Vec<Slot> = accumulator.observations.iter().map(|o| o.slot).collect();
+        assert_eq!(slots, vec![0, 10, 20]);
+
+        let mut accumulator = new_accumulator(0);
+        for slot in 0..(MAX_PRICE_OBSERVATIONS as Slot + 5) {
+            accumulator.update(slot, Decimal::one()).unwrap();
+        }
+        assert_eq!(accumulator.observations.len(), MAX_PRICE_OBSERVATIONS);
+        assert_eq!(accumulator.observations[0].slot, 5);
+    }
+
+    #[test]
+    fn cumulative_price_wraps() {
+        let mut accumulator = new_accumulator(0);
+        accumulator.update(0, Decimal::from(3u64)).unwrap();
+        accumulator.cumulative_price_wads = u128::MAX - 1;
+        accumulator.observations[0].cumulative_price_wads = u128::MAX - 1;
+        accumulator.update(10, Decimal::from(3u64)).unwrap();
+        assert!(accumulator.cumulative_price_wads < u128::MAX - 1);
+        assert_eq!(accumulator.twap(10, 10).unwrap(), Decimal::from(3u64));
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let mut accumulator = new_accumulator(1);
+        for slot in 0..3 {
+            accumulator.update(slot, Decimal::from(slot + 1)).unwrap();
+        }
+        let mut data = [0; PriceAccumulator::LEN];
+        PriceAccumulator::pack(accumulator.clone(), &mut data).unwrap();
+        assert_eq!(PriceAccumulator::unpack(&data).unwrap(), accumulator);
+    }
+}
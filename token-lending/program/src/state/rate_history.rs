@@ -0,0 +1,257 @@
+use super::*;
+use crate::math::{Decimal, Rate};
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use solana_program::{
+    clock::Slot,
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack, Sealed},
+    pubkey::Pubkey,
+};
+use std::convert::TryFrom;
+
+/// Number of snapshots kept by a rate history
+pub const MAX_RATE_SNAPSHOTS: usize = 128;
+
+/// Rates of a reserve at a slot
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateSnapshot {
+    /// Slot of the snapshot
+    pub slot: Slot,
+    /// Liquidity utilization rate
+    pub utilization_rate: Rate,
+    /// Borrow APR
+    pub borrow_rate: Rate,
+    /// Collateral tokens per liquidity token
+    pub collateral_exchange_rate: Rate,
+    /// Total liquidity supplied, borrowed or available
+    pub total_supply: Decimal,
+}
+
+impl RateSnapshot {
+    /// Take a snapshot of a reserve whose interest was just accrued
+    pub fn new(reserve: &Reserve) -> Result<Self, ProgramError> {
+        Ok(Self {
+            slot: reserve.last_update_slot,
+            utilization_rate: reserve.liquidity.utilization_rate()?,
+            borrow_rate: reserve.borrow_rate,
+            collateral_exchange_rate: reserve.collateral_exchange_rate()?.into(),
+            total_supply: reserve.liquidity.total_supply()?,
+        })
+    }
+}
+
+/// Rate history of a reserve, a ring buffer of snapshots recorded as interest accrues
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateHistory {
+    /// Version of the struct
+    pub version: u8,
+    /// Reserve address
+    pub reserve: Pubkey,
+    /// Minimum slots between two recorded snapshots
+    pub snapshot_interval: Slot,
+    /// Index the next snapshot is written to. Once the buffer is full, this is the oldest one.
+    pub next_index: u8,
+    /// Recorded snapshots in buffer order
+    pub snapshots: Vec<RateSnapshot>,
+}
+
+impl RateHistory {
+    /// Initialize a new rate history
+    pub fn new(reserve: Pubkey, snapshot_interval: Slot) -> Self {
+        Self {
+            version: PROGRAM_VERSION,
+            reserve,
+            snapshot_interval,
+            ..Self::default()
+        }
+    }
+
+    /// Most recent snapshot
+    pub fn latest(&self) -> Option<&RateSnapshot> {
+        let len = self.snapshots.len();
+        if len == 0 {
+            return None;
+        }
+        self.snapshots
+            .get((self.next_index as usize + len - 1) % len)
+    }
+
+    /// Snapshots, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &RateSnapshot> {
+        let (newer, older) = if self.snapshots.len() < MAX_RATE_SNAPSHOTS {
+            self.snapshots.split_at(self.snapshots.len())
+        } else {
+            self.snapshots.split_at(self.next_index as usize)
+        };
+        older.iter().chain(newer.iter())
+    }
+
+    /// Record a snapshot unless the latest one is less than the snapshot interval older, and at
+    /// most one per slot, overwriting the oldest one once the buffer is full. Returns whether it
+    /// was recorded.
+    pub fn record(&mut self, snapshot: RateSnapshot) -> bool {
+        if let Some(latest) = self.latest() {
+            if snapshot.slot.saturating_sub(latest.slot) < self.snapshot_interval.max(1) {
+                return false;
+            }
+        }
+
+        let index = self.next_index as usize;
+        if index < self.snapshots.len() {
+            self.snapshots[index] = snapshot;
+        } else {
+            self.snapshots.push(snapshot);
+        }
+        self.next_index = ((index + 1) % MAX_RATE_SNAPSHOTS) as u8;
+        true
+    }
+}
+
+impl Sealed for RateHistory {}
+impl IsInitialized for RateHistory {
+    fn is_initialized(&self) -> bool {
+        self.version != UNINITIALIZED_VERSION
+    }
+}
+
+const SNAPSHOT_LEN: usize = 72;
+const SNAPSHOTS_LEN: usize = SNAPSHOT_LEN * MAX_RATE_SNAPSHOTS;
+const RATE_HISTORY_LEN: usize = 9323;
+impl Pack for RateHistory {
+    const LEN: usize = 9323;
+
+    /// Unpacks a byte buffer into a [RateHistory](struct.RateHistory.html).
+    fn unpack_from_slice(input: &[u8]) -> Result<Self, ProgramError> {
+        let input = array_ref![input, 0, RATE_HISTORY_LEN];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (version, reserve, snapshot_interval, next_index, snapshot_count, snapshots, _padding) =
+            array_refs![input, 1, 32, 8, 1, 1, SNAPSHOTS_LEN, 64];
+        let version = u8::from_le_bytes(*version);
+        if version > PROGRAM_VERSION {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let next_index = u8::from_le_bytes(*next_index);
+        let snapshot_count = u8::from_le_bytes(*snapshot_count) as usize;
+        if snapshot_count > MAX_RATE_SNAPSHOTS || next_index as usize >= MAX_RATE_SNAPSHOTS {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let snapshots = snapshots
+            .chunks(SNAPSHOT_LEN)
+            .take(snapshot_count)
+            .map(|snapshot| {
+                let snapshot = array_ref![snapshot, 0, SNAPSHOT_LEN];
+                let (slot, utilization_rate, borrow_rate, collateral_exchange_rate, total_supply) =
+                    array_refs![snapshot, 8, 16, 16, 16, 16];
+                Ok(RateSnapshot {
+                    slot: u64::from_le_bytes(*slot),
+                    utilization_rate: Rate::try_from(unpack_decimal(utilization_rate))?,
+                    borrow_rate: Rate::try_from(unpack_decimal(borrow_rate))?,
+                    collateral_exchange_rate: Rate::try_from(unpack_decimal(
+                        collateral_exchange_rate,
+                    ))?,
+                    total_supply: unpack_decimal(total_supply),
+                })
+            })
+            .collect::<Result<_, ProgramError>>()?;
+
+        Ok(Self {
+            version,
+            reserve: Pubkey::new_from_array(*reserve),
+            snapshot_interval: u64::from_le_bytes(*snapshot_interval),
+            next_index,
+            snapshots,
+        })
+    }
+
+    fn pack_into_slice(&self, output: &mut [u8]) {
+        let output = array_mut_ref![output, 0, RATE_HISTORY_LEN];
+        #[allow(clippy::ptr_offset_with_cast)]
+        let (version, reserve, snapshot_interval, next_index, snapshot_count, snapshots, _padding) =
+            mut_array_refs![output, 1, 32, 8, 1, 1, SNAPSHOTS_LEN, 64];
+        *version = self.version.to_le_bytes();
+        reserve.copy_from_slice(self.reserve.as_ref());
+        *snapshot_interval = self.snapshot_interval.to_le_bytes();
+        *next_index = self.next_index.to_le_bytes();
+
+        *snapshot_count = (self.snapshots.len() as u8).to_le_bytes();
+        for (dst, snapshot) in snapshots
+            .chunks_mut(SNAPSHOT_LEN)
+            .zip(self.snapshots.iter())
+        {
+            let dst = array_mut_ref![dst, 0, SNAPSHOT_LEN];
+            let (slot, utilization_rate, borrow_rate, collateral_exchange_rate, total_supply) =
+                mut_array_refs![dst, 8, 16, 16, 16, 16];
+            *slot = snapshot.slot.to_le_bytes();
+            pack_decimal(snapshot.utilization_rate.into(), utilization_rate);
+            pack_decimal(snapshot.borrow_rate.into(), borrow_rate);
+            pack_decimal(
+                snapshot.collateral_exchange_rate.into(),
+                collateral_exchange_rate,
+            );
+            pack_decimal(snapshot.total_supply, total_supply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot(slot: Slot) -> RateSnapshot {
+        RateSnapshot {
+            slot,
+            utilization_rate: Rate::from_percent(50),
+            borrow_rate: Rate::from_bps(slot as u16),
+            collateral_exchange_rate: Rate::from_percent(100),
+            total_supply: Decimal::from(slot),
+        }
+    }
+
+    fn slots(history: &RateHistory) -> Vec<Slot> {
+        history.iter().map(|snapshot| snapshot.slot).collect()
+    }
+
+    #[test]
+    fn snapshot_interval() {
+        let mut history = RateHistory::new(Pubkey::new_unique(), 10);
+        assert!(history.latest().is_none());
+        for slot in 0..25 {
+            history.record(snapshot(slot));
+        }
+        assert_eq!(slots(&history), vec![0, 10, 20]);
+        assert_eq!(history.latest().map(|snapshot| snapshot.slot), Some(20));
+
+        // snapshots are at most one per slot
+        let mut history = RateHistory::new(Pubkey::new_unique(), 0);
+        assert!(history.record(snapshot(5)));
+        assert!(!history.record(snapshot(5)));
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mut history = RateHistory::new(Pubkey::new_unique(), 1);
+        let count = MAX_RATE_SNAPSHOTS as Slot + 5;
+        for slot in 0..count {
+            history.record(snapshot(slot));
+        }
+        assert_eq!(history.snapshots.len(), MAX_RATE_SNAPSHOTS);
+        assert_eq!(history.next_index, 5);
+        assert_eq!(slots(&history), (5..count).collect::<Vec<_>>());
+        assert_eq!(
+            history.latest().map(|snapshot| snapshot.slot),
+            Some(count - 1)
+        );
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let mut history = RateHistory::new(Pubkey::new_unique(), 1);
+        for slot in 0..3 {
+            history.record(snapshot(slot));
+        }
+        let mut data = vec![0; RateHistory::LEN];
+        RateHistory::pack(history.clone(), &mut data).unwrap();
+        assert_eq!(RateHistory::unpack(&data).unwrap(), history);
+    }
+}
@@ -5,6 +5,9 @@ use crate::{
     math::{Decimal, Rate, TryAdd, TryDiv, TryMul, TrySub},
 };
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use borsh::{BorshDeserialize, BorshSerialize};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 use solana_program::{
     clock::Slot,
     entrypoint::ProgramResult,
@@ -13,7 +16,10 @@ use solana_program::{
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
 };
-use std::convert::{TryFrom, TryInto};
+use std::{
+    cmp::Ordering,
+    convert::{TryFrom, TryInto},
+};
 
 /// Percentage of an obligation that can be repaid during each liquidation call
 pub const LIQUIDATION_CLOSE_FACTOR: u8 = 50;
@@ -30,10 +36,18 @@ pub struct Reserve {
     pub last_update_slot: Slot,
     /// Cumulative borrow rate
     pub cumulative_borrow_rate_wads: Decimal,
+    /// Optimal borrow rate moved by the adaptive rate model, unused when it is disabled
+    pub adaptive_optimal_borrow_rate: Rate,
+    /// Borrow APR as of the last interest accrual, for off-chain readers
+    pub borrow_rate: Rate,
+    /// Supply APR as of the last interest accrual, for off-chain readers
+    pub supply_rate: Rate,
     /// Lending market address
     pub lending_market: Pubkey,
-    /// Dex market state account
+    /// Dex market state account, or token swap pool account depending on `price_source`
     pub dex_market: COption<Pubkey>,
+    /// Kind of market the reserve is priced off
+    pub price_source: PriceSource,
     /// Reserve liquidity info
     pub liquidity: ReserveLiquidity,
     /// Reserve collateral info
@@ -51,6 +65,7 @@ impl Reserve {
             collateral: collateral_info,
             liquidity: liquidity_info,
             dex_market,
+            price_source,
             config,
         } = params;
 
@@ -58,38 +73,67 @@ impl Reserve {
             version: PROGRAM_VERSION,
             last_update_slot: current_slot,
             cumulative_borrow_rate_wads: Decimal::one(),
+            adaptive_optimal_borrow_rate: config.configured_optimal_borrow_rate(),
+            borrow_rate: Rate::zero(),
+            supply_rate: Rate::zero(),
             lending_market,
             collateral: collateral_info,
             liquidity: liquidity_info,
             dex_market,
+            price_source,
             config,
         }
     }
 
-    /// Calculate the current borrow rate
+    /// Calculate the current borrow rate off the borrow rate curve, with the curve's point at the
+    /// optimal utilization rate moved by the adaptive rate model when it is enabled
     pub fn current_borrow_rate(&self) -> Result<Rate, ProgramError> {
         let utilization_rate = self.liquidity.utilization_rate()?;
-        let optimal_utilization_rate = Rate::from_percent(self.config.optimal_utilization_rate);
-        let low_utilization = utilization_rate < optimal_utilization_rate;
-        if low_utilization || self.config.optimal_utilization_rate == 100 {
-            let normalized_rate = utilization_rate.try_div(optimal_utilization_rate)?;
-            let min_rate = Rate::from_percent(self.config.min_borrow_rate);
-            let rate_range =
-                Rate::from_percent(self.config.optimal_borrow_rate - self.config.min_borrow_rate);
-
-            Ok(normalized_rate.try_mul(rate_range)?.try_add(min_rate)?)
+        let curve = &self.config.borrow_rate_curve;
+        match self.config.optimal_point() {
+            Some(kink) if self.config.adaptive_rate.is_enabled() => curve.borrow_rate_with_kink(
+                utilization_rate,
+                kink.utilization_rate_bps,
+                self.optimal_borrow_rate()?,
+            ),
+            _ => curve.borrow_rate(utilization_rate),
+        }
+    }
+
+    /// Calculate the current supply rate, the borrow rate spread over all of the liquidity less
+    /// the reserve factor's share of the interest
+    pub fn current_supply_rate(&self) -> Result<Rate, ProgramError> {
+        let utilization_rate = self.liquidity.utilization_rate()?;
+        let depositors_share = Rate::one().try_sub(self.config.reserve_factor_rate())?;
+        self.current_borrow_rate()?
+            .try_mul(utilization_rate)?
+            .try_mul(depositors_share)
+    }
+
+    /// Borrow rate at the optimal utilization rate, as moved by the adaptive rate model when it
+    /// is enabled
+    pub fn optimal_borrow_rate(&self) -> Result<Rate, ProgramError> {
+        let adaptive_rate = &self.config.adaptive_rate;
+        if adaptive_rate.is_enabled() {
+            Ok(self
+                .adaptive_optimal_borrow_rate
+                .max(Rate::from_bps(adaptive_rate.min_optimal_borrow_rate_bps))
+                .min(Rate::from_bps(adaptive_rate.max_optimal_borrow_rate_bps)))
         } else {
-            let normalized_rate = utilization_rate
-                .try_sub(optimal_utilization_rate)?
-                .try_div(Rate::from_percent(
-                    100 - self.config.optimal_utilization_rate,
-                ))?;
-            let min_rate = Rate::from_percent(self.config.optimal_borrow_rate);
-            let rate_range =
-                Rate::from_percent(self.config.max_borrow_rate - self.config.optimal_borrow_rate);
-
-            Ok(normalized_rate.try_mul(rate_range)?.try_add(min_rate)?)
+            let optimal_utilization_rate = Rate::from_percent(self.config.optimal_utilization_rate);
+            self.config
+                .borrow_rate_curve
+                .borrow_rate(optimal_utilization_rate)
+        }
+    }
+
+    /// Replace the reserve config, starting the adaptive rate model from the configured optimal
+    /// borrow rate if the new config enables it
+    pub fn set_config(&mut self, config: ReserveConfig) {
+        if config.adaptive_rate.is_enabled() && !self.config.adaptive_rate.is_enabled() {
+            self.adaptive_optimal_borrow_rate = config.configured_optimal_borrow_rate();
         }
+        self.config = config;
     }
 
     /// Liquidate part of an unhealthy obligation
@@ -293,18 +337,68 @@ impl Reserve {
         Ok(liquidity_amount)
     }
 
-    /// Update borrow rate and accrue interest
+    /// Update borrow rate and accrue interest, then record the borrow and supply rates going
+    /// forward
     pub fn accrue_interest(&mut self, current_slot: Slot) -> ProgramResult {
         let slots_elapsed = self.update_slot(current_slot);
         if slots_elapsed > 0 {
+            let utilization_rate = self.liquidity.utilization_rate()?;
             let current_borrow_rate = self.current_borrow_rate()?;
             let compounded_interest_rate =
                 self.compound_interest(current_borrow_rate, slots_elapsed)?;
-            self.liquidity.borrowed_amount_wads = self
-                .liquidity
-                .borrowed_amount_wads
-                .try_mul(compounded_interest_rate)?;
+            self.liquidity
+                .accrue_interest(compounded_interest_rate, self.config.reserve_factor_rate())?;
+            self.adapt_optimal_borrow_rate(utilization_rate, slots_elapsed)?;
         }
+        self.borrow_rate = self.current_borrow_rate()?;
+        self.supply_rate = self.current_supply_rate()?;
+        Ok(())
+    }
+
+    /// Move the adaptive optimal borrow rate for the slots elapsed at the utilization rate the
+    /// reserve held over them. The rate rises in proportion to how far utilization sat above the
+    /// optimal utilization rate and falls in proportion to how far it sat below, each with its
+    /// own gain since borrowers react faster to lower rates than to higher ones.
+    fn adapt_optimal_borrow_rate(
+        &mut self,
+        utilization_rate: Rate,
+        slots_elapsed: u64,
+    ) -> ProgramResult {
+        let adaptive_rate = self.config.adaptive_rate;
+        if !adaptive_rate.is_enabled() {
+            return Ok(());
+        }
+
+        let optimal_utilization_rate = Rate::from_percent(self.config.optimal_utilization_rate);
+        let optimal_borrow_rate = self.optimal_borrow_rate()?;
+        self.adaptive_optimal_borrow_rate = match utilization_rate.cmp(&optimal_utilization_rate) {
+            Ordering::Greater => {
+                let deviation = utilization_rate
+                    .try_sub(optimal_utilization_rate)?
+                    .try_div(Rate::one().try_sub(optimal_utilization_rate)?)?;
+                let shift = Rate::from_scaled_val(adaptive_rate.rise_gain_wad)
+                    .try_mul(deviation)?
+                    .try_mul(slots_elapsed)?;
+                optimal_borrow_rate
+                    .try_add(shift)?
+                    .min(Rate::from_bps(adaptive_rate.max_optimal_borrow_rate_bps))
+            }
+            Ordering::Less => {
+                let deviation = optimal_utilization_rate
+                    .try_sub(utilization_rate)?
+                    .try_div(optimal_utilization_rate)?;
+                let shift = Rate::from_scaled_val(adaptive_rate.fall_gain_wad)
+                    .try_mul(deviation)?
+                    .try_mul(slots_elapsed)?;
+                let min_rate = Rate::from_bps(adaptive_rate.min_optimal_borrow_rate_bps);
+                if optimal_borrow_rate > min_rate.try_add(shift)? {
+                    optimal_borrow_rate.try_sub(shift)?
+                } else {
+                    min_rate
+                }
+            }
+            Ordering::Equal => optimal_borrow_rate,
+        };
         Ok(())
     }
 
@@ -348,12 +442,29 @@ pub struct NewReserveParams {
     pub collateral: ReserveCollateral,
     /// Reserve liquidity info
     pub liquidity: ReserveLiquidity,
-    /// Optional dex market address
+    /// Optional dex market or token swap pool address
     pub dex_market: COption<Pubkey>,
+    /// Kind of market `dex_market` refers to
+    pub price_source: PriceSource,
     /// Reserve configuration values
     pub config: ReserveConfig,
 }
 
+/// Market a reserve's liquidity is priced off when trading against the quote currency
+#[derive(Clone, Copy, Debug, PartialEq, BorshSerialize, BorshDeserialize, FromPrimitive)]
+pub enum PriceSource {
+    /// Serum dex market order book
+    DexMarket,
+    /// Constant product spl-token-swap pool
+    TokenSwap,
+}
+
+impl Default for PriceSource {
+    fn default() -> Self {
+        PriceSource::DexMarket
+    }
+}
+
 /// Create loan result
 pub struct LoanResult {
     /// Approved borrow amount
@@ -391,6 +502,10 @@ pub struct ReserveLiquidity {
     pub available_amount: u64,
     /// Reserve liquidity borrowed
     pub borrowed_amount_wads: Decimal,
+    /// Reserve liquidity borrowed through margin borrows, excluding interest
+    pub margin_borrowed_amount: u64,
+    /// Reserve factor's share of borrow interest, owed to the lending market owner
+    pub accumulated_fees_wads: Decimal,
 }
 
 impl ReserveLiquidity {
@@ -402,14 +517,54 @@ impl ReserveLiquidity {
             supply_pubkey,
             available_amount: 0,
             borrowed_amount_wads: Decimal::zero(),
+            margin_borrowed_amount: 0,
+            accumulated_fees_wads: Decimal::zero(),
         }
     }
 
-    /// Calculate the total reserve supply including active loans
+    /// Calculate the total reserve supply owed to depositors, including active loans and
+    /// excluding the fees owed to the lending market owner
     pub fn total_supply(&self) -> Result<Decimal, ProgramError> {
+        self.total_liquidity()?.try_sub(self.accumulated_fees_wads)
+    }
+
+    /// Calculate all of the reserve liquidity including active loans
+    fn total_liquidity(&self) -> Result<Decimal, ProgramError> {
         Decimal::from(self.available_amount).try_add(self.borrowed_amount_wads)
     }
 
+    /// Compound total borrows, setting the reserve factor's share of the new interest aside as
+    /// fees
+    fn accrue_interest(
+        &mut self,
+        compounded_interest_rate: Rate,
+        reserve_factor_rate: Rate,
+    ) -> ProgramResult {
+        let borrowed_amount_wads = self
+            .borrowed_amount_wads
+            .try_mul(compounded_interest_rate)?;
+        let interest = borrowed_amount_wads.try_sub(self.borrowed_amount_wads)?;
+        self.accumulated_fees_wads = self
+            .accumulated_fees_wads
+            .try_add(interest.try_mul(reserve_factor_rate)?)?;
+        self.borrowed_amount_wads = borrowed_amount_wads;
+        Ok(())
+    }
+
+    /// Withdraw the fees owed to the lending market owner as far as the available liquidity
+    /// covers them, returning the amount to transfer
+    pub fn withdraw_fees(&mut self) -> Result<u64, ProgramError> {
+        let withdraw_amount = self
+            .accumulated_fees_wads
+            .try_floor_u64()?
+            .min(self.available_amount);
+        self.available_amount -= withdraw_amount;
+        self.accumulated_fees_wads = self
+            .accumulated_fees_wads
+            .try_sub(Decimal::from(withdraw_amount))?;
+        Ok(withdraw_amount)
+    }
+
     /// Add new borrow amount to total borrows
     pub fn borrow(&mut self, borrow_amount: u64) -> ProgramResult {
         if borrow_amount > self.available_amount {
@@ -423,6 +578,30 @@ impl ReserveLiquidity {
         Ok(())
     }
 
+    /// Add new margin borrow amount to total borrows, up to the reserve margin borrow limit
+    pub fn margin_borrow(
+        &mut self,
+        borrow_amount: u64,
+        max_margin_borrow_amount: u64,
+    ) -> ProgramResult {
+        let margin_borrowed_amount = self
+            .margin_borrowed_amount
+            .checked_add(borrow_amount)
+            .ok_or(LendingError::MathOverflow)?;
+        if margin_borrowed_amount > max_margin_borrow_amount {
+            return Err(LendingError::MarginBorrowLimitExceeded.into());
+        }
+
+        self.borrow(borrow_amount)?;
+        self.margin_borrowed_amount = margin_borrowed_amount;
+        Ok(())
+    }
+
+    /// Release margin borrow amount settled by a repay or liquidation
+    pub fn release_margin_borrow(&mut self, release_amount: u64) {
+        self.margin_borrowed_amount = self.margin_borrowed_amount.saturating_sub(release_amount);
+    }
+
     /// Subtract repay amount from total borrows and add to available liquidity
     pub fn repay(&mut self, repay_amount: u64, settle_amount: Decimal) -> ProgramResult {
         self.available_amount = self
@@ -436,11 +615,13 @@ impl ReserveLiquidity {
 
     /// Calculate the liquidity utilization rate of the reserve
     pub fn utilization_rate(&self) -> Result<Rate, ProgramError> {
-        let total_supply = self.total_supply()?;
-        if total_supply == Decimal::zero() {
+        let total_liquidity = self.total_liquidity()?;
+        if total_liquidity == Decimal::zero() {
             return Ok(Rate::zero());
         }
-        self.borrowed_amount_wads.try_div(total_supply)?.try_into()
+        self.borrowed_amount_wads
+            .try_div(total_liquidity)?
+            .try_into()
     }
 }
 
@@ -525,7 +706,7 @@ impl From<CollateralExchangeRate> for Rate {
 }
 
 /// Reserve configuration values
-#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
 pub struct ReserveConfig {
     /// Optimal utilization rate as a percent
     pub optimal_utilization_rate: u8,
@@ -535,14 +716,235 @@ pub struct ReserveConfig {
     pub liquidation_bonus: u8,
     /// The percent at which an obligation is considered unhealthy
     pub liquidation_threshold: u8,
-    /// Min borrow APY
-    pub min_borrow_rate: u8,
-    /// Optimal (utilization) borrow APY
-    pub optimal_borrow_rate: u8,
-    /// Max borrow APY
-    pub max_borrow_rate: u8,
     /// Program owner fees assessed, separate from gains due to interest accrual
     pub fees: ReserveFees,
+    /// Max liquidity that can be borrowed through margin borrows, separate from regular borrows
+    pub max_margin_borrow_amount: u64,
+    /// Slots the liquidation health check averages the price over, using the price accumulator
+    /// of the reserve's market. Zero prices liquidations at the spot price.
+    pub liquidation_twap_slots: u64,
+    /// Borrow APR by utilization
+    pub borrow_rate_curve: BorrowRateCurve,
+    /// Adaptive rate model moving the optimal borrow rate with utilization
+    pub adaptive_rate: AdaptiveRateConfig,
+    /// Percent of borrow interest kept as fees for the lending market owner instead of accruing
+    /// to depositors
+    pub reserve_factor: u8,
+}
+
+impl ReserveConfig {
+    /// Point of the borrow rate curve at the optimal utilization rate, the kink the adaptive rate
+    /// model moves
+    pub fn optimal_point(&self) -> Option<BorrowRateCurvePoint> {
+        let utilization_rate_bps = u16::from(self.optimal_utilization_rate) * 100;
+        self.borrow_rate_curve
+            .points()
+            .iter()
+            .find(|point| point.utilization_rate_bps == utilization_rate_bps)
+            .copied()
+    }
+
+    /// Share of borrow interest kept as fees
+    pub fn reserve_factor_rate(&self) -> Rate {
+        Rate::from_percent(self.reserve_factor)
+    }
+
+    /// Rate the adaptive rate model starts from, the rate of the curve's optimal point, which
+    /// configs enabling the model must have
+    fn configured_optimal_borrow_rate(&self) -> Rate {
+        self.optimal_point()
+            .map_or_else(Rate::zero, |point| Rate::from_bps(point.borrow_rate_bps))
+    }
+}
+
+/// Adaptive rate model configuration. While utilization sits above the optimal utilization rate,
+/// the optimal borrow rate rises each slot by the rise gain scaled by how far utilization is
+/// towards 100%, and while it sits below, falls by the fall gain scaled by how far utilization is
+/// towards 0%. The optimal borrow rate stays within its bounds and replaces the rate of the
+/// borrow rate curve's point at the optimal utilization rate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct AdaptiveRateConfig {
+    /// Max rise of the optimal borrow rate per slot, as a Wad. Zero gains disable the model.
+    pub rise_gain_wad: u64,
+    /// Max fall of the optimal borrow rate per slot, as a Wad
+    pub fall_gain_wad: u64,
+    /// Lowest optimal borrow rate in basis points
+    pub min_optimal_borrow_rate_bps: u16,
+    /// Highest optimal borrow rate in basis points
+    pub max_optimal_borrow_rate_bps: u16,
+}
+
+impl AdaptiveRateConfig {
+    /// Adaptive rate model turned off
+    pub const DISABLED: Self = Self {
+        rise_gain_wad: 0,
+        fall_gain_wad: 0,
+        min_optimal_borrow_rate_bps: 0,
+        max_optimal_borrow_rate_bps: 0,
+    };
+
+    /// Check whether the optimal borrow rate adapts to utilization
+    pub fn is_enabled(&self) -> bool {
+        self.rise_gain_wad > 0 || self.fall_gain_wad > 0
+    }
+}
+
+/// Max number of points on a borrow rate curve
+pub const MAX_BORROW_RATE_CURVE_POINTS: usize = 8;
+
+/// Utilization rate of 100% in basis points
+const FULL_UTILIZATION_BPS: u16 = 10_000;
+
+/// Point of a borrow rate curve
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct BorrowRateCurvePoint {
+    /// Utilization rate in basis points
+    pub utilization_rate_bps: u16,
+    /// Borrow APR at this utilization rate in basis points
+    pub borrow_rate_bps: u16,
+}
+
+/// Borrow APR as a piecewise linear function of the utilization rate. A valid curve starts at 0%
+/// and ends at 100% utilization, with utilization increasing and rates never decreasing between
+/// consecutive points.
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct BorrowRateCurve {
+    /// Number of points in use
+    pub num_points: u8,
+    /// Points ordered by utilization rate, unused ones zeroed
+    pub points: [BorrowRateCurvePoint; MAX_BORROW_RATE_CURVE_POINTS],
+}
+
+impl BorrowRateCurve {
+    /// Curve without points
+    pub const EMPTY: Self = Self {
+        num_points: 0,
+        points: [BorrowRateCurvePoint {
+            utilization_rate_bps: 0,
+            borrow_rate_bps: 0,
+        }; MAX_BORROW_RATE_CURVE_POINTS],
+    };
+
+    /// Create a curve through the given points
+    pub fn new(points: &[BorrowRateCurvePoint]) -> Result<Self, ProgramError> {
+        if points.len() > MAX_BORROW_RATE_CURVE_POINTS {
+            return Err(LendingError::InvalidConfig.into());
+        }
+        let mut curve = Self::EMPTY;
+        curve.points[..points.len()].copy_from_slice(points);
+        curve.num_points = points.len() as u8;
+        Ok(curve)
+    }
+
+    /// Curve of the single kink model reserves had before curves, between the min, optimal and
+    /// max borrow rates at 0%, the optimal and 100% utilization, all in percent
+    pub fn from_single_kink(
+        optimal_utilization_rate: u8,
+        min_borrow_rate: u8,
+        optimal_borrow_rate: u8,
+        max_borrow_rate: u8,
+    ) -> Self {
+        let point = |utilization_rate: u8, borrow_rate: u8| BorrowRateCurvePoint {
+            utilization_rate_bps: u16::from(utilization_rate) * 100,
+            borrow_rate_bps: u16::from(borrow_rate) * 100,
+        };
+        let points = match optimal_utilization_rate {
+            0 => vec![point(0, optimal_borrow_rate), point(100, max_borrow_rate)],
+            100 => vec![point(0, min_borrow_rate), point(100, optimal_borrow_rate)],
+            _ => vec![
+                point(0, min_borrow_rate),
+                point(optimal_utilization_rate, optimal_borrow_rate),
+                point(100, max_borrow_rate),
+            ],
+        };
+        let mut curve = Self::EMPTY;
+        curve.points[..points.len()].copy_from_slice(&points);
+        curve.num_points = points.len() as u8;
+        curve
+    }
+
+    /// Points in use
+    pub fn points(&self) -> &[BorrowRateCurvePoint] {
+        let num_points = (self.num_points as usize).min(MAX_BORROW_RATE_CURVE_POINTS);
+        &self.points[..num_points]
+    }
+
+    /// Check whether the curve has no points
+    pub fn is_empty(&self) -> bool {
+        self.num_points == 0
+    }
+
+    /// Check that the curve spans 0% to 100% utilization and is monotonic
+    pub fn is_valid(&self) -> bool {
+        let points = self.points();
+        match (points.first(), points.last()) {
+            (Some(first), Some(last)) if points.len() >= 2 => {
+                self.num_points as usize <= MAX_BORROW_RATE_CURVE_POINTS
+                    && first.utilization_rate_bps == 0
+                    && last.utilization_rate_bps == FULL_UTILIZATION_BPS
+                    && points.windows(2).all(|pair| {
+                        pair[0].utilization_rate_bps < pair[1].utilization_rate_bps
+                            && pair[0].borrow_rate_bps <= pair[1].borrow_rate_bps
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// Interpolate the borrow rate at a utilization rate between the surrounding points
+    pub fn borrow_rate(&self, utilization_rate: Rate) -> Result<Rate, ProgramError> {
+        self.interpolate(utilization_rate, |point| {
+            Rate::from_bps(point.borrow_rate_bps)
+        })
+    }
+
+    /// Interpolate the borrow rate like `borrow_rate`, with the rate of the point at
+    /// `kink_utilization_rate_bps` replaced by `kink_rate`. The kink rate must lie between the
+    /// rates of its neighbouring points.
+    pub fn borrow_rate_with_kink(
+        &self,
+        utilization_rate: Rate,
+        kink_utilization_rate_bps: u16,
+        kink_rate: Rate,
+    ) -> Result<Rate, ProgramError> {
+        self.interpolate(utilization_rate, |point| {
+            if point.utilization_rate_bps == kink_utilization_rate_bps {
+                kink_rate
+            } else {
+                Rate::from_bps(point.borrow_rate_bps)
+            }
+        })
+    }
+
+    fn interpolate(
+        &self,
+        utilization_rate: Rate,
+        rate_at: impl Fn(&BorrowRateCurvePoint) -> Rate,
+    ) -> Result<Rate, ProgramError> {
+        let points = self.points();
+        let segment = points
+            .windows(2)
+            .find(|pair| utilization_rate <= Rate::from_bps(pair[1].utilization_rate_bps));
+        match segment {
+            Some(pair) => {
+                let start_utilization_rate = Rate::from_bps(pair[0].utilization_rate_bps);
+                let start_rate = rate_at(&pair[0]);
+                let normalized_rate =
+                    utilization_rate
+                        .try_sub(start_utilization_rate)?
+                        .try_div(Rate::from_bps(
+                            pair[1].utilization_rate_bps - pair[0].utilization_rate_bps,
+                        ))?;
+                let rate_range = rate_at(&pair[1]).try_sub(start_rate)?;
+
+                Ok(normalized_rate.try_mul(rate_range)?.try_add(start_rate)?)
+            }
+            None => points
+                .last()
+                .map(rate_at)
+                .ok_or_else(|| LendingError::InvalidConfig.into()),
+        }
+    }
 }
 
 /// Additional fee information on a reserve
@@ -550,7 +952,7 @@ pub struct ReserveConfig {
 /// These exist separately from interest accrual fees, and are specifically for
 /// the program owner and frontend host.  The fees are paid out as a percentage
 /// of collateral token amounts during repayments and liquidations.
-#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, BorshSerialize, BorshDeserialize)]
 pub struct ReserveFees {
     /// Fee assessed on `BorrowReserveLiquidity`, expressed as a Wad.
     /// Must be between 0 and 10^18, such that 10^18 = 1.  A few examples for
@@ -631,32 +1033,66 @@ impl Pack for Reserve {
             loan_to_value_ratio,
             liquidation_bonus,
             liquidation_threshold,
-            min_borrow_rate,
-            optimal_borrow_rate,
-            max_borrow_rate,
+            legacy_min_borrow_rate,
+            legacy_optimal_borrow_rate,
+            legacy_max_borrow_rate,
             borrow_fee_wad,
             host_fee_percentage,
             cumulative_borrow_rate,
             total_borrows,
             available_liquidity,
             collateral_mint_supply,
+            max_margin_borrow_amount,
+            margin_borrowed_amount,
+            price_source,
+            liquidation_twap_slots,
+            borrow_rate_curve,
+            adaptive_rise_gain_wad,
+            adaptive_fall_gain_wad,
+            adaptive_min_optimal_borrow_rate_bps,
+            adaptive_max_optimal_borrow_rate_bps,
+            adaptive_optimal_borrow_rate,
+            borrow_rate,
+            supply_rate,
+            reserve_factor,
+            accumulated_fees,
             __padding,
         ) = array_refs![
-            input, 1, 8, 32, 32, 1, 32, 32, 32, 32, 36, 1, 1, 1, 1, 1, 1, 1, 8, 1, 16, 16, 8, 8,
-            300
+            input, 1, 8, 32, 32, 1, 32, 32, 32, 32, 36, 1, 1, 1, 1, 1, 1, 1, 8, 1, 16, 16, 8, 8, 8,
+            8, 1, 8, 33, 8, 8, 2, 2, 8, 8, 8, 1, 16, 181
         ];
+        // reserves from before borrow rate curves have their single kink rates instead
+        let optimal_utilization_rate = u8::from_le_bytes(*optimal_utilization_rate);
+        let mut borrow_rate_curve = unpack_borrow_rate_curve(borrow_rate_curve)?;
+        if borrow_rate_curve.is_empty() {
+            borrow_rate_curve = BorrowRateCurve::from_single_kink(
+                optimal_utilization_rate,
+                u8::from_le_bytes(*legacy_min_borrow_rate),
+                u8::from_le_bytes(*legacy_optimal_borrow_rate),
+                u8::from_le_bytes(*legacy_max_borrow_rate),
+            );
+        }
         Ok(Self {
             version: u8::from_le_bytes(*version),
             last_update_slot: u64::from_le_bytes(*last_update_slot),
             cumulative_borrow_rate_wads: unpack_decimal(cumulative_borrow_rate),
+            adaptive_optimal_borrow_rate: Rate::from_scaled_val(u64::from_le_bytes(
+                *adaptive_optimal_borrow_rate,
+            )),
+            borrow_rate: Rate::from_scaled_val(u64::from_le_bytes(*borrow_rate)),
+            supply_rate: Rate::from_scaled_val(u64::from_le_bytes(*supply_rate)),
             lending_market: Pubkey::new_from_array(*lending_market),
             dex_market: unpack_coption_key(dex_market)?,
+            price_source: PriceSource::from_u8(price_source[0])
+                .ok_or(ProgramError::InvalidAccountData)?,
             liquidity: ReserveLiquidity {
                 mint_pubkey: Pubkey::new_from_array(*liquidity_mint),
                 mint_decimals: u8::from_le_bytes(*liquidity_mint_decimals),
                 supply_pubkey: Pubkey::new_from_array(*liquidity_supply),
                 available_amount: u64::from_le_bytes(*available_liquidity),
                 borrowed_amount_wads: unpack_decimal(total_borrows),
+                margin_borrowed_amount: u64::from_le_bytes(*margin_borrowed_amount),
+                accumulated_fees_wads: unpack_decimal(accumulated_fees),
             },
             collateral: ReserveCollateral {
                 mint_pubkey: Pubkey::new_from_array(*collateral_mint),
@@ -665,17 +1101,28 @@ impl Pack for Reserve {
                 fees_receiver: Pubkey::new_from_array(*collateral_fees_receiver),
             },
             config: ReserveConfig {
-                optimal_utilization_rate: u8::from_le_bytes(*optimal_utilization_rate),
+                optimal_utilization_rate,
                 loan_to_value_ratio: u8::from_le_bytes(*loan_to_value_ratio),
                 liquidation_bonus: u8::from_le_bytes(*liquidation_bonus),
                 liquidation_threshold: u8::from_le_bytes(*liquidation_threshold),
-                min_borrow_rate: u8::from_le_bytes(*min_borrow_rate),
-                optimal_borrow_rate: u8::from_le_bytes(*optimal_borrow_rate),
-                max_borrow_rate: u8::from_le_bytes(*max_borrow_rate),
                 fees: ReserveFees {
                     borrow_fee_wad: u64::from_le_bytes(*borrow_fee_wad),
                     host_fee_percentage: u8::from_le_bytes(*host_fee_percentage),
                 },
+                max_margin_borrow_amount: u64::from_le_bytes(*max_margin_borrow_amount),
+                liquidation_twap_slots: u64::from_le_bytes(*liquidation_twap_slots),
+                borrow_rate_curve,
+                adaptive_rate: AdaptiveRateConfig {
+                    rise_gain_wad: u64::from_le_bytes(*adaptive_rise_gain_wad),
+                    fall_gain_wad: u64::from_le_bytes(*adaptive_fall_gain_wad),
+                    min_optimal_borrow_rate_bps: u16::from_le_bytes(
+                        *adaptive_min_optimal_borrow_rate_bps,
+                    ),
+                    max_optimal_borrow_rate_bps: u16::from_le_bytes(
+                        *adaptive_max_optimal_borrow_rate_bps,
+                    ),
+                },
+                reserve_factor: u8::from_le_bytes(*reserve_factor),
             },
         })
     }
@@ -697,25 +1144,45 @@ impl Pack for Reserve {
             loan_to_value_ratio,
             liquidation_bonus,
             liquidation_threshold,
-            min_borrow_rate,
-            optimal_borrow_rate,
-            max_borrow_rate,
+            legacy_min_borrow_rate,
+            legacy_optimal_borrow_rate,
+            legacy_max_borrow_rate,
             borrow_fee_wad,
             host_fee_percentage,
             cumulative_borrow_rate,
             total_borrows,
             available_liquidity,
             collateral_mint_supply,
+            max_margin_borrow_amount,
+            margin_borrowed_amount,
+            price_source,
+            liquidation_twap_slots,
+            borrow_rate_curve,
+            adaptive_rise_gain_wad,
+            adaptive_fall_gain_wad,
+            adaptive_min_optimal_borrow_rate_bps,
+            adaptive_max_optimal_borrow_rate_bps,
+            adaptive_optimal_borrow_rate,
+            borrow_rate,
+            supply_rate,
+            reserve_factor,
+            accumulated_fees,
             _padding,
         ) = mut_array_refs![
             output, 1, 8, 32, 32, 1, 32, 32, 32, 32, 36, 1, 1, 1, 1, 1, 1, 1, 8, 1, 16, 16, 8, 8,
-            300
+            8, 8, 1, 8, 33, 8, 8, 2, 2, 8, 8, 8, 1, 16, 181
         ];
         *version = self.version.to_le_bytes();
         *last_update_slot = self.last_update_slot.to_le_bytes();
         pack_decimal(self.cumulative_borrow_rate_wads, cumulative_borrow_rate);
+        // rates are at most 655.35%, which fits a u64 Wad
+        *adaptive_optimal_borrow_rate =
+            (self.adaptive_optimal_borrow_rate.to_scaled_val() as u64).to_le_bytes();
+        *borrow_rate = (self.borrow_rate.to_scaled_val() as u64).to_le_bytes();
+        *supply_rate = (self.supply_rate.to_scaled_val() as u64).to_le_bytes();
         lending_market.copy_from_slice(self.lending_market.as_ref());
         pack_coption_key(&self.dex_market, dex_market);
+        price_source[0] = self.price_source as u8;
 
         // liquidity info
         liquidity_mint.copy_from_slice(self.liquidity.mint_pubkey.as_ref());
@@ -723,6 +1190,8 @@ impl Pack for Reserve {
         liquidity_supply.copy_from_slice(self.liquidity.supply_pubkey.as_ref());
         *available_liquidity = self.liquidity.available_amount.to_le_bytes();
         pack_decimal(self.liquidity.borrowed_amount_wads, total_borrows);
+        *margin_borrowed_amount = self.liquidity.margin_borrowed_amount.to_le_bytes();
+        pack_decimal(self.liquidity.accumulated_fees_wads, accumulated_fees);
 
         // collateral info
         collateral_mint.copy_from_slice(self.collateral.mint_pubkey.as_ref());
@@ -735,11 +1204,77 @@ impl Pack for Reserve {
         *loan_to_value_ratio = self.config.loan_to_value_ratio.to_le_bytes();
         *liquidation_bonus = self.config.liquidation_bonus.to_le_bytes();
         *liquidation_threshold = self.config.liquidation_threshold.to_le_bytes();
-        *min_borrow_rate = self.config.min_borrow_rate.to_le_bytes();
-        *optimal_borrow_rate = self.config.optimal_borrow_rate.to_le_bytes();
-        *max_borrow_rate = self.config.max_borrow_rate.to_le_bytes();
+        *legacy_min_borrow_rate = 0u8.to_le_bytes();
+        *legacy_optimal_borrow_rate = 0u8.to_le_bytes();
+        *legacy_max_borrow_rate = 0u8.to_le_bytes();
         *borrow_fee_wad = self.config.fees.borrow_fee_wad.to_le_bytes();
         *host_fee_percentage = self.config.fees.host_fee_percentage.to_le_bytes();
+        *max_margin_borrow_amount = self.config.max_margin_borrow_amount.to_le_bytes();
+        *liquidation_twap_slots = self.config.liquidation_twap_slots.to_le_bytes();
+        pack_borrow_rate_curve(&self.config.borrow_rate_curve, borrow_rate_curve);
+        *adaptive_rise_gain_wad = self.config.adaptive_rate.rise_gain_wad.to_le_bytes();
+        *adaptive_fall_gain_wad = self.config.adaptive_rate.fall_gain_wad.to_le_bytes();
+        *adaptive_min_optimal_borrow_rate_bps = self
+            .config
+            .adaptive_rate
+            .min_optimal_borrow_rate_bps
+            .to_le_bytes();
+        *adaptive_max_optimal_borrow_rate_bps = self
+            .config
+            .adaptive_rate
+            .max_optimal_borrow_rate_bps
+            .to_le_bytes();
+        *reserve_factor = self.config.reserve_factor.to_le_bytes();
+    }
+}
+
+const BORROW_RATE_CURVE_POINT_LEN: usize = 4;
+const BORROW_RATE_CURVE_LEN: usize = 1 + BORROW_RATE_CURVE_POINT_LEN * MAX_BORROW_RATE_CURVE_POINTS;
+
+fn unpack_borrow_rate_curve(
+    src: &[u8; BORROW_RATE_CURVE_LEN],
+) -> Result<BorrowRateCurve, ProgramError> {
+    let (num_points, points_data) = array_refs![
+        src,
+        1,
+        BORROW_RATE_CURVE_POINT_LEN * MAX_BORROW_RATE_CURVE_POINTS
+    ];
+    let num_points = u8::from_le_bytes(*num_points);
+    if num_points as usize > MAX_BORROW_RATE_CURVE_POINTS {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut curve = BorrowRateCurve::EMPTY;
+    curve.num_points = num_points;
+    for (point, data) in curve
+        .points
+        .iter_mut()
+        .zip(points_data.chunks_exact(BORROW_RATE_CURVE_POINT_LEN))
+    {
+        let data = array_ref![data, 0, BORROW_RATE_CURVE_POINT_LEN];
+        let (utilization_rate_bps, borrow_rate_bps) = array_refs![data, 2, 2];
+        point.utilization_rate_bps = u16::from_le_bytes(*utilization_rate_bps);
+        point.borrow_rate_bps = u16::from_le_bytes(*borrow_rate_bps);
+    }
+    Ok(curve)
+}
+
+fn pack_borrow_rate_curve(curve: &BorrowRateCurve, dst: &mut [u8; BORROW_RATE_CURVE_LEN]) {
+    let (num_points, points_data) = mut_array_refs![
+        dst,
+        1,
+        BORROW_RATE_CURVE_POINT_LEN * MAX_BORROW_RATE_CURVE_POINTS
+    ];
+    *num_points = curve.num_points.to_le_bytes();
+    for (point, data) in curve
+        .points
+        .iter()
+        .zip(points_data.chunks_exact_mut(BORROW_RATE_CURVE_POINT_LEN))
+    {
+        let data = array_mut_ref![data, 0, BORROW_RATE_CURVE_POINT_LEN];
+        let (utilization_rate_bps, borrow_rate_bps) = mut_array_refs![data, 2, 2];
+        *utilization_rate_bps = point.utilization_rate_bps.to_le_bytes();
+        *borrow_rate_bps = point.borrow_rate_bps.to_le_bytes();
     }
 }
 
@@ -748,7 +1283,6 @@ mod test {
     use super::*;
     use crate::math::{PERCENT_SCALER, WAD};
     use proptest::prelude::*;
-    use std::cmp::Ordering;
 
     const MAX_LIQUIDITY: u64 = u64::MAX / 5;
 
@@ -921,10 +1455,13 @@ mod test {
                     ..ReserveLiquidity::default()
                 },
                 config: ReserveConfig {
-                    min_borrow_rate,
-                    optimal_borrow_rate,
-                    max_borrow_rate,
                     optimal_utilization_rate,
+                    borrow_rate_curve: BorrowRateCurve::from_single_kink(
+                        optimal_utilization_rate,
+                        min_borrow_rate,
+                        optimal_borrow_rate,
+                        max_borrow_rate,
+                    ),
                     ..ReserveConfig::default()
                 },
                 ..Reserve::default()
@@ -934,22 +1471,22 @@ mod test {
             assert!(current_borrow_rate >= Rate::from_percent(min_borrow_rate));
             assert!(current_borrow_rate <= Rate::from_percent(max_borrow_rate));
 
-            let optimal_borrow_rate = Rate::from_percent(optimal_borrow_rate);
+            let optimal_rate = Rate::from_percent(optimal_borrow_rate);
             let current_rate = reserve.liquidity.utilization_rate()?;
             match current_rate.cmp(&Rate::from_percent(optimal_utilization_rate)) {
                 Ordering::Less => {
-                    if min_borrow_rate == reserve.config.optimal_borrow_rate {
-                        assert_eq!(current_borrow_rate, optimal_borrow_rate);
+                    if min_borrow_rate == optimal_borrow_rate {
+                        assert_eq!(current_borrow_rate, optimal_rate);
                     } else {
-                        assert!(current_borrow_rate < optimal_borrow_rate);
+                        assert!(current_borrow_rate < optimal_rate);
                     }
                 }
-                Ordering::Equal => assert!(current_borrow_rate == optimal_borrow_rate),
+                Ordering::Equal => assert!(current_borrow_rate == optimal_rate),
                 Ordering::Greater => {
-                    if max_borrow_rate == reserve.config.optimal_borrow_rate {
-                        assert_eq!(current_borrow_rate, optimal_borrow_rate);
+                    if max_borrow_rate == optimal_borrow_rate {
+                        assert_eq!(current_borrow_rate, optimal_rate);
                     } else {
-                        assert!(current_borrow_rate > optimal_borrow_rate);
+                        assert!(current_borrow_rate > optimal_rate);
                     }
                 }
             }
@@ -1106,9 +1643,13 @@ mod test {
                     ..ReserveLiquidity::default()
                 },
                 config: ReserveConfig {
-                    min_borrow_rate: borrow_rate,
-                    optimal_borrow_rate: borrow_rate,
                     optimal_utilization_rate: 100,
+                    borrow_rate_curve: BorrowRateCurve::from_single_kink(
+                        100,
+                        borrow_rate,
+                        borrow_rate,
+                        borrow_rate,
+                    ),
                     ..ReserveConfig::default()
                 },
                 ..Reserve::default()
@@ -1157,7 +1698,7 @@ mod test {
                     ..ReserveLiquidity::default()
                 },
                 config: ReserveConfig {
-                    max_borrow_rate: borrow_rate,
+                    borrow_rate_curve: BorrowRateCurve::from_single_kink(0, 0, 0, borrow_rate),
                     ..ReserveConfig::default()
                 },
                 ..Reserve::default()
@@ -1354,4 +1895,338 @@ mod test {
         assert_eq!(total_fee, 10); // 1% of 1000
         assert_eq!(host_fee, 0); // 0 host fee
     }
+
+    #[test]
+    fn margin_borrow_limit() {
+        let mut liquidity = ReserveLiquidity {
+            available_amount: 100,
+            ..ReserveLiquidity::default()
+        };
+
+        liquidity.margin_borrow(60, 80).unwrap();
+        assert_eq!(liquidity.margin_borrowed_amount, 60);
+        assert_eq!(
+            liquidity.margin_borrow(30, 80),
+            Err(LendingError::MarginBorrowLimitExceeded.into())
+        );
+
+        // regular borrows don't count towards the margin limit
+        liquidity.borrow(30).unwrap();
+        assert_eq!(liquidity.margin_borrowed_amount, 60);
+
+        liquidity.release_margin_borrow(60);
+        liquidity.margin_borrow(10, 80).unwrap();
+        assert_eq!(liquidity.margin_borrowed_amount, 10);
+        assert_eq!(liquidity.available_amount, 0);
+    }
+
+    #[test]
+    fn pack_price_source() {
+        let reserve = Reserve {
+            version: PROGRAM_VERSION,
+            dex_market: COption::Some(Pubkey::new_unique()),
+            price_source: PriceSource::TokenSwap,
+            config: ReserveConfig {
+                borrow_rate_curve: curve(&[(0, 0), (10_000, 300)]),
+                ..ReserveConfig::default()
+            },
+            ..Reserve::default()
+        };
+        let mut data = [0; Reserve::LEN];
+        Reserve::pack(reserve.clone(), &mut data).unwrap();
+        assert_eq!(Reserve::unpack(&data).unwrap(), reserve);
+
+        // Reserves packed before the price source existed have a zeroed byte
+        data[318] = 0;
+        assert_eq!(
+            Reserve::unpack(&data).unwrap().price_source,
+            PriceSource::DexMarket
+        );
+        data[318] = 2;
+        assert_eq!(
+            Reserve::unpack(&data),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    fn curve(points: &[(u16, u16)]) -> BorrowRateCurve {
+        let points: Vec<_> = points
+            .iter()
+            .map(
+                |&(utilization_rate_bps, borrow_rate_bps)| BorrowRateCurvePoint {
+                    utilization_rate_bps,
+                    borrow_rate_bps,
+                },
+            )
+            .collect();
+        BorrowRateCurve::new(&points).unwrap()
+    }
+
+    #[test]
+    fn borrow_rate_curve_validity() {
+        assert!(curve(&[(0, 0), (10_000, 300)]).is_valid());
+        assert!(curve(&[(0, 100), (8_000, 100), (9_500, 2_000), (10_000, 50_000)]).is_valid());
+
+        assert!(!BorrowRateCurve::EMPTY.is_valid());
+        assert!(!curve(&[(0, 100)]).is_valid());
+        // must span the full utilization range
+        assert!(!curve(&[(100, 0), (10_000, 300)]).is_valid());
+        assert!(!curve(&[(0, 0), (9_000, 300)]).is_valid());
+        // utilization must increase and rates must not decrease
+        assert!(!curve(&[(0, 0), (5_000, 100), (5_000, 200), (10_000, 300)]).is_valid());
+        assert!(!curve(&[(0, 0), (5_000, 200), (10_000, 100)]).is_valid());
+
+        let too_many_points = [BorrowRateCurvePoint::default(); MAX_BORROW_RATE_CURVE_POINTS + 1];
+        assert_eq!(
+            BorrowRateCurve::new(&too_many_points),
+            Err(LendingError::InvalidConfig.into())
+        );
+    }
+
+    #[test]
+    fn borrow_rate_curve_interpolation() {
+        let curve = curve(&[(0, 100), (8_000, 500), (9_000, 2_500), (10_000, 60_000)]);
+        let borrow_rate = |utilization_bps| curve.borrow_rate(Rate::from_bps(utilization_bps));
+
+        assert_eq!(borrow_rate(0), Ok(Rate::from_bps(100)));
+        assert_eq!(borrow_rate(4_000), Ok(Rate::from_bps(300)));
+        assert_eq!(borrow_rate(8_000), Ok(Rate::from_bps(500)));
+        assert_eq!(borrow_rate(8_500), Ok(Rate::from_bps(1_500)));
+        assert_eq!(borrow_rate(9_000), Ok(Rate::from_bps(2_500)));
+        assert_eq!(borrow_rate(10_000), Ok(Rate::from_bps(60_000)));
+
+        let reserve = Reserve {
+            liquidity: ReserveLiquidity {
+                borrowed_amount_wads: Decimal::from(85u64),
+                available_amount: 15,
+                ..ReserveLiquidity::default()
+            },
+            config: ReserveConfig {
+                borrow_rate_curve: curve,
+                ..ReserveConfig::default()
+            },
+            ..Reserve::default()
+        };
+        assert_eq!(reserve.current_borrow_rate(), Ok(Rate::from_bps(1_500)));
+    }
+
+    #[test]
+    fn pack_reserve_borrow_rate_curve() {
+        let reserve = Reserve {
+            version: PROGRAM_VERSION,
+            config: ReserveConfig {
+                borrow_rate_curve: curve(&[(0, 100), (8_000, 500), (10_000, 65_535)]),
+                ..ReserveConfig::default()
+            },
+            ..Reserve::default()
+        };
+        let mut data = [0; Reserve::LEN];
+        Reserve::pack(reserve.clone(), &mut data).unwrap();
+        assert_eq!(Reserve::unpack(&data).unwrap(), reserve);
+    }
+
+    #[test]
+    fn unpack_single_kink_reserve() {
+        let reserve = Reserve {
+            version: PROGRAM_VERSION,
+            config: ReserveConfig {
+                optimal_utilization_rate: 80,
+                borrow_rate_curve: curve(&[(0, 0), (10_000, 300)]),
+                ..ReserveConfig::default()
+            },
+            ..Reserve::default()
+        };
+        let mut data = [0; Reserve::LEN];
+        Reserve::pack(reserve, &mut data).unwrap();
+
+        // reserves packed before borrow rate curves have min, optimal and max borrow rates in
+        // percent and no curve points
+        data[242..245].copy_from_slice(&[1, 4, 30]);
+        data[327] = 0;
+        let reserve = Reserve::unpack(&data).unwrap();
+        assert_eq!(
+            reserve.config.borrow_rate_curve,
+            curve(&[(0, 100), (8_000, 400), (10_000, 3_000)])
+        );
+
+        // the legacy rates are cleared once the curve is packed in their place
+        Reserve::pack(reserve.clone(), &mut data).unwrap();
+        assert_eq!(&data[242..245], &[0, 0, 0]);
+        assert_eq!(Reserve::unpack(&data).unwrap(), reserve);
+
+        assert_eq!(
+            BorrowRateCurve::from_single_kink(0, 1, 4, 30),
+            curve(&[(0, 400), (10_000, 3_000)])
+        );
+        assert_eq!(
+            BorrowRateCurve::from_single_kink(100, 1, 4, 30),
+            curve(&[(0, 100), (10_000, 400)])
+        );
+    }
+
+    #[test]
+    fn adaptive_optimal_borrow_rate() {
+        let config = ReserveConfig {
+            optimal_utilization_rate: 50,
+            borrow_rate_curve: curve(&[(0, 0), (5_000, 400), (10_000, 3_000)]),
+            adaptive_rate: AdaptiveRateConfig {
+                rise_gain_wad: 100_000_000_000_000,
+                fall_gain_wad: 200_000_000_000_000,
+                min_optimal_borrow_rate_bps: 200,
+                max_optimal_borrow_rate_bps: 1_000,
+            },
+            ..ReserveConfig::default()
+        };
+        let mut reserve = Reserve::new(NewReserveParams {
+            current_slot: 0,
+            lending_market: Pubkey::default(),
+            collateral: ReserveCollateral::default(),
+            liquidity: ReserveLiquidity::default(),
+            dex_market: COption::None,
+            price_source: PriceSource::DexMarket,
+            config,
+        });
+        assert_eq!(reserve.optimal_borrow_rate(), Ok(Rate::from_percent(4)));
+
+        let mut accrue_at_utilization = |borrowed: u64, slots_elapsed: u64| {
+            reserve.liquidity.borrowed_amount_wads = Decimal::from(borrowed);
+            reserve.liquidity.available_amount = 100 - borrowed;
+            reserve
+                .accrue_interest(reserve.last_update_slot + slots_elapsed)
+                .unwrap();
+            reserve.optimal_borrow_rate().unwrap()
+        };
+
+        // utilization 60% of the way from optimal to full rises 0.6 bps per slot
+        assert_eq!(accrue_at_utilization(80, 10), Rate::from_bps(406));
+        assert_eq!(accrue_at_utilization(50, 10), Rate::from_bps(406));
+        assert_eq!(accrue_at_utilization(80, 1_000_000), Rate::from_bps(1_000));
+
+        // utilization 60% of the way from optimal to zero falls 1.2 bps per slot
+        assert_eq!(accrue_at_utilization(20, 10), Rate::from_bps(988));
+        assert_eq!(accrue_at_utilization(20, 1_000_000), Rate::from_bps(200));
+
+        // the adapted rate moves the curve's point at the optimal utilization rate
+        reserve.liquidity.borrowed_amount_wads = Decimal::from(50u64);
+        reserve.liquidity.available_amount = 50;
+        assert_eq!(reserve.current_borrow_rate(), Ok(Rate::from_bps(200)));
+        reserve.liquidity.borrowed_amount_wads = Decimal::from(25u64);
+        reserve.liquidity.available_amount = 75;
+        assert_eq!(reserve.current_borrow_rate(), Ok(Rate::from_bps(100)));
+        reserve.liquidity.borrowed_amount_wads = Decimal::from(75u64);
+        reserve.liquidity.available_amount = 25;
+        assert_eq!(reserve.current_borrow_rate(), Ok(Rate::from_bps(1_600)));
+
+        // disabling and enabling again restarts from the configured optimal borrow rate
+        let disabled = ReserveConfig {
+            adaptive_rate: AdaptiveRateConfig::DISABLED,
+            ..config
+        };
+        reserve.set_config(disabled);
+        assert_eq!(reserve.optimal_borrow_rate(), Ok(Rate::from_percent(4)));
+        reserve.set_config(config);
+        assert_eq!(reserve.optimal_borrow_rate(), Ok(Rate::from_percent(4)));
+
+        let mut data = [0; Reserve::LEN];
+        reserve.version = PROGRAM_VERSION;
+        Reserve::pack(reserve.clone(), &mut data).unwrap();
+        assert_eq!(Reserve::unpack(&data).unwrap(), reserve);
+    }
+
+    #[test]
+    fn supply_rate() {
+        let mut reserve = Reserve {
+            liquidity: ReserveLiquidity {
+                borrowed_amount_wads: Decimal::from(80u64),
+                available_amount: 20,
+                ..ReserveLiquidity::default()
+            },
+            config: ReserveConfig {
+                optimal_utilization_rate: 80,
+                borrow_rate_curve: curve(&[(0, 0), (8_000, 1_000), (10_000, 3_000)]),
+                ..ReserveConfig::default()
+            },
+            ..Reserve::default()
+        };
+        assert_eq!(reserve.current_borrow_rate(), Ok(Rate::from_percent(10)));
+        assert_eq!(reserve.current_supply_rate(), Ok(Rate::from_percent(8)));
+
+        // accruing records the rates going forward, even within the same slot
+        reserve.accrue_interest(0).unwrap();
+        assert_eq!(reserve.borrow_rate, Rate::from_percent(10));
+        assert_eq!(reserve.supply_rate, Rate::from_percent(8));
+
+        reserve.liquidity.available_amount = 0;
+        reserve.accrue_interest(0).unwrap();
+        assert_eq!(reserve.borrow_rate, Rate::from_percent(30));
+        assert_eq!(reserve.supply_rate, Rate::from_percent(30));
+
+        let mut data = [0; Reserve::LEN];
+        reserve.version = PROGRAM_VERSION;
+        Reserve::pack(reserve.clone(), &mut data).unwrap();
+        assert_eq!(Reserve::unpack(&data).unwrap(), reserve);
+    }
+    #[test]
+    fn reserve_factor() {
+        let mut reserve = Reserve {
+            liquidity: ReserveLiquidity {
+                borrowed_amount_wads: Decimal::from(80u64),
+                available_amount: 20,
+                ..ReserveLiquidity::default()
+            },
+            config: ReserveConfig {
+                optimal_utilization_rate: 80,
+                borrow_rate_curve: curve(&[(0, 0), (8_000, 1_000), (10_000, 3_000)]),
+                reserve_factor: 25,
+                ..ReserveConfig::default()
+            },
+            ..Reserve::default()
+        };
+        assert_eq!(reserve.current_supply_rate(), Ok(Rate::from_percent(6)));
+
+        // the reserve factor's share of the interest is kept out of the depositors' supply
+        reserve.accrue_interest(SLOTS_PER_YEAR).unwrap();
+        let interest = reserve
+            .liquidity
+            .borrowed_amount_wads
+            .try_sub(Decimal::from(80u64))
+            .unwrap();
+        let fees = interest.try_mul(Rate::from_percent(25)).unwrap();
+        assert_eq!(reserve.liquidity.accumulated_fees_wads, fees);
+        assert_eq!(
+            reserve.liquidity.total_supply(),
+            Decimal::from(100u64)
+                .try_add(interest)
+                .unwrap()
+                .try_sub(fees)
+        );
+        assert_eq!(reserve.liquidity.utilization_rate(), {
+            let total_liquidity = Decimal::from(100u64).try_add(interest).unwrap();
+            reserve
+                .liquidity
+                .borrowed_amount_wads
+                .try_div(total_liquidity)
+                .unwrap()
+                .try_into()
+        });
+
+        let mut data = [0; Reserve::LEN];
+        reserve.version = PROGRAM_VERSION;
+        Reserve::pack(reserve.clone(), &mut data).unwrap();
+        assert_eq!(Reserve::unpack(&data).unwrap(), reserve);
+
+        // only whole tokens are withdrawn, and only out of the available liquidity
+        let withdraw_amount = fees.try_floor_u64().unwrap();
+        assert_eq!(reserve.liquidity.withdraw_fees(), Ok(withdraw_amount));
+        assert_eq!(reserve.liquidity.available_amount, 20 - withdraw_amount);
+        assert_eq!(
+            reserve.liquidity.accumulated_fees_wads,
+            fees.try_sub(Decimal::from(withdraw_amount)).unwrap()
+        );
+
+        reserve.liquidity.available_amount = 0;
+        reserve.liquidity.accumulated_fees_wads = Decimal::from(5u64);
+        assert_eq!(reserve.liquidity.withdraw_fees(), Ok(0));
+        assert_eq!(reserve.liquidity.accumulated_fees_wads, Decimal::from(5u64));
+    }
 }
@@ -0,0 +1,411 @@
+//! Token swap pool used for simulating trades
+
+use crate::{
+    error::LendingError,
+    math::{Decimal, TryAdd, TryDiv, TryMul, TrySub},
+    state::TokenConverter,
+};
+use solana_program::{
+    account_info::AccountInfo, program_error::ProgramError, program_pack::Pack, pubkey::Pubkey,
+};
+use spl_token::state::Account as Token;
+use std::convert::TryFrom;
+
+/// Length of a token swap pool account, a version byte followed by the pool state
+pub const SWAP_POOL_LEN: usize = 324;
+
+const SWAP_V1_VERSION: u8 = 1;
+const CONSTANT_PRODUCT_CURVE: u8 = 0;
+
+const IS_INITIALIZED_OFFSET: usize = 1;
+const NONCE_OFFSET: usize = 2;
+const TOKEN_A_OFFSET: usize = 35;
+const TOKEN_B_OFFSET: usize = 67;
+const POOL_MINT_OFFSET: usize = 99;
+const TOKEN_A_MINT_OFFSET: usize = 131;
+const TOKEN_B_MINT_OFFSET: usize = 163;
+const POOL_FEE_ACCOUNT_OFFSET: usize = 195;
+const TRADE_FEE_NUMERATOR_OFFSET: usize = 227;
+const TRADE_FEE_DENOMINATOR_OFFSET: usize = 235;
+const OWNER_TRADE_FEE_NUMERATOR_OFFSET: usize = 243;
+const OWNER_TRADE_FEE_DENOMINATOR_OFFSET: usize = 251;
+const CURVE_TYPE_OFFSET: usize = 291;
+
+/// Token swap pool info
+#[derive(Clone, Debug, PartialEq)]
+pub struct SwapPool {
+    /// Nonce of the pool authority, derived from the pool address
+    pub nonce: u8,
+    /// Pool token A account
+    pub token_a: Pubkey,
+    /// Pool token B account
+    pub token_b: Pubkey,
+    /// Pool token mint
+    pub pool_mint: Pubkey,
+    /// Token A mint
+    pub token_a_mint: Pubkey,
+    /// Token B mint
+    pub token_b_mint: Pubkey,
+    /// Pool token account receiving the owner trade fees
+    pub pool_fee_account: Pubkey,
+    /// Fraction of the input amount charged by the pool, trade and owner fees combined
+    pub fee: Decimal,
+}
+
+impl SwapPool {
+    /// Read an initialized constant product pool from its account data
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.len() != SWAP_POOL_LEN || data[0] != SWAP_V1_VERSION {
+            return Err(LendingError::InvalidSwapPool.into());
+        }
+        if data[IS_INITIALIZED_OFFSET] != 1 {
+            return Err(LendingError::InvalidSwapPool.into());
+        }
+        if data[CURVE_TYPE_OFFSET] != CONSTANT_PRODUCT_CURVE {
+            return Err(LendingError::UnsupportedSwapCurve.into());
+        }
+
+        let trade_fee = Self::fee_at_offsets(
+            data,
+            TRADE_FEE_NUMERATOR_OFFSET,
+            TRADE_FEE_DENOMINATOR_OFFSET,
+        )?;
+        let owner_trade_fee = Self::fee_at_offsets(
+            data,
+            OWNER_TRADE_FEE_NUMERATOR_OFFSET,
+            OWNER_TRADE_FEE_DENOMINATOR_OFFSET,
+        )?;
+        let fee = trade_fee.try_add(owner_trade_fee)?;
+        if fee >= Decimal::one() {
+            return Err(LendingError::InvalidSwapPool.into());
+        }
+
+        Ok(Self {
+            nonce: data[NONCE_OFFSET],
+            token_a: Self::pubkey_at_offset(data, TOKEN_A_OFFSET),
+            token_b: Self::pubkey_at_offset(data, TOKEN_B_OFFSET),
+            pool_mint: Self::pubkey_at_offset(data, POOL_MINT_OFFSET),
+            token_a_mint: Self::pubkey_at_offset(data, TOKEN_A_MINT_OFFSET),
+            token_b_mint: Self::pubkey_at_offset(data, TOKEN_B_MINT_OFFSET),
+            pool_fee_account: Self::pubkey_at_offset(data, POOL_FEE_ACCOUNT_OFFSET),
+            fee,
+        })
+    }
+
+    /// Check if the pool trades between the two mints
+    pub fn trades(&self, mint: &Pubkey, other_mint: &Pubkey) -> bool {
+        (&self.token_a_mint == mint && &self.token_b_mint == other_mint)
+            || (&self.token_b_mint == mint && &self.token_a_mint == other_mint)
+    }
+
+    fn pubkey_at_offset(data: &[u8], offset: usize) -> Pubkey {
+        Pubkey::new(&data[offset..offset + 32])
+    }
+
+    fn u64_at_offset(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(<[u8; 8]>::try_from(&data[offset..offset + 8]).unwrap())
+    }
+
+    fn fee_at_offsets(
+        data: &[u8],
+        numerator_offset: usize,
+        denominator_offset: usize,
+    ) -> Result<Decimal, ProgramError> {
+        let numerator = Self::u64_at_offset(data, numerator_offset);
+        let denominator = Self::u64_at_offset(data, denominator_offset);
+        if numerator == 0 || denominator == 0 {
+            Ok(Decimal::zero())
+        } else {
+            Decimal::from(numerator).try_div(denominator)
+        }
+    }
+}
+
+/// Swap simulator, trades against a snapshot of a constant product pool
+pub struct SwapSimulator<'a> {
+    pool: SwapPool,
+    token_a_amount: u64,
+    token_b_amount: u64,
+    buy_token_mint: &'a Pubkey,
+}
+
+impl<'a> TokenConverter for SwapSimulator<'a> {
+    fn best_price(&mut self, token_mint: &Pubkey) -> Result<Decimal, ProgramError> {
+        let spot_price = self.spot_price(token_mint)?;
+        let fee_complement = Decimal::one().try_sub(self.pool.fee)?;
+        if token_mint == self.buy_token_mint {
+            spot_price.try_div(fee_complement)
+        } else {
+            spot_price.try_mul(fee_complement)
+        }
+    }
+
+    fn convert(
+        self,
+        from_amount: Decimal,
+        from_token_mint: &Pubkey,
+    ) -> Result<Decimal, ProgramError> {
+        if from_token_mint == self.buy_token_mint {
+            self.input_for_output(from_amount, from_token_mint)
+        } else {
+            self.output_for_input(from_amount, from_token_mint)
+        }
+    }
+}
+
+impl<'a> SwapSimulator<'a> {
+    /// Create a new SwapSimulator
+    pub fn new(
+        swap_pool_info: &AccountInfo,
+        token_a_info: &AccountInfo,
+        token_b_info: &AccountInfo,
+        buy_token_mint: &'a Pubkey,
+        sell_token_mint: &'a Pubkey,
+    ) -> Result<Self, ProgramError> {
+        let pool = SwapPool::unpack(&swap_pool_info.data.borrow())?;
+        if !pool.trades(buy_token_mint, sell_token_mint) {
+            return Err(LendingError::SwapPoolMintMismatch.into());
+        }
+        if &pool.token_a != token_a_info.key || &pool.token_b != token_b_info.key {
+            return Err(LendingError::InvalidSwapPool.into());
+        }
+
+        let token_a_amount = Token::unpack(&token_a_info.data.borrow())?.amount;
+        let token_b_amount = Token::unpack(&token_b_info.data.borrow())?.amount;
+
+        Ok(Self::from_pool(
+            pool,
+            token_a_amount,
+            token_b_amount,
+            buy_token_mint,
+        ))
+    }
+
+    /// Create a SwapSimulator from pool info and balances
+    pub fn from_pool(
+        pool: SwapPool,
+        token_a_amount: u64,
+        token_b_amount: u64,
+        buy_token_mint: &'a Pubkey,
+    ) -> Self {
+        Self {
+            pool,
+            token_a_amount,
+            token_b_amount,
+            buy_token_mint,
+        }
+    }
+
+    /// Pool price of a token in the other token, before fees
+    pub fn spot_price(&self, token_mint: &Pubkey) -> Result<Decimal, ProgramError> {
+        let (token_amount, other_amount) = self.amounts(token_mint)?;
+        if token_amount == 0 {
+            return Err(LendingError::TradeSimulationError.into());
+        }
+        Decimal::from(other_amount).try_div(token_amount)
+    }
+
+    /// Pool balances of a token and of the token it trades against
+    fn amounts(&self, token_mint: &Pubkey) -> Result<(u64, u64), ProgramError> {
+        if token_mint == &self.pool.token_a_mint {
+            Ok((self.token_a_amount, self.token_b_amount))
+        } else if token_mint == &self.pool.token_b_mint {
+            Ok((self.token_b_amount, self.token_a_amount))
+        } else {
+            Err(LendingError::SwapPoolMintMismatch.into())
+        }
+    }
+
+    /// Amount of the other token received for swapping `input_amount` into the pool
+    fn output_for_input(
+        &self,
+        input_amount: Decimal,
+        input_token_mint: &Pubkey,
+    ) -> Result<Decimal, ProgramError> {
+        let (input_pool_amount, output_pool_amount) = self.amounts(input_token_mint)?;
+        let fee_complement = Decimal::one().try_sub(self.pool.fee)?;
+        let input_less_fees = input_amount.try_mul(fee_complement)?;
+        if input_less_fees == Decimal::zero() {
+            return Ok(Decimal::zero());
+        }
+
+        // output / output_pool = input / (input_pool + input), keeping the product constant
+        let new_input_pool_amount = Decimal::from(input_pool_amount).try_add(input_less_fees)?;
+        input_less_fees
+            .try_div(new_input_pool_amount)?
+            .try_mul(output_pool_amount)
+    }
+
+    /// Amount of the other token that must be swapped into the pool to receive `output_amount`
+    fn input_for_output(
+        &self,
+        output_amount: Decimal,
+        output_token_mint: &Pubkey,
+    ) -> Result<Decimal, ProgramError> {
+        let (output_pool_amount, input_pool_amount) = self.amounts(output_token_mint)?;
+        let output_pool_amount = Decimal::from(output_pool_amount);
+        if output_amount >= output_pool_amount {
+            return Err(LendingError::TradeSimulationError.into());
+        }
+
+        // input / input_pool = output / (output_pool - output), keeping the product constant
+        let input_less_fees = output_amount
+            .try_div(output_pool_amount.try_sub(output_amount)?)?
+            .try_mul(input_pool_amount)?;
+        let fee_complement = Decimal::one().try_sub(self.pool.fee)?;
+        input_less_fees.try_div(fee_complement)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pool_data(trade_fee: (u64, u64), owner_trade_fee: (u64, u64), curve_type: u8) -> Vec<u8> {
+        let mut data = vec![0; SWAP_POOL_LEN];
+        data[0] = SWAP_V1_VERSION;
+        data[IS_INITIALIZED_OFFSET] = 1;
+        data[NONCE_OFFSET] = 254;
+        data[TOKEN_A_OFFSET..TOKEN_A_OFFSET + 32].copy_from_slice(&[1; 32]);
+        data[TOKEN_B_OFFSET..TOKEN_B_OFFSET + 32].copy_from_slice(&[2; 32]);
+        data[POOL_MINT_OFFSET..POOL_MINT_OFFSET + 32].copy_from_slice(&[5; 32]);
+        data[TOKEN_A_MINT_OFFSET..TOKEN_A_MINT_OFFSET + 32].copy_from_slice(&[3; 32]);
+        data[TOKEN_B_MINT_OFFSET..TOKEN_B_MINT_OFFSET + 32].copy_from_slice(&[4; 32]);
+        data[POOL_FEE_ACCOUNT_OFFSET..POOL_FEE_ACCOUNT_OFFSET + 32].copy_from_slice(&[6; 32]);
+        for (offset, value) in [
+            (TRADE_FEE_NUMERATOR_OFFSET, trade_fee.0),
+            (TRADE_FEE_DENOMINATOR_OFFSET, trade_fee.1),
+            (OWNER_TRADE_FEE_NUMERATOR_OFFSET, owner_trade_fee.0),
+            (OWNER_TRADE_FEE_DENOMINATOR_OFFSET, owner_trade_fee.1),
+        ]
+        .iter()
+        {
+            data[*offset..*offset + 8].copy_from_slice(&value.to_le_bytes());
+        }
+        data[CURVE_TYPE_OFFSET] = curve_type;
+        data
+    }
+
+    fn token_a_mint() -> Pubkey {
+        Pubkey::new_from_array([3; 32])
+    }
+
+    fn token_b_mint() -> Pubkey {
+        Pubkey::new_from_array([4; 32])
+    }
+
+    #[test]
+    fn unpack_pool() {
+        let pool = SwapPool::unpack(&pool_data((25, 10_000), (5, 10_000), 0)).unwrap();
+        assert_eq!(pool.token_a, Pubkey::new_from_array([1; 32]));
+        assert_eq!(pool.token_b, Pubkey::new_from_array([2; 32]));
+        assert_eq!(pool.nonce, 254);
+        assert_eq!(pool.pool_mint, Pubkey::new_from_array([5; 32]));
+        assert_eq!(pool.pool_fee_account, Pubkey::new_from_array([6; 32]));
+        assert_eq!(pool.fee, Decimal::from(3u64).try_div(1_000).unwrap());
+        assert!(pool.trades(&token_b_mint(), &token_a_mint()));
+        assert!(!pool.trades(&token_a_mint(), &Pubkey::default()));
+    }
+
+    #[test]
+    fn unpack_invalid_pool() {
+        let mut data = pool_data((0, 0), (0, 0), 0);
+        data[IS_INITIALIZED_OFFSET] = 0;
+        assert_eq!(
+            SwapPool::unpack(&data),
+            Err(LendingError::InvalidSwapPool.into())
+        );
+        assert_eq!(
+            SwapPool::unpack(&pool_data((0, 0), (0, 0), 0)[1..]),
+            Err(LendingError::InvalidSwapPool.into())
+        );
+        assert_eq!(
+            SwapPool::unpack(&pool_data((0, 0), (0, 0), 1)),
+            Err(LendingError::UnsupportedSwapCurve.into())
+        );
+        assert_eq!(
+            SwapPool::unpack(&pool_data((1, 1), (0, 0), 0)),
+            Err(LendingError::InvalidSwapPool.into())
+        );
+    }
+
+    #[test]
+    fn simulate_without_fees() {
+        let pool = SwapPool::unpack(&pool_data((0, 0), (0, 0), 0)).unwrap();
+        let buy_token_mint = token_a_mint();
+
+        // 1000 A against 4000 B
+        let mut simulator = SwapSimulator::from_pool(pool.clone(), 1_000, 4_000, &buy_token_mint);
+        assert_eq!(
+            simulator.best_price(&token_a_mint()).unwrap(),
+            Decimal::from(4u64)
+        );
+        assert_eq!(
+            simulator.best_price(&token_b_mint()).unwrap(),
+            Decimal::from(1u64).try_div(4).unwrap()
+        );
+
+        // Selling 1000 B moves the pool to 800 A against 5000 B
+        let simulator = SwapSimulator::from_pool(pool.clone(), 1_000, 4_000, &buy_token_mint);
+        assert_eq!(
+            simulator
+                .convert(Decimal::from(1_000u64), &token_b_mint())
+                .unwrap(),
+            Decimal::from(200u64)
+        );
+
+        // Buying 200 A takes the same 1000 B
+        let simulator = SwapSimulator::from_pool(pool, 1_000, 4_000, &buy_token_mint);
+        assert_eq!(
+            simulator
+                .convert(Decimal::from(200u64), &token_a_mint())
+                .unwrap(),
+            Decimal::from(1_000u64)
+        );
+    }
+
+    #[test]
+    fn simulate_with_fees() {
+        // 20% fee
+        let pool = SwapPool::unpack(&pool_data((1, 10), (1, 10), 0)).unwrap();
+        let buy_token_mint = token_a_mint();
+
+        let mut simulator = SwapSimulator::from_pool(pool.clone(), 1_000, 4_000, &buy_token_mint);
+        assert_eq!(
+            simulator.best_price(&token_a_mint()).unwrap(),
+            Decimal::from(5u64)
+        );
+        assert_eq!(
+            simulator.best_price(&token_b_mint()).unwrap(),
+            Decimal::from(2u64).try_div(10).unwrap()
+        );
+
+        // 1250 B in, 1000 B after fees
+        let simulator = SwapSimulator::from_pool(pool.clone(), 1_000, 4_000, &buy_token_mint);
+        assert_eq!(
+            simulator
+                .convert(Decimal::from(1_250u64), &token_b_mint())
+                .unwrap(),
+            Decimal::from(200u64)
+        );
+
+        let simulator = SwapSimulator::from_pool(pool, 1_000, 4_000, &buy_token_mint);
+        assert_eq!(
+            simulator
+                .convert(Decimal::from(200u64), &token_a_mint())
+                .unwrap(),
+            Decimal::from(1_250u64)
+        );
+    }
+
+    #[test]
+    fn simulate_beyond_pool() {
+        let pool = SwapPool::unpack(&pool_data((0, 0), (0, 0), 0)).unwrap();
+        let buy_token_mint = token_a_mint();
+        let simulator = SwapSimulator::from_pool(pool, 1_000, 4_000, &buy_token_mint);
+        assert_eq!(
+            simulator.convert(Decimal::from(1_000u64), &token_a_mint()),
+            Err(LendingError::TradeSimulationError.into())
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Time weighted prices for liquidation health checks
+
+use crate::{
+    math::{Decimal, TryDiv},
+    state::TokenConverter,
+};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+/// Token converter that prices tokens at a time weighted average price and trades them on the
+/// wrapped converter. Only the price used to judge obligation health is averaged; the amounts
+/// exchanged in a liquidation still follow the current market.
+pub struct TwapConverter<'a, C: TokenConverter> {
+    spot: C,
+    price: Decimal,
+    base_token_mint: &'a Pubkey,
+}
+
+impl<'a, C: TokenConverter> TwapConverter<'a, C> {
+    /// Create a new TwapConverter from an average price of the base token in the quote token
+    pub fn new(spot: C, price: Decimal, base_token_mint: &'a Pubkey) -> Self {
+        Self {
+            spot,
+            price,
+            base_token_mint,
+        }
+    }
+}
+
+impl<'a, C: TokenConverter> TokenConverter for TwapConverter<'a, C> {
+    fn best_price(&mut self, token_mint: &Pubkey) -> Result<Decimal, ProgramError> {
+        if token_mint == self.base_token_mint {
+            Ok(self.price)
+        } else {
+            Decimal::one().try_div(self.price)
+        }
+    }
+
+    fn convert(
+        self,
+        from_amount: Decimal,
+        from_token_mint: &Pubkey,
+    ) -> Result<Decimal, ProgramError> {
+        self.spot.convert(from_amount, from_token_mint)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::swap_pool::{SwapPool, SwapSimulator};
+
+    #[test]
+    fn prices_at_twap_and_trades_at_spot() {
+        let base_mint = Pubkey::new_unique();
+        let quote_mint = Pubkey::new_unique();
+        let pool = SwapPool {
+            nonce: 0,
+            token_a: Pubkey::new_unique(),
+            token_b: Pubkey::new_unique(),
+            pool_mint: Pubkey::new_unique(),
+            token_a_mint: base_mint,
+            token_b_mint: quote_mint,
+            pool_fee_account: Pubkey::new_unique(),
+            fee: Decimal::zero(),
+        };
+        let spot = || SwapSimulator::from_pool(pool.clone(), 1_000, 10_000, &base_mint);
+        let twap = Decimal::from(8u64);
+
+        let mut converter = TwapConverter::new(spot(), twap, &base_mint);
+        assert_eq!(converter.best_price(&base_mint).unwrap(), twap);
+        assert_eq!(
+            converter.best_price(&quote_mint).unwrap(),
+            Decimal::one().try_div(8).unwrap()
+        );
+        assert_eq!(
+            converter
+                .convert(Decimal::from(100u64), &quote_mint)
+                .unwrap(),
+            spot().convert(Decimal::from(100u64), &quote_mint).unwrap()
+        );
+    }
+}
@@ -3,6 +3,7 @@
 mod helpers;
 
 use helpers::*;
+use solana_program::program_pack::Pack;
 use solana_program_test::*;
 use solana_sdk::{
     pubkey::Pubkey,
@@ -10,10 +11,10 @@ use solana_sdk::{
     transaction::Transaction,
 };
 use spl_token_lending::{
-    instruction::accrue_reserve_interest,
+    instruction::{accrue_reserve_interest, accrue_reserve_interest_with_rate_histories},
     math::{Decimal, Rate, TryMul},
     processor::process_instruction,
-    state::SLOTS_PER_YEAR,
+    state::{BorrowRateCurve, RateHistory, SLOTS_PER_YEAR},
 };
 
 const LAMPORTS_TO_SOL: u64 = 1_000_000_000;
@@ -42,8 +43,8 @@ async fn test_success() {
 
     // Configure reserve to a fixed borrow rate of 1%
     const BORROW_RATE: u8 = 1;
-    reserve_config.min_borrow_rate = BORROW_RATE;
-    reserve_config.optimal_borrow_rate = BORROW_RATE;
+    reserve_config.borrow_rate_curve =
+        BorrowRateCurve::from_single_kink(100, BORROW_RATE, BORROW_RATE, BORROW_RATE);
     reserve_config.optimal_utilization_rate = 100;
 
     let usdc_reserve = add_reserve(
@@ -105,4 +106,74 @@ async fn test_success() {
         sol_reserve.liquidity.borrowed_amount_wads,
         usdc_reserve.liquidity.borrowed_amount_wads
     );
+
+    // rates are recorded for off-chain readers
+    assert_eq!(sol_reserve.borrow_rate, Rate::from_percent(BORROW_RATE));
+    assert_eq!(
+        sol_reserve.supply_rate,
+        sol_reserve.current_supply_rate().unwrap()
+    );
+    assert!(sol_reserve.supply_rate > Rate::zero());
+    assert!(sol_reserve.supply_rate < sol_reserve.borrow_rate);
+}
+
+#[tokio::test]
+async fn test_rate_history() {
+    let mut test = ProgramTest::new(
+        "spl_token_lending",
+        spl_token_lending::id(),
+        processor!(process_instruction),
+    );
+
+    // limit to track compute unit increase
+    test.set_bpf_compute_max_units(120_000);
+
+    let user_accounts_owner = Keypair::new();
+    let usdc_mint = add_usdc_mint(&mut test);
+    let lending_market = add_lending_market(&mut test, usdc_mint.pubkey);
+
+    let usdc_reserve = add_reserve(
+        &mut test,
+        &user_accounts_owner,
+        &lending_market,
+        AddReserveArgs {
+            borrow_amount: 100,
+            liquidity_amount: INITIAL_USDC_RESERVE_SUPPLY_FRACTIONAL,
+            liquidity_mint_decimals: usdc_mint.decimals,
+            liquidity_mint_pubkey: usdc_mint.pubkey,
+            slots_elapsed: SLOTS_PER_YEAR,
+            config: TEST_RESERVE_CONFIG,
+            ..AddReserveArgs::default()
+        },
+    );
+    let rate_history = add_rate_history(&mut test, &usdc_reserve, 1);
+
+    let (mut banks_client, payer, recent_blockhash) = test.start().await;
+    let mut transaction = Transaction::new_with_payer(
+        &[accrue_reserve_interest_with_rate_histories(
+            spl_token_lending::id(),
+            vec![(usdc_reserve.pubkey, Some(rate_history))],
+        )],
+        Some(&payer.pubkey()),
+    );
+
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_ok());
+
+    let usdc_reserve = usdc_reserve.get_state(&mut banks_client).await;
+    let rate_history_account = banks_client
+        .get_account(rate_history)
+        .await
+        .unwrap()
+        .unwrap();
+    let rate_history = RateHistory::unpack(&rate_history_account.data).unwrap();
+
+    assert_eq!(rate_history.snapshots.len(), 1);
+    let snapshot = rate_history.latest().unwrap();
+    assert_eq!(snapshot.slot, usdc_reserve.last_update_slot);
+    assert_eq!(snapshot.borrow_rate, usdc_reserve.borrow_rate);
+    assert_eq!(
+        snapshot.utilization_rate,
+        usdc_reserve.liquidity.utilization_rate().unwrap()
+    );
 }
@@ -21,7 +21,8 @@ use spl_token_lending::{
     math::{Decimal, Rate, TryAdd, TryMul},
     processor::process_instruction,
     state::{
-        LendingMarket, NewReserveParams, Obligation, Reserve, ReserveCollateral, ReserveConfig,
+        AdaptiveRateConfig, BorrowRateCurve, BorrowRateCurvePoint, LendingMarket, NewReserveParams,
+        Obligation, PriceSource, RateHistory, Reserve, ReserveCollateral, ReserveConfig,
         ReserveFees, ReserveLiquidity, INITIAL_COLLATERAL_RATIO, PROGRAM_VERSION,
     },
 };
@@ -29,19 +30,43 @@ use std::str::FromStr;
 pub mod genesis;
 use genesis::GenesisAccounts;
 
+const fn curve_point(utilization_rate_bps: u16, borrow_rate_bps: u16) -> BorrowRateCurvePoint {
+    BorrowRateCurvePoint {
+        utilization_rate_bps,
+        borrow_rate_bps,
+    }
+}
+
+/// Borrow rate curve from 0% through 4% at 80% utilization to 30%
+pub const TEST_BORROW_RATE_CURVE: BorrowRateCurve = BorrowRateCurve {
+    num_points: 3,
+    points: [
+        curve_point(0, 0),
+        curve_point(8_000, 400),
+        curve_point(10_000, 3_000),
+        curve_point(0, 0),
+        curve_point(0, 0),
+        curve_point(0, 0),
+        curve_point(0, 0),
+        curve_point(0, 0),
+    ],
+};
+
 pub const TEST_RESERVE_CONFIG: ReserveConfig = ReserveConfig {
     optimal_utilization_rate: 80,
     loan_to_value_ratio: 50,
     liquidation_bonus: 5,
     liquidation_threshold: 55,
-    min_borrow_rate: 0,
-    optimal_borrow_rate: 4,
-    max_borrow_rate: 30,
     fees: ReserveFees {
         borrow_fee_wad: 100_000_000_000,
         /// 0.00001% (Aave borrow fee)
         host_fee_percentage: 20,
     },
+    max_margin_borrow_amount: u64::MAX,
+    liquidation_twap_slots: 0,
+    borrow_rate_curve: TEST_BORROW_RATE_CURVE,
+    adaptive_rate: AdaptiveRateConfig::DISABLED,
+    reserve_factor: 0,
 };
 
 pub const USDC_MINT: &str = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
@@ -143,6 +168,21 @@ pub fn add_lending_market(test: &mut ProgramTest, quote_token_mint: Pubkey) -> T
     }
 }
 
+pub fn add_rate_history(
+    test: &mut ProgramTest,
+    reserve: &TestReserve,
+    snapshot_interval: u64,
+) -> Pubkey {
+    let pubkey = Pubkey::new_unique();
+    test.add_packable_account(
+        pubkey,
+        u32::MAX as u64,
+        &RateHistory::new(reserve.pubkey, snapshot_interval),
+        &spl_token_lending::id(),
+    );
+    pubkey
+}
+
 pub struct AddObligationArgs<'a> {
     pub borrow_reserve: &'a TestReserve,
     pub collateral_reserve: &'a TestReserve,
@@ -354,6 +394,7 @@ pub fn add_reserve(
         current_slot: 1u64.wrapping_sub(slots_elapsed),
         lending_market: lending_market.pubkey,
         dex_market: dex_market_pubkey.into(),
+        price_source: PriceSource::DexMarket,
         liquidity: reserve_liquidity,
         collateral: reserve_collateral,
         config,
@@ -564,23 +605,9 @@ impl TestLendingMarket {
             user_accounts_owner,
         } = args;
 
-        let dex_market_orders_pubkey = if repay_reserve.dex_market.is_none() {
-            dex_market.asks_pubkey
-        } else {
-            dex_market.bids_pubkey
-        };
-
-        let memory_keypair = Keypair::new();
         let user_transfer_authority = Keypair::new();
         let mut transaction = Transaction::new_with_payer(
             &[
-                create_account(
-                    &payer.pubkey(),
-                    &memory_keypair.pubkey(),
-                    0,
-                    65548,
-                    &spl_token_lending::id(),
-                ),
                 approve(
                     &spl_token::id(),
                     &repay_reserve.user_liquidity_account,
@@ -604,8 +631,9 @@ impl TestLendingMarket {
                     self.authority,
                     user_transfer_authority.pubkey(),
                     dex_market.pubkey,
-                    dex_market_orders_pubkey,
-                    memory_keypair.pubkey(),
+                    dex_market.bids_pubkey,
+                    dex_market.asks_pubkey,
+                    None,
                 ),
             ],
             Some(&payer.pubkey()),
@@ -613,12 +641,7 @@ impl TestLendingMarket {
 
         let recent_blockhash = banks_client.get_recent_blockhash().await.unwrap();
         transaction.sign(
-            &[
-                &payer,
-                &memory_keypair,
-                &user_accounts_owner,
-                &user_transfer_authority,
-            ],
+            &[&payer, &user_accounts_owner, &user_transfer_authority],
             recent_blockhash,
         );
         assert!(banks_client.process_transaction(transaction).await.is_ok());
@@ -630,7 +653,6 @@ impl TestLendingMarket {
         payer: &Keypair,
         args: BorrowArgs<'_>,
     ) {
-        let memory_keypair = Keypair::new();
         let user_transfer_authority = Keypair::new();
 
         let BorrowArgs {
@@ -643,12 +665,6 @@ impl TestLendingMarket {
             obligation,
         } = args;
 
-        let dex_market_orders_pubkey = if deposit_reserve.dex_market.is_none() {
-            dex_market.asks_pubkey
-        } else {
-            dex_market.bids_pubkey
-        };
-
         let approve_amount = if borrow_amount_type == BorrowAmountType::CollateralDepositAmount {
             amount
         } else {
@@ -666,13 +682,6 @@ impl TestLendingMarket {
                     approve_amount,
                 )
                 .unwrap(),
-                create_account(
-                    &payer.pubkey(),
-                    &memory_keypair.pubkey(),
-                    0,
-                    65548,
-                    &spl_token_lending::id(),
-                ),
                 borrow_reserve_liquidity(
                     spl_token_lending::id(),
                     amount,
@@ -691,8 +700,8 @@ impl TestLendingMarket {
                     obligation.token_mint,
                     obligation.token_account,
                     dex_market.pubkey,
-                    dex_market_orders_pubkey,
-                    memory_keypair.pubkey(),
+                    dex_market.bids_pubkey,
+                    dex_market.asks_pubkey,
                     Some(deposit_reserve.collateral_host),
                 ),
             ],
@@ -701,12 +710,7 @@ impl TestLendingMarket {
 
         let recent_blockhash = banks_client.get_recent_blockhash().await.unwrap();
         transaction.sign(
-            &vec![
-                payer,
-                user_accounts_owner,
-                &memory_keypair,
-                &user_transfer_authority,
-            ],
+            &vec![payer, user_accounts_owner, &user_transfer_authority],
             recent_blockhash,
         );
 
@@ -719,7 +723,6 @@ impl TestLendingMarket {
         payer: &Keypair,
         args: MarginBorrowArgs<'_>,
     ) {
-        let memory_keypair = Keypair::new();
         let user_transfer_authority = Keypair::new();
 
         let MarginBorrowArgs {
@@ -733,12 +736,6 @@ impl TestLendingMarket {
             obligation,
         } = args;
 
-        let dex_market_orders_pubkey = if deposit_reserve.dex_market.is_none() {
-            dex_market.asks_pubkey
-        } else {
-            dex_market.bids_pubkey
-        };
-
         let mut transaction = Transaction::new_with_payer(
             &[
                 approve(
@@ -750,13 +747,6 @@ impl TestLendingMarket {
                     collateral_amount,
                 )
                 .unwrap(),
-                create_account(
-                    &payer.pubkey(),
-                    &memory_keypair.pubkey(),
-                    0,
-                    65548,
-                    &spl_token_lending::id(),
-                ),
                 margin_borrow_reserve_liquidity(
                     spl_token_lending::id(),
                     collateral_amount,
@@ -776,8 +766,8 @@ impl TestLendingMarket {
                     obligation.token_mint,
                     obligation.token_account,
                     dex_market.pubkey,
-                    dex_market_orders_pubkey,
-                    memory_keypair.pubkey(),
+                    dex_market.bids_pubkey,
+                    dex_market.asks_pubkey,
                     Some(deposit_reserve.collateral_host),
                 ),
             ],
@@ -786,12 +776,7 @@ impl TestLendingMarket {
 
         let recent_blockhash = banks_client.get_recent_blockhash().await.unwrap();
         transaction.sign(
-            &vec![
-                payer,
-                user_accounts_owner,
-                &memory_keypair,
-                &user_transfer_authority,
-            ],
+            &vec![payer, user_accounts_owner, &user_transfer_authority],
             recent_blockhash,
         );
 
@@ -951,6 +936,7 @@ impl TestReserve {
                     lending_market.owner.pubkey(),
                     user_transfer_authority_keypair.pubkey(),
                     dex_market_pubkey,
+                    PriceSource::DexMarket,
                 ),
             ],
             Some(&payer.pubkey()),
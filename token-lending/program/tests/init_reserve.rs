@@ -14,7 +14,9 @@ use spl_token_lending::{
     error::LendingError,
     instruction::init_reserve,
     processor::process_instruction,
-    state::{ReserveFees, INITIAL_COLLATERAL_RATIO},
+    state::{
+        BorrowRateCurve, BorrowRateCurvePoint, PriceSource, ReserveFees, INITIAL_COLLATERAL_RATIO,
+    },
 };
 
 #[tokio::test]
@@ -124,6 +126,7 @@ async fn test_already_initialized() {
             lending_market.owner.pubkey(),
             user_transfer_authority.pubkey(),
             Some(sol_usdc_dex_market.pubkey),
+            PriceSource::DexMarket,
         )],
         Some(&payer.pubkey()),
     );
@@ -229,4 +232,72 @@ async fn test_invalid_fees() {
             )
         );
     }
+
+    // borrow rate decreasing with utilization
+    {
+        let mut config = TEST_RESERVE_CONFIG;
+        config.borrow_rate_curve = BorrowRateCurve::new(&[
+            BorrowRateCurvePoint {
+                utilization_rate_bps: 0,
+                borrow_rate_bps: 500,
+            },
+            BorrowRateCurvePoint {
+                utilization_rate_bps: 8_000,
+                borrow_rate_bps: 400,
+            },
+            BorrowRateCurvePoint {
+                utilization_rate_bps: 10_000,
+                borrow_rate_bps: 3_000,
+            },
+        ])
+        .unwrap();
+
+        assert_eq!(
+            TestReserve::init(
+                "sol".to_owned(),
+                &mut banks_client,
+                &lending_market,
+                RESERVE_AMOUNT,
+                config,
+                spl_token::native_mint::id(),
+                sol_user_liquidity_account,
+                &payer,
+                &user_accounts_owner,
+                &sol_usdc_dex_market,
+            )
+            .await
+            .unwrap_err(),
+            TransactionError::InstructionError(
+                8,
+                InstructionError::Custom(LendingError::InvalidConfig as u32)
+            )
+        );
+    }
+
+    // no borrow rate curve
+    {
+        let mut config = TEST_RESERVE_CONFIG;
+        config.borrow_rate_curve = BorrowRateCurve::EMPTY;
+
+        assert_eq!(
+            TestReserve::init(
+                "sol".to_owned(),
+                &mut banks_client,
+                &lending_market,
+                RESERVE_AMOUNT,
+                config,
+                spl_token::native_mint::id(),
+                sol_user_liquidity_account,
+                &payer,
+                &user_accounts_owner,
+                &sol_usdc_dex_market,
+            )
+            .await
+            .unwrap_err(),
+            TransactionError::InstructionError(
+                8,
+                InstructionError::Custom(LendingError::InvalidConfig as u32)
+            )
+        );
+    }
 }
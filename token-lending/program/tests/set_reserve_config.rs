@@ -0,0 +1,114 @@
+#![cfg(feature = "test-bpf")]
+
+mod helpers;
+
+use helpers::*;
+use solana_program_test::*;
+use solana_sdk::{
+    instruction::InstructionError,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use spl_token_lending::{
+    error::LendingError, instruction::set_reserve_config, processor::process_instruction,
+};
+
+#[tokio::test]
+async fn test_success() {
+    let mut test = ProgramTest::new(
+        "spl_token_lending",
+        spl_token_lending::id(),
+        processor!(process_instruction),
+    );
+
+    let user_accounts_owner = Keypair::new();
+    let usdc_mint = add_usdc_mint(&mut test);
+    let lending_market = add_lending_market(&mut test, usdc_mint.pubkey);
+
+    let usdc_reserve = add_reserve(
+        &mut test,
+        &user_accounts_owner,
+        &lending_market,
+        AddReserveArgs {
+            liquidity_amount: 42,
+            liquidity_mint_decimals: usdc_mint.decimals,
+            liquidity_mint_pubkey: usdc_mint.pubkey,
+            config: TEST_RESERVE_CONFIG,
+            ..AddReserveArgs::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = test.start().await;
+
+    let mut config = TEST_RESERVE_CONFIG;
+    config.loan_to_value_ratio = 60;
+    config.borrow_rate_curve.points[2].borrow_rate_bps = 5_000;
+    config.max_margin_borrow_amount = 1_000;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[set_reserve_config(
+            spl_token_lending::id(),
+            config,
+            usdc_reserve.pubkey,
+            lending_market.pubkey,
+            lending_market.owner.pubkey(),
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &lending_market.owner], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_ok());
+
+    let reserve = usdc_reserve.get_state(&mut banks_client).await;
+    assert_eq!(reserve.config, config);
+}
+
+#[tokio::test]
+async fn test_invalid_owner() {
+    let mut test = ProgramTest::new(
+        "spl_token_lending",
+        spl_token_lending::id(),
+        processor!(process_instruction),
+    );
+
+    let user_accounts_owner = Keypair::new();
+    let usdc_mint = add_usdc_mint(&mut test);
+    let lending_market = add_lending_market(&mut test, usdc_mint.pubkey);
+
+    let usdc_reserve = add_reserve(
+        &mut test,
+        &user_accounts_owner,
+        &lending_market,
+        AddReserveArgs {
+            liquidity_amount: 42,
+            liquidity_mint_decimals: usdc_mint.decimals,
+            liquidity_mint_pubkey: usdc_mint.pubkey,
+            config: TEST_RESERVE_CONFIG,
+            ..AddReserveArgs::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = test.start().await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[set_reserve_config(
+            spl_token_lending::id(),
+            TEST_RESERVE_CONFIG,
+            usdc_reserve.pubkey,
+            lending_market.pubkey,
+            user_accounts_owner.pubkey(),
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user_accounts_owner], recent_blockhash);
+    assert_eq!(
+        banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(LendingError::InvalidMarketOwner as u32)
+        )
+    );
+}
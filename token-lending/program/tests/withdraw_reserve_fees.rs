@@ -0,0 +1,161 @@
+#![cfg(feature = "test-bpf")]
+
+mod helpers;
+
+use helpers::*;
+use solana_program_test::*;
+use solana_sdk::{
+    instruction::InstructionError,
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use spl_token_lending::{
+    error::LendingError,
+    instruction::{accrue_reserve_interest, withdraw_reserve_fees},
+    math::{Decimal, TrySub},
+    processor::process_instruction,
+    state::{BorrowRateCurve, SLOTS_PER_YEAR},
+};
+
+const FRACTIONAL_TO_USDC: u64 = 1_000_000;
+
+#[tokio::test]
+async fn test_success() {
+    let mut test = ProgramTest::new(
+        "spl_token_lending",
+        spl_token_lending::id(),
+        processor!(process_instruction),
+    );
+
+    let user_accounts_owner = Keypair::new();
+    let usdc_mint = add_usdc_mint(&mut test);
+    let lending_market = add_lending_market(&mut test, usdc_mint.pubkey);
+
+    // Fixed borrow rate of 10%, half of which is kept as fees
+    let mut reserve_config = TEST_RESERVE_CONFIG;
+    reserve_config.borrow_rate_curve = BorrowRateCurve::from_single_kink(100, 10, 10, 10);
+    reserve_config.optimal_utilization_rate = 100;
+    reserve_config.reserve_factor = 50;
+
+    let usdc_reserve = add_reserve(
+        &mut test,
+        &user_accounts_owner,
+        &lending_market,
+        AddReserveArgs {
+            borrow_amount: 100 * FRACTIONAL_TO_USDC,
+            liquidity_amount: 100 * FRACTIONAL_TO_USDC,
+            liquidity_mint_decimals: usdc_mint.decimals,
+            liquidity_mint_pubkey: usdc_mint.pubkey,
+            slots_elapsed: SLOTS_PER_YEAR,
+            config: reserve_config,
+            ..AddReserveArgs::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = test.start().await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[accrue_reserve_interest(
+            spl_token_lending::id(),
+            vec![usdc_reserve.pubkey],
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_ok());
+
+    let reserve = usdc_reserve.get_state(&mut banks_client).await;
+    let fees = reserve.liquidity.accumulated_fees_wads;
+    let withdraw_amount = fees.try_floor_u64().unwrap();
+    assert!(withdraw_amount > 0);
+
+    let balance_before =
+        get_token_balance(&mut banks_client, usdc_reserve.user_liquidity_account).await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[withdraw_reserve_fees(
+            spl_token_lending::id(),
+            usdc_reserve.pubkey,
+            usdc_reserve.liquidity_supply,
+            usdc_reserve.user_liquidity_account,
+            lending_market.pubkey,
+            lending_market.authority,
+            lending_market.owner.pubkey(),
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &lending_market.owner], recent_blockhash);
+    assert!(banks_client.process_transaction(transaction).await.is_ok());
+
+    let balance_after =
+        get_token_balance(&mut banks_client, usdc_reserve.user_liquidity_account).await;
+    assert_eq!(balance_after - balance_before, withdraw_amount);
+
+    let reserve_after = usdc_reserve.get_state(&mut banks_client).await;
+    assert_eq!(
+        reserve_after.liquidity.accumulated_fees_wads,
+        fees.try_sub(Decimal::from(withdraw_amount)).unwrap()
+    );
+    assert_eq!(
+        reserve_after.liquidity.available_amount,
+        reserve.liquidity.available_amount - withdraw_amount
+    );
+    // depositors' supply is untouched by the withdrawal
+    assert_eq!(
+        reserve_after.liquidity.total_supply(),
+        reserve.liquidity.total_supply()
+    );
+}
+
+#[tokio::test]
+async fn test_invalid_owner() {
+    let mut test = ProgramTest::new(
+        "spl_token_lending",
+        spl_token_lending::id(),
+        processor!(process_instruction),
+    );
+
+    let user_accounts_owner = Keypair::new();
+    let usdc_mint = add_usdc_mint(&mut test);
+    let lending_market = add_lending_market(&mut test, usdc_mint.pubkey);
+
+    let usdc_reserve = add_reserve(
+        &mut test,
+        &user_accounts_owner,
+        &lending_market,
+        AddReserveArgs {
+            liquidity_amount: 42,
+            liquidity_mint_decimals: usdc_mint.decimals,
+            liquidity_mint_pubkey: usdc_mint.pubkey,
+            config: TEST_RESERVE_CONFIG,
+            ..AddReserveArgs::default()
+        },
+    );
+
+    let (mut banks_client, payer, recent_blockhash) = test.start().await;
+
+    let mut transaction = Transaction::new_with_payer(
+        &[withdraw_reserve_fees(
+            spl_token_lending::id(),
+            usdc_reserve.pubkey,
+            usdc_reserve.liquidity_supply,
+            usdc_reserve.user_liquidity_account,
+            lending_market.pubkey,
+            lending_market.authority,
+            user_accounts_owner.pubkey(),
+        )],
+        Some(&payer.pubkey()),
+    );
+    transaction.sign(&[&payer, &user_accounts_owner], recent_blockhash);
+    assert_eq!(
+        banks_client
+            .process_transaction(transaction)
+            .await
+            .unwrap_err()
+            .unwrap(),
+        TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(LendingError::InvalidMarketOwner as u32)
+        )
+    );
+}